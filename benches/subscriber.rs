@@ -0,0 +1,68 @@
+// What tracing costs the instrumented code, without any I/O: spans are handed to an exporter
+// which drops them. Run with `cargo bench`, and compare with a baseline saved from the previous
+// release with `cargo bench -- --save-baseline <release>` to catch regressions.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::hint::black_box;
+use tracing_datadog_apm::datadog_client::{Client, ServiceName, SpanName, SpanType, Traces};
+use tracing_datadog_apm::span::SpanBuilder;
+use tracing_datadog_apm::subscriber::{TracingSubscriberDatadog, TracingSubscriberDatadogConfig};
+
+fn dispatch() -> tracing::Dispatch {
+    tracing::Dispatch::new(TracingSubscriberDatadog::new(
+        Client::create_with_exporter(|traces: Traces| {
+            black_box(traces);
+        }),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("request"), (ServiceName("bench"), SpanType::Web))
+            .add_mapping(SpanName("query"), (ServiceName("bench-db"), SpanType::Db)),
+    ))
+}
+
+fn bench_spans(c: &mut Criterion) {
+    tracing::dispatcher::with_default(&dispatch(), || {
+        c.bench_function("root span", |b| {
+            b.iter(|| tracing::info_span!("request", resource = "GET /orders").in_scope(|| {}))
+        });
+        c.bench_function("trace of 10 spans", |b| {
+            b.iter(|| {
+                tracing::info_span!("request", resource = "GET /orders").in_scope(|| {
+                    for _ in 0..9 {
+                        tracing::info_span!("query", resource = "SELECT").in_scope(|| {});
+                    }
+                })
+            })
+        });
+        c.bench_function("unmapped span", |b| {
+            b.iter(|| tracing::info_span!("unmapped").in_scope(|| {}))
+        });
+        c.bench_function("span with fields", |b| {
+            b.iter(|| {
+                tracing::info_span!(
+                    "request",
+                    resource = "GET /orders",
+                    http_method = "GET",
+                    http_url = "/orders?page=2",
+                    http_status_code = 200u64
+                )
+                .in_scope(|| {})
+            })
+        });
+    });
+}
+
+fn bench_span_builder(c: &mut Criterion) {
+    let mut span_builder = SpanBuilder::default();
+    span_builder
+        .service(ServiceName("bench"))
+        .name(SpanName("request"))
+        .resource(String::from("GET /orders"))
+        .add_tag("http.method", "GET")
+        .add_tag("http.url", "/orders?page=2");
+    c.bench_function("span builder build", |b| {
+        b.iter(|| black_box(span_builder.build()))
+    });
+}
+
+criterion_group!(benches, bench_spans, bench_span_builder);
+criterion_main!(benches);
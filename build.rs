@@ -0,0 +1,19 @@
+// Records the version of the Rust compiler, which is sent to the agent in the
+// `Datadog-Meta-Lang-Version` header like official tracers send the version of their runtime
+use std::process::Command;
+
+fn main() {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    let version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .and_then(|output| output.split_whitespace().nth(1).map(String::from))
+        .unwrap_or_else(|| String::from("unknown"));
+    println!(
+        "cargo:rustc-env=TRACING_DATADOG_APM_RUSTC_VERSION={}",
+        version
+    );
+    println!("cargo:rerun-if-changed=build.rs");
+}
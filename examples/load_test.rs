@@ -0,0 +1,207 @@
+//! Generates spans at a fixed rate against a mock agent, and reports the throughput of the
+//! exporter, the spans it dropped, and what tracing costs the instrumented thread:
+//!
+//! ```text
+//! cargo run --release --example load_test -- [spans per second] [seconds]
+//! ```
+//!
+//! Every span is a trace of its own, so the traces the mock agent receives are the spans which
+//! made it through sampling, queueing and sending.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing_datadog_apm::datadog_client::{
+    Client, ClientConfig, ServiceName, SpanName, SpanType, TRACE_COUNT_HEADER,
+};
+use tracing_datadog_apm::subscriber::{TracingSubscriberDatadog, TracingSubscriberDatadogConfig};
+
+// Counts the allocations of each thread, to report those of the instrumented thread
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS
+            .try_with(|allocations| allocations.set(allocations.get() + 1))
+            .ok();
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS
+            .try_with(|allocations| allocations.set(allocations.get() + 1))
+            .ok();
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn thread_allocations() -> u64 {
+    ALLOCATIONS.with(Cell::get)
+}
+
+#[derive(Default)]
+struct AgentCounters {
+    requests: AtomicU64,
+    traces: AtomicU64,
+    bytes: AtomicU64,
+}
+
+// Accepts every payload, like an agent with room to spare
+fn spawn_mock_agent() -> (u16, Arc<AgentCounters>) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind the mock agent");
+    let port = listener.local_addr().unwrap().port();
+    let counters = Arc::new(AgentCounters::default());
+    let agent_counters = Arc::clone(&counters);
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let counters = Arc::clone(&agent_counters);
+            std::thread::spawn(move || serve(stream, &counters));
+        }
+    });
+    (port, counters)
+}
+
+fn serve(stream: TcpStream, counters: &AgentCounters) {
+    let mut writer = stream
+        .try_clone()
+        .expect("Failed to clone the agent connection");
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut content_length = 0;
+        let mut trace_count = 0;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return;
+            }
+            if line == "\r\n" {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or(0);
+                } else if name.eq_ignore_ascii_case(TRACE_COUNT_HEADER) {
+                    trace_count = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        if reader.read_exact(&mut body).is_err() {
+            return;
+        }
+        counters.requests.fetch_add(1, Ordering::Relaxed);
+        counters.traces.fetch_add(trace_count, Ordering::Relaxed);
+        counters
+            .bytes
+            .fetch_add(content_length as u64, Ordering::Relaxed);
+        let response =
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n{}";
+        if writer.write_all(response.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+fn percentile(sorted: &[Duration], percentile: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    sorted[((sorted.len() - 1) as f64 * percentile).round() as usize]
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let spans_per_sec: u64 = args
+        .next()
+        .and_then(|arg| arg.parse().ok())
+        .unwrap_or(10_000);
+    let seconds: u64 = args.next().and_then(|arg| arg.parse().ok()).unwrap_or(10);
+
+    let (port, agent) = spawn_mock_agent();
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(
+            ClientConfig::new()
+                .datadog_agent_host("127.0.0.1")
+                .datadog_agent_port(port as u32),
+        ),
+        TracingSubscriberDatadogConfig::new().add_mapping(
+            SpanName("request"),
+            (ServiceName("load-test"), SpanType::Web),
+        ),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+
+    let total_spans = spans_per_sec * seconds;
+    let interval = Duration::from_secs(1) / spans_per_sec.max(1) as u32;
+    let mut latencies = Vec::with_capacity(total_spans as usize);
+    let mut allocations = 0;
+    let started = Instant::now();
+    tracing::dispatcher::with_default(&dispatch, || {
+        for i in 0..total_spans {
+            if let Some(ahead) =
+                (started + interval * i as u32).checked_duration_since(Instant::now())
+            {
+                std::thread::sleep(ahead);
+            }
+            let allocations_before = thread_allocations();
+            let span_started = Instant::now();
+            tracing::info_span!("request", resource = "GET /load").in_scope(|| {});
+            latencies.push(span_started.elapsed());
+            allocations += thread_allocations() - allocations_before;
+        }
+    });
+    let generated_in = started.elapsed();
+    let shed_traces = dispatch
+        .downcast_ref::<TracingSubscriberDatadog>()
+        .map_or(0, TracingSubscriberDatadog::shed_traces);
+    // dropping the subscriber flushes its client
+    drop(dispatch);
+    let exported_in = started.elapsed();
+
+    latencies.sort_unstable();
+    let received = agent.traces.load(Ordering::Relaxed);
+    println!(
+        "generated {} spans in {:.2?} ({:.0} spans/s)",
+        total_spans,
+        generated_in,
+        total_spans as f64 / generated_in.as_secs_f64()
+    );
+    println!(
+        "agent received {} spans in {} requests, {} bytes ({:.0} spans/s)",
+        received,
+        agent.requests.load(Ordering::Relaxed),
+        agent.bytes.load(Ordering::Relaxed),
+        received as f64 / exported_in.as_secs_f64()
+    );
+    println!(
+        "dropped {} spans ({} shed by the subscriber)",
+        total_spans.saturating_sub(received),
+        shed_traces
+    );
+    println!(
+        "added latency per span: p50 {:.2?}, p99 {:.2?}, max {:.2?}",
+        percentile(&latencies, 0.5),
+        percentile(&latencies, 0.99),
+        latencies.last().copied().unwrap_or_default()
+    );
+    println!(
+        "allocations per span on the instrumented thread: {:.1}",
+        allocations as f64 / total_spans.max(1) as f64
+    );
+}
@@ -0,0 +1,347 @@
+// Agentless mode: traces are posted to the public trace intake of Datadog, with an API key, in
+// the protobuf format the agent itself sends (`AgentPayload` of the datadog-agent `pb` package).
+// The encoder below only writes the fields the intake needs:
+//
+// AgentPayload { 1: hostName, 2: env, 5: repeated TracerPayload }
+// TracerPayload { 2: languageName, 4: tracerVersion, 6: repeated TraceChunk, 8: env,
+//                 9: hostname, 10: appVersion }
+// TraceChunk { 1: priority, 3: repeated Span }
+// Span { 1: service, 2: name, 3: resource, 4: traceID, 5: spanID, 6: parentID, 7: start,
+//        8: duration, 9: error, 10: meta map, 11: metrics map, 12: type, 13: meta_struct map,
+//        14: repeated SpanLink }
+// SpanLink { 1: traceID, 3: spanID, 4: attributes map }
+
+use super::base64;
+use super::span::SAMPLING_PRIORITY_METRIC_KEY;
+use super::v07::{self, TracerMetadata};
+use std::env;
+
+pub(crate) const API_KEY_HEADER: &str = "DD-API-KEY";
+pub(crate) const CONTENT_TYPE: &str = "application/x-protobuf";
+
+const DEFAULT_DD_SITE: &str = "datadoghq.com";
+
+/// The Datadog site of the `DD_SITE` env var, `datadoghq.com` by default.
+pub(crate) fn site_from_env() -> String {
+    let site = env::var("DD_SITE")
+        .ok()
+        .filter(|site| !site.trim().is_empty())
+        .unwrap_or_else(|| String::from(DEFAULT_DD_SITE));
+    String::from(site.trim().trim_end_matches('/'))
+}
+
+#[inline]
+pub(crate) fn intake_url(site: &str) -> String {
+    format!("https://trace.agent.{}/api/v0.2/traces", site)
+}
+
+/// Encodes traces, as serialized for the agent's v0.3 format, into an intake payload. The
+/// traces are expected to share their env and version, like the payloads of `group_by_service`,
+/// and fall back to those of the process. Rejected traces, which the agent would drop, are left
+/// out, as the intake keeps every trace.
+pub(crate) fn encode(trace_json: &serde_json::Value, tracer_metadata: &TracerMetadata) -> Vec<u8> {
+    let traces = trace_json.as_array().map(Vec::as_slice).unwrap_or_default();
+    let env = v07::unified_service_tag(trace_json, "env").unwrap_or(&tracer_metadata.env);
+    let app_version =
+        v07::unified_service_tag(trace_json, "version").unwrap_or(&tracer_metadata.app_version);
+    let hostname = tracer_metadata.hostname.as_str();
+
+    let mut tracer_payload = Vec::new();
+    write_string(&mut tracer_payload, 2, "rust");
+    write_string(&mut tracer_payload, 4, env!("CARGO_PKG_VERSION"));
+    for trace in traces {
+        let mut chunk = Vec::new();
+        let spans = trace.as_array().map(Vec::as_slice).unwrap_or_default();
+        let priority = spans
+            .iter()
+            .find_map(|span| span["metrics"][SAMPLING_PRIORITY_METRIC_KEY].as_f64())
+            .map_or(1, |priority| priority as i32);
+        if priority <= 0 {
+            continue;
+        }
+        write_int32_field(&mut chunk, 1, priority);
+        for span in spans {
+            write_bytes(&mut chunk, 3, &encode_span(span));
+        }
+        write_bytes(&mut tracer_payload, 6, &chunk);
+    }
+    write_string(&mut tracer_payload, 8, env);
+    write_string(&mut tracer_payload, 9, hostname);
+    write_string(&mut tracer_payload, 10, app_version);
+
+    let mut agent_payload = Vec::new();
+    write_string(&mut agent_payload, 1, hostname);
+    write_string(&mut agent_payload, 2, env);
+    write_bytes(&mut agent_payload, 5, &tracer_payload);
+    agent_payload
+}
+
+#[inline]
+fn encode_span(span: &serde_json::Value) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    let string = |key: &str| span[key].as_str().unwrap_or_default();
+    write_string(&mut encoded, 1, string("service"));
+    write_string(&mut encoded, 2, string("name"));
+    write_string(&mut encoded, 3, string("resource"));
+    write_varint_field(
+        &mut encoded,
+        4,
+        span["trace_id"].as_u64().unwrap_or_default(),
+    );
+    write_varint_field(
+        &mut encoded,
+        5,
+        span["span_id"].as_u64().unwrap_or_default(),
+    );
+    write_varint_field(
+        &mut encoded,
+        6,
+        span["parent_id"].as_u64().unwrap_or_default(),
+    );
+    write_varint_field(
+        &mut encoded,
+        7,
+        span["start"].as_i64().unwrap_or_default() as u64,
+    );
+    write_varint_field(
+        &mut encoded,
+        8,
+        span["duration"].as_i64().unwrap_or_default() as u64,
+    );
+    write_varint_field(
+        &mut encoded,
+        9,
+        span["error"].as_i64().unwrap_or_default() as u64,
+    );
+    for (key, value) in span["meta"].as_object().into_iter().flatten() {
+        let mut entry = Vec::new();
+        write_string(&mut entry, 1, key);
+        write_string(&mut entry, 2, value.as_str().unwrap_or_default());
+        write_bytes(&mut encoded, 10, &entry);
+    }
+    for (key, value) in span["metrics"].as_object().into_iter().flatten() {
+        let mut entry = Vec::new();
+        write_string(&mut entry, 1, key);
+        write_double(&mut entry, 2, value.as_f64().unwrap_or_default());
+        write_bytes(&mut encoded, 11, &entry);
+    }
+    write_string(&mut encoded, 12, string("type"));
+    for (key, value) in span["meta_struct"].as_object().into_iter().flatten() {
+        let Some(bytes) = value.as_str().and_then(base64::decode) else {
+            continue;
+        };
+        let mut entry = Vec::new();
+        write_string(&mut entry, 1, key);
+        write_bytes(&mut entry, 2, &bytes);
+        write_bytes(&mut encoded, 13, &entry);
+    }
+    for span_link in span["span_links"].as_array().into_iter().flatten() {
+        let mut link = Vec::new();
+        write_varint_field(
+            &mut link,
+            1,
+            span_link["trace_id"].as_u64().unwrap_or_default(),
+        );
+        write_varint_field(
+            &mut link,
+            3,
+            span_link["span_id"].as_u64().unwrap_or_default(),
+        );
+        for (key, value) in span_link["attributes"].as_object().into_iter().flatten() {
+            let mut entry = Vec::new();
+            write_string(&mut entry, 1, key);
+            write_string(&mut entry, 2, value.as_str().unwrap_or_default());
+            write_bytes(&mut link, 4, &entry);
+        }
+        write_bytes(&mut encoded, 14, &link);
+    }
+    encoded
+}
+
+const WIRE_TYPE_VARINT: u64 = 0;
+const WIRE_TYPE_FIXED64: u64 = 1;
+const WIRE_TYPE_LENGTH_DELIMITED: u64 = 2;
+
+#[inline]
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+// Default values are left out, like protobuf encoders do
+#[inline]
+fn write_varint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    if value != 0 {
+        write_varint(buf, field << 3 | WIRE_TYPE_VARINT);
+        write_varint(buf, value);
+    }
+}
+
+// Negative values are sign-extended to 64 bits, like protobuf encodes `int32` fields
+#[inline]
+fn write_int32_field(buf: &mut Vec<u8>, field: u64, value: i32) {
+    write_varint_field(buf, field, i64::from(value) as u64);
+}
+
+#[inline]
+fn write_double(buf: &mut Vec<u8>, field: u64, value: f64) {
+    write_varint(buf, field << 3 | WIRE_TYPE_FIXED64);
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+#[inline]
+fn write_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    write_varint(buf, field << 3 | WIRE_TYPE_LENGTH_DELIMITED);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+#[inline]
+fn write_string(buf: &mut Vec<u8>, field: u64, value: &str) {
+    if !value.is_empty() {
+        write_bytes(buf, field, value.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_varint() {
+        let mut buf = Vec::new();
+        write_varint(&mut buf, 1);
+        write_varint(&mut buf, 300);
+        write_varint(&mut buf, u64::MAX);
+        assert_eq!(
+            buf,
+            [1, 0xac, 0x02, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]
+        );
+    }
+
+    #[test]
+    fn test_write_int32_field() {
+        let mut buf = Vec::new();
+        write_int32_field(&mut buf, 1, -1);
+        assert_eq!(
+            buf,
+            [0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]
+        );
+    }
+
+    #[test]
+    fn test_encode_span() {
+        let span = serde_json::json!({
+            "service": "orders",
+            "trace_id": 150,
+            "error": 0,
+            "meta": {"env": "prod"},
+            "metrics": {"rows": 1.0},
+        });
+
+        assert_eq!(
+            encode_span(&span),
+            [
+                [0x0a, 6].as_slice(),
+                b"orders",
+                &[0x20, 0x96, 0x01],
+                &[0x52, 11, 0x0a, 3],
+                b"env",
+                &[0x12, 4],
+                b"prod",
+                &[0x5a, 15, 0x0a, 4],
+                b"rows",
+                &[0x11],
+                &1.0f64.to_le_bytes(),
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn test_encode_span_meta_struct() {
+        let span = serde_json::json!({"meta_struct": {"a": base64::encode(&[1, 2])}});
+
+        assert_eq!(encode_span(&span), [0x6a, 7, 0x0a, 1, b'a', 0x12, 2, 1, 2]);
+    }
+
+    fn tracer_metadata() -> TracerMetadata {
+        TracerMetadata {
+            env: String::from("staging"),
+            hostname: String::from("host"),
+            app_version: String::from("1.2"),
+        }
+    }
+
+    #[test]
+    fn test_encode_span_links() {
+        let span = serde_json::json!({
+            "span_links": [{"trace_id": 1, "span_id": 2, "attributes": {"k": "v"}}],
+        });
+
+        assert_eq!(
+            encode_span(&span),
+            [0x72, 12, 0x08, 1, 0x18, 2, 0x22, 6, 0x0a, 1, b'k', 0x12, 1, b'v']
+        );
+    }
+
+    #[test]
+    fn test_encode_nests_spans_in_chunks() {
+        let traces = serde_json::json!([[{"name": "a", "meta": {"env": "prod"}}]]);
+
+        let payload = encode(&traces, &tracer_metadata());
+
+        let mut chunk = vec![0x08, 0x01];
+        write_bytes(&mut chunk, 3, &encode_span(&traces[0][0]));
+        let mut tracer_payload = Vec::new();
+        write_string(&mut tracer_payload, 2, "rust");
+        write_string(&mut tracer_payload, 4, env!("CARGO_PKG_VERSION"));
+        write_bytes(&mut tracer_payload, 6, &chunk);
+        write_string(&mut tracer_payload, 8, "prod");
+        write_string(&mut tracer_payload, 9, "host");
+        write_string(&mut tracer_payload, 10, "1.2");
+        let mut expected = Vec::new();
+        write_string(&mut expected, 1, "host");
+        write_string(&mut expected, 2, "prod");
+        write_bytes(&mut expected, 5, &tracer_payload);
+        assert_eq!(payload, expected);
+    }
+
+    #[test]
+    fn test_encode_falls_back_to_the_env_of_the_process() {
+        let traces = serde_json::json!([[{"name": "a", "meta": {"version": "1.3"}}]]);
+
+        let payload = encode(&traces, &tracer_metadata());
+
+        let mut expected = Vec::new();
+        write_string(&mut expected, 1, "host");
+        write_string(&mut expected, 2, "staging");
+        assert!(payload.starts_with(&expected));
+        let mut app_version = Vec::new();
+        write_string(&mut app_version, 10, "1.3");
+        assert!(payload.ends_with(&app_version));
+    }
+
+    #[test]
+    fn test_encode_leaves_out_rejected_traces() {
+        let traces = serde_json::json!([
+            [{"name": "a", "metrics": {"_sampling_priority_v1": -1.0}}],
+            [{"name": "b", "metrics": {"_sampling_priority_v1": 0.0}}],
+        ]);
+
+        let payload = encode(&traces, &tracer_metadata());
+
+        assert_eq!(payload, encode(&serde_json::json!([]), &tracer_metadata()));
+    }
+
+    #[test]
+    fn test_intake_url() {
+        assert_eq!(
+            intake_url("datadoghq.eu"),
+            "https://trace.agent.datadoghq.eu/api/v0.2/traces"
+        );
+    }
+}
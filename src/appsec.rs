@@ -0,0 +1,96 @@
+use super::sampling::SamplingPriority;
+use super::span::SpanBuilder;
+
+pub const APPSEC_TAG_PREFIX: &str = "_dd.appsec.";
+pub const APPSEC_EVENT_TAG: &str = "appsec.event";
+pub const DECISION_MAKER_TAG: &str = "_dd.p.dm";
+/// The decision maker of traces kept because of a security event.
+pub const APPSEC_DECISION_MAKER: &str = "-5";
+
+/// Application security (Datadog ASM) tags of a request span, collected by a security library
+/// through a hook of an HTTP integration, e.g. `ActixDatadogTracer::appsec`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AppSecTags {
+    tags: Vec<(String, String)>,
+    keep: bool,
+}
+
+impl AppSecTags {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the `_dd.appsec.<name>` tag, e.g. `json` for the security events of the request.
+    #[inline]
+    pub fn tag(&mut self, name: &str, value: impl Into<String>) -> &mut Self {
+        self.tags
+            .push((format!("{}{}", APPSEC_TAG_PREFIX, name), value.into()));
+        self
+    }
+
+    /// Keeps the trace whatever the sampler decided, like ASM does for requests with a security
+    /// event: the span is tagged `appsec.event` and gets the `UserKeep` priority, with `appsec`
+    /// as the decision maker.
+    #[inline]
+    pub fn keep(&mut self) -> &mut Self {
+        self.keep = true;
+        self
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty() && !self.keep
+    }
+
+    #[inline]
+    pub fn apply(&self, span_builder: &mut SpanBuilder) {
+        for (key, value) in &self.tags {
+            span_builder.add_tag(key.clone(), value.clone());
+        }
+        if self.keep {
+            span_builder
+                .add_tag(APPSEC_EVENT_TAG, "true")
+                .add_tag(DECISION_MAKER_TAG, APPSEC_DECISION_MAKER)
+                .sampling_priority(SamplingPriority::UserKeep);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tags_are_prefixed() {
+        let mut span_builder = SpanBuilder::default();
+
+        AppSecTags::new()
+            .tag("json", "{\"triggers\":[]}")
+            .apply(&mut span_builder);
+
+        assert_eq!(
+            span_builder.meta_value("_dd.appsec.json"),
+            Some("{\"triggers\":[]}")
+        );
+        assert_eq!(span_builder.meta_value(APPSEC_EVENT_TAG), None);
+        assert_eq!(span_builder.sampling_priority, None);
+    }
+
+    #[test]
+    fn test_keep_overrides_rejection() {
+        let mut span_builder = SpanBuilder::default();
+        span_builder.sampling_priority(SamplingPriority::AutoReject);
+
+        AppSecTags::new().keep().apply(&mut span_builder);
+
+        assert_eq!(
+            span_builder.sampling_priority,
+            Some(SamplingPriority::UserKeep)
+        );
+        assert_eq!(span_builder.meta_value(APPSEC_EVENT_TAG), Some("true"));
+        assert_eq!(
+            span_builder.meta_value(DECISION_MAKER_TAG),
+            Some(APPSEC_DECISION_MAKER)
+        );
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+/// Value which replaces the values of a meta key once the key has reached its cardinality limit.
+pub const TRUNCATED_CARDINALITY: &str = "__truncated_cardinality__";
+
+const DEFAULT_EXEMPT_KEYS: [&str; 4] = ["error.msg", "error.stack", "http.url", "sql.query"];
+
+/// Guards against tag explosions (e.g. accidentally tagging user ids), which degrade Datadog
+/// facets. It tracks the distinct values seen for every meta key and, once a key has
+/// `max_values_per_key` of them, replaces any new value of that key with
+/// `__truncated_cardinality__`. A warning is logged the first time a key is truncated.
+///
+/// Keys whose values are expected to be unique (`error.msg`, `error.stack`, `http.url` and
+/// `sql.query`) are exempt by default; more can be added with `exempt_key`.
+#[derive(Debug)]
+pub struct CardinalityGuard {
+    max_values_per_key: usize,
+    exempt_keys: HashSet<String>,
+    seen_values: Mutex<HashMap<String, SeenValues>>,
+}
+
+#[derive(Debug, Default)]
+struct SeenValues {
+    values: HashSet<String>,
+    truncated: bool,
+}
+
+impl CardinalityGuard {
+    pub fn new(max_values_per_key: usize) -> Self {
+        Self {
+            max_values_per_key,
+            exempt_keys: DEFAULT_EXEMPT_KEYS
+                .iter()
+                .map(|key| key.to_string())
+                .collect(),
+            seen_values: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn exempt_key(mut self, key: impl Into<String>) -> Self {
+        self.exempt_keys.insert(key.into());
+        self
+    }
+
+    /// Replaces the values of the keys which are past their cardinality limit.
+    #[inline]
+    pub fn guard(&self, meta: &mut HashMap<String, String>) {
+        let mut seen_values = match self.seen_values.lock() {
+            Ok(seen_values) => seen_values,
+            Err(e) => {
+                log::error!("Unable to acquire lock on seen tag values; err {}", e);
+                return;
+            }
+        };
+        for (key, value) in meta.iter_mut() {
+            if self.exempt_keys.contains(key) {
+                continue;
+            }
+            let seen = seen_values.entry(key.clone()).or_default();
+            if seen.values.contains(value) {
+                continue;
+            }
+            if seen.values.len() < self.max_values_per_key {
+                seen.values.insert(value.clone());
+                continue;
+            }
+            if !seen.truncated {
+                seen.truncated = true;
+                log::warn!(
+                    "Tag {} has more than {} distinct values; further values are replaced with {}",
+                    key,
+                    self.max_values_per_key,
+                    TRUNCATED_CARDINALITY
+                );
+            }
+            *value = String::from(TRUNCATED_CARDINALITY);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(key: &str, value: &str) -> HashMap<String, String> {
+        HashMap::from([(String::from(key), String::from(value))])
+    }
+
+    #[test]
+    fn test_guard_keeps_values_under_limit() {
+        let guard = CardinalityGuard::new(2);
+        for value in ["a", "b", "a", "b"] {
+            let mut span_meta = meta("user", value);
+            guard.guard(&mut span_meta);
+            assert_eq!(span_meta["user"], value);
+        }
+    }
+
+    #[test]
+    fn test_guard_truncates_values_past_limit() {
+        let guard = CardinalityGuard::new(2);
+        guard.guard(&mut meta("user", "a"));
+        guard.guard(&mut meta("user", "b"));
+
+        let mut span_meta = meta("user", "c");
+        guard.guard(&mut span_meta);
+        assert_eq!(span_meta["user"], TRUNCATED_CARDINALITY);
+
+        // values seen before the limit was reached are kept
+        let mut span_meta = meta("user", "a");
+        guard.guard(&mut span_meta);
+        assert_eq!(span_meta["user"], "a");
+    }
+
+    #[test]
+    fn test_guard_skips_exempt_keys() {
+        let guard = CardinalityGuard::new(1).exempt_key("request.id");
+        for value in ["a", "b"] {
+            let mut span_meta = meta("request.id", value);
+            guard.guard(&mut span_meta);
+            assert_eq!(span_meta["request.id"], value);
+
+            let mut span_meta = meta("http.url", value);
+            guard.guard(&mut span_meta);
+            assert_eq!(span_meta["http.url"], value);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+// Guards against clock skew between the host and the Datadog agent, which shows up in Datadog as
+// negative gaps between parents and children exported from different hosts. The offset of the
+// local clock is estimated from the `Date` header of the agent's `/info` endpoint, and applied to
+// span timestamps when they are serialized.
+
+use super::datadog_client::TimestampResolution;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub(crate) const OFFSET_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+// The `Date` header only has a resolution of one second, so smaller offsets are noise
+const MIN_CORRECTED_OFFSET_NS: i64 = 1_000_000_000;
+
+// Estimates how far the agent's clock is ahead of the local one, assuming its `Date` was taken
+// halfway through the request
+#[inline]
+pub(crate) fn estimate_offset_ns(sent: SystemTime, received: SystemTime, agent: SystemTime) -> i64 {
+    let round_trip = received.duration_since(sent).unwrap_or_default();
+    let midpoint = sent + round_trip / 2;
+    let offset_ns = match agent.duration_since(midpoint) {
+        Ok(ahead) => ahead.as_nanos() as i64,
+        Err(e) => -(e.duration().as_nanos() as i64),
+    };
+    if offset_ns.abs() < MIN_CORRECTED_OFFSET_NS {
+        0
+    } else {
+        offset_ns
+    }
+}
+
+// Parses an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`, the format of the `Date` header
+#[inline]
+pub(crate) fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let mut parts = date.split_whitespace().skip(1);
+    let day: u64 = parts.next()?.parse().ok()?;
+    let month = match parts.next()? {
+        "Jan" => 1,
+        "Feb" => 2,
+        "Mar" => 3,
+        "Apr" => 4,
+        "May" => 5,
+        "Jun" => 6,
+        "Jul" => 7,
+        "Aug" => 8,
+        "Sep" => 9,
+        "Oct" => 10,
+        "Nov" => 11,
+        "Dec" => 12,
+        _ => return None,
+    };
+    let year: u64 = parts.next()?.parse().ok()?;
+    let mut time = parts.next()?.split(':');
+    let hours: u64 = time.next()?.parse().ok()?;
+    let minutes: u64 = time.next()?.parse().ok()?;
+    let seconds: u64 = time.next()?.parse().ok()?;
+    if parts.next()? != "GMT" || year < 1970 || day == 0 || day > 31 {
+        return None;
+    }
+    let days = days_since_epoch(year, month, day);
+    Some(UNIX_EPOCH + Duration::from_secs(days * 86_400 + hours * 3_600 + minutes * 60 + seconds))
+}
+
+// Days from 1970-01-01 to a date of the proleptic Gregorian calendar
+#[inline]
+fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// Shifts the start of every span by the clock offset, and truncates starts and durations to the
+// timestamp resolution
+#[inline]
+pub(crate) fn adjust_timestamps(
+    trace_json: &mut serde_json::Value,
+    offset_ns: i64,
+    resolution: TimestampResolution,
+) {
+    if offset_ns == 0 && resolution == TimestampResolution::Nanos {
+        return;
+    }
+    let spans = trace_json
+        .as_array_mut()
+        .into_iter()
+        .flatten()
+        .filter_map(serde_json::Value::as_array_mut)
+        .flatten();
+    for span in spans {
+        if let Some(start) = span.get("start").and_then(serde_json::Value::as_u64) {
+            let start = start.saturating_add_signed(offset_ns);
+            span["start"] = resolution.truncate(start).into();
+        }
+        if let Some(duration) = span.get("duration").and_then(serde_json::Value::as_u64) {
+            span["duration"] = resolution.truncate(duration).into();
+        }
+        let span_events = span
+            .get_mut("span_events")
+            .and_then(serde_json::Value::as_array_mut)
+            .into_iter()
+            .flatten();
+        for span_event in span_events {
+            if let Some(time) = span_event
+                .get("time_unix_nano")
+                .and_then(serde_json::Value::as_u64)
+            {
+                let time = time.saturating_add_signed(offset_ns);
+                span_event["time_unix_nano"] = resolution.truncate(time).into();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_http_date() {
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(UNIX_EPOCH + Duration::from_secs(784_111_777))
+        );
+        assert_eq!(
+            parse_http_date("Thu, 29 Feb 2024 00:00:00 GMT"),
+            Some(UNIX_EPOCH + Duration::from_secs(1_709_164_800))
+        );
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date(""), None);
+    }
+
+    #[test]
+    fn test_estimate_offset_ns() {
+        let sent = UNIX_EPOCH + Duration::from_secs(1_000);
+        let received = sent + Duration::from_millis(200);
+
+        let ahead = estimate_offset_ns(sent, received, sent + Duration::from_secs(5));
+        let behind = estimate_offset_ns(sent, received, sent - Duration::from_secs(5));
+        let within_resolution = estimate_offset_ns(sent, received, sent);
+
+        assert_eq!(ahead, 4_900_000_000);
+        assert_eq!(behind, -5_100_000_000);
+        assert_eq!(within_resolution, 0);
+    }
+
+    #[test]
+    fn test_adjust_timestamps() {
+        let mut trace_json = json!([[{"start": 10_000_123_456u64, "duration": 2_345_678u64}]]);
+
+        adjust_timestamps(&mut trace_json, -2_000_000_000, TimestampResolution::Millis);
+
+        assert_eq!(
+            trace_json,
+            json!([[{"start": 8_000_000_000u64, "duration": 2_000_000u64}]])
+        );
+    }
+
+    #[test]
+    fn test_adjust_timestamps_of_span_events() {
+        let mut trace_json = json!([[{"span_events": [{"time_unix_nano": 10_000_123_456u64}]}]]);
+
+        adjust_timestamps(&mut trace_json, -2_000_000_000, TimestampResolution::Millis);
+
+        assert_eq!(
+            trace_json,
+            json!([[{"span_events": [{"time_unix_nano": 8_000_000_000u64}]}]])
+        );
+    }
+}
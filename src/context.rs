@@ -0,0 +1,33 @@
+use super::propagation::{ORIGIN_TAG, TRACE_TAG_PREFIX};
+use super::subscriber::with_span_builder;
+
+pub use super::propagation::SpanContext;
+
+/// The Datadog context of the current span, i.e. the span entered on this thread, or by the
+/// future being polled when called from an instrumented task. Unlike the subscriber's own
+/// bookkeeping, this is public API, so application code and other libraries can read the active
+/// trace and span ids, e.g. to correlate logs or propagate the trace.
+///
+/// Returns `None` outside of any span, and for spans which are not traced by a
+/// `TracingSubscriberDatadog`.
+#[inline]
+pub fn current_context() -> Option<SpanContext> {
+    context_of(&tracing::Span::current())
+}
+
+/// The Datadog context of `span`, like `current_context`.
+#[inline]
+pub fn context_of(span: &tracing::Span) -> Option<SpanContext> {
+    with_span_builder(span, |span_builder| SpanContext {
+        trace_id: span_builder.trace_id,
+        span_id: span_builder.span_id,
+        sampling_priority: span_builder.sampling_priority,
+        origin: span_builder.meta_value(ORIGIN_TAG).map(String::from),
+        trace_tags: span_builder
+            .meta_mut()
+            .iter()
+            .filter(|(key, _)| key.starts_with(TRACE_TAG_PREFIX))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect(),
+    })
+}
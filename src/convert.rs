@@ -0,0 +1,304 @@
+// Conversions of traces to the JSON formats of other tracing tools, for debugging and
+// migrations. Neither format has a notion of the resource or type of a span, so they are kept
+// as the `resource.name` and `span.type` attributes, next to the meta and metrics of the span.
+
+use super::span::{Span, Trace, Traces};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+const SPAN_KIND_META_KEY: &str = "span.kind";
+const ERROR_MSG_META_KEY: &str = "error.msg";
+
+/// Converts captured traces, e.g. from `Client::create_with_exporter` or decoded with
+/// `TracesDecode`, to the JSON of other tools:
+///
+/// ```ignore
+/// let traces = Traces::from_json(&payload)?;
+/// std::fs::write("traces.otlp.json", traces.to_otlp().to_string())?;
+/// std::fs::write("traces.jaeger.json", traces.to_jaeger_json().to_string())?;
+/// ```
+pub trait TracesConvert {
+    /// The traces as an OTLP `ExportTraceServiceRequest` in the OTLP/JSON encoding, with one
+    /// resource per service.
+    fn to_otlp(&self) -> Value;
+
+    /// The traces in the JSON format of the Jaeger UI and its `/api/traces` endpoint, which the
+    /// UI can load from a file.
+    fn to_jaeger_json(&self) -> Value;
+}
+
+impl TracesConvert for Traces {
+    fn to_otlp(&self) -> Value {
+        let mut spans_by_service: BTreeMap<&str, Vec<Value>> = BTreeMap::new();
+        for span in self.iter().flatten() {
+            spans_by_service
+                .entry(span.service())
+                .or_default()
+                .push(otlp_span(span));
+        }
+        let resource_spans: Vec<Value> = spans_by_service
+            .into_iter()
+            .map(|(service, spans)| {
+                json!({
+                    "resource": {
+                        "attributes": [
+                            otlp_attribute("service.name", json!({ "stringValue": service })),
+                        ],
+                    },
+                    "scopeSpans": [{
+                        "scope": {
+                            "name": env!("CARGO_PKG_NAME"),
+                            "version": env!("CARGO_PKG_VERSION"),
+                        },
+                        "spans": spans,
+                    }],
+                })
+            })
+            .collect();
+        json!({ "resourceSpans": resource_spans })
+    }
+
+    fn to_jaeger_json(&self) -> Value {
+        let data: Vec<Value> = self.iter().map(jaeger_trace).collect();
+        json!({ "data": data })
+    }
+}
+
+#[inline]
+fn otlp_span(span: &Span) -> Value {
+    let mut attributes: Vec<Value> = attributes(span)
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Value::String(value) => json!({ "stringValue": value }),
+                value => json!({ "doubleValue": value }),
+            };
+            otlp_attribute(&key, value)
+        })
+        .collect();
+    attributes.sort_by(|a, b| a["key"].as_str().cmp(&b["key"].as_str()));
+    let status = if span.is_error() {
+        json!({
+            "code": 2,
+            "message": span.meta().get(ERROR_MSG_META_KEY).map_or("", String::as_str),
+        })
+    } else {
+        json!({ "code": 0 })
+    };
+    let start = span.start();
+    json!({
+        "traceId": trace_id_hex(span.trace_id()),
+        "spanId": span_id_hex(span.span_id()),
+        "parentSpanId": span.parent_id().map(span_id_hex).unwrap_or_default(),
+        "name": span.name(),
+        "kind": otlp_span_kind(span.meta().get(SPAN_KIND_META_KEY).map(String::as_str)),
+        // 64 bit integers are strings in OTLP/JSON
+        "startTimeUnixNano": start.to_string(),
+        "endTimeUnixNano": (start + span.duration()).to_string(),
+        "attributes": attributes,
+        "status": status,
+    })
+}
+
+#[inline]
+fn otlp_attribute(key: &str, value: Value) -> Value {
+    json!({ "key": key, "value": value })
+}
+
+// Values of the `SpanKind` enum of OTLP
+#[inline]
+fn otlp_span_kind(kind: Option<&str>) -> u8 {
+    match kind {
+        Some("internal") => 1,
+        Some("server") => 2,
+        Some("client") => 3,
+        Some("producer") => 4,
+        Some("consumer") => 5,
+        _ => 0,
+    }
+}
+
+#[inline]
+fn jaeger_trace(trace: &Trace) -> Value {
+    let mut process_ids: BTreeMap<&str, String> = BTreeMap::new();
+    for span in trace {
+        let next_id = format!("p{}", process_ids.len() + 1);
+        process_ids.entry(span.service()).or_insert(next_id);
+    }
+    let spans: Vec<Value> = trace
+        .iter()
+        .map(|span| jaeger_span(span, &process_ids[span.service()]))
+        .collect();
+    let processes: serde_json::Map<String, Value> = process_ids
+        .iter()
+        .map(|(service, id)| (id.clone(), json!({ "serviceName": service, "tags": [] })))
+        .collect();
+    let trace_id = trace
+        .first()
+        .map(|span| trace_id_hex(span.trace_id()))
+        .unwrap_or_default();
+    json!({
+        "traceID": trace_id,
+        "spans": spans,
+        "processes": processes,
+    })
+}
+
+#[inline]
+fn jaeger_span(span: &Span, process_id: &str) -> Value {
+    let trace_id = trace_id_hex(span.trace_id());
+    let references: Vec<Value> = span
+        .parent_id()
+        .map(|parent_id| {
+            json!({
+                "refType": "CHILD_OF",
+                "traceID": trace_id,
+                "spanID": span_id_hex(parent_id),
+            })
+        })
+        .into_iter()
+        .collect();
+    let mut tags: Vec<Value> = attributes(span)
+        .into_iter()
+        .map(|(key, value)| {
+            let value_type = if value.is_string() {
+                "string"
+            } else {
+                "float64"
+            };
+            json!({ "key": key, "type": value_type, "value": value })
+        })
+        .collect();
+    if span.is_error() {
+        tags.push(json!({ "key": "error", "type": "bool", "value": true }));
+    }
+    tags.sort_by(|a, b| a["key"].as_str().cmp(&b["key"].as_str()));
+    json!({
+        "traceID": trace_id,
+        "spanID": span_id_hex(span.span_id()),
+        "operationName": span.name(),
+        "references": references,
+        // Jaeger times are in microseconds
+        "startTime": span.start() / 1000,
+        "duration": span.duration() / 1000,
+        "tags": tags,
+        "logs": [],
+        "processID": process_id,
+    })
+}
+
+// The meta and metrics of a span, with its resource and type
+#[inline]
+fn attributes(span: &Span) -> Vec<(String, Value)> {
+    let mut attributes: Vec<(String, Value)> = span
+        .meta()
+        .iter()
+        .filter(|(key, _)| key.as_str() != SPAN_KIND_META_KEY)
+        .map(|(key, value)| (key.clone(), Value::from(value.as_str())))
+        .chain(
+            span.metrics()
+                .iter()
+                .map(|(key, value)| (key.clone(), Value::from(*value))),
+        )
+        .collect();
+    attributes.push((String::from("resource.name"), Value::from(span.resource())));
+    if !span.span_type().is_empty() {
+        attributes.push((String::from("span.type"), Value::from(span.span_type())));
+    }
+    attributes
+}
+
+// Datadog trace ids are the lower 64 bits of the 128 bit ids of OTLP and Jaeger
+#[inline]
+fn trace_id_hex(trace_id: u64) -> String {
+    format!("{:032x}", trace_id)
+}
+
+#[inline]
+fn span_id_hex(span_id: u64) -> String {
+    format!("{:016x}", span_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::span::TracesDecode;
+
+    fn traces() -> Traces {
+        Traces::from_json(
+            br#"[[
+                {"trace_id": 1, "span_id": 2, "parent_id": 0, "name": "request", "service": "api",
+                 "resource": "GET /", "type": "web", "start": 5000, "duration": 3000,
+                 "meta": {"span.kind": "server"}, "metrics": {"rows": 2.0}},
+                {"trace_id": 1, "span_id": 3, "parent_id": 2, "name": "query", "service": "db",
+                 "resource": "SELECT", "start": 6000, "duration": 1000, "error": 1,
+                 "meta": {"error.msg": "boom"}}
+            ]]"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_to_otlp() {
+        let otlp = traces().to_otlp();
+
+        let resource_spans = otlp["resourceSpans"].as_array().unwrap();
+        assert_eq!(resource_spans.len(), 2);
+        assert_eq!(
+            resource_spans[0]["resource"]["attributes"][0]["value"]["stringValue"],
+            "api"
+        );
+        let request = &resource_spans[0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(request["traceId"], "00000000000000000000000000000001");
+        assert_eq!(request["spanId"], "0000000000000002");
+        assert_eq!(request["parentSpanId"], "");
+        assert_eq!(request["kind"], 2);
+        assert_eq!(request["startTimeUnixNano"], "5000");
+        assert_eq!(request["endTimeUnixNano"], "8000");
+        assert_eq!(
+            request["attributes"],
+            json!([
+                {"key": "resource.name", "value": {"stringValue": "GET /"}},
+                {"key": "rows", "value": {"doubleValue": 2.0}},
+                {"key": "span.type", "value": {"stringValue": "web"}},
+            ])
+        );
+        let query = &resource_spans[1]["scopeSpans"][0]["spans"][0];
+        assert_eq!(query["parentSpanId"], "0000000000000002");
+        assert_eq!(query["status"], json!({"code": 2, "message": "boom"}));
+    }
+
+    #[test]
+    fn test_to_jaeger_json() {
+        let jaeger = traces().to_jaeger_json();
+
+        let trace = &jaeger["data"][0];
+        assert_eq!(trace["traceID"], "00000000000000000000000000000001");
+        assert_eq!(
+            trace["processes"],
+            json!({
+                "p1": {"serviceName": "api", "tags": []},
+                "p2": {"serviceName": "db", "tags": []},
+            })
+        );
+        let (request, query) = (&trace["spans"][0], &trace["spans"][1]);
+        assert_eq!(request["operationName"], "request");
+        assert_eq!(request["references"], json!([]));
+        assert_eq!(request["startTime"], 5);
+        assert_eq!(request["duration"], 3);
+        assert_eq!(request["processID"], "p1");
+        assert_eq!(
+            query["references"],
+            json!([{
+                "refType": "CHILD_OF",
+                "traceID": trace["traceID"],
+                "spanID": "0000000000000002",
+            }])
+        );
+        assert_eq!(query["processID"], "p2");
+        assert!(query["tags"]
+            .as_array()
+            .unwrap()
+            .contains(&json!({"key": "error", "type": "bool", "value": true})));
+    }
+}
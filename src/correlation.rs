@@ -0,0 +1,104 @@
+use std::num::NonZeroU64;
+
+/// Maps a UUID, e.g. from `uuid::Uuid::as_u128`, to a Datadog id, so traces can be keyed by
+/// internal ids which aren't `u64`. The mapping is a hash, stable across processes and versions
+/// of this crate, so every service deriving the trace id of a request from its UUID agrees on
+/// it.
+#[inline]
+pub fn id_from_uuid(uuid: u128) -> NonZeroU64 {
+    let hash = mix((uuid >> 64) as u64 ^ mix(uuid as u64));
+    NonZeroU64::new(hash).unwrap_or(NonZeroU64::MIN)
+}
+
+/// Maps a correlation id to a Datadog id like `id_from_uuid`. UUIDs are parsed first, so their
+/// hyphenated, simple, upper and lower case forms map to the same id; other ids are hashed as
+/// they are.
+#[inline]
+pub fn id_from_correlation_id(correlation_id: &str) -> NonZeroU64 {
+    match parse_uuid(correlation_id) {
+        Some(uuid) => id_from_uuid(uuid),
+        None => {
+            let hash = correlation_id.bytes().fold(FNV_OFFSET_BASIS, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+            });
+            NonZeroU64::new(mix(hash)).unwrap_or(NonZeroU64::MIN)
+        }
+    }
+}
+
+/// Parses a UUID in its hyphenated (`67e55044-10b1-426f-9247-bb680e5fe0c8`) or simple form.
+#[inline]
+pub fn parse_uuid(value: &str) -> Option<u128> {
+    let hex: String = match value.len() {
+        36 => {
+            let groups: Vec<&str> = value.split('-').collect();
+            let lengths: Vec<usize> = groups.iter().map(|group| group.len()).collect();
+            if lengths != [8, 4, 4, 4, 12] {
+                return None;
+            }
+            groups.concat()
+        }
+        32 => String::from(value),
+        _ => return None,
+    };
+    if !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    u128::from_str_radix(&hex, 16).ok()
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+// The finalizer of SplitMix64, so that ids differing in a few bits map to unrelated ids
+#[inline]
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: &str = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+
+    #[test]
+    fn test_parse_uuid() {
+        assert_eq!(parse_uuid(UUID), Some(0x67e5504410b1426f9247bb680e5fe0c8));
+        assert_eq!(
+            parse_uuid("67E5504410B1426F9247BB680E5FE0C8"),
+            parse_uuid(UUID)
+        );
+        assert_eq!(parse_uuid("67e55044-10b1-426f-9247bb680e5fe0c8-"), None);
+        assert_eq!(parse_uuid("+7e5504410b1426f9247bb680e5fe0c8"), None);
+        assert_eq!(parse_uuid("order-42"), None);
+    }
+
+    #[test]
+    fn test_ids_are_stable() {
+        // a change of these values breaks the ids agreed on by services on different versions
+        assert_eq!(
+            id_from_uuid(0x67e5504410b1426f9247bb680e5fe0c8).get(),
+            1167905224259429577
+        );
+        assert_eq!(
+            id_from_correlation_id("order-42").get(),
+            6371757786061637967
+        );
+        assert_eq!(
+            id_from_correlation_id(UUID),
+            id_from_uuid(0x67e5504410b1426f9247bb680e5fe0c8)
+        );
+        assert_eq!(
+            id_from_correlation_id(UUID),
+            id_from_correlation_id(&UUID.to_uppercase())
+        );
+        assert_ne!(
+            id_from_correlation_id("order-42"),
+            id_from_correlation_id("order-43")
+        );
+        assert_ne!(id_from_uuid(1), id_from_uuid(1 << 64));
+    }
+}
@@ -1,13 +1,23 @@
+use super::agentless;
+use super::clock::{self, adjust_timestamps};
+use super::dogstatsd::{DogStatsdFallback, SampledOutStats};
+#[cfg(any(feature = "async_std", windows))]
+use super::http1;
+use super::local_export::{FileExporter, StdoutExporter};
+use super::sampling::AgentSampleRates;
+use super::self_instrumentation::{ExporterTracer, SEND_SPAN_NAME, SERIALIZE_SPAN_NAME};
+pub use super::span::*;
+use super::v05;
+use super::v07::{self, TracerMetadata};
+use super::validation::{validate_traces, Violation, MAX_PAYLOAD_BYTES};
 use rand::Rng;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::fmt::Debug;
-use std::num::NonZeroU64;
-use std::str::FromStr;
-use std::sync::mpsc::Sender;
-use std::sync::Mutex;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread::JoinHandle;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime};
 
 /// ClientConfig comes with sensible defaults. Calling either ClientConfig::default() or
 /// ClientConfig::new() will create a ClientConfig instance with these defaults. If any
@@ -16,11 +26,209 @@ use std::time::{Duration, SystemTime, UNIX_EPOCH};
 ///
 /// If you intend to use entirely default values, you don't need to instantiate a ClientConfig.
 /// Instead, you can just call Client::create_default().
+#[derive(Clone)]
 pub struct ClientConfig {
     datadog_agent_host: String,
     datadog_agent_port: u32,
     connect_timeout_ms: u64,
     request_timeout_ms: u64,
+    adaptive_request_timeout: bool,
+    max_request_timeout_ms: u64,
+    slow_agent_threshold_ms: u64,
+    inline_export: bool,
+    max_send_attempts: u32,
+    send_deadline_ms: u64,
+    retry_backoff_ms: u64,
+    max_retained_payloads: usize,
+    circuit_breaker_failures: u32,
+    circuit_breaker_probe_ms: u64,
+    pool_idle_timeout_ms: u64,
+    pool_max_idle_per_host: usize,
+    http2_prior_knowledge: bool,
+    dogstatsd_fallback_port: Option<u16>,
+    sampled_out_stats_port: Option<u16>,
+    serialize_on_caller: bool,
+    on_payload: Option<PayloadHook>,
+    clock_skew_correction: bool,
+    timestamp_resolution: TimestampResolution,
+    max_payload_bytes: usize,
+    max_batch_traces: usize,
+    queue_capacity: usize,
+    overflow_policy: OverflowPolicy,
+    max_batch_age_ms: u64,
+    span_debug_logging: SpanDebugLogging,
+    self_instrumentation: Option<ServiceName>,
+    trace_api_version: TraceApiVersion,
+    compression: Compression,
+    idle_park_ms: Option<u64>,
+    windows_pipe_name: Option<String>,
+    api_key: Option<String>,
+    datadog_site: Option<String>,
+    hostname: Option<String>,
+    env: Option<String>,
+    app_version: Option<String>,
+    trace_output: TraceOutput,
+}
+
+type PayloadHook = Arc<dyn Fn(&[u8], PayloadMeta) + Send + Sync>;
+
+/// Describes a payload passed to the `ClientConfig::on_payload` hook.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PayloadMeta {
+    /// Size of the payload in bytes.
+    pub size: usize,
+    pub trace_count: usize,
+    pub span_count: usize,
+    /// URL of the agent endpoint the payload was sent to.
+    pub destination: String,
+    /// Whether the agent accepted the payload, retries included.
+    pub delivered: bool,
+}
+
+/// Resolution of the span timestamps and durations sent to the Datadog agent.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TimestampResolution {
+    #[default]
+    Nanos,
+    /// Truncates timestamps and durations to whole milliseconds, which compress better.
+    Millis,
+}
+
+impl TimestampResolution {
+    #[inline]
+    pub(crate) fn truncate(self, nanos: u64) -> u64 {
+        match self {
+            TimestampResolution::Nanos => nanos,
+            TimestampResolution::Millis => nanos - nanos % 1_000_000,
+        }
+    }
+}
+
+/// Where a Client created with `Client::create_with_config` sends traces.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum TraceOutput {
+    /// The Datadog agent, or the intake when an API key is set.
+    #[default]
+    Agent,
+    /// Pretty-prints traces to stdout as trees of spans, see `local_export::StdoutExporter`.
+    Stdout,
+    /// Appends traces to a file as lines of JSON, see `local_export::FileExporter`.
+    File(PathBuf),
+}
+
+/// Version of the agent endpoint, and wire format, traces are sent with.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TraceApiVersion {
+    /// `/v0.3/traces`, with JSON payloads.
+    #[default]
+    V03,
+    /// `/v0.5/traces`, with msgpack payloads whose strings are interned in a shared string
+    /// table, which are much smaller for high-throughput services. v0.5 has no field for span
+    /// links or `meta_struct`, so they are not sent.
+    V05,
+    /// `/v0.7/traces`, with msgpack payloads in the agent's own format, whose unified service
+    /// tags are first-class fields: the service of each span, and the env and version of the
+    /// payload. Supported agents also accept structured span events in it: `tracing::Event`s are
+    /// then recorded as span events, with their time and fields, rather than flattened into the
+    /// meta of their span.
+    V07,
+}
+
+impl TraceApiVersion {
+    #[inline]
+    fn path(self) -> &'static str {
+        match self {
+            TraceApiVersion::V03 => "/v0.3/traces",
+            TraceApiVersion::V05 => "/v0.5/traces",
+            TraceApiVersion::V07 => "/v0.7/traces",
+        }
+    }
+
+    #[inline]
+    fn content_type(self) -> &'static str {
+        match self {
+            TraceApiVersion::V03 => "application/json",
+            TraceApiVersion::V05 => v05::CONTENT_TYPE,
+            TraceApiVersion::V07 => v07::CONTENT_TYPE,
+        }
+    }
+
+    #[inline]
+    fn encode(self, trace_json: &serde_json::Value, tracer_metadata: &TracerMetadata) -> Vec<u8> {
+        match self {
+            TraceApiVersion::V03 => trace_json.to_string().into_bytes(),
+            TraceApiVersion::V05 => v05::encode(trace_json),
+            TraceApiVersion::V07 => v07::encode(trace_json, tracer_metadata),
+        }
+    }
+}
+
+/// Compression of payloads, available behind the `gzip` and `zstd` features. The payloads
+/// passed to `on_payload` are compressed too.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    #[cfg(feature = "gzip")]
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+    #[inline]
+    fn content_encoding(self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Some("gzip"),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Some("zstd"),
+        }
+    }
+
+    #[inline]
+    fn compress(self, body: Vec<u8>) -> Vec<u8> {
+        match self {
+            Compression::None => body,
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                use std::io::Write;
+
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder
+                    .write_all(&body)
+                    .and_then(|_| encoder.finish())
+                    .expect("compressing into memory can't fail")
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::encode_all(body.as_slice(), zstd::DEFAULT_COMPRESSION_LEVEL)
+                .expect("compressing into memory can't fail"),
+        }
+    }
+}
+
+/// Which spans `Client::send_traces` logs in full, as the JSON sent to the agent, at DEBUG level.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SpanDebugLogging {
+    #[default]
+    Off,
+    /// Logs a random sample of one in this many spans.
+    OneIn(u32),
+    /// Logs every span which has an error.
+    Errors,
+}
+
+impl SpanDebugLogging {
+    #[inline]
+    fn should_log(self, span: &Span) -> bool {
+        match self {
+            SpanDebugLogging::Off => false,
+            SpanDebugLogging::OneIn(n) => n > 0 && rand::thread_rng().gen_ratio(1, n),
+            SpanDebugLogging::Errors => span.is_error(),
+        }
+    }
 }
 
 impl ClientConfig {
@@ -28,6 +236,93 @@ impl ClientConfig {
         Self::default()
     }
 
+    /// Preset for pods whose agent runs as a DaemonSet: the agent host is read from the
+    /// `DD_AGENT_HOST` env var (usually the node IP, from the downward API) and the port from
+    /// `DD_TRACE_AGENT_PORT`. The agent is reached over the node network, so timeouts adapt to
+    /// its latency and failed batches are retried once.
+    pub fn kubernetes() -> Self {
+        Self::new()
+            .agent_from_env("localhost")
+            .connect_timeout_ms(250)
+            .request_timeout_ms(250)
+            .adaptive_request_timeout(true)
+            .max_request_timeout_ms(2000)
+            .max_send_attempts(2)
+            .send_deadline_ms(3000)
+    }
+
+    /// Preset for Docker Compose setups, where the agent is the `datadog-agent` service (unless
+    /// `DD_AGENT_HOST` and `DD_TRACE_AGENT_PORT` say otherwise) and may start after the app, so
+    /// failed batches are retried.
+    pub fn docker_compose() -> Self {
+        Self::new()
+            .agent_from_env("datadog-agent")
+            .connect_timeout_ms(250)
+            .request_timeout_ms(250)
+            .max_send_attempts(3)
+            .send_deadline_ms(2000)
+    }
+
+    /// Preset for AWS Lambda functions with the Datadog Lambda extension, which listens on
+    /// `localhost:8126`. Functions are frozen between invocations, so traces must be flushed
+    /// before returning from each invocation (e.g. with `Client::flush_async`); sends are kept
+    /// short so flushes don't add to the billed duration. Connections aren't kept idle, as they
+    /// don't survive freezes.
+    pub fn lambda_extension() -> Self {
+        Self::new()
+            .datadog_agent_host("localhost")
+            .datadog_agent_port(8126)
+            .connect_timeout_ms(50)
+            .request_timeout_ms(200)
+            .max_send_attempts(1)
+            .send_deadline_ms(300)
+            .pool_max_idle_per_host(0)
+    }
+
+    /// Preset for running an app on a workstation with a local agent: short timeouts, no
+    /// retries, and error spans logged in full at DEBUG level to debug instrumentation.
+    pub fn local_dev() -> Self {
+        Self::new()
+            .datadog_agent_host("localhost")
+            .datadog_agent_port(8126)
+            .connect_timeout_ms(50)
+            .request_timeout_ms(100)
+            .max_send_attempts(1)
+            .span_debug_logging(SpanDebugLogging::Errors)
+    }
+
+    /// Preset for environments without an agent: traces are posted straight to the Datadog
+    /// intake of the site set in `DD_SITE` (`datadoghq.com` by default), with the API key of the
+    /// `DD_API_KEY` env var. The intake is reached over the internet, so timeouts are longer and
+    /// failed batches are retried.
+    pub fn agentless() -> Self {
+        let config = Self::new()
+            .connect_timeout_ms(1000)
+            .request_timeout_ms(2000)
+            .max_send_attempts(3)
+            .send_deadline_ms(10_000);
+        match std::env::var("DD_API_KEY") {
+            Ok(api_key) if !api_key.trim().is_empty() => config.api_key(api_key.trim()),
+            _ => {
+                log::error!("DD_API_KEY is not set, sending traces to the agent instead");
+                config
+            }
+        }
+    }
+
+    // Reads the agent address from the env vars of the Datadog tracers
+    fn agent_from_env(self, default_host: &str) -> Self {
+        let host = std::env::var("DD_AGENT_HOST")
+            .ok()
+            .filter(|host| !host.trim().is_empty())
+            .unwrap_or_else(|| String::from(default_host));
+        let port = std::env::var("DD_TRACE_AGENT_PORT")
+            .ok()
+            .and_then(|port| port.trim().parse().ok())
+            .unwrap_or(self.datadog_agent_port);
+        self.datadog_agent_host(host).datadog_agent_port(port)
+    }
+
     pub fn datadog_agent_host(mut self, host: impl Into<String>) -> Self {
         self.datadog_agent_host = host.into();
         self
@@ -38,6 +333,32 @@ impl ClientConfig {
         self
     }
 
+    /// On Windows, sends traces over the named pipe the agent listens on for APM (e.g.
+    /// `datadog-apm` for `\\.\pipe\datadog-apm`, set by `DD_APM_WINDOWS_PIPE_NAME` on the
+    /// agent) instead of TCP. Requests over the pipe time out like requests over TCP, and clock
+    /// skew correction still queries the agent over TCP. It's ignored on other platforms and by
+    /// the `async-std` transport.
+    pub fn windows_pipe_name(mut self, pipe_name: impl Into<String>) -> Self {
+        self.windows_pipe_name = Some(pipe_name.into());
+        self
+    }
+
+    /// Sends traces straight to the Datadog intake with this API key, bypassing the agent, in
+    /// the protobuf format of the agent. The agent address, `trace_api_version`, clock skew
+    /// correction and the named pipe are then ignored, and the `async-std` transport, which
+    /// doesn't support TLS, can't be used.
+    pub fn api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    /// Datadog site of the intake traces are sent to with an API key, e.g. `datadoghq.eu`.
+    /// Defaults to the `DD_SITE` env var, or `datadoghq.com`.
+    pub fn datadog_site(mut self, site: impl Into<String>) -> Self {
+        self.datadog_site = Some(site.into());
+        self
+    }
+
     pub fn connect_timeout_ms(mut self, ms: u64) -> Self {
         self.connect_timeout_ms = ms;
         self
@@ -47,6 +368,271 @@ impl ClientConfig {
         self.request_timeout_ms = ms;
         self
     }
+
+    /// When enabled, the request timeout follows the recent latencies of the Datadog agent
+    /// (twice their 99th percentile), never going below `request_timeout_ms` nor above
+    /// `max_request_timeout_ms`. This keeps payloads from being dropped during agent GC pauses.
+    pub fn adaptive_request_timeout(mut self, enabled: bool) -> Self {
+        self.adaptive_request_timeout = enabled;
+        self
+    }
+
+    pub fn max_request_timeout_ms(mut self, ms: u64) -> Self {
+        self.max_request_timeout_ms = ms;
+        self
+    }
+
+    /// The agent is reported as slow in the client stats once the median of its recent
+    /// latencies is above this threshold.
+    pub fn slow_agent_threshold_ms(mut self, ms: u64) -> Self {
+        self.slow_agent_threshold_ms = ms;
+        self
+    }
+
+    /// How many times a batch of traces is attempted before it is dropped. Each attempt is
+    /// bounded by `connect_timeout_ms` and `request_timeout_ms`, and all attempts are bounded by
+    /// `send_deadline_ms`.
+    pub fn max_send_attempts(mut self, attempts: u32) -> Self {
+        self.max_send_attempts = attempts.max(1);
+        self
+    }
+
+    /// Total time allowed to send a batch of traces, across all attempts. Once it has elapsed,
+    /// the batch is abandoned, which keeps shutdown flushes within a predictable bound.
+    pub fn send_deadline_ms(mut self, ms: u64) -> Self {
+        self.send_deadline_ms = ms;
+        self
+    }
+
+    /// The delay before the first retry of a failed batch, doubled for each later retry up to
+    /// 30 seconds. Half of each delay is random, so clients which failed together don't retry
+    /// together. Delays between the attempts of a batch are bounded by `send_deadline_ms`.
+    /// Defaults to 100 ms.
+    pub fn retry_backoff_ms(mut self, ms: u64) -> Self {
+        self.retry_backoff_ms = ms;
+        self
+    }
+
+    /// How many payloads which failed all of their attempts are kept to be retried later, with
+    /// the same backoff, instead of being dropped. Beyond it, the oldest retained payload is
+    /// dropped, so memory stays bounded while the agent is down. Payloads are retried up to 8
+    /// times. Defaults to `0`, which retains nothing. The async transports don't retain
+    /// payloads.
+    pub fn max_retained_payloads(mut self, payloads: usize) -> Self {
+        self.max_retained_payloads = payloads;
+        self
+    }
+
+    /// How many payloads in a row may fail all of their attempts before the circuit breaker
+    /// opens. While it's open, traces are dropped without contacting the agent, so an outage
+    /// doesn't cost every batch its timeouts, and retained payloads wait. A probe is let through
+    /// every `circuit_breaker_probe_ms`, and the breaker closes once one is accepted. Dropped
+    /// traces are counted in `ClientStats::circuit_breaker_dropped_traces`. Defaults to `0`,
+    /// which never opens it. The async transports don't have a circuit breaker.
+    pub fn circuit_breaker_failures(mut self, failures: u32) -> Self {
+        self.circuit_breaker_failures = failures;
+        self
+    }
+
+    /// How long the circuit breaker stays open before a probe is sent to the agent. Defaults to
+    /// 10 seconds.
+    pub fn circuit_breaker_probe_ms(mut self, ms: u64) -> Self {
+        self.circuit_breaker_probe_ms = ms;
+        self
+    }
+
+    /// How long a connection to the agent is kept open for reuse while no traces are sent.
+    /// Keeping it above the interval between sends under low traffic avoids reconnecting
+    /// for every batch.
+    pub fn pool_idle_timeout_ms(mut self, ms: u64) -> Self {
+        self.pool_idle_timeout_ms = ms;
+        self
+    }
+
+    /// How many idle connections to the agent are kept open for reuse; `0` disables reuse.
+    pub fn pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.pool_max_idle_per_host = max;
+        self
+    }
+
+    /// Talks HTTP/2 to the agent without negotiating it first, so every batch is multiplexed
+    /// on a single connection. Only enable this if the agent (or a proxy in front of it) accepts
+    /// HTTP/2 over cleartext. The `async-std` transport always uses HTTP/1.1.
+    pub fn http2_prior_knowledge(mut self, enabled: bool) -> Self {
+        self.http2_prior_knowledge = enabled;
+        self
+    }
+
+    /// Opts into a degraded mode for environments where only the DogStatsD UDP port of the agent
+    /// is reachable: batches which can't be sent to the trace endpoint are summarized as
+    /// DogStatsD metrics (`trace.<span name>.hits`, `.errors` and `.duration`, tagged with
+    /// service, resource and env) and sent over UDP to this port of the agent host.
+    /// The `async-std` transport doesn't support it.
+    pub fn dogstatsd_fallback_port(mut self, port: u16) -> Self {
+        self.dogstatsd_fallback_port = Some(port);
+        self
+    }
+
+    /// The agent computes trace metrics from the traces it receives, including the traces
+    /// rejected by sampling, which it drops afterwards. With this option, spans rejected by the
+    /// sampler of a `TracingSubscriberDatadog` are dropped and aggregated in the process instead,
+    /// to spare sending them, and their hits, errors and total duration are sent every 10
+    /// seconds (and on flushes) as DogStatsD metrics (`trace.<span name>.hits`, `.errors` and
+    /// `.duration`, tagged with service, resource and env) over UDP to this port of the agent
+    /// host.
+    pub fn sampled_out_stats_port(mut self, port: u16) -> Self {
+        self.sampled_out_stats_port = Some(port);
+        self
+    }
+
+    /// By default, traces are sent to the daemon as they are and serialized there, to keep
+    /// serialization off the request threads. Enabling this serializes them on the calling thread
+    /// before they are enqueued instead, e.g. to keep a busy daemon focused on sending.
+    pub fn serialize_on_caller(mut self, enabled: bool) -> Self {
+        self.serialize_on_caller = enabled;
+        self
+    }
+
+    /// Calls `hook` with every payload sent to the Datadog agent, once its send has been
+    /// attempted, so compliance teams can archive or audit exactly what was sent. The hook runs
+    /// on the daemon, so it should be quick.
+    pub fn on_payload(mut self, hook: impl Fn(&[u8], PayloadMeta) + Send + Sync + 'static) -> Self {
+        self.on_payload = Some(Arc::new(hook));
+        self
+    }
+
+    /// Corrects span timestamps for the skew between the local clock and the clock of the
+    /// Datadog agent, which otherwise shows up as negative gaps between parent and child spans
+    /// exported from different hosts. The skew is estimated every 5 minutes from the `Date`
+    /// header of the agent's `/info` endpoint, so skews below one second are not corrected.
+    /// The `async-std` transport doesn't support it.
+    pub fn clock_skew_correction(mut self, enabled: bool) -> Self {
+        self.clock_skew_correction = enabled;
+        self
+    }
+
+    /// Resolution of the span timestamps and durations sent to the agent, nanoseconds by
+    /// default.
+    pub fn timestamp_resolution(mut self, resolution: TimestampResolution) -> Self {
+        self.timestamp_resolution = resolution;
+        self
+    }
+
+    /// Version of the agent endpoint traces are sent to, `/v0.3/traces` by default.
+    pub fn trace_api_version(mut self, version: TraceApiVersion) -> Self {
+        self.trace_api_version = version;
+        self
+    }
+
+    /// Compression of the payloads sent to the agent or intake, which is announced in their
+    /// `Content-Encoding` header. Payloads are not compressed by default.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Parks the daemon thread once it has received no traces for this long, closing its
+    /// connections to the agent until the next trace wakes it up. By default the daemon waits
+    /// for traces with its connections kept alive.
+    pub fn idle_park_ms(mut self, idle_ms: u64) -> Self {
+        self.idle_park_ms = Some(idle_ms);
+        self
+    }
+
+    /// Hostname sent with traces, in v0.7 and intake payloads. Defaults to the `DD_HOSTNAME` env
+    /// var, or `HOSTNAME`.
+    pub fn hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.hostname = Some(hostname.into());
+        self
+    }
+
+    /// Env of the process, sent in the `env` field of v0.7 payloads whose spans have no `env`
+    /// tag. Defaults to the `DD_ENV` env var.
+    pub fn env(mut self, env: impl Into<String>) -> Self {
+        self.env = Some(env.into());
+        self
+    }
+
+    /// Version of the application, sent in the `app_version` field of v0.7 payloads whose spans
+    /// have no `version` tag. Defaults to the `DD_VERSION` env var.
+    pub fn app_version(mut self, app_version: impl Into<String>) -> Self {
+        self.app_version = Some(app_version.into());
+        self
+    }
+
+    /// Traces which are pending when the daemon wakes up are sent together, in payloads of up
+    /// to about this many bytes, as estimated by `Span::estimated_wire_size`. Defaults to the
+    /// 10 MiB limit of the Datadog agent. A single batch of traces which is larger on its own is
+    /// still sent alone.
+    pub fn max_payload_bytes(mut self, bytes: usize) -> Self {
+        self.max_payload_bytes = bytes;
+        self
+    }
+
+    /// Holds traces in the daemon thread for up to this long, so they're sent to the agent in
+    /// fewer, larger requests under load. A batch is sent once its first trace is this old, once
+    /// it holds `max_batch_traces` traces, or on a flush. `0`, the default, sends traces as soon
+    /// as the daemon picks them up. The async transports don't hold traces.
+    pub fn max_batch_age_ms(mut self, ms: u64) -> Self {
+        self.max_batch_age_ms = ms;
+        self
+    }
+
+    /// How many traces a held batch may accumulate before it is sent regardless of
+    /// `max_batch_age_ms`. Defaults to 1000.
+    pub fn max_batch_traces(mut self, traces: usize) -> Self {
+        self.max_batch_traces = traces.max(1);
+        self
+    }
+
+    /// How many `send_traces` calls may wait in the queue of the daemon thread, e.g. while the
+    /// agent is down, before `overflow_policy` applies. Defaults to 10000. The async transports
+    /// have unbounded queues.
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = capacity.max(1);
+        self
+    }
+
+    /// What happens to traces sent while the queue is full, `DropNewest` by default. Dropped
+    /// traces are counted in `ClientStats::dropped_traces`.
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.overflow_policy = policy;
+        self
+    }
+
+    /// Logs a sample of the spans sent, serialized in full, at DEBUG level. This makes it
+    /// practical to debug tags in production, e.g. with `SpanDebugLogging::OneIn(10_000)` or
+    /// `SpanDebugLogging::Errors`, without drowning the logs. Off by default.
+    pub fn span_debug_logging(mut self, logging: SpanDebugLogging) -> Self {
+        self.span_debug_logging = logging;
+        self
+    }
+
+    /// Traces the exporter itself under `service`: every batch of traces gets a
+    /// `datadog_exporter.payload` span, with `datadog_exporter.serialize` and
+    /// `datadog_exporter.send` children, to see exporter latency and failures in Datadog. These
+    /// spans are sent with the next batch of application traces, so they never cause a batch of
+    /// their own. Not supported by `Client::create_async_std`. Off by default.
+    pub fn self_instrumentation(mut self, service: ServiceName) -> Self {
+        self.self_instrumentation = Some(service);
+        self
+    }
+
+    /// Meant for local development: sends traces to stdout or a file instead of the Datadog
+    /// agent, on the calling thread, so spans can be looked at without running an agent. The
+    /// agent and sending settings are then unused. `TraceOutput::Agent` by default.
+    pub fn trace_output(mut self, output: TraceOutput) -> Self {
+        self.trace_output = output;
+        self
+    }
+
+    /// Meant for debugging: instead of passing traces to a daemon thread, `send_traces` sends
+    /// them to the Datadog agent on the calling thread and only returns once the request is done.
+    /// This uses a blocking HTTP client, so it must not be enabled inside an async runtime.
+    pub fn inline_export(mut self, enabled: bool) -> Self {
+        self.inline_export = enabled;
+        self
+    }
 }
 
 impl Default for ClientConfig {
@@ -56,298 +642,2696 @@ impl Default for ClientConfig {
             datadog_agent_port: 8126,
             connect_timeout_ms: 100,
             request_timeout_ms: 100,
+            adaptive_request_timeout: false,
+            max_request_timeout_ms: 1000,
+            slow_agent_threshold_ms: 250,
+            inline_export: false,
+            max_send_attempts: 1,
+            send_deadline_ms: 1000,
+            retry_backoff_ms: 100,
+            max_retained_payloads: 0,
+            circuit_breaker_failures: 0,
+            circuit_breaker_probe_ms: 10_000,
+            pool_idle_timeout_ms: 90_000,
+            pool_max_idle_per_host: 1,
+            http2_prior_knowledge: false,
+            dogstatsd_fallback_port: None,
+            sampled_out_stats_port: None,
+            serialize_on_caller: false,
+            on_payload: None,
+            clock_skew_correction: false,
+            timestamp_resolution: TimestampResolution::Nanos,
+            max_payload_bytes: MAX_PAYLOAD_BYTES,
+            max_batch_traces: 1000,
+            queue_capacity: 10_000,
+            overflow_policy: OverflowPolicy::DropNewest,
+            max_batch_age_ms: 0,
+            span_debug_logging: SpanDebugLogging::Off,
+            self_instrumentation: None,
+            trace_api_version: TraceApiVersion::V03,
+            compression: Compression::None,
+            idle_park_ms: None,
+            windows_pipe_name: None,
+            api_key: None,
+            datadog_site: None,
+            hostname: None,
+            env: None,
+            app_version: None,
+            trace_output: TraceOutput::Agent,
+        }
+    }
+}
+
+/// What `Client::send_traces` does when the queue of the daemon thread is full.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drops the traces being sent.
+    #[default]
+    DropNewest,
+    /// Drops the traces which have waited the longest to make room for the ones being sent.
+    DropOldest,
+    /// Blocks the sending thread until there's room, or drops the traces being sent after
+    /// `timeout_ms`.
+    Block { timeout_ms: u64 },
+}
+
+/// A point-in-time view of what the Client's daemon has been doing.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ClientStats {
+    pub requests_sent: u64,
+    pub requests_failed: u64,
+    pub request_timeout_ms: u64,
+    pub agent_slow: bool,
+    /// How long the most recently sent batch waited in the export queue
+    pub queue_wait_ms: u64,
+    /// How many traces were dropped because the export queue was full
+    pub dropped_traces: u64,
+    /// How many times the daemon thread died and was restarted
+    pub daemon_restarts: u64,
+    /// Whether the circuit breaker is open, so traces are dropped without contacting the agent
+    pub circuit_breaker_open: bool,
+    /// How many traces were dropped because the circuit breaker was open
+    pub circuit_breaker_dropped_traces: u64,
+}
+
+/// Whether the daemon thread of a Client is sending traces, from `Client::health`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ClientHealth {
+    /// The daemon thread is running, or the Client doesn't have one.
+    Healthy,
+    /// The daemon thread died, e.g. because it panicked, and traces are dropped until it's
+    /// restarted by a later send, after a backoff which grows with each restart.
+    DaemonDead,
+    /// The daemon thread stopped after `Client::shutdown`.
+    ShutDown,
+    /// The transport of the Client couldn't be set up, e.g. because of the TLS or proxy settings
+    /// of the environment, and every trace is dropped.
+    TransportFailed,
+}
+
+#[derive(Default)]
+struct ClientStatsRecorder {
+    requests_sent: AtomicU64,
+    requests_failed: AtomicU64,
+    request_timeout_ms: AtomicU64,
+    agent_slow: AtomicBool,
+    queue_wait_ms: AtomicU64,
+    dropped_traces: AtomicU64,
+    daemon_restarts: AtomicU64,
+    circuit_breaker_open: AtomicBool,
+    circuit_breaker_dropped_traces: AtomicU64,
+    // the sample rates of the agent's latest response
+    agent_sample_rates: Arc<AgentSampleRates>,
+}
+
+impl ClientStatsRecorder {
+    #[inline]
+    fn snapshot(&self) -> ClientStats {
+        ClientStats {
+            requests_sent: self.requests_sent.load(Ordering::Relaxed),
+            requests_failed: self.requests_failed.load(Ordering::Relaxed),
+            request_timeout_ms: self.request_timeout_ms.load(Ordering::Relaxed),
+            agent_slow: self.agent_slow.load(Ordering::Relaxed),
+            queue_wait_ms: self.queue_wait_ms.load(Ordering::Relaxed),
+            dropped_traces: self.dropped_traces.load(Ordering::Relaxed),
+            daemon_restarts: self.daemon_restarts.load(Ordering::Relaxed),
+            circuit_breaker_open: self.circuit_breaker_open.load(Ordering::Relaxed),
+            circuit_breaker_dropped_traces: self
+                .circuit_breaker_dropped_traces
+                .load(Ordering::Relaxed),
+        }
+    }
+}
+
+const AGENT_LATENCY_WINDOW: usize = 64;
+const MIN_SAMPLES_FOR_SLOW_AGENT: usize = 16;
+
+// Keeps the latencies of the most recent requests to the agent to adapt the request timeout
+// and to detect an agent which is consistently slow to respond
+struct AgentLatency {
+    samples_ms: VecDeque<u64>,
+    adaptive: bool,
+    base_timeout_ms: u64,
+    max_timeout_ms: u64,
+    slow_threshold_ms: u64,
+}
+
+impl AgentLatency {
+    fn new(config: &ClientConfig) -> Self {
+        Self {
+            samples_ms: VecDeque::with_capacity(AGENT_LATENCY_WINDOW),
+            adaptive: config.adaptive_request_timeout,
+            base_timeout_ms: config.request_timeout_ms,
+            max_timeout_ms: config.max_request_timeout_ms.max(config.request_timeout_ms),
+            slow_threshold_ms: config.slow_agent_threshold_ms,
+        }
+    }
+
+    #[inline]
+    fn record(&mut self, latency: Duration) {
+        if self.samples_ms.len() == AGENT_LATENCY_WINDOW {
+            self.samples_ms.pop_front();
+        }
+        self.samples_ms.push_back(latency.as_millis() as u64);
+    }
+
+    #[inline]
+    fn percentile(&self, percentile: f64) -> Option<u64> {
+        if self.samples_ms.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<u64> = self.samples_ms.iter().copied().collect();
+        sorted.sort_unstable();
+        let index = ((sorted.len() - 1) as f64 * percentile).round() as usize;
+        sorted.get(index).copied()
+    }
+
+    #[inline]
+    fn request_timeout_ms(&self) -> u64 {
+        if !self.adaptive {
+            return self.base_timeout_ms;
+        }
+        self.percentile(0.99)
+            .map(|p99| p99.saturating_mul(2))
+            .unwrap_or(self.base_timeout_ms)
+            .clamp(self.base_timeout_ms, self.max_timeout_ms)
+    }
+
+    #[inline]
+    fn is_slow(&self) -> bool {
+        self.samples_ms.len() >= MIN_SAMPLES_FOR_SLOW_AGENT
+            && self
+                .percentile(0.5)
+                .is_some_and(|median| median > self.slow_threshold_ms)
+    }
+}
+
+// Bounds the attempts to send a single batch of traces: there are at most `max_send_attempts`
+// attempts, and none of them may run past the send deadline
+struct SendBudget {
+    attempts_left: u32,
+    deadline: Instant,
+}
+
+impl SendBudget {
+    #[inline]
+    fn new(max_attempts: u32, deadline_ms: u64) -> Self {
+        Self {
+            attempts_left: max_attempts,
+            deadline: Instant::now() + Duration::from_millis(deadline_ms),
+        }
+    }
+
+    // Returns the timeout of the next attempt, or None if the batch should be abandoned
+    #[inline]
+    fn next_attempt(&mut self, attempt_timeout: Duration) -> Option<Duration> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if self.attempts_left == 0 || remaining.is_zero() {
+            return None;
+        }
+        self.attempts_left -= 1;
+        Some(attempt_timeout.min(remaining))
+    }
+
+    // Returns how long to wait before the next attempt, or None if there is no next attempt
+    #[inline]
+    fn retry_delay(&self, retry_backoff: Duration, failures: u32) -> Option<Duration> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if self.attempts_left == 0 || remaining.is_zero() {
+            return None;
+        }
+        Some(backoff_delay(retry_backoff, failures).min(remaining))
+    }
+}
+
+// Stops sending to an agent once `failures_to_open` payloads in a row failed, until a probe sent
+// after `probe_interval` is accepted
+struct CircuitBreaker {
+    failures_to_open: u32,
+    probe_interval: Duration,
+    consecutive_failures: u32,
+    // when the next probe may be sent, while the breaker is open
+    probe_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    fn new(config: &ClientConfig) -> Self {
+        Self {
+            failures_to_open: config.circuit_breaker_failures,
+            probe_interval: Duration::from_millis(config.circuit_breaker_probe_ms),
+            consecutive_failures: 0,
+            probe_at: None,
+        }
+    }
+
+    // When the next probe may be sent, while the breaker is open and payloads must wait for it
+    #[inline]
+    fn next_probe_at(&self) -> Option<Instant> {
+        self.probe_at.filter(|probe_at| Instant::now() < *probe_at)
+    }
+
+    // Whether a payload may be sent: always while the breaker is closed, and as a probe once
+    // the probe interval has elapsed while it's open
+    #[inline]
+    fn allows_send(&self) -> bool {
+        self.next_probe_at().is_none()
+    }
+
+    // Records whether a payload was sent, returning whether the breaker is open if that changed
+    #[inline]
+    fn record(&mut self, sent: bool) -> Option<bool> {
+        if self.failures_to_open == 0 {
+            return None;
+        }
+        if sent {
+            self.consecutive_failures = 0;
+            return self.probe_at.take().map(|_| false);
+        }
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        if self.consecutive_failures < self.failures_to_open {
+            return None;
         }
+        let was_open = self.probe_at.is_some();
+        self.probe_at = Some(Instant::now() + self.probe_interval);
+        (!was_open).then_some(true)
+    }
+}
+
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_RETAINED_FAILURES: u32 = 8;
+
+// Exponential backoff with jitter: the delay doubles with each failure, and its second half is
+// random
+#[inline]
+fn backoff_delay(retry_backoff: Duration, failures: u32) -> Duration {
+    let delay = retry_backoff
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(MAX_RETRY_BACKOFF);
+    let half = delay / 2;
+    half + half.mul_f64(rand::thread_rng().gen::<f64>())
+}
+
+// A payload which failed all of its attempts, kept to be retried once its backoff has elapsed
+struct RetainedPayload {
+    trace_json: serde_json::Value,
+    body: Vec<u8>,
+    failures: u32,
+    retry_at: Instant,
+}
+
+/// Outcome of flushing the traces which were enqueued on a Client.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FlushResult {
+    /// Every trace enqueued before the flush was requested has been attempted.
+    Flushed,
+    /// The daemon thread is not running, so enqueued traces can't be attempted.
+    DaemonUnavailable,
+    /// The enqueued traces were not all attempted within the timeout of `Client::flush`.
+    TimedOut,
+}
+
+/// What `Client::shutdown` made of the traces which were enqueued when it was called.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Traces attempted before the deadline, whether the agent accepted them or not.
+    pub attempted_traces: usize,
+    /// Traces left unsent because the deadline had passed.
+    pub abandoned_traces: usize,
+}
+
+enum DaemonMessage {
+    Traces {
+        payload: TracePayload,
+        enqueued_at: Instant,
+        has_error: bool,
+        has_root: bool,
+        trace_count: usize,
+        estimated_size: usize,
+    },
+    Flush(Box<dyn FnOnce() + Send>),
+    Shutdown {
+        deadline: Instant,
+        on_drained: Box<dyn FnOnce(ShutdownReport) + Send>,
+    },
+}
+
+// Traces are either serialized by the caller before being enqueued, or by the daemon
+enum TracePayload {
+    Serialized(serde_json::Value),
+    Owned(Traces),
+}
+
+impl TracePayload {
+    #[inline]
+    fn into_json(self) -> serde_json::Value {
+        match self {
+            TracePayload::Serialized(trace_json) => trace_json,
+            TracePayload::Owned(traces) => serialize_traces(traces),
+        }
+    }
+}
+
+#[inline]
+fn serialize_traces(traces: Traces) -> serde_json::Value {
+    serde_json::to_value(traces).unwrap_or_else(|e| {
+        log::error!("Failed to serialize traces into JSON value. Err: {}", e);
+        serde_json::Value::default()
+    })
+}
+
+// Accumulates the traces of several messages into a single payload, up to a byte threshold
+struct PayloadBatch {
+    traces: Vec<serde_json::Value>,
+    messages: usize,
+    estimated_size: usize,
+    max_bytes: usize,
+    started: Option<Instant>,
+}
+
+impl PayloadBatch {
+    #[inline]
+    fn new(max_bytes: usize) -> Self {
+        Self {
+            traces: Vec::new(),
+            messages: 0,
+            estimated_size: 0,
+            max_bytes,
+            started: None,
+        }
+    }
+
+    // How long the batch may still be held before it's sent, if it is held at all
+    #[inline]
+    fn remaining_age(&self, max_age: Duration) -> Option<Duration> {
+        self.started
+            .map(|started| max_age.saturating_sub(started.elapsed()))
+    }
+
+    #[inline]
+    fn is_due(&self, max_traces: usize, max_age: Duration) -> bool {
+        self.traces.len() >= max_traces || self.remaining_age(max_age) == Some(Duration::ZERO)
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.messages == 0
+    }
+
+    #[inline]
+    fn fits(&self, estimated_size: usize) -> bool {
+        self.is_empty() || self.estimated_size + estimated_size <= self.max_bytes
+    }
+
+    #[inline]
+    fn push(&mut self, trace_json: serde_json::Value, estimated_size: usize) {
+        match trace_json {
+            serde_json::Value::Array(traces) => self.traces.extend(traces),
+            trace_json => self.traces.push(trace_json),
+        }
+        self.messages += 1;
+        self.estimated_size += estimated_size;
+        self.started.get_or_insert_with(Instant::now);
+    }
+
+    #[inline]
+    fn take(&mut self) -> serde_json::Value {
+        self.messages = 0;
+        self.estimated_size = 0;
+        self.started = None;
+        serde_json::Value::Array(std::mem::take(&mut self.traces))
+    }
+}
+
+// Orders the messages which were pending in the queue so that batches containing an error are
+// sent first, as they are the ones we can't afford to have held up behind a slow agent. During a
+// shutdown, batches containing a root span come next, as they give the most complete picture of
+// requests. Flushes go last, so they still resolve after every batch which was enqueued before
+// them, followed by a shutdown.
+#[inline]
+fn prioritize(messages: &mut [DaemonMessage]) {
+    let shutting_down = messages
+        .iter()
+        .any(|message| matches!(message, DaemonMessage::Shutdown { .. }));
+    messages.sort_by_key(|message| match message {
+        DaemonMessage::Traces {
+            has_error: true, ..
+        } => 0,
+        DaemonMessage::Traces { has_root: true, .. } if shutting_down => 1,
+        DaemonMessage::Traces { .. } => 2,
+        DaemonMessage::Flush(_) => 3,
+        DaemonMessage::Shutdown { .. } => 4,
+    });
+}
+
+// Spends the deadline of a shutdown on pending traces in the order of `prioritize`, and counts
+// the traces it abandons once the deadline has passed
+struct ShutdownDrain {
+    deadline: Instant,
+    report: ShutdownReport,
+}
+
+impl ShutdownDrain {
+    // The drain of the shutdown among pending messages, if any
+    #[inline]
+    fn find(messages: &[DaemonMessage]) -> Option<Self> {
+        messages.iter().find_map(|message| match message {
+            DaemonMessage::Shutdown { deadline, .. } => Some(Self {
+                deadline: *deadline,
+                report: ShutdownReport::default(),
+            }),
+            _ => None,
+        })
+    }
+
+    // Whether traces may still be sent
+    #[inline]
+    fn admit(&mut self, trace_count: usize) -> bool {
+        if Instant::now() < self.deadline {
+            self.report.attempted_traces += trace_count;
+            true
+        } else {
+            self.report.abandoned_traces += trace_count;
+            false
+        }
+    }
+}
+
+/// Sends traces to the Datadog agent, by default from a daemon thread.
+///
+/// Clients survive forks (daemonizing servers, pre-fork workers): the daemon thread doesn't
+/// exist in a forked child, so the first trace sent from the child respawns it there. Traces
+/// which were still queued when the process forked are only sent by the parent. The `async-std`
+/// daemon is not respawned.
+pub struct Client {
+    transport: Transport,
+    stats: Arc<ClientStatsRecorder>,
+    serialize_on_caller: bool,
+    span_debug_logging: SpanDebugLogging,
+    send_deadline: Duration,
+    sampled_out_stats: Option<SampledOutStats>,
+    span_events: bool,
+}
+
+// Only the v0.7 format has a field for span events, and agentless payloads are never in it
+#[inline]
+fn sends_span_events(config: &ClientConfig) -> bool {
+    config.trace_api_version == TraceApiVersion::V07 && config.api_key.is_none()
+}
+
+#[inline]
+fn sampled_out_stats(config: &ClientConfig) -> Option<SampledOutStats> {
+    config.sampled_out_stats_port.and_then(|port| {
+        SampledOutStats::new(&config.datadog_agent_host, port)
+            .map_err(|e| log::error!("Failed to set up sampled out stats; err {}", e))
+            .ok()
+    })
+}
+
+enum Transport {
+    Daemon {
+        daemon: RwLock<Daemon>,
+        config: ClientConfig,
+    },
+    Inline(AgentExporter),
+    #[cfg(feature = "async_std")]
+    AsyncStd(async_std::channel::Sender<DaemonMessage>),
+    #[cfg(feature = "tokio")]
+    Tokio(tokio::sync::mpsc::UnboundedSender<DaemonMessage>),
+    Validate(ViolationCallback),
+    Export(Box<dyn SpanExporter>),
+    // the transport couldn't be set up, so traces are dropped
+    Failed,
+}
+
+type ViolationCallback = Box<dyn Fn(&Violation) + Send + Sync>;
+
+// The daemon thread of a Client and the process it was spawned in
+struct Daemon {
+    // bounded by the queue capacity
+    traces: crossbeam_channel::Sender<DaemonMessage>,
+    // flushes and shutdowns, which are always enqueued and never evicted
+    control: crossbeam_channel::Sender<DaemonMessage>,
+    // evicts the oldest traces of a full queue
+    evictions: crossbeam_channel::Receiver<DaemonMessage>,
+    // disconnected once the daemon thread is exiting, even if it panicked
+    exited: crossbeam_channel::Receiver<()>,
+    thread: std::thread::Thread,
+    // taken when the thread is joined
+    handle: Option<JoinHandle<()>>,
+    state: Arc<DaemonState>,
+    pid: u32,
+    started_at: Instant,
+    // how many times in a row the daemon died and was restarted before this one
+    restarts: u32,
+}
+
+// Whether this thread runs a current-thread `tokio` runtime, which blocking would stall
+#[cfg(feature = "tokio")]
+#[inline]
+fn on_current_thread_runtime() -> bool {
+    tokio::runtime::Handle::try_current()
+        .is_ok_and(|handle| handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::CurrentThread)
+}
+
+// The pid of the process, cached so that sends don't make a `getpid` syscall each. The cache is
+// cleared in forked children by a fork handler registered before the first pid is cached, so
+// children read their own pid on their first send.
+#[cfg(unix)]
+static CURRENT_PID: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+
+#[cfg(unix)]
+#[inline]
+fn current_pid() -> u32 {
+    let pid = CURRENT_PID.load(Ordering::Relaxed);
+    if pid != 0 {
+        return pid;
+    }
+    static FORK_HANDLER: std::sync::Once = std::sync::Once::new();
+    FORK_HANDLER.call_once(|| {
+        extern "C" fn clear_pid() {
+            CURRENT_PID.store(0, Ordering::Relaxed);
+        }
+        // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+        if unsafe { libc::pthread_atfork(None, None, Some(clear_pid)) } != 0 {
+            log::error!("Failed to register fork handler, forked children may drop traces");
+        }
+    });
+    let pid = std::process::id();
+    CURRENT_PID.store(pid, Ordering::Relaxed);
+    pid
+}
+
+// Reading the pid doesn't make a syscall outside of unix
+#[cfg(not(unix))]
+#[inline]
+fn current_pid() -> u32 {
+    std::process::id()
+}
+
+// A dead daemon thread is restarted once it has run for at least the backoff, which doubles
+// with each restart in a row, up to the maximum. A daemon which ran for longer than the
+// maximum starts over from the first backoff.
+const DAEMON_RESTART_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_DAEMON_RESTART_BACKOFF: Duration = Duration::from_secs(30);
+
+// Whether a daemon thread is parked, stopped or shut down, and whether its Client is suspended
+#[derive(Default)]
+struct DaemonState {
+    parked: AtomicBool,
+    suspended: AtomicBool,
+    stopped: AtomicBool,
+    shut_down: AtomicBool,
+}
+
+// The receiving ends of the queues of a daemon thread
+#[derive(Clone)]
+struct DaemonQueues {
+    traces: crossbeam_channel::Receiver<DaemonMessage>,
+    control: crossbeam_channel::Receiver<DaemonMessage>,
+}
+
+impl DaemonQueues {
+    // Waits until either queue has a message, for at most `timeout` if any. Messages are left in
+    // their queue, so that traces waiting for a suspended daemon thread still fill it up, unless
+    // one had to be taken out to tell whether the queues are disconnected.
+    fn wait(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<DaemonMessage>, crossbeam_channel::RecvTimeoutError> {
+        let mut select = crossbeam_channel::Select::new();
+        select.recv(&self.traces);
+        select.recv(&self.control);
+        match timeout {
+            Some(timeout) => {
+                select
+                    .ready_timeout(timeout)
+                    .map_err(|_| crossbeam_channel::RecvTimeoutError::Timeout)?;
+            }
+            None => {
+                select.ready();
+            }
+        }
+        if !self.is_empty() {
+            return Ok(None);
+        }
+        // queues are ready once disconnected too, which both are when the Client is dropped, or
+        // when the trace which was ready got evicted
+        match self.control.try_recv() {
+            Ok(message) => Ok(Some(message)),
+            Err(crossbeam_channel::TryRecvError::Empty) => Ok(None),
+            Err(crossbeam_channel::TryRecvError::Disconnected) => self
+                .traces
+                .try_recv()
+                .map(Some)
+                .map_err(|_| crossbeam_channel::RecvTimeoutError::Disconnected),
+        }
+    }
+
+    // The messages waiting in the queues. Flushes and shutdowns are taken before traces, so that
+    // every trace which was enqueued before them is taken along.
+    #[inline]
+    fn pending(&self) -> impl Iterator<Item = DaemonMessage> + '_ {
+        let control: Vec<_> = self.control.try_iter().collect();
+        control.into_iter().chain(self.traces.try_iter())
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.traces.is_empty() && self.control.is_empty()
+    }
+}
+
+impl Daemon {
+    // Whether the daemon thread stopped without being shut down, e.g. because it panicked
+    #[inline]
+    fn is_dead(&self) -> bool {
+        self.state.stopped.load(Ordering::SeqCst) && !self.state.shut_down.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    fn restart_backoff(&self) -> Duration {
+        DAEMON_RESTART_BACKOFF
+            .saturating_mul(1 << self.restarts.min(16))
+            .min(MAX_DAEMON_RESTART_BACKOFF)
+    }
+
+    // Sends a message to the daemon thread, applying the overflow policy to trace messages
+    #[inline]
+    fn enqueue(
+        &self,
+        message: DaemonMessage,
+        config: &ClientConfig,
+        stats: &ClientStatsRecorder,
+    ) -> bool {
+        if self.state.stopped.load(Ordering::SeqCst) {
+            log::error!("Failed to send message on channel; the daemon thread has stopped");
+            return false;
+        }
+        let DaemonMessage::Traces { trace_count, .. } = message else {
+            return self
+                .control
+                .send(message)
+                .map_err(|e| log::error!("Failed to send message on channel; err {:?}", e))
+                .is_ok();
+        };
+        match self.send_traces(message, config.overflow_policy, stats) {
+            Ok(()) => true,
+            Err(crossbeam_channel::TrySendError::Full(_)) => {
+                stats
+                    .dropped_traces
+                    .fetch_add(trace_count as u64, Ordering::Relaxed);
+                false
+            }
+            Err(e) => {
+                log::error!("Failed to send message on channel; err {:?}", e);
+                false
+            }
+        }
+    }
+
+    // Sends a trace message to the bounded queue, which is still full if the message is returned
+    #[inline]
+    fn send_traces(
+        &self,
+        mut message: DaemonMessage,
+        overflow_policy: OverflowPolicy,
+        stats: &ClientStatsRecorder,
+    ) -> Result<(), crossbeam_channel::TrySendError<DaemonMessage>> {
+        match overflow_policy {
+            OverflowPolicy::DropNewest => self.traces.try_send(message),
+            OverflowPolicy::DropOldest => loop {
+                match self.traces.try_send(message) {
+                    Err(crossbeam_channel::TrySendError::Full(rejected)) => {
+                        self.evict_oldest(stats);
+                        message = rejected;
+                    }
+                    result => break result,
+                }
+            },
+            OverflowPolicy::Block { timeout_ms } => self
+                .traces
+                .send_timeout(message, Duration::from_millis(timeout_ms))
+                .map_err(|e| match e {
+                    crossbeam_channel::SendTimeoutError::Timeout(message) => {
+                        crossbeam_channel::TrySendError::Full(message)
+                    }
+                    crossbeam_channel::SendTimeoutError::Disconnected(message) => {
+                        crossbeam_channel::TrySendError::Disconnected(message)
+                    }
+                }),
+        }
+    }
+
+    // Drops the oldest trace message of the queue, unless the daemon thread just dequeued it
+    #[inline]
+    fn evict_oldest(&self, stats: &ClientStatsRecorder) {
+        if let Ok(DaemonMessage::Traces { trace_count, .. }) = self.evictions.try_recv() {
+            stats
+                .dropped_traces
+                .fetch_add(trace_count as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+// Stops the queues of a daemon thread when the thread exits, even if it panicked
+struct StopOnExit {
+    state: Arc<DaemonState>,
+    queues: DaemonQueues,
+}
+
+impl Drop for StopOnExit {
+    fn drop(&mut self) {
+        self.state.stop(&self.queues);
+    }
+}
+
+impl DaemonState {
+    // Parks the daemon thread until it's woken up by a message, or by `Client::resume` if it has
+    // messages to send already
+    fn park(&self, queues: &DaemonQueues, has_messages: bool) {
+        self.parked.store(true, Ordering::SeqCst);
+        while self.parked.load(Ordering::SeqCst) {
+            // messages enqueued (or a resume) before `parked` was set didn't unpark the thread
+            if !self.suspended.load(Ordering::SeqCst) && (has_messages || !queues.is_empty()) {
+                break;
+            }
+            std::thread::park();
+        }
+        self.parked.store(false, Ordering::SeqCst);
+    }
+
+    // Clients keep a receiver of the trace queue to evict traces, so the queue outlives the
+    // daemon thread: messages left in the queues are dropped, and later ones are refused
+    fn stop(&self, queues: &DaemonQueues) {
+        self.stopped.store(true, Ordering::SeqCst);
+        queues.pending().for_each(drop);
+    }
+
+    #[inline]
+    fn wake(&self, daemon: &std::thread::Thread) {
+        if !self.suspended.load(Ordering::SeqCst)
+            && self.parked.load(Ordering::SeqCst)
+            && self.parked.swap(false, Ordering::SeqCst)
+        {
+            daemon.unpark();
+        }
+    }
+}
+
+impl Client {
+    pub fn create_default() -> Self {
+        Self::create_with_config(ClientConfig::default())
+    }
+
+    pub fn create_with_config(config: ClientConfig) -> Self {
+        let stats = Arc::new(ClientStatsRecorder::default());
+        stats
+            .request_timeout_ms
+            .store(config.request_timeout_ms, Ordering::Relaxed);
+
+        let serialize_on_caller = config.serialize_on_caller;
+        let span_debug_logging = config.span_debug_logging;
+        let send_deadline = Duration::from_millis(config.send_deadline_ms);
+        let sampled_out_stats = sampled_out_stats(&config);
+        let span_events = sends_span_events(&config);
+        let transport = match &config.trace_output {
+            TraceOutput::Agent => Self::agent_transport(config, &stats),
+            TraceOutput::Stdout => {
+                log::info!("Printing traces to stdout instead of sending them to Datadog agent");
+                Transport::Export(Box::new(StdoutExporter::new()))
+            }
+            TraceOutput::File(path) => {
+                log::info!(
+                    "Writing traces to {} instead of sending them to Datadog agent",
+                    path.display()
+                );
+                match FileExporter::open(path) {
+                    Ok(file_exporter) => Transport::Export(Box::new(file_exporter)),
+                    Err(e) => {
+                        log::error!("Failed to open file for traces, dropping them; err {}", e);
+                        Transport::Failed
+                    }
+                }
+            }
+        };
+
+        Self {
+            transport,
+            stats,
+            serialize_on_caller,
+            span_debug_logging,
+            send_deadline,
+            sampled_out_stats,
+            span_events,
+        }
+    }
+
+    fn agent_transport(config: ClientConfig, stats: &Arc<ClientStatsRecorder>) -> Transport {
+        if config.inline_export {
+            log::info!("Sending traces to Datadog agent inline on the calling threads");
+            match AgentExporter::with_stats(&config, Arc::clone(stats)) {
+                Ok(agent_exporter) => Transport::Inline(agent_exporter),
+                Err(e) => {
+                    log::error!(
+                        "Failed to construct client for inline export, dropping traces; err {:?}",
+                        e
+                    );
+                    Transport::Failed
+                }
+            }
+        } else {
+            let daemon = Self::spawn_daemon(config.clone(), Arc::clone(stats));
+            Transport::Daemon {
+                daemon: RwLock::new(daemon),
+                config,
+            }
+        }
+    }
+
+    fn spawn_daemon(config: ClientConfig, stats: Arc<ClientStatsRecorder>) -> Daemon {
+        // flushes and shutdowns have their own queue, so that they are always enqueued, and
+        // evicting traces never reorders them
+        let (traces, traces_receiver) =
+            crossbeam_channel::bounded::<DaemonMessage>(config.queue_capacity);
+        let (control, control_receiver) = crossbeam_channel::unbounded::<DaemonMessage>();
+        let evictions = traces_receiver.clone();
+        let queues = DaemonQueues {
+            traces: traces_receiver,
+            control: control_receiver,
+        };
+        let (exit_sender, exited) = crossbeam_channel::bounded::<()>(0);
+        let state = Arc::new(DaemonState::default());
+
+        let daemon_state = Arc::clone(&state);
+        let daemon: JoinHandle<()> = std::thread::spawn(move || {
+            let _exit_sender = exit_sender;
+            let _stop_on_exit = StopOnExit {
+                state: Arc::clone(&daemon_state),
+                queues: queues.clone(),
+            };
+            log::info!("Starting daemon thread to pass traces to Datadog agent");
+            // spans and events of the HTTP client would otherwise be traced, and exported by
+            // this same thread, forever
+            let _no_tracing = tracing::dispatcher::set_default(&tracing::Dispatch::none());
+            let mut agent_sender = match AgentSender::new(&config, stats) {
+                Ok(agent_sender) => agent_sender,
+                Err(e) => {
+                    log::error!("Failed to construct client, killing daemon; err {:?}", e);
+                    return;
+                }
+            };
+            let max_batch_age = Duration::from_millis(config.max_batch_age_ms);
+            let mut batch = PayloadBatch::new(config.max_payload_bytes);
+            let mut messages = Vec::new();
+            loop {
+                if messages.is_empty() {
+                    // held batches and retained payloads wake the daemon up when they're due
+                    let wait = batch
+                        .remaining_age(max_batch_age)
+                        .into_iter()
+                        .chain(agent_sender.next_retry_in())
+                        .min();
+                    let received = match (wait, config.idle_park_ms) {
+                        (Some(wait), _) => queues.wait(Some(wait)),
+                        (None, Some(idle_ms)) => queues.wait(Some(Duration::from_millis(idle_ms))),
+                        (None, None) => queues.wait(None),
+                    };
+                    match received {
+                        Ok(message) => messages.extend(message),
+                        Err(crossbeam_channel::RecvTimeoutError::Timeout) if wait.is_some() => {
+                            if daemon_state.suspended.load(Ordering::SeqCst) {
+                                agent_sender.release_http_client();
+                                daemon_state.park(&queues, true);
+                            } else if batch.remaining_age(max_batch_age) == Some(Duration::ZERO) {
+                                agent_sender.send(batch.take());
+                            } else {
+                                agent_sender.retry_retained(false);
+                            }
+                            continue;
+                        }
+                        Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                            log::debug!("Daemon thread is idle, parking it");
+                            agent_sender.release_http_client();
+                            daemon_state.park(&queues, false);
+                            continue;
+                        }
+                        Err(e) => {
+                            if !batch.is_empty() {
+                                agent_sender.send(batch.take());
+                            }
+                            agent_sender.retry_retained(true);
+                            log::info!("Client was dropped, stopping daemon thread; err {:?}", e);
+                            break;
+                        }
+                    }
+                }
+                if daemon_state.suspended.load(Ordering::SeqCst) {
+                    log::info!("Client was suspended, parking daemon thread");
+                    agent_sender.release_http_client();
+                    daemon_state.park(&queues, true);
+                    continue;
+                }
+                messages.extend(queues.pending());
+                prioritize(&mut messages);
+                let mut shutdown_drain = ShutdownDrain::find(&messages);
+                let shutting_down = shutdown_drain.is_some();
+                if let Some(drain) = &mut shutdown_drain {
+                    // held traces are sent with the first batch of the shutdown
+                    drain.report.attempted_traces += batch.traces.len();
+                }
+                for message in messages.drain(..) {
+                    match message {
+                        DaemonMessage::Traces {
+                            payload,
+                            enqueued_at,
+                            estimated_size,
+                            trace_count,
+                            ..
+                        } => {
+                            if shutdown_drain
+                                .as_mut()
+                                .is_some_and(|drain| !drain.admit(trace_count))
+                            {
+                                continue;
+                            }
+                            if !batch.fits(estimated_size) {
+                                agent_sender.send(batch.take());
+                            }
+                            let mut trace_json = payload.into_json();
+                            record_queue_wait(&mut trace_json, enqueued_at, &agent_sender.stats);
+                            batch.push(trace_json, estimated_size);
+                        }
+                        DaemonMessage::Flush(on_flushed) => {
+                            if !batch.is_empty() {
+                                agent_sender.send(batch.take());
+                            }
+                            on_flushed()
+                        }
+                        DaemonMessage::Shutdown {
+                            deadline,
+                            on_drained,
+                        } => {
+                            if !batch.is_empty() {
+                                agent_sender.send(batch.take());
+                            }
+                            if Instant::now() < deadline {
+                                agent_sender.retry_retained(true);
+                            }
+                            let report = shutdown_drain
+                                .take()
+                                .map(|drain| drain.report)
+                                .unwrap_or_default();
+                            on_drained(report)
+                        }
+                    }
+                }
+                if !batch.is_empty()
+                    && (shutting_down || batch.is_due(config.max_batch_traces, max_batch_age))
+                {
+                    agent_sender.send(batch.take());
+                }
+                if shutting_down {
+                    log::info!("Client was shut down, stopping daemon thread");
+                    daemon_state.shut_down.store(true, Ordering::SeqCst);
+                    break;
+                }
+            }
+        });
+
+        Daemon {
+            traces,
+            control,
+            evictions,
+            exited,
+            thread: daemon.thread().clone(),
+            handle: Some(daemon),
+            state,
+            pid: current_pid(),
+            started_at: Instant::now(),
+            restarts: 0,
+        }
+    }
+
+    /// Creates a Client whose daemon is an `async-std` task rather than an OS thread, so
+    /// `async-std` applications export traces without blocking any thread. It must be called
+    /// from within an `async-std` application.
+    #[cfg(feature = "async_std")]
+    pub fn create_async_std(config: ClientConfig) -> Self {
+        let stats = Arc::new(ClientStatsRecorder::default());
+        stats
+            .request_timeout_ms
+            .store(config.request_timeout_ms, Ordering::Relaxed);
+        if config.api_key.is_some() {
+            log::error!(
+                "The async-std transport can't send traces to the intake, only to the agent"
+            );
+        }
+        let (sender, receiver) = async_std::channel::unbounded::<DaemonMessage>();
+        let serialize_on_caller = config.serialize_on_caller;
+        let span_debug_logging = config.span_debug_logging;
+        let send_deadline = Duration::from_millis(config.send_deadline_ms);
+        let sampled_out_stats = sampled_out_stats(&config);
+        let span_events = sends_span_events(&config);
+
+        let mut async_std_sender = AsyncStdSender {
+            tracer_metadata: tracer_metadata(&config),
+            agent_latency: AgentLatency::new(&config),
+            config,
+            stats: Arc::clone(&stats),
+        };
+        async_std::task::spawn(async move {
+            log::info!("Starting async-std task to pass traces to Datadog agent");
+            while let Ok(message) = receiver.recv().await {
+                let mut messages = vec![message];
+                messages.extend(std::iter::from_fn(|| receiver.try_recv().ok()));
+                if drain_async(&mut async_std_sender, messages).await {
+                    break;
+                }
+            }
+            log::info!("Client was dropped, stopping async-std task");
+        });
+
+        Self {
+            transport: Transport::AsyncStd(sender),
+            stats,
+            serialize_on_caller,
+            span_debug_logging,
+            send_deadline,
+            sampled_out_stats,
+            span_events,
+        }
+    }
+
+    /// Creates a Client whose daemon is a `tokio` task sending traces with the async `reqwest`
+    /// client, so `tokio` applications don't need a dedicated thread, and can await flushes with
+    /// `flush_async`. It must be called from within a `tokio` runtime. Clock skew correction,
+    /// the DogStatsD fallback, self-instrumentation and named pipes are not supported.
+    #[cfg(feature = "tokio")]
+    pub fn create_tokio(config: ClientConfig) -> Self {
+        let stats = Arc::new(ClientStatsRecorder::default());
+        stats
+            .request_timeout_ms
+            .store(config.request_timeout_ms, Ordering::Relaxed);
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<DaemonMessage>();
+        let serialize_on_caller = config.serialize_on_caller;
+        let span_debug_logging = config.span_debug_logging;
+        let send_deadline = Duration::from_millis(config.send_deadline_ms);
+        let sampled_out_stats = sampled_out_stats(&config);
+        let span_events = sends_span_events(&config);
+
+        let transport = match TokioSender::new(config, Arc::clone(&stats)) {
+            Ok(mut tokio_sender) => {
+                tokio::spawn(async move {
+                    log::info!("Starting tokio task to pass traces to Datadog agent");
+                    while let Some(message) = receiver.recv().await {
+                        let mut messages = vec![message];
+                        messages.extend(std::iter::from_fn(|| receiver.try_recv().ok()));
+                        if drain_async(&mut tokio_sender, messages).await {
+                            break;
+                        }
+                    }
+                    log::info!("Client was dropped, stopping tokio task");
+                });
+                Transport::Tokio(sender)
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to construct HTTP client, dropping traces; err {:?}",
+                    e
+                );
+                Transport::Failed
+            }
+        };
+
+        Self {
+            transport,
+            stats,
+            serialize_on_caller,
+            span_debug_logging,
+            send_deadline,
+            sampled_out_stats,
+            span_events,
+        }
+    }
+
+    /// Creates a Client which never sends traces, but checks them against the constraints of
+    /// Datadog (id ranges, tag name rules, payload size, missing service or resource) and calls
+    /// `on_violation` for every violation. Meant for CI, to catch instrumentation regressions:
+    ///
+    /// ```ignore
+    /// let client = Client::create_validating(|violation| log::warn!("{}", violation));
+    /// ```
+    pub fn create_validating(on_violation: impl Fn(&Violation) + Send + Sync + 'static) -> Self {
+        log::info!("Validating traces instead of sending them to Datadog agent");
+        Self {
+            transport: Transport::Validate(Box::new(on_violation)),
+            stats: Arc::new(ClientStatsRecorder::default()),
+            serialize_on_caller: false,
+            span_debug_logging: SpanDebugLogging::Off,
+            send_deadline: Duration::ZERO,
+            sampled_out_stats: None,
+            span_events: false,
+        }
+    }
+
+    /// Creates a Client which hands every trace to `exporter` on the calling thread instead of
+    /// sending it to the agent, so the host of spans built in a wasm guest (or any other
+    /// embedding, or a backend like an internal collector) decides how they are exported.
+    /// Failed exports are logged:
+    ///
+    /// ```ignore
+    /// let client = Client::create_with_exporter(|traces: Traces| host::export(traces));
+    /// let exporter: Box<dyn SpanExporter> = Box::new(CollectorExporter::new(url));
+    /// let client = Client::create_with_exporter(exporter);
+    /// ```
+    pub fn create_with_exporter(exporter: impl SpanExporter + 'static) -> Self {
+        Self {
+            transport: Transport::Export(Box::new(exporter)),
+            stats: Arc::new(ClientStatsRecorder::default()),
+            serialize_on_caller: false,
+            span_debug_logging: SpanDebugLogging::Off,
+            send_deadline: Duration::ZERO,
+            sampled_out_stats: None,
+            span_events: false,
+        }
+    }
+
+    pub fn stats(&self) -> ClientStats {
+        self.stats.snapshot()
+    }
+
+    /// The sample rates the agent returns to the payloads of this Client, to sample traces with
+    /// `sampling::AgentRateSampler`. Only the daemon thread and `tokio` clients sending to the
+    /// agent over HTTP read them.
+    pub fn agent_sample_rates(&self) -> Arc<AgentSampleRates> {
+        Arc::clone(&self.stats.agent_sample_rates)
+    }
+
+    /// Whether the daemon thread is alive to send traces. A daemon thread which died is
+    /// restarted by the sends following it, at most once per backoff.
+    pub fn health(&self) -> ClientHealth {
+        let daemon = match &self.transport {
+            Transport::Daemon { daemon, .. } => daemon,
+            Transport::Failed => return ClientHealth::TransportFailed,
+            // the task stops with its runtime
+            #[cfg(feature = "tokio")]
+            Transport::Tokio(sender) if sender.is_closed() => return ClientHealth::DaemonDead,
+            _ => return ClientHealth::Healthy,
+        };
+        match daemon.read() {
+            Ok(daemon) if daemon.state.shut_down.load(Ordering::SeqCst) => ClientHealth::ShutDown,
+            Ok(daemon) if daemon.is_dead() => ClientHealth::DaemonDead,
+            Ok(_) => ClientHealth::Healthy,
+            Err(e) => {
+                log::error!("Failed to get lock on daemon; err {:?}", e);
+                ClientHealth::DaemonDead
+            }
+        }
+    }
+
+    /// Whether spans dropped by sampling are counted, see `ClientConfig::sampled_out_stats_port`.
+    #[inline]
+    pub fn records_sampled_out(&self) -> bool {
+        self.sampled_out_stats.is_some()
+    }
+
+    /// Whether `tracing::Event`s are recorded as span events, see `TraceApiVersion::V07`.
+    #[inline]
+    pub fn records_span_events(&self) -> bool {
+        self.span_events
+    }
+
+    /// Counts a span dropped by sampling in the trace metrics sent over DogStatsD. It has no
+    /// effect unless `ClientConfig::sampled_out_stats_port` is set.
+    #[inline]
+    pub fn record_sampled_out(&self, span: &Span) {
+        if let Some(sampled_out_stats) = &self.sampled_out_stats {
+            sampled_out_stats.record(span);
+        }
+    }
+
+    #[inline]
+    pub fn send_traces(&self, traces: Traces) {
+        if self.span_debug_logging != SpanDebugLogging::Off && log::log_enabled!(log::Level::Debug)
+        {
+            self.log_sampled_spans(&traces);
+        }
+        match &self.transport {
+            Transport::Validate(on_violation) => {
+                validate_traces(&traces).iter().for_each(on_violation);
+                return;
+            }
+            Transport::Inline(agent_exporter) => {
+                // the agent sender logs and counts its own failures
+                agent_exporter.export(traces).ok();
+                return;
+            }
+            Transport::Export(exporter) => {
+                if let Err(e) = exporter.export(traces) {
+                    log::error!("Failed to export traces; err {}", e);
+                }
+                return;
+            }
+            Transport::Failed => return,
+            _ => {}
+        }
+        let has_error = traces.iter().flatten().any(|span| span.is_error());
+        let has_root = traces
+            .iter()
+            .flatten()
+            .any(|span| span.parent_id().is_none());
+        let trace_count = traces.len();
+        let estimated_size = traces.iter().map(estimated_trace_size).sum();
+        let payload = if self.serialize_on_caller {
+            TracePayload::Serialized(serialize_traces(traces))
+        } else {
+            TracePayload::Owned(traces)
+        };
+        self.send_message(DaemonMessage::Traces {
+            payload,
+            enqueued_at: Instant::now(),
+            has_error,
+            has_root,
+            trace_count,
+            estimated_size,
+        });
+    }
+
+    #[inline]
+    fn log_sampled_spans(&self, traces: &Traces) {
+        for span in traces.iter().flatten() {
+            if self.span_debug_logging.should_log(span) {
+                match serde_json::to_string(span) {
+                    Ok(span_json) => log::debug!("Sending span {}", span_json),
+                    Err(e) => log::error!("Failed to serialize span for logging; err {:?}", e),
+                }
+            }
+        }
+    }
+
+    /// Blocks until every trace enqueued before this call has been attempted by the daemon, or
+    /// until `timeout` has elapsed, e.g. before the process exits.
+    ///
+    /// The task of a `tokio` Client can't make progress while the only thread of a current-thread
+    /// runtime is blocked, so from such a thread the flush is only requested, and `TimedOut` is
+    /// returned without waiting. Await `flush_async` instead.
+    pub fn flush(&self, timeout: Duration) -> FlushResult {
+        self.flush_sampled_out_stats();
+        let (flushed_sender, flushed_receiver) = crossbeam_channel::bounded(1);
+        let enqueued = match &self.transport {
+            // inline sends are attempted (and validations and exports done) before `send_traces`
+            // returns
+            Transport::Inline(_) | Transport::Validate(_) | Transport::Export(_) => {
+                return FlushResult::Flushed;
+            }
+            _ => self.send_message(DaemonMessage::Flush(Box::new(move || {
+                flushed_sender.send(()).ok();
+            }))),
+        };
+        if !enqueued {
+            return FlushResult::DaemonUnavailable;
+        }
+        #[cfg(feature = "tokio")]
+        if matches!(self.transport, Transport::Tokio(_)) && on_current_thread_runtime() {
+            return FlushResult::TimedOut;
+        }
+        match flushed_receiver.recv_timeout(timeout) {
+            Ok(()) => FlushResult::Flushed,
+            Err(crossbeam_channel::RecvTimeoutError::Timeout) => FlushResult::TimedOut,
+            Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+                FlushResult::DaemonUnavailable
+            }
+        }
+    }
+
+    /// Sends the enqueued traces within `timeout` and stops the daemon, returning once its
+    /// requests have completed and its thread has exited. The budget goes to error traces first,
+    /// then traces with a root span, then the rest; traces still pending when it runs out are
+    /// abandoned and counted in the report. A batch started before the deadline may still take
+    /// up to `send_deadline_ms` to complete. A suspended Client is resumed first. Traces sent
+    /// after a shutdown are dropped.
+    pub fn shutdown(&self, timeout: Duration) -> Result<ShutdownReport, FlushResult> {
+        let (drained_sender, drained_receiver) = crossbeam_channel::bounded(1);
+        let joined_by = Instant::now() + timeout + self.send_deadline;
+        self.flush_sampled_out_stats();
+        self.resume();
+        let enqueued = match &self.transport {
+            // inline sends are attempted (and validations and exports done) before `send_traces`
+            // returns
+            Transport::Inline(_) | Transport::Validate(_) | Transport::Export(_) => {
+                return Ok(ShutdownReport::default());
+            }
+            _ => self.send_message(DaemonMessage::Shutdown {
+                deadline: Instant::now() + timeout,
+                on_drained: Box::new(move |report| {
+                    drained_sender.send(report).ok();
+                }),
+            }),
+        };
+        if !enqueued {
+            return Err(FlushResult::DaemonUnavailable);
+        }
+        match drained_receiver.recv_deadline(joined_by) {
+            Ok(report) => self.join_daemon(joined_by).map(|()| report),
+            Err(crossbeam_channel::RecvTimeoutError::Timeout) => Err(FlushResult::TimedOut),
+            Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+                Err(FlushResult::DaemonUnavailable)
+            }
+        }
+    }
+
+    #[inline]
+    fn flush_sampled_out_stats(&self) {
+        if let Some(sampled_out_stats) = &self.sampled_out_stats {
+            sampled_out_stats.flush();
+        }
+    }
+
+    // Waits for the daemon thread to exit after a shutdown
+    #[inline]
+    fn join_daemon(&self, deadline: Instant) -> Result<(), FlushResult> {
+        let Transport::Daemon { daemon, .. } = &self.transport else {
+            return Ok(());
+        };
+        let exited = match daemon.read() {
+            Ok(daemon) => daemon.exited.clone(),
+            Err(e) => {
+                log::error!("Failed to get lock on daemon; err {:?}", e);
+                return Err(FlushResult::DaemonUnavailable);
+            }
+        };
+        if let Err(crossbeam_channel::RecvTimeoutError::Timeout) = exited.recv_deadline(deadline) {
+            return Err(FlushResult::TimedOut);
+        }
+        // the thread has nothing left to do but to return
+        let handle = daemon
+            .write()
+            .ok()
+            .and_then(|mut daemon| daemon.handle.take());
+        if let Some(Err(e)) = handle.map(JoinHandle::join) {
+            log::error!("Daemon thread panicked; err {:?}", e);
+        }
+        Ok(())
+    }
+
+    /// Resolves once every trace enqueued before this call has been attempted by the daemon,
+    /// which makes assertions on exported traces deterministic in async tests.
+    #[cfg(feature = "tokio")]
+    pub fn flush_async(&self) -> impl std::future::Future<Output = FlushResult> {
+        self.flush_sampled_out_stats();
+        let (flushed_sender, flushed_receiver) = tokio::sync::oneshot::channel();
+        let enqueued = match &self.transport {
+            // inline sends are attempted (and validations and exports done) before `send_traces`
+            // returns
+            Transport::Inline(_) | Transport::Validate(_) | Transport::Export(_) => {
+                flushed_sender.send(()).is_ok()
+            }
+            _ => self.send_message(DaemonMessage::Flush(Box::new(move || {
+                flushed_sender.send(()).ok();
+            }))),
+        };
+        async move {
+            if enqueued && flushed_receiver.await.is_ok() {
+                FlushResult::Flushed
+            } else {
+                FlushResult::DaemonUnavailable
+            }
+        }
+    }
+
+    /// Stops sending traces until `resume` is called, e.g. while the process sleeps for a long
+    /// period: the daemon thread parks and closes its connections to the agent, and traces sent
+    /// in the meantime are queued. Flushes time out while the Client is suspended. Only Clients
+    /// with a daemon thread can be suspended.
+    pub fn suspend(&self) {
+        if let Transport::Daemon { daemon, .. } = &self.transport {
+            match daemon.read() {
+                Ok(daemon) => daemon.state.suspended.store(true, Ordering::SeqCst),
+                Err(e) => log::error!("Failed to get lock on daemon; err {:?}", e),
+            }
+        }
+    }
+
+    /// Resumes sending traces after `suspend`, starting with the traces queued in the meantime.
+    pub fn resume(&self) {
+        if let Transport::Daemon { daemon, .. } = &self.transport {
+            match daemon.read() {
+                Ok(daemon) => {
+                    daemon.state.suspended.store(false, Ordering::SeqCst);
+                    daemon.state.wake(&daemon.thread);
+                }
+                Err(e) => log::error!("Failed to get lock on daemon; err {:?}", e),
+            }
+        }
+    }
+
+    // Replaces the daemon thread of the parent process, which doesn't exist in a forked child
+    #[inline]
+    fn respawn_daemon_after_fork(&self, daemon: &RwLock<Daemon>, config: &ClientConfig) {
+        let pid = current_pid();
+        if daemon.read().is_ok_and(|daemon| daemon.pid == pid) {
+            return;
+        }
+        let Ok(mut daemon) = daemon.write() else {
+            return;
+        };
+        if daemon.pid == pid {
+            return;
+        }
+        log::info!(
+            "Process forked from {} to {}, respawning daemon thread",
+            daemon.pid,
+            pid
+        );
+        let stale = std::mem::replace(
+            &mut *daemon,
+            Self::spawn_daemon(config.clone(), Arc::clone(&self.stats)),
+        );
+        // the thread of the handle only exists in the parent process, so it must be neither
+        // detached nor joined from the child
+        std::mem::forget(stale.handle);
+    }
+
+    // Replaces a daemon thread which died, once it's past its restart backoff
+    #[inline]
+    fn restart_dead_daemon(&self, daemon: &RwLock<Daemon>, config: &ClientConfig) {
+        if daemon.read().is_ok_and(|daemon| !daemon.is_dead()) {
+            return;
+        }
+        let Ok(mut daemon) = daemon.write() else {
+            return;
+        };
+        let lifetime = daemon.started_at.elapsed();
+        if !daemon.is_dead() || lifetime < daemon.restart_backoff() {
+            return;
+        }
+        let restarts = if lifetime > MAX_DAEMON_RESTART_BACKOFF {
+            0
+        } else {
+            daemon.restarts + 1
+        };
+        log::warn!(
+            "Daemon thread died after {:?}, restarting it ({} restarts in a row)",
+            lifetime,
+            restarts
+        );
+        let mut restarted = Self::spawn_daemon(config.clone(), Arc::clone(&self.stats));
+        restarted.restarts = restarts;
+        // the dead thread has nothing left to do, so it's detached rather than joined
+        drop(std::mem::replace(&mut *daemon, restarted));
+        self.stats.daemon_restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn send_message(&self, message: DaemonMessage) -> bool {
+        match &self.transport {
+            // the channel is lock-free and the daemon is only locked for writing after a fork or
+            // a crash, so producers on different threads never wait on each other
+            Transport::Daemon { daemon, config } => {
+                self.respawn_daemon_after_fork(daemon, config);
+                self.restart_dead_daemon(daemon, config);
+                match daemon.read() {
+                    Ok(daemon) => {
+                        let sent = daemon.enqueue(message, config, &self.stats);
+                        daemon.state.wake(&daemon.thread);
+                        sent
+                    }
+                    Err(e) => {
+                        log::error!("Failed to get lock on daemon; err {:?}", e);
+                        false
+                    }
+                }
+            }
+            Transport::Inline(_)
+            | Transport::Validate(_)
+            | Transport::Export(_)
+            | Transport::Failed => false,
+            #[cfg(feature = "async_std")]
+            Transport::AsyncStd(sender) => sender
+                .try_send(message)
+                .map_err(|e| log::error!("Failed to send message on channel; err {:?}", e))
+                .is_ok(),
+            #[cfg(feature = "tokio")]
+            Transport::Tokio(sender) => sender
+                .send(message)
+                .map_err(|e| log::error!("Failed to send message on channel; err {:?}", e))
+                .is_ok(),
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.flush_sampled_out_stats();
+        // a parked daemon thread wouldn't see its channel close, so it's woken up to send what's
+        // left in the queue and stop
+        if let Transport::Daemon { daemon, .. } = &mut self.transport {
+            if let Ok(daemon) = daemon.get_mut() {
+                if daemon.pid == current_pid() {
+                    daemon.traces = crossbeam_channel::bounded(1).0;
+                    daemon.control = crossbeam_channel::unbounded().0;
+                    daemon.state.suspended.store(false, Ordering::SeqCst);
+                    daemon.state.parked.store(false, Ordering::SeqCst);
+                    daemon.thread.unpark();
+                }
+            }
+        }
+    }
+}
+
+// Settings of the HTTP client of an AgentSender, which is rebuilt after the daemon was parked
+#[derive(Copy, Clone)]
+struct HttpClientSettings {
+    connect_timeout_ms: u64,
+    request_timeout_ms: u64,
+    pool_idle_timeout_ms: u64,
+    pool_max_idle_per_host: usize,
+    http2_prior_knowledge: bool,
+}
+
+impl HttpClientSettings {
+    fn new(config: &ClientConfig) -> Self {
+        Self {
+            connect_timeout_ms: config.connect_timeout_ms,
+            request_timeout_ms: config.request_timeout_ms,
+            pool_idle_timeout_ms: config.pool_idle_timeout_ms,
+            pool_max_idle_per_host: config.pool_max_idle_per_host,
+            http2_prior_knowledge: config.http2_prior_knowledge,
+        }
+    }
+
+    fn build(self) -> reqwest::Result<reqwest::blocking::Client> {
+        let mut client_builder = reqwest::blocking::ClientBuilder::new()
+            .connect_timeout(Duration::from_millis(self.connect_timeout_ms))
+            .timeout(Duration::from_millis(self.request_timeout_ms))
+            .pool_idle_timeout(Duration::from_millis(self.pool_idle_timeout_ms))
+            .pool_max_idle_per_host(self.pool_max_idle_per_host);
+        if self.http2_prior_knowledge {
+            client_builder = client_builder.http2_prior_knowledge();
+        }
+        client_builder.build()
+    }
+}
+
+/// The default exporter, which sends traces to the Datadog agent over HTTP on the calling thread,
+/// with the retries, retention and circuit breaker of its config, like the Clients exporting
+/// inline (see `ClientConfig::inline_export`) do. Exports fail if the agent didn't accept every
+/// trace.
+pub struct AgentExporter {
+    agent_sender: Mutex<AgentSender>,
+}
+
+impl AgentExporter {
+    pub fn new(config: &ClientConfig) -> reqwest::Result<Self> {
+        Self::with_stats(config, Arc::new(ClientStatsRecorder::default()))
+    }
+
+    fn with_stats(config: &ClientConfig, stats: Arc<ClientStatsRecorder>) -> reqwest::Result<Self> {
+        Ok(Self {
+            agent_sender: Mutex::new(AgentSender::new(config, stats)?),
+        })
+    }
+}
+
+impl SpanExporter for AgentExporter {
+    fn export(&self, traces: Traces) -> Result<(), ExportError> {
+        let mut agent_sender = self
+            .agent_sender
+            .lock()
+            .map_err(|e| format!("Failed to get lock on agent sender; err {:?}", e))?;
+        if agent_sender.send(serialize_traces(traces)) {
+            Ok(())
+        } else {
+            Err(ExportError::from("Datadog agent didn't accept the traces"))
+        }
+    }
+}
+
+// Sends traces to the Datadog agent, whether from the daemon thread or inline
+struct AgentSender {
+    client: Option<reqwest::blocking::Client>,
+    http_client_settings: HttpClientSettings,
+    dd_agent_url: String,
+    agent_latency: AgentLatency,
+    max_send_attempts: u32,
+    send_deadline_ms: u64,
+    retry_backoff: Duration,
+    max_retained_payloads: usize,
+    retained: VecDeque<RetainedPayload>,
+    circuit_breaker: CircuitBreaker,
+    dogstatsd_fallback: Option<DogStatsdFallback>,
+    on_payload: Option<PayloadHook>,
+    clock_skew_correction: bool,
+    agent_info_url: String,
+    clock_offset_ns: i64,
+    clock_offset_measured_at: Option<Instant>,
+    timestamp_resolution: TimestampResolution,
+    trace_api_version: TraceApiVersion,
+    compression: Compression,
+    #[cfg(windows)]
+    windows_pipe_name: Option<String>,
+    api_key: Option<String>,
+    tracer_metadata: TracerMetadata,
+    exporter_tracer: Option<ExporterTracer>,
+    stats: Arc<ClientStatsRecorder>,
+}
+
+impl AgentSender {
+    fn new(config: &ClientConfig, stats: Arc<ClientStatsRecorder>) -> reqwest::Result<Self> {
+        let http_client_settings = HttpClientSettings::new(config);
+        let client = http_client_settings.build()?;
+        #[cfg(not(windows))]
+        if config.windows_pipe_name.is_some() {
+            log::warn!("Named pipes are only supported on Windows, sending traces over TCP");
+        }
+        Ok(Self {
+            client: Some(client),
+            http_client_settings,
+            dd_agent_url: agent_url(config),
+            agent_latency: AgentLatency::new(config),
+            max_send_attempts: config.max_send_attempts,
+            send_deadline_ms: config.send_deadline_ms,
+            retry_backoff: Duration::from_millis(config.retry_backoff_ms),
+            max_retained_payloads: config.max_retained_payloads,
+            retained: VecDeque::new(),
+            circuit_breaker: CircuitBreaker::new(config),
+            dogstatsd_fallback: config.dogstatsd_fallback_port.and_then(|port| {
+                DogStatsdFallback::new(&config.datadog_agent_host, port)
+                    .map_err(|e| log::error!("Failed to set up DogStatsD fallback; err {}", e))
+                    .ok()
+            }),
+            on_payload: config.on_payload.clone(),
+            clock_skew_correction: config.clock_skew_correction && config.api_key.is_none(),
+            agent_info_url: format!(
+                "http://{}:{}/info",
+                config.datadog_agent_host, config.datadog_agent_port
+            ),
+            clock_offset_ns: 0,
+            clock_offset_measured_at: None,
+            timestamp_resolution: config.timestamp_resolution,
+            trace_api_version: config.trace_api_version,
+            compression: config.compression,
+            #[cfg(windows)]
+            windows_pipe_name: config
+                .windows_pipe_name
+                .clone()
+                .filter(|_| config.api_key.is_none()),
+            api_key: config.api_key.clone(),
+            tracer_metadata: tracer_metadata(config),
+            exporter_tracer: config.self_instrumentation.map(ExporterTracer::new),
+            stats,
+        })
+    }
+
+    // Sends traces, returning whether the agent accepted all of them
+    #[inline]
+    fn send(&mut self, mut trace_json: serde_json::Value) -> bool {
+        self.retry_retained(false);
+        if !self.circuit_breaker.allows_send() {
+            self.drop_while_open(&trace_json);
+            return false;
+        }
+        self.refresh_clock_offset();
+        let mut payload_trace = self
+            .exporter_tracer
+            .as_mut()
+            .and_then(|exporter_tracer| exporter_tracer.trace_payload(&mut trace_json));
+        adjust_timestamps(
+            &mut trace_json,
+            self.clock_offset_ns,
+            self.timestamp_resolution,
+        );
+        let serialize_started = SystemTime::now();
+        let payloads: Vec<(serde_json::Value, Vec<u8>)> = group_by_service(trace_json)
+            .into_iter()
+            .map(|payload| {
+                let body = self.encode(&payload);
+                (payload, body)
+            })
+            .collect();
+        let send_started = SystemTime::now();
+        let mut size = 0;
+        let mut sent = true;
+        for (payload, body) in &payloads {
+            size += body.len();
+            sent &= if self.circuit_breaker.allows_send() {
+                self.send_payload(payload, body)
+            } else {
+                self.drop_while_open(payload);
+                false
+            };
+        }
+        if let Some(payload_trace) = &mut payload_trace {
+            payload_trace.phase(SERIALIZE_SPAN_NAME, serialize_started, send_started);
+            payload_trace
+                .phase(SEND_SPAN_NAME, send_started, SystemTime::now())
+                .resource(self.dd_agent_url.clone())
+                .error(!sent);
+        }
+        if let (Some(exporter_tracer), Some(payload_trace)) =
+            (&mut self.exporter_tracer, payload_trace)
+        {
+            exporter_tracer.finish(payload_trace, size, sent);
+        }
+        sent
+    }
+
+    #[inline]
+    fn encode(&self, trace_json: &serde_json::Value) -> Vec<u8> {
+        self.compression.compress(encode_body(
+            trace_json,
+            self.api_key.is_some(),
+            &self.tracer_metadata,
+            self.trace_api_version,
+        ))
+    }
+
+    // Drops the HTTP client, closing its connections and its runtime thread, until next needed
+    #[inline]
+    fn release_http_client(&mut self) {
+        self.client = None;
+    }
+
+    #[inline]
+    fn http_client(&mut self) -> Option<reqwest::blocking::Client> {
+        if self.client.is_none() {
+            self.client = self
+                .http_client_settings
+                .build()
+                .map_err(|e| log::error!("Failed to construct HTTP client; err {:?}", e))
+                .ok();
+        }
+        self.client.clone()
+    }
+
+    // Sends a payload in a single request, retrying within the send budget
+    #[inline]
+    fn send_payload(&mut self, trace_json: &serde_json::Value, body: &[u8]) -> bool {
+        let sent = self.attempt_payload(trace_json, body, self.max_send_attempts);
+        if !sent {
+            self.retain(trace_json.clone(), body.to_vec(), 1);
+        }
+        sent
+    }
+
+    // Attempts a payload up to `max_attempts` times within the send deadline, backing off
+    // between attempts
+    #[inline]
+    fn attempt_payload(
+        &mut self,
+        trace_json: &serde_json::Value,
+        body: &[u8],
+        max_attempts: u32,
+    ) -> bool {
+        let trace_count = trace_json.as_array().map_or(0, Vec::len);
+        let client = self.http_client();
+        let mut budget = SendBudget::new(max_attempts, self.send_deadline_ms);
+        let mut sent = false;
+        let mut failures = 0;
+        while let Some(request_timeout) = budget.next_attempt(Duration::from_millis(
+            self.agent_latency.request_timeout_ms(),
+        )) {
+            let started = Instant::now();
+            sent = self.send_attempt(client.as_ref(), body, trace_count, request_timeout);
+            self.agent_latency.record(started.elapsed());
+            if sent {
+                break;
+            }
+            failures += 1;
+            if let Some(delay) = budget.retry_delay(self.retry_backoff, failures) {
+                std::thread::sleep(delay);
+            }
+        }
+        if let Some(on_payload) = &self.on_payload {
+            on_payload(
+                body,
+                payload_meta(trace_json, body, &self.dd_agent_url, sent),
+            );
+        }
+        record_send(&self.stats, &self.agent_latency, sent);
+        if let Some(open) = self.circuit_breaker.record(sent) {
+            if open {
+                log::warn!("Datadog agent is unreachable, dropping traces until a probe succeeds");
+            } else {
+                log::info!("Datadog agent is reachable again, sending traces");
+            }
+            self.stats
+                .circuit_breaker_open
+                .store(open, Ordering::Relaxed);
+        }
+        sent
+    }
+
+    // Keeps a payload which failed `failures` times for a later retry, or drops it
+    #[inline]
+    fn retain(&mut self, trace_json: serde_json::Value, body: Vec<u8>, failures: u32) {
+        if self.max_retained_payloads == 0 || failures > MAX_RETAINED_FAILURES {
+            self.drop_payload(&trace_json);
+            return;
+        }
+        self.retained.push_back(RetainedPayload {
+            trace_json,
+            body,
+            failures,
+            retry_at: Instant::now() + backoff_delay(self.retry_backoff, failures),
+        });
+        if self.retained.len() > self.max_retained_payloads {
+            if let Some(evicted) = self.retained.pop_front() {
+                log::warn!("Too many payloads waiting for a retry, dropping the oldest one");
+                self.drop_payload(&evicted.trace_json);
+            }
+        }
+    }
+
+    #[inline]
+    fn drop_payload(&self, trace_json: &serde_json::Value) {
+        if let Some(fallback) = &self.dogstatsd_fallback {
+            fallback.send_summaries(trace_json);
+        }
+    }
+
+    // Drops a payload without contacting the agent, which is known to be down
+    #[inline]
+    fn drop_while_open(&self, trace_json: &serde_json::Value) {
+        let trace_count = trace_json.as_array().map_or(0, Vec::len);
+        self.stats
+            .circuit_breaker_dropped_traces
+            .fetch_add(trace_count as u64, Ordering::Relaxed);
+        self.drop_payload(trace_json);
+    }
+
+    // Retries the retained payloads whose backoff has elapsed, or all of them for a last time
+    // if `last_attempt`. While the circuit breaker is open, they wait for the next probe.
+    fn retry_retained(&mut self, last_attempt: bool) {
+        if self.retained.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        let (due, waiting): (VecDeque<_>, VecDeque<_>) = std::mem::take(&mut self.retained)
+            .into_iter()
+            .partition(|payload| last_attempt || payload.retry_at <= now);
+        self.retained = waiting;
+        for payload in due {
+            if let Some(probe_at) = self.circuit_breaker.next_probe_at() {
+                if last_attempt {
+                    self.drop_while_open(&payload.trace_json);
+                } else {
+                    self.retained.push_back(RetainedPayload {
+                        retry_at: payload.retry_at.max(probe_at),
+                        ..payload
+                    });
+                }
+                continue;
+            }
+            if self.attempt_payload(&payload.trace_json, &payload.body, 1) {
+                continue;
+            }
+            if last_attempt {
+                self.drop_payload(&payload.trace_json);
+            } else {
+                self.retain(payload.trace_json, payload.body, payload.failures + 1);
+            }
+        }
+    }
+
+    // How long until the next retained payload is due for a retry
+    #[inline]
+    fn next_retry_in(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.retained
+            .iter()
+            .map(|payload| payload.retry_at.saturating_duration_since(now))
+            .min()
+    }
+
+    #[inline]
+    fn send_attempt(
+        &self,
+        client: Option<&reqwest::blocking::Client>,
+        body: &[u8],
+        trace_count: usize,
+        request_timeout: Duration,
+    ) -> bool {
+        #[cfg(windows)]
+        if let Some(pipe_name) = &self.windows_pipe_name {
+            let response = send_traces_over_windows_pipe(
+                pipe_name,
+                self.trace_api_version,
+                self.compression,
+                body,
+                trace_count,
+                request_timeout,
+            );
+            return self.accepted(response);
+        }
+        let mut content_headers = reqwest::header::HeaderMap::new();
+        content_headers.insert(
+            reqwest::header::CONTENT_TYPE,
+            reqwest::header::HeaderValue::from_static(body_content_type(
+                self.api_key.is_some(),
+                self.trace_api_version,
+            )),
+        );
+        if let Some(content_encoding) = self.compression.content_encoding() {
+            content_headers.insert(
+                reqwest::header::CONTENT_ENCODING,
+                reqwest::header::HeaderValue::from_static(content_encoding),
+            );
+        }
+        let response = client.and_then(|client| {
+            send_traces_to_datadog_agent(
+                client,
+                &self.dd_agent_url,
+                self.api_key.as_deref(),
+                content_headers,
+                body,
+                trace_count,
+                request_timeout,
+            )
+        });
+        self.accepted(response)
+    }
+
+    // Whether the payload was accepted, given the body of the response if it was, whose sample
+    // rates are then applied
+    #[inline]
+    fn accepted(&self, response: Option<Vec<u8>>) -> bool {
+        match response {
+            Some(response) => {
+                self.stats.agent_sample_rates.update(&response);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Re-estimates the offset of the agent's clock if the last estimate is outdated. A failed
+    // estimate keeps the previous offset until the next refresh, so an unreachable agent isn't
+    // queried before every send.
+    #[inline]
+    fn refresh_clock_offset(&mut self) {
+        if !self.clock_skew_correction
+            || self
+                .clock_offset_measured_at
+                .is_some_and(|measured_at| measured_at.elapsed() < clock::OFFSET_REFRESH_INTERVAL)
+        {
+            return;
+        }
+        self.clock_offset_measured_at = Some(Instant::now());
+        let Some(client) = self.http_client() else {
+            return;
+        };
+        let sent = SystemTime::now();
+        let maybe_agent_time = client
+            .get(&self.agent_info_url)
+            .timeout(Duration::from_millis(
+                self.agent_latency.request_timeout_ms(),
+            ))
+            .send()
+            .map_err(|e| log::error!("Failed to get time of Datadog agent; error: {}", e))
+            .ok()
+            .and_then(|resp| {
+                let date = resp.headers().get(reqwest::header::DATE)?.to_str().ok()?;
+                clock::parse_http_date(date)
+            });
+        if let Some(agent_time) = maybe_agent_time {
+            let offset_ns = clock::estimate_offset_ns(sent, SystemTime::now(), agent_time);
+            if offset_ns != self.clock_offset_ns {
+                log::info!(
+                    "Correcting span timestamps for a clock skew of {}ms with the Datadog agent",
+                    offset_ns / 1_000_000
+                );
+            }
+            self.clock_offset_ns = offset_ns;
+        }
+    }
+}
+
+pub const QUEUE_WAIT_METRIC_KEY: &str = "_dd.tracer_queue_ms";
+
+/// Header telling the agent how many traces a payload holds.
+pub const TRACE_COUNT_HEADER: &str = "X-Datadog-Trace-Count";
+
+/// Header telling the agent the language of the tracer, `rust`.
+pub const META_LANG_HEADER: &str = "Datadog-Meta-Lang";
+
+/// Header telling the agent the version of the Rust compiler the tracer was built with.
+pub const META_LANG_VERSION_HEADER: &str = "Datadog-Meta-Lang-Version";
+
+/// Header telling the agent the version of this crate.
+pub const META_TRACER_VERSION_HEADER: &str = "Datadog-Meta-Tracer-Version";
+
+// Headers identifying the tracer, which official tracers send with every payload, and which the
+// agent uses in its metrics
+const TRACER_HEADERS: [(&str, &str); 3] = [
+    (META_LANG_HEADER, "rust"),
+    (
+        META_LANG_VERSION_HEADER,
+        env!("TRACING_DATADOG_APM_RUSTC_VERSION"),
+    ),
+    (META_TRACER_VERSION_HEADER, env!("CARGO_PKG_VERSION")),
+];
+
+// Splits a batch into one payload per service, env and version of the root span of each trace, in
+// the order they first appear, so every request to the agent is consistent (v0.7 payloads carry
+// the env and version) and its trace count header is accurate. A batch with a single group (or
+// no trace at all) is returned as is.
+#[inline]
+fn group_by_service(trace_json: serde_json::Value) -> Vec<serde_json::Value> {
+    let serde_json::Value::Array(traces) = trace_json else {
+        return vec![trace_json];
+    };
+    let mut groups: Vec<((String, String, String), Vec<serde_json::Value>)> = Vec::new();
+    for trace in traces {
+        let root = &trace[0];
+        let key = (
+            String::from(root["service"].as_str().unwrap_or_default()),
+            String::from(root["meta"]["env"].as_str().unwrap_or_default()),
+            String::from(root["meta"]["version"].as_str().unwrap_or_default()),
+        );
+        match groups.iter_mut().find(|(group_key, _)| *group_key == key) {
+            Some((_, group)) => group.push(trace),
+            None => groups.push((key, vec![trace])),
+        }
+    }
+    if groups.is_empty() {
+        return vec![serde_json::Value::Array(Vec::new())];
+    }
+    groups
+        .into_iter()
+        .map(|(_, group)| serde_json::Value::Array(group))
+        .collect()
+}
+
+// Tags every span of a batch with how long the batch waited in the export queue before the
+// daemon picked it up, to tell exporter-induced delays apart from application latency
+#[inline]
+fn record_queue_wait(
+    trace_json: &mut serde_json::Value,
+    enqueued_at: Instant,
+    stats: &ClientStatsRecorder,
+) {
+    let queue_wait_ms = enqueued_at.elapsed().as_millis() as u64;
+    stats.queue_wait_ms.store(queue_wait_ms, Ordering::Relaxed);
+    tag_queue_wait(trace_json, queue_wait_ms);
+}
+
+#[inline]
+fn tag_queue_wait(trace_json: &mut serde_json::Value, queue_wait_ms: u64) {
+    let spans = trace_json
+        .as_array_mut()
+        .into_iter()
+        .flatten()
+        .filter_map(serde_json::Value::as_array_mut)
+        .flatten();
+    for span in spans {
+        if let Some(metrics) = span
+            .get_mut("metrics")
+            .and_then(serde_json::Value::as_object_mut)
+        {
+            metrics.insert(String::from(QUEUE_WAIT_METRIC_KEY), queue_wait_ms.into());
+        }
+    }
+}
+
+// The body of a request, in the intake's format in agentless mode
+#[inline]
+fn encode_body(
+    trace_json: &serde_json::Value,
+    agentless: bool,
+    tracer_metadata: &TracerMetadata,
+    trace_api_version: TraceApiVersion,
+) -> Vec<u8> {
+    if agentless {
+        agentless::encode(trace_json, tracer_metadata)
+    } else {
+        trace_api_version.encode(trace_json, tracer_metadata)
+    }
+}
+
+#[inline]
+fn body_content_type(agentless: bool, trace_api_version: TraceApiVersion) -> &'static str {
+    if agentless {
+        agentless::CONTENT_TYPE
+    } else {
+        trace_api_version.content_type()
     }
 }
 
-pub struct Client {
-    sender_mutex: Mutex<Sender<serde_json::Value>>,
-    _daemon: JoinHandle<()>,
+#[inline]
+fn hostname_from_env() -> String {
+    std::env::var("DD_HOSTNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_default()
 }
 
-impl Client {
-    pub fn create_default() -> Self {
-        Self::create_with_config(ClientConfig::default())
+fn tracer_metadata(config: &ClientConfig) -> TracerMetadata {
+    TracerMetadata {
+        env: config
+            .env
+            .clone()
+            .unwrap_or_else(|| std::env::var("DD_ENV").unwrap_or_default()),
+        hostname: config.hostname.clone().unwrap_or_else(hostname_from_env),
+        app_version: config
+            .app_version
+            .clone()
+            .unwrap_or_else(|| std::env::var("DD_VERSION").unwrap_or_default()),
     }
+}
 
-    pub fn create_with_config(config: ClientConfig) -> Self {
-        let (sender, receiver) = std::sync::mpsc::channel::<serde_json::Value>();
+// URL of the trace endpoint of the agent or of the intake, or path of the agent's named pipe
+fn agent_url(config: &ClientConfig) -> String {
+    if config.api_key.is_some() {
+        let site = config
+            .datadog_site
+            .clone()
+            .unwrap_or_else(agentless::site_from_env);
+        return agentless::intake_url(&site);
+    }
+    #[cfg(windows)]
+    if let Some(pipe_name) = &config.windows_pipe_name {
+        return format!(
+            "{}{}",
+            windows_pipe_path(pipe_name),
+            config.trace_api_version.path()
+        );
+    }
+    format!(
+        "http://{}:{}{}",
+        config.datadog_agent_host,
+        config.datadog_agent_port,
+        config.trace_api_version.path()
+    )
+}
 
-        let daemon: JoinHandle<()> = std::thread::spawn(move || {
-            log::info!("Starting daemon thread to pass traces to Datadog agent");
-            let client = reqwest::blocking::ClientBuilder::new()
-                .connect_timeout(Duration::from_millis(config.connect_timeout_ms))
-                .timeout(Duration::from_millis(config.request_timeout_ms))
-                .build()
-                .map_err(|e| log::error!("Failed to construct client, killing daemon; err {:?}", e))
-                .unwrap();
-            let dd_agent_url = format!(
-                "http://{}:{}/v0.3/traces",
-                config.datadog_agent_host, config.datadog_agent_port
-            );
-            loop {
-                match receiver.recv() {
-                    Ok(trace_json) => {
-                        send_traces_to_datadog_agent(&client, &dd_agent_url, trace_json);
-                    }
-                    Err(e) => log::error!("Failed to receive traces on mpsc channel; err {:?}", e),
-                }
-            }
-        });
+#[cfg(windows)]
+#[inline]
+fn windows_pipe_path(pipe_name: &str) -> String {
+    format!(r"\\.\pipe\{}", pipe_name)
+}
 
-        Self {
-            sender_mutex: Mutex::new(sender),
-            _daemon: daemon,
-        }
+#[inline]
+fn payload_meta(
+    trace_json: &serde_json::Value,
+    body: &[u8],
+    destination: &str,
+    delivered: bool,
+) -> PayloadMeta {
+    let traces = trace_json.as_array().map(Vec::as_slice).unwrap_or_default();
+    PayloadMeta {
+        size: body.len(),
+        trace_count: traces.len(),
+        span_count: traces
+            .iter()
+            .filter_map(serde_json::Value::as_array)
+            .map(Vec::len)
+            .sum(),
+        destination: String::from(destination),
+        delivered,
     }
+}
 
-    #[inline]
-    pub fn send_traces(&self, traces: Traces) {
-        let trace_json = serde_json::to_value(traces).unwrap_or_else(|e| {
-            log::error!("Failed to serialize traces into JSON value. Err: {}", e);
-            serde_json::Value::default()
-        });
-        match self.sender_mutex.lock() {
-            Ok(sender) => match sender.send(trace_json) {
-                Ok(_) => {}
-                Err(e) => log::error!("Failed to send traces on mpsc channel; err {:?}", e),
-            },
-            Err(e) => log::error!("Failed to get lock on sender; err {:?}", e),
+#[inline]
+fn record_send(stats: &ClientStatsRecorder, agent_latency: &AgentLatency, sent: bool) {
+    if sent {
+        stats.requests_sent.fetch_add(1, Ordering::Relaxed);
+    } else {
+        stats.requests_failed.fetch_add(1, Ordering::Relaxed);
+    }
+    stats
+        .request_timeout_ms
+        .store(agent_latency.request_timeout_ms(), Ordering::Relaxed);
+    let agent_slow = agent_latency.is_slow();
+    if stats.agent_slow.swap(agent_slow, Ordering::Relaxed) != agent_slow {
+        if agent_slow {
+            log::warn!("Datadog agent is consistently responding slowly");
+        } else {
+            log::info!("Datadog agent is responding normally again");
         }
     }
 }
 
+// Returns the body of the response if the payload was accepted
 #[inline]
 fn send_traces_to_datadog_agent(
     client: &reqwest::blocking::Client,
     dd_agent_url: &str,
-    trace_json: serde_json::Value,
-) {
-    match client.put(dd_agent_url).body(trace_json.to_string()).send() {
-        Ok(resp) => log::debug!(
-            "Successfully sent trace to Datadog agent; response: {:?}",
-            resp
-        ),
-        Err(e) => log::error!("Failed to send trace to Datadog agent; error: {}", e),
+    api_key: Option<&str>,
+    content_headers: reqwest::header::HeaderMap,
+    body: &[u8],
+    trace_count: usize,
+    request_timeout: Duration,
+) -> Option<Vec<u8>> {
+    let mut request = match api_key {
+        // the intake takes traces by POST
+        Some(api_key) => client
+            .post(dd_agent_url)
+            .header(agentless::API_KEY_HEADER, api_key),
+        None => client.put(dd_agent_url),
     };
-}
-
-pub type Traces = Vec<Trace>;
-
-pub type Trace = Vec<Span>;
-
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct Span {
-    duration: u64,
-    error: u32,
-    meta: HashMap<String, String>,
-    metrics: HashMap<String, u64>,
-    name: &'static str,
-    parent_id: Option<u64>,
-    resource: String,
-    service: &'static str,
-    span_id: u64,
-    start: u64,
-    trace_id: u64,
-    r#type: &'static str,
-}
-
-#[derive(Debug, Clone, Eq, PartialEq)]
-pub enum SpanType {
-    Web,
-    Db,
-    Cache,
-    Custom,
-}
-
-impl SpanType {
-    #[inline]
-    pub fn as_str(&self) -> &'static str {
-        match *self {
-            SpanType::Web => "web",
-            SpanType::Db => "db",
-            SpanType::Cache => "cache",
-            SpanType::Custom => "custom",
+    for (name, value) in TRACER_HEADERS {
+        request = request.header(name, value);
+    }
+    request = request
+        .headers(content_headers)
+        .header(TRACE_COUNT_HEADER, trace_count)
+        .timeout(request_timeout)
+        .body(body.to_vec());
+    match request.send() {
+        Ok(resp) if resp.status().is_success() => {
+            log::debug!(
+                "Successfully sent trace to Datadog agent; response: {:?}",
+                resp
+            );
+            Some(resp.bytes().map(|body| body.to_vec()).unwrap_or_default())
+        }
+        Ok(resp) => {
+            log::error!("Datadog agent rejected trace; response: {:?}", resp);
+            None
+        }
+        Err(e) => {
+            log::error!("Failed to send trace to Datadog agent; error: {}", e);
+            None
         }
     }
 }
 
-impl FromStr for SpanType {
-    type Err = ();
+#[cfg(any(feature = "async_std", windows))]
+#[inline]
+fn http1_headers(compression: Compression, trace_count: &str) -> Vec<(&'static str, &str)> {
+    let mut headers = TRACER_HEADERS.to_vec();
+    headers.push((TRACE_COUNT_HEADER, trace_count));
+    if let Some(content_encoding) = compression.content_encoding() {
+        headers.push(("Content-Encoding", content_encoding));
+    }
+    headers
+}
 
-    #[inline]
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(match &*s.trim().to_lowercase() {
-            "web" => SpanType::Web,
-            "db" => SpanType::Db,
-            "cache" => SpanType::Cache,
-            "custom" => SpanType::Custom,
-            _ => SpanType::Custom,
-        })
+// Sends traces over the named pipe of the agent, with a request per connection, and returns the
+// body of the response if the payload was accepted
+#[cfg(windows)]
+fn send_traces_over_windows_pipe(
+    pipe_name: &str,
+    trace_api_version: TraceApiVersion,
+    compression: Compression,
+    body: &[u8],
+    trace_count: usize,
+    request_timeout: Duration,
+) -> Option<Vec<u8>> {
+    let pipe = match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(windows_pipe_path(pipe_name))
+    {
+        Ok(pipe) => pipe,
+        Err(e) => {
+            log::error!("Failed to open named pipe of Datadog agent; error: {}", e);
+            return None;
+        }
+    };
+    let request = http1::put_request(
+        "localhost",
+        trace_api_version.path(),
+        trace_api_version.content_type(),
+        &http1_headers(compression, &trace_count.to_string()),
+        body,
+    );
+    match http1::exchange_with_timeout(pipe, request, request_timeout) {
+        Ok(response) => match http1::response_status(&response) {
+            Some(status) if (200..300).contains(&status) => {
+                log::debug!(
+                    "Successfully sent trace to Datadog agent; status: {}",
+                    status
+                );
+                Some(http1::response_body(&response).to_vec())
+            }
+            status => {
+                log::error!("Datadog agent rejected trace; status: {:?}", status);
+                None
+            }
+        },
+        Err(e) => {
+            log::error!("Failed to send trace to Datadog agent; error: {}", e);
+            None
+        }
     }
 }
 
-#[derive(Copy, Clone, Debug)]
-pub struct ServiceName(pub &'static str);
+// Sends batches of traces from the daemon task of an async Client
+#[cfg(any(feature = "async_std", feature = "tokio"))]
+trait AsyncBatchSender {
+    fn max_payload_bytes(&self) -> usize;
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
-pub struct SpanName(pub &'static str);
+    fn stats(&self) -> &ClientStatsRecorder;
 
-#[derive(Copy, Clone, Debug)]
-pub enum SpanMetaKey {
-    Service,
-    Env,
-    Version,
-    HttpMethod,
-    HttpUrl,
-    HttpStatusCode,
-    ErrorType,
-    ErrorMsg,
-    ErrorStack,
+    async fn send(&mut self, trace_json: serde_json::Value);
 }
 
-impl std::fmt::Display for SpanMetaKey {
-    #[inline]
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Service => f.write_str("service"),
-            Self::Env => f.write_str("env"),
-            Self::Version => f.write_str("version"),
-            Self::HttpMethod => f.write_str("http.method"),
-            Self::HttpUrl => f.write_str("http.url"),
-            Self::HttpStatusCode => f.write_str("http.status_code"),
-            Self::ErrorType => f.write_str("error.type"),
-            Self::ErrorMsg => f.write_str("error.msg"),
-            Self::ErrorStack => f.write_str("error.stack"),
+// Sends the messages pending in the queue of an async daemon task, like the daemon thread does,
+// and returns whether the Client was shut down
+#[cfg(any(feature = "async_std", feature = "tokio"))]
+async fn drain_async(sender: &mut impl AsyncBatchSender, mut messages: Vec<DaemonMessage>) -> bool {
+    prioritize(&mut messages);
+    let mut shutdown_drain = ShutdownDrain::find(&messages);
+    let shutting_down = shutdown_drain.is_some();
+    let mut batch = PayloadBatch::new(sender.max_payload_bytes());
+    for message in messages {
+        match message {
+            DaemonMessage::Traces {
+                payload,
+                enqueued_at,
+                estimated_size,
+                trace_count,
+                ..
+            } => {
+                if shutdown_drain
+                    .as_mut()
+                    .is_some_and(|drain| !drain.admit(trace_count))
+                {
+                    continue;
+                }
+                if !batch.fits(estimated_size) {
+                    sender.send(batch.take()).await;
+                }
+                let mut trace_json = payload.into_json();
+                record_queue_wait(&mut trace_json, enqueued_at, sender.stats());
+                batch.push(trace_json, estimated_size);
+            }
+            DaemonMessage::Flush(on_flushed) => {
+                if !batch.is_empty() {
+                    sender.send(batch.take()).await;
+                }
+                on_flushed()
+            }
+            DaemonMessage::Shutdown { on_drained, .. } => {
+                if !batch.is_empty() {
+                    sender.send(batch.take()).await;
+                }
+                let report = shutdown_drain
+                    .take()
+                    .map(|drain| drain.report)
+                    .unwrap_or_default();
+                on_drained(report)
+            }
         }
     }
+    if !batch.is_empty() {
+        sender.send(batch.take()).await;
+    }
+    shutting_down
 }
 
-#[derive(Debug, Clone)]
-pub struct SpanBuilder {
-    error: bool,
-    meta: HashMap<String, String>,
-    metrics: HashMap<String, u64>,
-    name: SpanName,
-    pub parent_id: Option<NonZeroU64>,
-    resource: String,
-    service: ServiceName,
-    pub span_id: NonZeroU64,
-    start: SystemTime,
-    pub trace_id: NonZeroU64,
-    r#type: SpanType,
-}
-
-impl Default for SpanBuilder {
-    #[inline]
-    fn default() -> Self {
-        Self {
-            error: false,
-            meta: HashMap::new(),
-            metrics: HashMap::new(),
-            name: SpanName(""),
-            parent_id: None,
-            resource: String::new(),
-            service: ServiceName(""),
-            span_id: generate_id(),
-            start: SystemTime::now(),
-            trace_id: generate_id(),
-            r#type: SpanType::Custom,
-        }
-    }
+#[cfg(feature = "async_std")]
+struct AsyncStdSender {
+    config: ClientConfig,
+    tracer_metadata: TracerMetadata,
+    agent_latency: AgentLatency,
+    stats: Arc<ClientStatsRecorder>,
 }
 
-impl SpanBuilder {
+#[cfg(feature = "async_std")]
+impl AsyncBatchSender for AsyncStdSender {
     #[inline]
-    pub fn name(&mut self, name: SpanName) -> &mut Self {
-        self.name = name;
-        self
+    fn max_payload_bytes(&self) -> usize {
+        self.config.max_payload_bytes
     }
 
     #[inline]
-    pub fn service(&mut self, service: ServiceName) -> &mut Self {
-        self.service = service;
-        self
+    fn stats(&self) -> &ClientStatsRecorder {
+        &self.stats
     }
 
-    #[inline]
-    pub fn resource(&mut self, resource: String) -> &mut Self {
-        self.resource = resource;
-        self
+    async fn send(&mut self, trace_json: serde_json::Value) {
+        send_async_std(
+            &self.config,
+            &self.tracer_metadata,
+            &mut self.agent_latency,
+            &self.stats,
+            trace_json,
+        )
+        .await
     }
+}
 
-    #[inline]
-    pub fn span_type(&mut self, span_type: SpanType) -> &mut Self {
-        self.r#type = span_type;
-        self
-    }
+// The tokio counterpart of `AgentSender`
+#[cfg(feature = "tokio")]
+struct TokioSender {
+    client: reqwest::Client,
+    dd_agent_url: String,
+    tracer_metadata: TracerMetadata,
+    agent_latency: AgentLatency,
+    config: ClientConfig,
+    stats: Arc<ClientStatsRecorder>,
+}
 
-    #[inline]
-    pub fn trace_id(&mut self, trace_id: NonZeroU64) -> &mut Self {
-        self.trace_id = trace_id;
-        self
+#[cfg(feature = "tokio")]
+impl TokioSender {
+    fn new(config: ClientConfig, stats: Arc<ClientStatsRecorder>) -> reqwest::Result<Self> {
+        let mut client_builder = reqwest::ClientBuilder::new()
+            .connect_timeout(Duration::from_millis(config.connect_timeout_ms))
+            .timeout(Duration::from_millis(config.request_timeout_ms))
+            .pool_idle_timeout(Duration::from_millis(config.pool_idle_timeout_ms))
+            .pool_max_idle_per_host(config.pool_max_idle_per_host);
+        if config.http2_prior_knowledge {
+            client_builder = client_builder.http2_prior_knowledge();
+        }
+        Ok(Self {
+            client: client_builder.build()?,
+            dd_agent_url: agent_url(&config),
+            tracer_metadata: tracer_metadata(&config),
+            agent_latency: AgentLatency::new(&config),
+            config,
+            stats,
+        })
     }
 
-    #[inline]
-    pub fn start(&mut self, start: SystemTime) -> &mut Self {
-        self.start = start;
-        self
+    // Sends a payload in a single request, retrying within the send budget
+    async fn send_payload(&mut self, trace_json: &serde_json::Value) {
+        let agentless = self.config.api_key.is_some();
+        let body = self.config.compression.compress(encode_body(
+            trace_json,
+            agentless,
+            &self.tracer_metadata,
+            self.config.trace_api_version,
+        ));
+        let trace_count = trace_json.as_array().map_or(0, Vec::len);
+        let mut budget =
+            SendBudget::new(self.config.max_send_attempts, self.config.send_deadline_ms);
+        let mut sent = false;
+        let mut failures = 0;
+        while let Some(request_timeout) = budget.next_attempt(Duration::from_millis(
+            self.agent_latency.request_timeout_ms(),
+        )) {
+            let mut request = match &self.config.api_key {
+                // the intake takes traces by POST
+                Some(api_key) => self
+                    .client
+                    .post(&self.dd_agent_url)
+                    .header(agentless::API_KEY_HEADER, api_key),
+                None => self.client.put(&self.dd_agent_url),
+            };
+            for (name, value) in TRACER_HEADERS {
+                request = request.header(name, value);
+            }
+            if let Some(content_encoding) = self.config.compression.content_encoding() {
+                request = request.header(reqwest::header::CONTENT_ENCODING, content_encoding);
+            }
+            request = request
+                .header(
+                    reqwest::header::CONTENT_TYPE,
+                    body_content_type(agentless, self.config.trace_api_version),
+                )
+                .header(TRACE_COUNT_HEADER, trace_count)
+                .timeout(request_timeout)
+                .body(body.clone());
+            let started = Instant::now();
+            sent = match request.send().await {
+                Ok(resp) if resp.status().is_success() => {
+                    log::debug!(
+                        "Successfully sent trace to Datadog agent; response: {:?}",
+                        resp
+                    );
+                    if let Ok(response) = resp.bytes().await {
+                        self.stats.agent_sample_rates.update(&response);
+                    }
+                    true
+                }
+                Ok(resp) => {
+                    log::error!("Datadog agent rejected trace; response: {:?}", resp);
+                    false
+                }
+                Err(e) => {
+                    log::error!("Failed to send trace to Datadog agent; error: {}", e);
+                    false
+                }
+            };
+            self.agent_latency.record(started.elapsed());
+            if sent {
+                break;
+            }
+            failures += 1;
+            let retry_backoff = Duration::from_millis(self.config.retry_backoff_ms);
+            if let Some(delay) = budget.retry_delay(retry_backoff, failures) {
+                tokio::time::sleep(delay).await;
+            }
+        }
+        if let Some(on_payload) = &self.config.on_payload {
+            on_payload(
+                &body,
+                payload_meta(trace_json, &body, &self.dd_agent_url, sent),
+            );
+        }
+        record_send(&self.stats, &self.agent_latency, sent);
     }
+}
 
+#[cfg(feature = "tokio")]
+impl AsyncBatchSender for TokioSender {
     #[inline]
-    pub fn error(&mut self, error: bool) -> &mut Self {
-        self.error = error;
-        self
+    fn max_payload_bytes(&self) -> usize {
+        self.config.max_payload_bytes
     }
 
     #[inline]
-    pub fn add_meta(&mut self, key: SpanMetaKey, value: impl Into<String>) -> &mut Self {
-        self.meta.insert(key.to_string(), value.into());
-        self
+    fn stats(&self) -> &ClientStatsRecorder {
+        &self.stats
     }
 
-    #[inline]
-    pub fn metrics(&mut self, metrics: HashMap<String, u64>) -> &mut Self {
-        self.metrics = metrics;
-        self
+    async fn send(&mut self, mut trace_json: serde_json::Value) {
+        adjust_timestamps(&mut trace_json, 0, self.config.timestamp_resolution);
+        for payload in group_by_service(trace_json) {
+            self.send_payload(&payload).await;
+        }
     }
+}
 
-    #[inline]
-    pub fn parent_id(&mut self, parent_id: NonZeroU64) -> &mut Self {
-        self.parent_id = Some(parent_id);
-        self
+// The async-std counterpart of `AgentSender::send`
+#[cfg(feature = "async_std")]
+async fn send_async_std(
+    config: &ClientConfig,
+    tracer_metadata: &TracerMetadata,
+    agent_latency: &mut AgentLatency,
+    stats: &ClientStatsRecorder,
+    mut trace_json: serde_json::Value,
+) {
+    adjust_timestamps(&mut trace_json, 0, config.timestamp_resolution);
+    for payload in group_by_service(trace_json) {
+        send_payload_async_std(config, tracer_metadata, agent_latency, stats, &payload).await;
     }
+}
 
-    #[inline]
-    pub fn build(&self) -> Span {
-        let duration = SystemTime::now()
-            .duration_since(self.start)
-            .unwrap_or_else(|_| Duration::from_nanos(0))
-            .as_nanos() as u64;
-        Span {
-            duration,
-            error: if self.error { 1 } else { 0 },
-            meta: self.meta.clone(),
-            metrics: self.metrics.clone(),
-            name: self.name.0,
-            parent_id: self.parent_id.map(NonZeroU64::get),
-            resource: self.resource.clone(),
-            service: self.service.0,
-            span_id: self.span_id.get(),
-            start: self
-                .start
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_nanos() as u64,
-            trace_id: self.trace_id.get(),
-            r#type: self.r#type.as_str(),
+#[cfg(feature = "async_std")]
+async fn send_payload_async_std(
+    config: &ClientConfig,
+    tracer_metadata: &TracerMetadata,
+    agent_latency: &mut AgentLatency,
+    stats: &ClientStatsRecorder,
+    trace_json: &serde_json::Value,
+) {
+    let body = config
+        .compression
+        .compress(config.trace_api_version.encode(trace_json, tracer_metadata));
+    let trace_count = trace_json.as_array().map_or(0, Vec::len);
+    let mut budget = SendBudget::new(config.max_send_attempts, config.send_deadline_ms);
+    let mut sent = false;
+    let mut failures = 0;
+    while let Some(request_timeout) =
+        budget.next_attempt(Duration::from_millis(agent_latency.request_timeout_ms()))
+    {
+        let started = Instant::now();
+        sent = send_traces_to_datadog_agent_async_std(config, &body, trace_count, request_timeout)
+            .await;
+        agent_latency.record(started.elapsed());
+        if sent {
+            break;
         }
+        failures += 1;
+        let retry_backoff = Duration::from_millis(config.retry_backoff_ms);
+        if let Some(delay) = budget.retry_delay(retry_backoff, failures) {
+            async_std::task::sleep(delay).await;
+        }
+    }
+    if let Some(on_payload) = &config.on_payload {
+        let destination = format!(
+            "http://{}:{}{}",
+            config.datadog_agent_host,
+            config.datadog_agent_port,
+            config.trace_api_version.path()
+        );
+        on_payload(&body, payload_meta(trace_json, &body, &destination, sent));
     }
+    record_send(stats, agent_latency, sent);
 }
 
-#[inline]
-pub fn generate_id() -> NonZeroU64 {
-    rand::thread_rng().gen()
+#[cfg(feature = "async_std")]
+async fn send_traces_to_datadog_agent_async_std(
+    config: &ClientConfig,
+    body: &[u8],
+    trace_count: usize,
+    request_timeout: Duration,
+) -> bool {
+    use async_std::io::{ReadExt, WriteExt};
+    use async_std::net::TcpStream;
+
+    let connect = TcpStream::connect((
+        config.datadog_agent_host.as_str(),
+        config.datadog_agent_port as u16,
+    ));
+    let mut stream = match async_std::future::timeout(
+        Duration::from_millis(config.connect_timeout_ms).min(request_timeout),
+        connect,
+    )
+    .await
+    {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            log::error!("Failed to connect to Datadog agent; error: {}", e);
+            return false;
+        }
+        Err(_) => {
+            log::error!("Timed out connecting to Datadog agent");
+            return false;
+        }
+    };
+    let request = http1::put_request(
+        &format!(
+            "{}:{}",
+            config.datadog_agent_host, config.datadog_agent_port
+        ),
+        config.trace_api_version.path(),
+        config.trace_api_version.content_type(),
+        &http1_headers(config.compression, &trace_count.to_string()),
+        body,
+    );
+    let exchange = async {
+        stream.write_all(&request).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok::<Vec<u8>, std::io::Error>(response)
+    };
+    match async_std::future::timeout(request_timeout, exchange).await {
+        Ok(Ok(response)) => match http1::response_status(&response) {
+            Some(status) if (200..300).contains(&status) => {
+                log::debug!(
+                    "Successfully sent trace to Datadog agent; status: {}",
+                    status
+                );
+                true
+            }
+            status => {
+                log::error!("Datadog agent rejected trace; status: {:?}", status);
+                false
+            }
+        },
+        Ok(Err(e)) => {
+            log::error!("Failed to send trace to Datadog agent; error: {}", e);
+            false
+        }
+        Err(_) => {
+            log::error!("Timed out sending trace to Datadog agent");
+            false
+        }
+    }
 }
 
 #[cfg(test)]
@@ -422,146 +3406,562 @@ mod tests {
     }
 
     #[test]
-    fn test_span_type_web() {
-        let span_type = SpanType::from_str("web").unwrap();
-        assert_eq!(span_type, SpanType::Web);
-        assert_eq!(span_type.as_str(), "web");
+    fn test_config_adaptive_request_timeout() {
+        let config = ClientConfig::new()
+            .adaptive_request_timeout(true)
+            .max_request_timeout_ms(2000)
+            .slow_agent_threshold_ms(300);
+        assert!(config.adaptive_request_timeout);
+        assert_eq!(config.max_request_timeout_ms, 2000);
+        assert_eq!(config.slow_agent_threshold_ms, 300);
+        assert_eq!(config.request_timeout_ms, 100);
+    }
+
+    #[test]
+    fn test_config_inline_export() {
+        let config = ClientConfig::new().inline_export(true);
+        assert!(config.inline_export);
+        assert!(!ClientConfig::new().inline_export);
+    }
+
+    #[test]
+    fn test_config_trace_output() {
+        let config = ClientConfig::new().trace_output(TraceOutput::Stdout);
+        assert_eq!(config.trace_output, TraceOutput::Stdout);
+        assert_eq!(ClientConfig::new().trace_output, TraceOutput::Agent);
     }
 
     #[test]
-    fn test_span_type_db() {
-        let span_type = SpanType::from_str("db").unwrap();
-        assert_eq!(span_type, SpanType::Db);
-        assert_eq!(span_type.as_str(), "db");
+    fn test_agent_latency_fixed_timeout_when_not_adaptive() {
+        let mut agent_latency = AgentLatency::new(&ClientConfig::new());
+        agent_latency.record(Duration::from_millis(400));
+        assert_eq!(agent_latency.request_timeout_ms(), 100);
     }
 
     #[test]
-    fn test_span_type_cache() {
-        let span_type = SpanType::from_str("cache").unwrap();
-        assert_eq!(span_type, SpanType::Cache);
-        assert_eq!(span_type.as_str(), "cache");
+    fn test_agent_latency_adaptive_timeout_follows_latencies() {
+        let mut agent_latency =
+            AgentLatency::new(&ClientConfig::new().adaptive_request_timeout(true));
+        assert_eq!(agent_latency.request_timeout_ms(), 100);
+        agent_latency.record(Duration::from_millis(30));
+        assert_eq!(agent_latency.request_timeout_ms(), 100);
+        agent_latency.record(Duration::from_millis(300));
+        assert_eq!(agent_latency.request_timeout_ms(), 600);
+        agent_latency.record(Duration::from_millis(5000));
+        assert_eq!(agent_latency.request_timeout_ms(), 1000);
     }
 
     #[test]
-    fn test_span_type_custom() {
-        let span_type = SpanType::from_str("custom").unwrap();
-        assert_eq!(span_type, SpanType::Custom);
-        assert_eq!(span_type.as_str(), "custom");
+    fn test_agent_latency_slow_agent() {
+        let mut agent_latency = AgentLatency::new(&ClientConfig::new());
+        for _ in 0..MIN_SAMPLES_FOR_SLOW_AGENT - 1 {
+            agent_latency.record(Duration::from_millis(500));
+        }
+        assert!(!agent_latency.is_slow());
+        agent_latency.record(Duration::from_millis(500));
+        assert!(agent_latency.is_slow());
+        for _ in 0..AGENT_LATENCY_WINDOW {
+            agent_latency.record(Duration::from_millis(5));
+        }
+        assert!(!agent_latency.is_slow());
     }
 
     #[test]
-    fn test_span_type_from_str_capitalized() {
-        let span_type = SpanType::from_str("Web").unwrap();
-        assert_eq!(span_type, SpanType::Web);
-        assert_eq!(span_type.as_str(), "web");
+    fn test_config_send_attempts_and_deadline() {
+        let config = ClientConfig::new();
+        assert_eq!(config.max_send_attempts, 1);
+        assert_eq!(config.send_deadline_ms, 1000);
+
+        let config = ClientConfig::new()
+            .max_send_attempts(0)
+            .send_deadline_ms(500);
+        assert_eq!(config.max_send_attempts, 1);
+        assert_eq!(config.send_deadline_ms, 500);
     }
 
     #[test]
-    fn test_span_type_other_string_defaults_to_custom() {
-        let span_type = SpanType::from_str("fake type").unwrap();
-        assert_eq!(span_type, SpanType::Custom);
-        assert_eq!(span_type.as_str(), "custom");
+    fn test_config_connection_reuse() {
+        let config = ClientConfig::new();
+        assert_eq!(config.pool_idle_timeout_ms, 90_000);
+        assert_eq!(config.pool_max_idle_per_host, 1);
+        assert!(!config.http2_prior_knowledge);
+
+        let config = ClientConfig::new()
+            .pool_idle_timeout_ms(5_000)
+            .pool_max_idle_per_host(0)
+            .http2_prior_knowledge(true);
+        assert_eq!(config.pool_idle_timeout_ms, 5_000);
+        assert_eq!(config.pool_max_idle_per_host, 0);
+        assert!(config.http2_prior_knowledge);
     }
 
     #[test]
-    fn test_default_span_builder() {
+    fn test_tag_queue_wait() {
         let span = SpanBuilder::default().build();
-        assert_eq!(span.error, 0);
-        assert_eq!(span.meta, HashMap::new());
-        assert_eq!(span.metrics, HashMap::new());
-        assert_eq!(span.name, "");
-        assert_eq!(span.parent_id, None);
-        assert_eq!(span.resource, "");
-        assert_eq!(span.service, "");
-        assert!(span.span_id > 0);
-        assert!(span.start > 0);
-        assert!(span.trace_id > 0);
-        assert_eq!(span.r#type, "custom");
-    }
-
-    #[test]
-    fn test_span_builder() {
-        let start = SystemTime::now()
-            .checked_sub(Duration::from_nanos(100))
-            .unwrap();
-        let parent_id = NonZeroU64::new(5).unwrap();
-        let trace_id = NonZeroU64::new(100).unwrap();
-        let name = "foo";
-        let resource = "bar";
-        let service = "aliceandbob";
-        let r#type = SpanType::Db;
-        let span = SpanBuilder::default()
-            .start(start)
-            .parent_id(parent_id)
-            .trace_id(trace_id)
-            .error(true)
-            .name(SpanName(name))
-            .resource(String::from(resource))
-            .service(ServiceName(service))
-            .span_type(r#type)
-            .build();
-        assert!(span.duration > 100);
-        assert_eq!(span.error, 1);
-        assert_eq!(span.meta, HashMap::new());
-        assert_eq!(span.metrics, HashMap::new());
-        assert_eq!(span.name, name);
-        assert_eq!(span.parent_id, Some(parent_id.get()));
-        assert_eq!(span.resource, resource);
-        assert_eq!(span.service, service);
-        assert!(span.span_id > 0);
+        let mut trace_json = serde_json::to_value(vec![vec![span.clone(), span]]).unwrap();
+
+        tag_queue_wait(&mut trace_json, 42);
+
+        for span in trace_json[0].as_array().unwrap() {
+            assert_eq!(span["metrics"][QUEUE_WAIT_METRIC_KEY], 42);
+        }
+    }
+
+    #[test]
+    fn test_group_by_service() {
+        let trace_json = serde_json::json!([
+            [{"service": "orders", "meta": {"env": "prod"}}],
+            [{"service": "orders", "meta": {"env": "staging"}}],
+            [{"service": "orders", "meta": {"env": "prod"}}, {"service": "db"}],
+            [{"service": "orders", "meta": {"env": "prod", "version": "2"}}],
+        ]);
+
+        let groups = group_by_service(trace_json);
+
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups[0].as_array().unwrap().len(), 2);
+        assert_eq!(groups[0][1][1]["service"], "db");
+        assert_eq!(groups[1][0][0]["meta"]["env"], "staging");
+        assert_eq!(groups[2][0][0]["meta"]["version"], "2");
+        assert_eq!(
+            group_by_service(serde_json::json!([])),
+            [serde_json::json!([])]
+        );
+    }
+
+    #[test]
+    fn test_prioritize_sends_errors_first_and_flushes_last() {
+        let traces = |label: &str, has_error: bool, has_root: bool| DaemonMessage::Traces {
+            payload: TracePayload::Serialized(serde_json::Value::from(label)),
+            enqueued_at: Instant::now(),
+            has_error,
+            has_root,
+            trace_count: 1,
+            estimated_size: 0,
+        };
+        let mut messages = vec![
+            DaemonMessage::Shutdown {
+                deadline: Instant::now(),
+                on_drained: Box::new(|_| {}),
+            },
+            traces("ok-1", false, false),
+            DaemonMessage::Flush(Box::new(|| {})),
+            traces("error-1", true, false),
+            traces("root-1", false, true),
+            traces("ok-2", false, false),
+            traces("error-2", true, true),
+        ];
+
+        prioritize(&mut messages);
+
+        let order: Vec<String> = messages
+            .into_iter()
+            .map(|message| match message {
+                DaemonMessage::Traces { payload, .. } => payload.into_json().to_string(),
+                DaemonMessage::Flush(_) => String::from("flush"),
+                DaemonMessage::Shutdown { .. } => String::from("shutdown"),
+            })
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                "\"error-1\"",
+                "\"error-2\"",
+                "\"root-1\"",
+                "\"ok-1\"",
+                "\"ok-2\"",
+                "flush",
+                "shutdown"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_prioritize_keeps_root_spans_in_order_outside_shutdowns() {
+        let traces = |label: &str, has_root: bool| DaemonMessage::Traces {
+            payload: TracePayload::Serialized(serde_json::Value::from(label)),
+            enqueued_at: Instant::now(),
+            has_error: false,
+            has_root,
+            trace_count: 1,
+            estimated_size: 0,
+        };
+        let mut messages = vec![traces("child", false), traces("root", true)];
+
+        prioritize(&mut messages);
+
+        let order: Vec<String> = messages
+            .into_iter()
+            .filter_map(|message| match message {
+                DaemonMessage::Traces { payload, .. } => Some(payload.into_json().to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(order, vec!["\"child\"", "\"root\""]);
+    }
+
+    #[test]
+    fn test_shutdown_drain_abandons_traces_after_deadline() {
+        let mut drain = ShutdownDrain {
+            deadline: Instant::now() + Duration::from_secs(60),
+            report: ShutdownReport::default(),
+        };
+        assert!(drain.admit(2));
+        drain.deadline = Instant::now();
+        assert!(!drain.admit(3));
+        assert_eq!(
+            drain.report,
+            ShutdownReport {
+                attempted_traces: 2,
+                abandoned_traces: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn test_shutdown_joins_the_daemon_thread() {
+        let client = Client::create_with_config(ClientConfig::new().datadog_agent_port(1));
+        client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+
+        let report = client.shutdown(Duration::from_secs(10));
+
+        assert_eq!(report.map(|report| report.attempted_traces), Ok(1));
+        match &client.transport {
+            Transport::Daemon { daemon, .. } => {
+                assert!(daemon.read().unwrap().handle.is_none());
+            }
+            _ => panic!("Expected a daemon transport"),
+        }
+    }
+
+    #[test]
+    fn test_failed_transport_drops_traces_and_reports_it() {
+        let client = Client {
+            transport: Transport::Failed,
+            stats: Arc::new(ClientStatsRecorder::default()),
+            serialize_on_caller: false,
+            span_debug_logging: SpanDebugLogging::Off,
+            send_deadline: Duration::ZERO,
+            sampled_out_stats: None,
+            span_events: false,
+        };
+
+        client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+
+        assert_eq!(client.health(), ClientHealth::TransportFailed);
         assert_eq!(
-            span.start,
-            start.duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64
+            client.flush(Duration::from_millis(10)),
+            FlushResult::DaemonUnavailable
+        );
+        assert_eq!(client.stats().requests_sent, 0);
+    }
+
+    #[test]
+    fn test_dead_daemon_is_restarted_after_its_backoff() {
+        let client = Client::create_with_config(ClientConfig::new().datadog_agent_port(1));
+        let exited = match &client.transport {
+            Transport::Daemon { daemon, .. } => daemon.read().unwrap().exited.clone(),
+            _ => panic!("Expected a daemon transport"),
+        };
+        // wait out the first backoff, so the next send restarts the daemon
+        std::thread::sleep(DAEMON_RESTART_BACKOFF);
+
+        client.send_message(DaemonMessage::Flush(Box::new(|| panic!("daemon panicked"))));
+        exited.recv().ok();
+
+        assert_eq!(client.health(), ClientHealth::DaemonDead);
+        assert_eq!(client.flush(Duration::from_secs(10)), FlushResult::Flushed);
+        assert_eq!(client.health(), ClientHealth::Healthy);
+        assert_eq!(client.stats().daemon_restarts, 1);
+        client.shutdown(Duration::from_secs(10)).unwrap();
+        assert_eq!(client.health(), ClientHealth::ShutDown);
+    }
+
+    #[test]
+    fn test_daemon_restart_backoff_doubles() {
+        let mut daemon = Client::spawn_daemon(
+            ClientConfig::new().datadog_agent_port(1),
+            Arc::new(ClientStatsRecorder::default()),
         );
-        assert_eq!(span.trace_id, trace_id.get());
-        assert_eq!(span.r#type, "db");
+        assert_eq!(daemon.restart_backoff(), DAEMON_RESTART_BACKOFF);
+        daemon.restarts = 2;
+        assert_eq!(daemon.restart_backoff(), DAEMON_RESTART_BACKOFF * 4);
+        daemon.restarts = 40;
+        assert_eq!(daemon.restart_backoff(), MAX_DAEMON_RESTART_BACKOFF);
+    }
+
+    #[test]
+    fn test_send_budget_limits_attempts() {
+        let mut budget = SendBudget::new(2, 1000);
+        let attempt_timeout = Duration::from_millis(100);
+        assert_eq!(budget.next_attempt(attempt_timeout), Some(attempt_timeout));
+        assert_eq!(budget.next_attempt(attempt_timeout), Some(attempt_timeout));
+        assert_eq!(budget.next_attempt(attempt_timeout), None);
     }
 
+    #[cfg(feature = "gzip")]
     #[test]
-    fn test_span_meta_key_service() {
-        assert_eq!(&*SpanMetaKey::Service.to_string(), "service");
+    fn test_gzip_compression() {
+        use std::io::Read;
+
+        let body = br#"[[{"name":"request"}]]"#.repeat(100);
+
+        let compressed = Compression::Gzip.compress(body.clone());
+
+        assert!(compressed.len() < body.len());
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+        assert_eq!(Compression::Gzip.content_encoding(), Some("gzip"));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_compression() {
+        let body = br#"[[{"name":"request"}]]"#.repeat(100);
+
+        let compressed = Compression::Zstd.compress(body.clone());
+
+        assert!(compressed.len() < body.len());
+        assert_eq!(zstd::decode_all(compressed.as_slice()).unwrap(), body);
+        assert_eq!(Compression::Zstd.content_encoding(), Some("zstd"));
+    }
+
+    #[test]
+    fn test_no_compression() {
+        assert_eq!(Compression::None.compress(vec![1, 2, 3]), [1, 2, 3]);
+        assert_eq!(Compression::None.content_encoding(), None);
+    }
+
+    #[test]
+    fn test_backoff_delay_doubles_with_jitter_up_to_a_cap() {
+        let retry_backoff = Duration::from_millis(100);
+        for (failures, full) in [(1, 100), (2, 200), (4, 800)] {
+            let delay = backoff_delay(retry_backoff, failures);
+            assert!(delay >= Duration::from_millis(full / 2));
+            assert!(delay <= Duration::from_millis(full));
+        }
+        assert!(backoff_delay(retry_backoff, u32::MAX) <= MAX_RETRY_BACKOFF);
+    }
+
+    #[test]
+    fn test_send_budget_has_no_retry_delay_without_attempts_left() {
+        let mut budget = SendBudget::new(1, 50);
+        let retry_backoff = Duration::from_secs(10);
+        assert!(budget.retry_delay(retry_backoff, 1).unwrap() <= Duration::from_millis(50));
+
+        budget.next_attempt(Duration::from_millis(10));
+        assert_eq!(budget.retry_delay(retry_backoff, 1), None);
+    }
+
+    #[test]
+    fn test_send_budget_clamps_attempts_to_deadline() {
+        let mut budget = SendBudget::new(5, 50);
+        assert!(budget.next_attempt(Duration::from_secs(10)).unwrap() <= Duration::from_millis(50));
+
+        let mut budget = SendBudget::new(5, 0);
+        assert_eq!(budget.next_attempt(Duration::from_secs(10)), None);
+    }
+
+    #[test]
+    fn test_circuit_breaker_opens_after_consecutive_failures() {
+        let config = ClientConfig::new().circuit_breaker_failures(2);
+        let mut breaker = CircuitBreaker::new(&config);
+
+        assert_eq!(breaker.record(false), None);
+        assert_eq!(breaker.record(true), None);
+        assert_eq!(breaker.record(false), None);
+        assert_eq!(breaker.record(false), Some(true));
+
+        assert!(!breaker.allows_send());
+    }
+
+    #[test]
+    fn test_circuit_breaker_closes_after_a_successful_probe() {
+        let config = ClientConfig::new()
+            .circuit_breaker_failures(1)
+            .circuit_breaker_probe_ms(0);
+        let mut breaker = CircuitBreaker::new(&config);
+        assert_eq!(breaker.record(false), Some(true));
+
+        assert!(breaker.allows_send());
+        assert_eq!(breaker.record(false), None);
+        assert!(breaker.allows_send());
+        assert_eq!(breaker.record(true), Some(false));
+        assert_eq!(breaker.next_probe_at(), None);
+    }
+
+    #[test]
+    fn test_disabled_circuit_breaker_never_opens() {
+        let mut breaker = CircuitBreaker::new(&ClientConfig::new());
+
+        for _ in 0..100 {
+            assert_eq!(breaker.record(false), None);
+        }
+        assert!(breaker.allows_send());
+    }
+
+    fn daemon_thread_and_pid(client: &Client) -> (std::thread::ThreadId, u32) {
+        match &client.transport {
+            Transport::Daemon { daemon, .. } => {
+                let daemon = daemon.read().unwrap();
+                (daemon.thread.id(), daemon.pid)
+            }
+            _ => panic!("Expected a daemon transport"),
+        }
+    }
+
+    fn flush(client: &Client) {
+        let (flushed_sender, flushed_receiver) = std::sync::mpsc::channel();
+        assert!(client.send_message(DaemonMessage::Flush(Box::new(move || {
+            flushed_sender.send(()).ok();
+        }))));
+        flushed_receiver
+            .recv_timeout(Duration::from_secs(5))
+            .expect("daemon never flushed");
+    }
+
+    #[test]
+    fn test_daemon_is_kept_within_a_process() {
+        let client = Client::create_with_config(ClientConfig::new().datadog_agent_port(1));
+        let (thread_before, pid_before) = daemon_thread_and_pid(&client);
+
+        flush(&client);
+
+        assert_eq!(pid_before, std::process::id());
+        assert_eq!(daemon_thread_and_pid(&client).0, thread_before);
     }
 
     #[test]
-    fn test_span_meta_key_env() {
-        assert_eq!(&*SpanMetaKey::Env.to_string(), "env");
+    fn test_daemon_is_respawned_after_fork() {
+        let client = Client::create_with_config(ClientConfig::new().datadog_agent_port(1));
+        let (thread_before, _) = daemon_thread_and_pid(&client);
+        // pretend the daemon was spawned by a parent process
+        if let Transport::Daemon { daemon, .. } = &client.transport {
+            daemon.write().unwrap().pid = std::process::id().wrapping_add(1);
+        }
+
+        flush(&client);
+
+        let (thread_after, pid_after) = daemon_thread_and_pid(&client);
+        assert_ne!(thread_after, thread_before);
+        assert_eq!(pid_after, std::process::id());
     }
 
     #[test]
-    fn test_span_meta_key_version() {
-        assert_eq!(&*SpanMetaKey::Version.to_string(), "version");
+    fn test_config_presets() {
+        let lambda = ClientConfig::lambda_extension();
+        assert_eq!(lambda.datadog_agent_host, "localhost");
+        assert_eq!(lambda.datadog_agent_port, 8126);
+        assert_eq!(lambda.pool_max_idle_per_host, 0);
+
+        let local = ClientConfig::local_dev();
+        assert_eq!(local.max_send_attempts, 1);
+        assert_eq!(local.span_debug_logging, SpanDebugLogging::Errors);
+
+        let kubernetes = ClientConfig::kubernetes();
+        assert!(kubernetes.adaptive_request_timeout);
+        assert_eq!(kubernetes.max_send_attempts, 2);
+        if std::env::var("DD_AGENT_HOST").is_err() {
+            assert_eq!(kubernetes.datadog_agent_host, "localhost");
+            assert_eq!(
+                ClientConfig::docker_compose().datadog_agent_host,
+                "datadog-agent"
+            );
+        }
     }
 
     #[test]
-    fn test_span_meta_key_http_method() {
-        assert_eq!(&*SpanMetaKey::HttpMethod.to_string(), "http.method");
+    fn test_config_serialize_on_caller() {
+        assert!(!ClientConfig::new().serialize_on_caller);
+        assert!(
+            ClientConfig::new()
+                .serialize_on_caller(true)
+                .serialize_on_caller
+        );
     }
 
     #[test]
-    fn test_span_meta_key_http_url() {
-        assert_eq!(&*SpanMetaKey::HttpUrl.to_string(), "http.url");
+    fn test_span_debug_logging_should_log() {
+        let span = SpanBuilder::default().build();
+        let error_span = SpanBuilder::default().error(true).build();
+
+        assert!(!SpanDebugLogging::Off.should_log(&error_span));
+        assert!(!SpanDebugLogging::OneIn(0).should_log(&span));
+        assert!(SpanDebugLogging::OneIn(1).should_log(&span));
+        assert!(!SpanDebugLogging::Errors.should_log(&span));
+        assert!(SpanDebugLogging::Errors.should_log(&error_span));
     }
 
     #[test]
-    fn test_span_meta_key_http_status_code() {
+    fn test_trace_payload_into_json() {
+        let traces = vec![vec![SpanBuilder::default().build()]];
+        let serialized = serde_json::to_value(&traces).unwrap();
+
+        assert_eq!(TracePayload::Owned(traces.clone()).into_json(), serialized);
         assert_eq!(
-            &*SpanMetaKey::HttpStatusCode.to_string(),
-            "http.status_code"
+            TracePayload::Serialized(serialized.clone()).into_json(),
+            serialized
         );
     }
 
     #[test]
-    fn test_span_meta_key_error_msg() {
-        assert_eq!(&*SpanMetaKey::ErrorMsg.to_string(), "error.msg");
+    fn test_payload_batch() {
+        let mut batch = PayloadBatch::new(100);
+        assert!(batch.is_empty());
+        assert!(batch.fits(1000));
+
+        batch.push(serde_json::json!([["a"], ["b"]]), 60);
+        assert!(batch.fits(40));
+        assert!(!batch.fits(41));
+        batch.push(serde_json::json!([["c"]]), 40);
+
+        assert_eq!(batch.take(), serde_json::json!([["a"], ["b"], ["c"]]));
+        assert!(batch.is_empty());
+        assert!(batch.fits(100));
+    }
+
+    #[test]
+    fn test_payload_batch_is_due_by_size_or_age() {
+        let max_age = Duration::from_secs(60);
+        let mut batch = PayloadBatch::new(100);
+        assert_eq!(batch.remaining_age(max_age), None);
+        assert!(!batch.is_due(2, max_age));
+
+        batch.push(serde_json::json!([["a"]]), 10);
+        assert!(batch.remaining_age(max_age).unwrap() > Duration::from_secs(59));
+        assert!(!batch.is_due(2, max_age));
+        assert!(batch.is_due(2, Duration::ZERO));
+        batch.push(serde_json::json!([["b"]]), 10);
+        assert!(batch.is_due(2, max_age));
+
+        batch.take();
+        assert_eq!(batch.remaining_age(max_age), None);
     }
 
     #[test]
-    fn test_span_meta_key_error_stack() {
-        assert_eq!(&*SpanMetaKey::ErrorStack.to_string(), "error.stack");
+    fn test_config_max_payload_bytes() {
+        assert_eq!(ClientConfig::new().max_payload_bytes, 10 * 1024 * 1024);
+        assert_eq!(
+            ClientConfig::new()
+                .max_payload_bytes(1024)
+                .max_payload_bytes,
+            1024
+        );
     }
 
     #[test]
-    fn test_span_meta_key_error_type() {
-        assert_eq!(&*SpanMetaKey::ErrorType.to_string(), "error.type");
+    fn test_agent_url() {
+        let config = ClientConfig::new()
+            .datadog_agent_host("agent")
+            .trace_api_version(TraceApiVersion::V05)
+            .windows_pipe_name("datadog-apm");
+
+        #[cfg(windows)]
+        assert_eq!(agent_url(&config), r"\\.\pipe\datadog-apm/v0.5/traces");
+        #[cfg(not(windows))]
+        assert_eq!(agent_url(&config), "http://agent:8126/v0.5/traces");
     }
 }
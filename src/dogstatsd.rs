@@ -0,0 +1,321 @@
+// Degraded export over the DogStatsD UDP port, for environments where the trace endpoint of the
+// agent can't be reached: instead of whole spans, a summary of each span (hits, errors and
+// duration, tagged with service, resource and env) is sent as DogStatsD metrics.
+//
+// The same metrics keep trace metrics accurate when sampling drops traces before they reach the
+// agent, which computes trace metrics from the traces it receives: the spans of dropped traces
+// are aggregated in the process, and their totals sent every `SAMPLED_OUT_FLUSH_INTERVAL`.
+
+use super::span::Span;
+use std::collections::HashMap;
+use std::net::UdpSocket;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Keeps datagrams under the usual MTU so they aren't fragmented
+const MAX_DATAGRAM_BYTES: usize = 1432;
+
+pub(crate) struct DogStatsdFallback {
+    socket: UdpSocket,
+}
+
+impl DogStatsdFallback {
+    pub(crate) fn new(host: &str, port: u16) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        socket.set_nonblocking(true)?;
+        socket.connect((host, port))?;
+        Ok(Self { socket })
+    }
+
+    #[inline]
+    pub(crate) fn send_summaries(&self, trace_json: &serde_json::Value) {
+        let lines = summary_lines(trace_json);
+        log::debug!(
+            "Sending summaries of {} spans to DogStatsD",
+            lines.len() / 3
+        );
+        self.send_lines(&lines);
+    }
+
+    #[inline]
+    fn send_lines(&self, lines: &[String]) {
+        for datagram in datagrams(lines) {
+            if let Err(e) = self.socket.send(datagram.as_bytes()) {
+                log::error!("Failed to send span summaries to DogStatsD; err {}", e);
+                return;
+            }
+        }
+    }
+}
+
+pub(crate) const SAMPLED_OUT_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+// Spans are aggregated by name and by the tags of their metrics
+#[derive(Hash, PartialEq, Eq)]
+struct StatsKey {
+    name: String,
+    tags: String,
+}
+
+#[derive(Default)]
+struct StatsBucket {
+    hits: u64,
+    errors: u64,
+    duration_ns: u64,
+}
+
+struct Aggregation {
+    buckets: HashMap<StatsKey, StatsBucket>,
+    flushed_at: Instant,
+}
+
+/// Totals of the spans dropped by sampling, sent as DogStatsD metrics.
+pub(crate) struct SampledOutStats {
+    dogstatsd: DogStatsdFallback,
+    aggregation: Mutex<Aggregation>,
+}
+
+impl SampledOutStats {
+    pub(crate) fn new(host: &str, port: u16) -> std::io::Result<Self> {
+        Ok(Self {
+            dogstatsd: DogStatsdFallback::new(host, port)?,
+            aggregation: Mutex::new(Aggregation {
+                buckets: HashMap::new(),
+                flushed_at: Instant::now(),
+            }),
+        })
+    }
+
+    // Adds a span to the totals, which are sent if the last flush is old enough
+    #[inline]
+    pub(crate) fn record(&self, span: &Span) {
+        let Ok(mut aggregation) = self.aggregation.lock() else {
+            return;
+        };
+        let key = StatsKey {
+            name: sanitize(if span.name().is_empty() {
+                "span"
+            } else {
+                span.name()
+            }),
+            tags: tags(
+                span.service(),
+                span.resource(),
+                span.meta().get("env").map(String::as_str),
+            ),
+        };
+        let bucket = aggregation.buckets.entry(key).or_default();
+        bucket.hits += 1;
+        bucket.errors += u64::from(span.is_error());
+        bucket.duration_ns += span.duration();
+        if aggregation.flushed_at.elapsed() >= SAMPLED_OUT_FLUSH_INTERVAL {
+            self.send(&mut aggregation);
+        }
+    }
+
+    /// Sends the totals aggregated since the last flush.
+    #[inline]
+    pub(crate) fn flush(&self) {
+        if let Ok(mut aggregation) = self.aggregation.lock() {
+            self.send(&mut aggregation);
+        }
+    }
+
+    #[inline]
+    fn send(&self, aggregation: &mut Aggregation) {
+        aggregation.flushed_at = Instant::now();
+        if aggregation.buckets.is_empty() {
+            return;
+        }
+        let lines = stats_lines(aggregation.buckets.drain());
+        log::debug!(
+            "Sending totals of spans dropped by sampling to DogStatsD in {} lines",
+            lines.len()
+        );
+        self.dogstatsd.send_lines(&lines);
+    }
+}
+
+#[inline]
+fn stats_lines(buckets: impl Iterator<Item = (StatsKey, StatsBucket)>) -> Vec<String> {
+    let mut lines = Vec::new();
+    for (StatsKey { name, tags }, bucket) in buckets {
+        lines.push(format!("trace.{}.hits:{}|c|#{}", name, bucket.hits, tags));
+        lines.push(format!(
+            "trace.{}.errors:{}|c|#{}",
+            name, bucket.errors, tags
+        ));
+        lines.push(format!(
+            "trace.{}.duration:{}|c|#{}",
+            name,
+            bucket.duration_ns as f64 / 1e9,
+            tags
+        ));
+    }
+    lines
+}
+
+#[inline]
+fn tags(service: &str, resource: &str, env: Option<&str>) -> String {
+    let mut tags = vec![
+        format!("service:{}", sanitize(service)),
+        format!("resource:{}", sanitize(resource)),
+    ];
+    if let Some(env) = env.filter(|env| !env.is_empty()) {
+        tags.push(format!("env:{}", sanitize(env)));
+    }
+    tags.join(",")
+}
+
+#[inline]
+fn summary_lines(trace_json: &serde_json::Value) -> Vec<String> {
+    let spans = trace_json
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(serde_json::Value::as_array)
+        .flatten();
+    let mut lines = Vec::new();
+    for span in spans {
+        let field = |key: &str| span.get(key).and_then(serde_json::Value::as_str);
+        let name = sanitize(field("name").unwrap_or("span"));
+        let tags = tags(
+            field("service").unwrap_or_default(),
+            field("resource").unwrap_or_default(),
+            span.get("meta")
+                .and_then(|meta| meta.get("env"))
+                .and_then(serde_json::Value::as_str),
+        );
+        let is_error = span.get("error").and_then(serde_json::Value::as_u64) == Some(1);
+        let duration_secs = span
+            .get("duration")
+            .and_then(serde_json::Value::as_u64)
+            .unwrap_or_default() as f64
+            / 1e9;
+        lines.push(format!("trace.{}.hits:1|c|#{}", name, tags));
+        lines.push(format!(
+            "trace.{}.errors:{}|c|#{}",
+            name,
+            u8::from(is_error),
+            tags
+        ));
+        lines.push(format!(
+            "trace.{}.duration:{}|d|#{}",
+            name, duration_secs, tags
+        ));
+    }
+    lines
+}
+
+// `|`, `,`, `:` and `#` delimit the parts of a DogStatsD line, so they can't appear in names or
+// tag values
+#[inline]
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '|' | ',' | ':' | '#' | '\n' | ' ' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[inline]
+fn datagrams(lines: &[String]) -> Vec<String> {
+    let mut datagrams: Vec<String> = Vec::new();
+    for line in lines {
+        match datagrams.last_mut() {
+            Some(datagram) if datagram.len() + 1 + line.len() <= MAX_DATAGRAM_BYTES => {
+                datagram.push('\n');
+                datagram.push_str(line);
+            }
+            _ => datagrams.push(line.clone()),
+        }
+    }
+    datagrams
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_summary_lines() {
+        let trace_json = json!([[{
+            "name": "request",
+            "service": "web",
+            "resource": "GET /users, /admins",
+            "duration": 1_500_000_000u64,
+            "error": 1,
+            "meta": {"env": "prod"},
+        }]]);
+
+        assert_eq!(
+            summary_lines(&trace_json),
+            vec![
+                "trace.request.hits:1|c|#service:web,resource:GET_/users__/admins,env:prod",
+                "trace.request.errors:1|c|#service:web,resource:GET_/users__/admins,env:prod",
+                "trace.request.duration:1.5|d|#service:web,resource:GET_/users__/admins,env:prod",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sampled_out_stats_are_aggregated() {
+        let dogstatsd = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = dogstatsd.local_addr().unwrap().port();
+        let stats = SampledOutStats::new("127.0.0.1", port).unwrap();
+        let mut span_builder = crate::span::SpanBuilder::default();
+        span_builder
+            .name(crate::span::SpanName("request"))
+            .service(crate::span::ServiceName("web"))
+            .resource(String::from("GET /"));
+
+        stats.record(&span_builder.build_with_duration(Duration::from_millis(500)));
+        stats.record(
+            &span_builder
+                .error(true)
+                .build_with_duration(Duration::from_millis(1500)),
+        );
+        stats.flush();
+
+        let mut buf = [0; MAX_DATAGRAM_BYTES];
+        let received = dogstatsd.recv(&mut buf).unwrap();
+        let datagram = std::str::from_utf8(&buf[..received]).unwrap();
+        assert_eq!(
+            datagram,
+            [
+                "trace.request.hits:2|c|#service:web,resource:GET_/",
+                "trace.request.errors:1|c|#service:web,resource:GET_/",
+                "trace.request.duration:2|c|#service:web,resource:GET_/",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_datagrams_stay_under_max_size() {
+        let lines: Vec<String> = (0..100).map(|i| format!("trace.x.hits:{}|c", i)).collect();
+
+        let datagrams = datagrams(&lines);
+
+        assert!(datagrams.len() > 1);
+        assert!(datagrams.iter().all(|d| d.len() <= MAX_DATAGRAM_BYTES));
+        assert_eq!(datagrams.join("\n"), lines.join("\n"));
+    }
+
+    #[test]
+    fn test_send_summaries() {
+        let dogstatsd = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = dogstatsd.local_addr().unwrap().port();
+        let fallback = DogStatsdFallback::new("127.0.0.1", port).unwrap();
+
+        fallback.send_summaries(&json!([[{"name": "request", "service": "web"}]]));
+
+        let mut buf = [0; MAX_DATAGRAM_BYTES];
+        let received = dogstatsd.recv(&mut buf).unwrap();
+        let datagram = std::str::from_utf8(&buf[..received]).unwrap();
+        assert!(datagram.starts_with("trace.request.hits:1|c|#service:web,resource:"));
+    }
+}
@@ -0,0 +1,159 @@
+use super::span::SpanBuilder;
+use std::env;
+use std::time::Duration;
+
+pub use regex::Regex;
+
+/// Rules dropping spans when they close, before they are exported, e.g. health check requests
+/// or spans too short to be worth their ingestion cost. A span is dropped if any rule matches it.
+///
+/// Rules are applied to every span rather than to whole traces: children of a dropped span which
+/// closed before it were exported already. Use a `Sampler` to drop whole traces.
+#[derive(Clone, Debug, Default)]
+pub struct DropRules {
+    rules: Vec<DropRule>,
+}
+
+#[derive(Clone, Debug)]
+enum DropRule {
+    Tag(String, Option<String>),
+    TagRegex(String, Regex),
+    ShorterThan(Duration),
+}
+
+impl DropRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the rules from the `DD_APM_FILTER_TAGS_REJECT` and
+    /// `DD_APM_FILTER_TAGS_REGEX_REJECT` env vars, with the semantics of the Datadog agent:
+    /// space-separated `key:value` tags (or `key` alone for any value), matching exactly in the
+    /// first one and as regexes in the second one.
+    pub fn from_env() -> Self {
+        let mut drop_rules = Self::new();
+        if let Ok(value) = env::var("DD_APM_FILTER_TAGS_REJECT") {
+            drop_rules = drop_rules.parse_tags(&value);
+        }
+        if let Ok(value) = env::var("DD_APM_FILTER_TAGS_REGEX_REJECT") {
+            drop_rules = drop_rules.parse_tag_regexes(&value);
+        }
+        drop_rules
+    }
+
+    /// Adds `key:value` (or `key`) rules, space-separated like in `DD_APM_FILTER_TAGS_REJECT`.
+    pub fn parse_tags(mut self, value: &str) -> Self {
+        for tag in value.split_whitespace() {
+            self = match tag.split_once(':') {
+                Some((key, value)) => self.reject_tag_value(key, value),
+                None => self.reject_tag(tag),
+            };
+        }
+        self
+    }
+
+    /// Adds `key:regex` (or `key`) rules, space-separated like in
+    /// `DD_APM_FILTER_TAGS_REGEX_REJECT`. Invalid regexes are logged and skipped.
+    pub fn parse_tag_regexes(mut self, value: &str) -> Self {
+        for tag in value.split_whitespace() {
+            let Some((key, pattern)) = tag.split_once(':') else {
+                self = self.reject_tag(tag);
+                continue;
+            };
+            match Regex::new(pattern) {
+                Ok(regex) => self = self.reject_tag_matching(key, regex),
+                Err(e) => log::error!("Ignoring invalid drop rule {}; err {}", tag, e),
+            }
+        }
+        self
+    }
+
+    /// Drops spans with the `key` tag, whatever its value.
+    pub fn reject_tag(mut self, key: impl Into<String>) -> Self {
+        self.rules.push(DropRule::Tag(key.into(), None));
+        self
+    }
+
+    /// Drops spans whose `key` tag is `value`.
+    pub fn reject_tag_value(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.rules
+            .push(DropRule::Tag(key.into(), Some(value.into())));
+        self
+    }
+
+    /// Drops spans whose `key` tag matches `regex`, e.g. `http.url` and `^/health`. Regexes match
+    /// anywhere in the value unless anchored.
+    pub fn reject_tag_matching(mut self, key: impl Into<String>, regex: Regex) -> Self {
+        self.rules.push(DropRule::TagRegex(key.into(), regex));
+        self
+    }
+
+    /// Drops spans which lasted less than `duration`, unless they have an error.
+    pub fn reject_shorter_than(mut self, duration: Duration) -> Self {
+        self.rules.push(DropRule::ShorterThan(duration));
+        self
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether a span which lasted `duration` is dropped.
+    #[inline]
+    pub fn should_drop(&self, span_builder: &SpanBuilder, duration: Duration) -> bool {
+        self.rules.iter().any(|rule| match rule {
+            DropRule::Tag(key, None) => span_builder.meta_value(key).is_some(),
+            DropRule::Tag(key, Some(value)) => span_builder.meta_value(key) == Some(value),
+            DropRule::TagRegex(key, regex) => span_builder
+                .meta_value(key)
+                .is_some_and(|value| regex.is_match(value)),
+            DropRule::ShorterThan(min_duration) => duration < *min_duration && !span_builder.error,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span_with_tag(key: &str, value: &str) -> SpanBuilder {
+        let mut span_builder = SpanBuilder::default();
+        span_builder.add_tag(String::from(key), value);
+        span_builder
+    }
+
+    #[test]
+    fn test_tag_rules() {
+        let drop_rules = DropRules::new()
+            .parse_tags("http.status_code:404 synthetics")
+            .parse_tag_regexes("http.url:^/health");
+        let duration = Duration::from_millis(1);
+
+        assert!(drop_rules.should_drop(&span_with_tag("http.status_code", "404"), duration));
+        assert!(!drop_rules.should_drop(&span_with_tag("http.status_code", "200"), duration));
+        assert!(drop_rules.should_drop(&span_with_tag("synthetics", "true"), duration));
+        assert!(drop_rules.should_drop(&span_with_tag("http.url", "/healthz"), duration));
+        assert!(!drop_rules.should_drop(&span_with_tag("http.url", "/orders/health"), duration));
+        assert!(!drop_rules.should_drop(&SpanBuilder::default(), duration));
+    }
+
+    #[test]
+    fn test_short_spans_are_dropped_unless_errors() {
+        let drop_rules = DropRules::new().reject_shorter_than(Duration::from_micros(100));
+        let mut span_builder = SpanBuilder::default();
+
+        assert!(drop_rules.should_drop(&span_builder, Duration::from_micros(99)));
+        assert!(!drop_rules.should_drop(&span_builder, Duration::from_micros(100)));
+        span_builder.error(true);
+        assert!(!drop_rules.should_drop(&span_builder, Duration::from_micros(99)));
+    }
+
+    #[test]
+    fn test_invalid_regexes_are_skipped() {
+        let drop_rules = DropRules::new().parse_tag_regexes("http.url:( component:^actix");
+
+        assert!(!drop_rules.should_drop(&span_with_tag("http.url", "("), Duration::ZERO));
+        assert!(drop_rules.should_drop(&span_with_tag("component", "actix-web"), Duration::ZERO));
+    }
+}
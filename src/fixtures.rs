@@ -0,0 +1,126 @@
+// Test support for asserting the stability of the payloads sent to Datadog across crate
+// upgrades: a deterministic trace, deterministic serializations of it, and golden file
+// assertions.
+
+use super::datadog_client::{
+    ServiceName, SpanBuilder, SpanMetaKey, SpanName, SpanType, Trace, Traces,
+};
+use std::collections::HashMap;
+use std::num::NonZeroU64;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Env var which makes `assert_golden` (re)write golden files instead of comparing with them.
+pub const UPDATE_GOLDEN_ENV_VAR: &str = "UPDATE_GOLDEN";
+
+/// A trace whose ids, timestamps and tags are always the same: a `web` request span with a `db`
+/// child span which failed.
+pub fn deterministic_trace() -> Trace {
+    let trace_id = NonZeroU64::new(0x1234_5678_9abc_def0).unwrap();
+    let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+    let mut request = SpanBuilder::default();
+    request
+        .name(SpanName("request"))
+        .service(ServiceName("fixture-web"))
+        .resource(String::from("GET /users/{id}"))
+        .span_type(SpanType::Web)
+        .trace_id(trace_id)
+        .start(start)
+        .add_meta(SpanMetaKey::HttpMethod, "GET")
+        .add_meta(SpanMetaKey::HttpUrl, "/users/42")
+        .add_meta(SpanMetaKey::HttpStatusCode, "500")
+        .metrics(HashMap::from([(String::from("users.loaded"), 1.0)]));
+    request.span_id = NonZeroU64::new(1).unwrap();
+
+    let mut query = SpanBuilder::default();
+    query
+        .name(SpanName("db.query"))
+        .service(ServiceName("fixture-db"))
+        .resource(String::from("SELECT * FROM users WHERE id = ?"))
+        .span_type(SpanType::Db)
+        .trace_id(trace_id)
+        .parent_id(request.span_id)
+        .start(start + Duration::from_millis(5))
+        .error(true)
+        .add_meta(SpanMetaKey::ErrorType, "Timeout")
+        .add_meta(SpanMetaKey::ErrorMsg, "query timed out");
+    query.span_id = NonZeroU64::new(2).unwrap();
+
+    vec![
+        request.build_with_duration(Duration::from_millis(30)),
+        query.build_with_duration(Duration::from_millis(20)),
+    ]
+}
+
+/// Serializes traces to pretty printed JSON, with the keys of every object sorted.
+pub fn to_golden_json(traces: &Traces) -> String {
+    let value = serde_json::to_value(traces).expect("Traces always serialize to JSON");
+    let mut json = serde_json::to_string_pretty(&value).expect("JSON values always serialize");
+    json.push('\n');
+    json
+}
+
+/// Serializes traces to msgpack in the agent format, with the keys of every map sorted.
+pub fn to_golden_msgpack(traces: &Traces) -> Vec<u8> {
+    let value = serde_json::to_value(traces).expect("Traces always serialize to JSON");
+    rmp_serde::to_vec_named(&value).expect("JSON values always serialize to msgpack")
+}
+
+/// Asserts that `actual` matches the golden file at `path`. The file is written instead if it
+/// doesn't exist yet, or if the `UPDATE_GOLDEN` env var is set.
+///
+/// ```ignore
+/// let traces = vec![fixtures::deterministic_trace()];
+/// fixtures::assert_golden("tests/golden/trace.json", fixtures::to_golden_json(&traces));
+/// ```
+pub fn assert_golden(path: impl AsRef<Path>, actual: impl AsRef<[u8]>) {
+    let path = path.as_ref();
+    let actual = actual.as_ref();
+    if std::env::var_os(UPDATE_GOLDEN_ENV_VAR).is_some() || !path.exists() {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).expect("Failed to create golden file directory");
+        }
+        std::fs::write(path, actual).expect("Failed to write golden file");
+        return;
+    }
+    let expected = std::fs::read(path).expect("Failed to read golden file");
+    assert!(
+        expected == actual,
+        "Payload differs from golden file {}; rerun with {}=1 to update it if this is intended\nexpected: {}\nactual: {}",
+        path.display(),
+        UPDATE_GOLDEN_ENV_VAR,
+        String::from_utf8_lossy(&expected),
+        String::from_utf8_lossy(actual)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datadog_client::TracesDecode;
+
+    #[test]
+    fn test_deterministic_trace_is_deterministic() {
+        assert_eq!(deterministic_trace(), deterministic_trace());
+        assert_eq!(
+            to_golden_json(&vec![deterministic_trace()]),
+            to_golden_json(&vec![deterministic_trace()])
+        );
+        assert_eq!(
+            to_golden_msgpack(&vec![deterministic_trace()]),
+            to_golden_msgpack(&vec![deterministic_trace()])
+        );
+    }
+
+    #[test]
+    fn test_golden_serializations_decode() {
+        let traces = vec![deterministic_trace()];
+
+        let from_json = Traces::from_json(to_golden_json(&traces).as_bytes()).unwrap();
+        let from_msgpack = Traces::from_msgpack(&to_golden_msgpack(&traces)).unwrap();
+
+        assert_eq!(from_json, traces);
+        assert_eq!(from_msgpack, traces);
+    }
+}
@@ -0,0 +1,105 @@
+const GRPC_STATUS_CODE_NAMES: [&str; 17] = [
+    "OK",
+    "CANCELLED",
+    "UNKNOWN",
+    "INVALID_ARGUMENT",
+    "DEADLINE_EXCEEDED",
+    "NOT_FOUND",
+    "ALREADY_EXISTS",
+    "PERMISSION_DENIED",
+    "RESOURCE_EXHAUSTED",
+    "FAILED_PRECONDITION",
+    "ABORTED",
+    "OUT_OF_RANGE",
+    "UNIMPLEMENTED",
+    "INTERNAL",
+    "UNAVAILABLE",
+    "DATA_LOSS",
+    "UNAUTHENTICATED",
+];
+
+/// Decides which gRPC status codes mark a span as an error when it is closed. By default, only
+/// the codes signaling a failure of the server are errors (`UNKNOWN`, `DEADLINE_EXCEEDED`,
+/// `UNIMPLEMENTED`, `INTERNAL`, `UNAVAILABLE` and `DATA_LOSS`), while the codes caused by the
+/// request itself (e.g. `NOT_FOUND` or `INVALID_ARGUMENT`) are not.
+///
+/// Each code can be reclassified with `error_code`:
+///
+/// ```ignore
+/// let classifier = GrpcStatusClassifier::default()
+///     .error_code(5, true) // NOT_FOUND
+///     .error_code(12, false); // UNIMPLEMENTED
+/// ```
+#[derive(Clone, Debug)]
+pub struct GrpcStatusClassifier {
+    error_codes: [bool; 17],
+}
+
+impl GrpcStatusClassifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets whether the given status code marks a span as an error. Unknown codes are ignored.
+    pub fn error_code(mut self, code: u32, is_error: bool) -> Self {
+        if let Some(error_code) = self.error_codes.get_mut(code as usize) {
+            *error_code = is_error;
+        }
+        self
+    }
+
+    #[inline]
+    pub fn is_error(&self, code: u32) -> bool {
+        self.error_codes.get(code as usize).copied().unwrap_or(true)
+    }
+}
+
+impl Default for GrpcStatusClassifier {
+    fn default() -> Self {
+        let mut error_codes = [false; 17];
+        for code in [2, 4, 12, 13, 14, 15] {
+            error_codes[code] = true;
+        }
+        Self { error_codes }
+    }
+}
+
+/// The canonical name of a gRPC status code, e.g. `UNAVAILABLE` for `14`.
+#[inline]
+pub fn grpc_status_code_name(code: u32) -> Option<&'static str> {
+    GRPC_STATUS_CODE_NAMES.get(code as usize).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_classifier() {
+        let classifier = GrpcStatusClassifier::default();
+        assert!(!classifier.is_error(0)); // OK
+        assert!(!classifier.is_error(5)); // NOT_FOUND
+        assert!(!classifier.is_error(3)); // INVALID_ARGUMENT
+        assert!(classifier.is_error(13)); // INTERNAL
+        assert!(classifier.is_error(14)); // UNAVAILABLE
+    }
+
+    #[test]
+    fn test_classifier_per_code_override() {
+        let classifier = GrpcStatusClassifier::new()
+            .error_code(5, true)
+            .error_code(14, false)
+            .error_code(99, false);
+        assert!(classifier.is_error(5));
+        assert!(!classifier.is_error(14));
+        assert!(classifier.is_error(99));
+    }
+
+    #[test]
+    fn test_grpc_status_code_name() {
+        assert_eq!(grpc_status_code_name(0), Some("OK"));
+        assert_eq!(grpc_status_code_name(14), Some("UNAVAILABLE"));
+        assert_eq!(grpc_status_code_name(16), Some("UNAUTHENTICATED"));
+        assert_eq!(grpc_status_code_name(17), None);
+    }
+}
@@ -0,0 +1,160 @@
+use std::env;
+
+/// Maps HTTP request and response headers to span tags, as configured by the standard
+/// `DD_TRACE_HEADER_TAGS` env var of Datadog tracers: a comma-separated list of `header:tag`
+/// pairs. A header without a tag is tagged as `http.request.headers.<header>` on requests and
+/// `http.response.headers.<header>` on responses.
+///
+/// It is applied by the HTTP middlewares (e.g. the actix-web one).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeaderTags {
+    mappings: Vec<HeaderTag>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct HeaderTag {
+    header: String,
+    tag: Option<String>,
+}
+
+impl HeaderTags {
+    pub fn from_env() -> Self {
+        env::var("DD_TRACE_HEADER_TAGS")
+            .map(|value| Self::parse(&value))
+            .unwrap_or_default()
+    }
+
+    pub fn parse(value: &str) -> Self {
+        let mappings = value
+            .split(',')
+            .filter_map(|mapping| {
+                let (header, tag) = match mapping.split_once(':') {
+                    Some((header, tag)) => (header, Some(tag.trim()).filter(|tag| !tag.is_empty())),
+                    None => (mapping, None),
+                };
+                let header = header.trim().to_lowercase();
+                if header.is_empty() {
+                    return None;
+                }
+                Some(HeaderTag {
+                    header,
+                    tag: tag.map(String::from),
+                })
+            })
+            .collect();
+        Self { mappings }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.mappings.is_empty()
+    }
+
+    /// The tags for the headers of a request, given as `(name, value)` pairs.
+    #[inline]
+    pub fn request_tags<'a>(
+        &self,
+        headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Vec<(String, String)> {
+        self.tags(headers, "http.request.headers.")
+    }
+
+    /// The tags for the headers of a response, given as `(name, value)` pairs.
+    #[inline]
+    pub fn response_tags<'a>(
+        &self,
+        headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Vec<(String, String)> {
+        self.tags(headers, "http.response.headers.")
+    }
+
+    #[inline]
+    fn tags<'a>(
+        &self,
+        headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+        default_prefix: &str,
+    ) -> Vec<(String, String)> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        headers
+            .into_iter()
+            .filter_map(|(name, value)| {
+                let mapping = self
+                    .mappings
+                    .iter()
+                    .find(|mapping| mapping.header.eq_ignore_ascii_case(name))?;
+                let tag = match &mapping.tag {
+                    Some(tag) => tag.clone(),
+                    None => format!("{}{}", default_prefix, normalize(&mapping.header)),
+                };
+                Some((tag, value.trim().to_string()))
+            })
+            .collect()
+    }
+}
+
+#[inline]
+fn normalize(header: &str) -> String {
+    header
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let header_tags = HeaderTags::parse(" X-Request-Id:request.id, User-Agent ,, :nope");
+        assert_eq!(
+            header_tags.mappings,
+            vec![
+                HeaderTag {
+                    header: String::from("x-request-id"),
+                    tag: Some(String::from("request.id"))
+                },
+                HeaderTag {
+                    header: String::from("user-agent"),
+                    tag: None
+                },
+            ]
+        );
+        assert!(HeaderTags::parse("").is_empty());
+    }
+
+    #[test]
+    fn test_request_and_response_tags() {
+        let header_tags = HeaderTags::parse("x-request-id:request.id,content-type");
+        let headers = [
+            ("X-Request-Id", "abc"),
+            ("Content-Type", "application/json"),
+            ("Authorization", "secret"),
+        ];
+        assert_eq!(
+            header_tags.request_tags(headers),
+            vec![
+                (String::from("request.id"), String::from("abc")),
+                (
+                    String::from("http.request.headers.content-type"),
+                    String::from("application/json")
+                ),
+            ]
+        );
+        assert_eq!(
+            header_tags.response_tags([("content-type", "text/plain")]),
+            vec![(
+                String::from("http.response.headers.content-type"),
+                String::from("text/plain")
+            )]
+        );
+    }
+}
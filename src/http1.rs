@@ -0,0 +1,195 @@
+// Minimal HTTP/1.1 support for transports which can't use reqwest. The Datadog agent only needs
+// a single request per connection, so requests are sent with `Connection: close` and the whole
+// response is read until the agent closes the connection.
+
+#[cfg(any(windows, test))]
+use std::io::{self, Read, Write};
+#[cfg(any(windows, test))]
+use std::time::Duration;
+
+#[inline]
+pub(crate) fn put_request(
+    host: &str,
+    path: &str,
+    content_type: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Vec<u8> {
+    let mut request = format!(
+        "PUT {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        path,
+        host,
+        content_type,
+        body.len()
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    let mut request = request.into_bytes();
+    request.extend_from_slice(body);
+    request
+}
+
+#[inline]
+pub(crate) fn response_status(response: &[u8]) -> Option<u16> {
+    let status_line = response.split(|b| *b == b'\n').next()?;
+    let status_line = std::str::from_utf8(status_line).ok()?;
+    let mut parts = status_line.split_whitespace();
+    if !parts.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+// The body of a response, which is left empty if the response has no blank line after its headers
+#[cfg(any(windows, test))]
+#[inline]
+pub(crate) fn response_body(response: &[u8]) -> &[u8] {
+    response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map_or(&[], |headers_end| &response[headers_end + 4..])
+}
+
+// Sends a request over a blocking stream, like the named pipe of the agent on Windows, and reads
+// the whole response
+#[cfg(any(windows, test))]
+pub(crate) fn exchange(mut stream: impl Read + Write, request: &[u8]) -> io::Result<Vec<u8>> {
+    stream.write_all(request)?;
+    stream.flush()?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    Ok(response)
+}
+
+// Like `exchange`, but gives up after `timeout`. Blocking reads and writes can't be interrupted,
+// so the exchange runs on a thread of its own, which is left to finish it in the background.
+#[cfg(any(windows, test))]
+pub(crate) fn exchange_with_timeout(
+    stream: impl Read + Write + Send + 'static,
+    request: Vec<u8>,
+    timeout: Duration,
+) -> io::Result<Vec<u8>> {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::Builder::new()
+        .name(String::from("datadog-agent-exchange"))
+        .spawn(move || {
+            sender.send(exchange(stream, &request)).ok();
+        })?;
+    receiver.recv_timeout(timeout).unwrap_or_else(|_| {
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "timed out waiting for the response",
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_put_request() {
+        let request = put_request(
+            "localhost:8126",
+            "/v0.3/traces",
+            "application/json",
+            &[("X-Datadog-Trace-Count", "0")],
+            b"[]",
+        );
+        assert_eq!(
+            String::from_utf8(request).unwrap(),
+            "PUT /v0.3/traces HTTP/1.1\r\nHost: localhost:8126\r\nContent-Type: application/json\r\nContent-Length: 2\r\nConnection: close\r\nX-Datadog-Trace-Count: 0\r\n\r\n[]"
+        );
+    }
+
+    #[test]
+    fn test_response_status() {
+        assert_eq!(
+            response_status(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK"),
+            Some(200)
+        );
+        assert_eq!(
+            response_status(b"HTTP/1.0 413 Payload Too Large\r\n"),
+            Some(413)
+        );
+        assert_eq!(response_status(b"garbage"), None);
+        assert_eq!(response_status(b""), None);
+    }
+
+    // A stream whose peer answers with a canned response
+    struct CannedStream {
+        written: Vec<u8>,
+        response: io::Cursor<Vec<u8>>,
+    }
+
+    impl Read for CannedStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.response.read(buf)
+        }
+    }
+
+    impl Write for CannedStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_response_body() {
+        assert_eq!(
+            response_body(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK"),
+            b"OK"
+        );
+        assert_eq!(response_body(b"HTTP/1.1 200 OK\r\n"), b"");
+    }
+
+    #[test]
+    fn test_exchange() {
+        let mut stream = CannedStream {
+            written: Vec::new(),
+            response: io::Cursor::new(b"HTTP/1.1 200 OK\r\n\r\n".to_vec()),
+        };
+
+        let response = exchange(&mut stream, b"PUT / HTTP/1.1\r\n\r\n").unwrap();
+
+        assert_eq!(stream.written, b"PUT / HTTP/1.1\r\n\r\n");
+        assert_eq!(response_status(&response), Some(200));
+    }
+
+    // A stream whose peer never answers
+    struct SilentStream;
+
+    impl Read for SilentStream {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            std::thread::sleep(Duration::from_secs(5));
+            Ok(0)
+        }
+    }
+
+    impl Write for SilentStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_exchange_with_timeout() {
+        let response = exchange_with_timeout(
+            SilentStream,
+            b"PUT / HTTP/1.1\r\n\r\n".to_vec(),
+            Duration::from_millis(50),
+        );
+
+        assert_eq!(response.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,82 @@
+use std::env;
+use std::ops::RangeInclusive;
+#[cfg(feature = "http")]
+use std::sync::OnceLock;
+
+/// Decides which HTTP status codes mark a span as an error, as configured by the standard
+/// `DD_TRACE_HTTP_SERVER_ERROR_STATUSES` env var of Datadog tracers: a comma-separated list of
+/// status codes and ranges, e.g. `500-599,429`. By default, only `5xx` statuses are errors.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpStatusClassifier {
+    error_statuses: Vec<RangeInclusive<u16>>,
+}
+
+impl HttpStatusClassifier {
+    pub fn from_env() -> Self {
+        env::var("DD_TRACE_HTTP_SERVER_ERROR_STATUSES")
+            .ok()
+            .map(|value| Self::parse(&value))
+            .filter(|classifier| !classifier.error_statuses.is_empty())
+            .unwrap_or_default()
+    }
+
+    /// Parses a comma-separated list of status codes and ranges. Invalid entries are ignored.
+    pub fn parse(value: &str) -> Self {
+        let error_statuses = value
+            .split(',')
+            .filter_map(|entry| {
+                let (start, end) = entry.split_once('-').unwrap_or((entry, entry));
+                let start = start.trim().parse().ok()?;
+                let end = end.trim().parse().ok()?;
+                Some(start..=end).filter(|range: &RangeInclusive<u16>| !range.is_empty())
+            })
+            .collect();
+        Self { error_statuses }
+    }
+
+    #[inline]
+    pub fn is_error(&self, status: u16) -> bool {
+        self.error_statuses
+            .iter()
+            .any(|range| range.contains(&status))
+    }
+
+    // Read once from the env, for the typed helpers of `SpanBuilder`
+    #[cfg(feature = "http")]
+    #[inline]
+    pub(crate) fn global() -> &'static Self {
+        static GLOBAL: OnceLock<HttpStatusClassifier> = OnceLock::new();
+        GLOBAL.get_or_init(Self::from_env)
+    }
+}
+
+impl Default for HttpStatusClassifier {
+    fn default() -> Self {
+        Self {
+            error_statuses: vec![500..=599],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_classifier() {
+        let classifier = HttpStatusClassifier::default();
+        assert!(classifier.is_error(500));
+        assert!(classifier.is_error(599));
+        assert!(!classifier.is_error(404));
+        assert!(!classifier.is_error(200));
+    }
+
+    #[test]
+    fn test_parse() {
+        let classifier = HttpStatusClassifier::parse("500-503, 429,foo,600-500");
+        assert!(classifier.is_error(429));
+        assert!(classifier.is_error(502));
+        assert!(!classifier.is_error(504));
+        assert!(!classifier.is_error(550));
+    }
+}
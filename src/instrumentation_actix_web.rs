@@ -1,18 +1,198 @@
+use super::appsec::AppSecTags;
+use super::datadog_client::{SpanMetaKey, SpanName, SpanType};
+use super::header_tags::HeaderTags;
+use super::http_status::HttpStatusClassifier;
+use super::integrations::IntegrationMapping;
+use super::propagation::{self, Extractor, SpanContext};
+use super::sampling::SamplingPriority;
+use super::subscriber::{set_tag, with_span_builder, DatadogSpanExt, TracingSubscriberDatadog};
 use actix_web::body::MessageBody;
-use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
-use actix_web::Error;
+use actix_web::dev::Extensions;
+use actix_web::dev::{Payload, ResponseHead, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::HeaderMap;
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest, HttpResponse};
 use futures::future::{ok, Ready};
+use std::any::Any;
+use std::cell::Cell;
+use std::convert::Infallible;
 use std::fmt::{Debug, Formatter};
 use std::future::Future;
+use std::num::NonZeroU64;
 use std::pin::Pin;
-use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
-use tracing::instrument;
+use tracing::field::Empty;
+use tracing::Instrument;
 
 type PinnedBoxedFuture<T> = Pin<Box<dyn Future<Output = T>>>;
 
-#[derive(Debug)]
-pub struct ActixDatadogTracer;
+type TenantExtractor = Arc<dyn Fn(&ServiceRequest) -> Option<String> + Send + Sync>;
+
+/// Extension point for application security libraries (Datadog ASM), to attach `_dd.appsec.*`
+/// tags to request spans and keep the traces of requests with a security event, so these
+/// events ride on the tracing pipeline.
+pub trait AppSecHook: Send + Sync {
+    /// Inspects a request before it is handled.
+    fn on_request(&self, _request: &ServiceRequest, _tags: &mut AppSecTags) {}
+
+    /// Inspects the response to a request. Not called for requests failing with an `Error`.
+    fn on_response(
+        &self,
+        _request: &HttpRequest,
+        _response: &ResponseHead,
+        _tags: &mut AppSecTags,
+    ) {
+    }
+}
+
+const DEFAULT_SPAN_NAME: &str = "request";
+const DEFAULT_REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Maps the request spans of the default `ActixDatadogTracer` to the service of the process.
+pub const INTEGRATION_MAPPINGS: &[IntegrationMapping] = &[IntegrationMapping {
+    target: module_path!(),
+    span_name: SpanName(DEFAULT_SPAN_NAME),
+    service_suffix: None,
+    span_type: SpanType::Web,
+}];
+
+/// Middleware which traces every request. By default it records the resource, HTTP method,
+/// URL, status code and errors of each request in a span named `request`. It can additionally
+/// tag each request with the tenant it belongs to, which is inherited by every child span of
+/// the request. Headers are tagged as configured by the `DD_TRACE_HEADER_TAGS` env var, and the
+/// `x-request-id` header as `http.request_id`. An `AppSecHook` can add security tags.
+#[derive(Clone)]
+pub struct ActixDatadogTracer {
+    span_name: &'static str,
+    tenant_extractor: Option<TenantExtractor>,
+    // read from `DD_TRACE_HEADER_TAGS` when the middleware is created, unless set
+    header_tags: Option<HeaderTags>,
+    // read from `DD_TRACE_HTTP_SERVER_ERROR_STATUSES` when the middleware is created, unless set
+    status_classifier: Option<HttpStatusClassifier>,
+    excluded_paths: Vec<String>,
+    request_id_header: &'static str,
+    appsec_hook: Option<Arc<dyn AppSecHook>>,
+    earliest_start: bool,
+}
+
+/// The default tracer, so that the middleware can still be added as `.wrap(ActixDatadogTracer)`,
+/// like before it was configurable.
+#[allow(non_upper_case_globals)]
+pub const ActixDatadogTracer: ActixDatadogTracer = ActixDatadogTracer::DEFAULT;
+
+impl ActixDatadogTracer {
+    /// The default tracer, whose request spans are named `request`.
+    pub const DEFAULT: Self = Self::new(DEFAULT_SPAN_NAME);
+
+    /// Creates a tracer whose request spans are named `span_name` instead of `request`, so that
+    /// multiple apps or scopes in a process can be mapped to different services and span types.
+    pub const fn new(span_name: &'static str) -> Self {
+        Self {
+            span_name,
+            tenant_extractor: None,
+            header_tags: None,
+            status_classifier: None,
+            excluded_paths: Vec::new(),
+            request_id_header: DEFAULT_REQUEST_ID_HEADER,
+            appsec_hook: None,
+            earliest_start: false,
+        }
+    }
+
+    /// Tags each request with the host it was sent to (without the port) as its tenant, for
+    /// apps serving multiple products distinguished by the Host header.
+    pub fn tenant_from_host(self) -> Self {
+        self.tenant(|req| {
+            Some(strip_port(req.connection_info().host()))
+                .filter(|host| !host.is_empty())
+                .map(String::from)
+        })
+    }
+
+    /// Tags each request with the tenant returned by the closure, if any.
+    pub fn tenant(
+        mut self,
+        extractor: impl Fn(&ServiceRequest) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.tenant_extractor = Some(Arc::new(extractor));
+        self
+    }
+
+    /// Overrides the headers to tag, which are read from `DD_TRACE_HEADER_TAGS` by default.
+    pub fn header_tags(mut self, header_tags: HeaderTags) -> Self {
+        self.header_tags = Some(header_tags);
+        self
+    }
+
+    /// Overrides the statuses which mark request spans as errors, which are read from
+    /// `DD_TRACE_HTTP_SERVER_ERROR_STATUSES` by default.
+    pub fn http_status_classifier(mut self, classifier: HttpStatusClassifier) -> Self {
+        self.status_classifier = Some(classifier);
+        self
+    }
+
+    /// Doesn't trace requests whose path matches `pattern`, like actix's `Logger::exclude`.
+    /// A `*` in the pattern matches any sequence of characters, so `/static/*` excludes every
+    /// path under `/static/`. Excluded requests are checked before any span is created.
+    pub fn exclude(mut self, pattern: impl Into<String>) -> Self {
+        self.excluded_paths.push(pattern.into());
+        self
+    }
+
+    /// Overrides the header whose value is tagged as `http.request_id`, `x-request-id` by
+    /// default. The request id of the current request is returned by
+    /// `subscriber::current_request_id()`.
+    pub fn request_id_header(mut self, header: &'static str) -> Self {
+        self.request_id_header = header;
+        self
+    }
+
+    /// Lets `hook` tag the request spans with application security tags. Tags added on request
+    /// are applied before the request is handled, so a trace kept by the hook keeps the child
+    /// spans of the request too.
+    pub fn appsec(mut self, hook: impl AppSecHook + 'static) -> Self {
+        self.appsec_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Starts request spans at the earliest known time instead of when the request reaches this
+    /// middleware, to include the time spent in earlier middleware and reading the request: when
+    /// the connection was accepted for the first request of a connection, if recorded with
+    /// `on_connect`, or else when the request was received, if recorded by the `RequestReceived`
+    /// middleware.
+    pub fn earliest_start(mut self, enabled: bool) -> Self {
+        self.earliest_start = enabled;
+        self
+    }
+
+    #[inline]
+    fn is_excluded(&self, path: &str) -> bool {
+        self.excluded_paths
+            .iter()
+            .any(|pattern| glob_matches(pattern, path))
+    }
+}
+
+impl Default for ActixDatadogTracer {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl Debug for ActixDatadogTracer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ActixDatadogTracer")
+            .field("span_name", &self.span_name)
+            .field("tenant_extractor", &self.tenant_extractor.is_some())
+            .field("header_tags", &self.header_tags)
+            .field("status_classifier", &self.status_classifier)
+            .field("excluded_paths", &self.excluded_paths)
+            .field("request_id_header", &self.request_id_header)
+            .field("appsec_hook", &self.appsec_hook.is_some())
+            .field("earliest_start", &self.earliest_start)
+            .finish()
+    }
+}
 
 impl<S, B> Transform<S, ServiceRequest> for ActixDatadogTracer
 where
@@ -27,12 +207,26 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ok(ActixDatadogTracerMiddleware { service })
+        ok(ActixDatadogTracerMiddleware {
+            service,
+            tracer: self.clone(),
+            header_tags: self
+                .header_tags
+                .clone()
+                .unwrap_or_else(HeaderTags::from_env),
+            status_classifier: self
+                .status_classifier
+                .clone()
+                .unwrap_or_else(|| HttpStatusClassifier::global().clone()),
+        })
     }
 }
 
 pub struct ActixDatadogTracerMiddleware<S> {
     service: S,
+    tracer: ActixDatadogTracer,
+    header_tags: HeaderTags,
+    status_classifier: HttpStatusClassifier,
 }
 
 impl<S> Debug for ActixDatadogTracerMiddleware<S> {
@@ -53,129 +247,454 @@ where
 
     actix_service::forward_ready!(service);
 
-    #[instrument(
-        name = "request",
-        skip_all,
-        fields(
-            trace_id,
-            parent_id,
-            resource,
-            start,
-            http_method,
-            http_url,
-            http_status_code,
-            error_type,
-            error_msg,
-            error_stack
-        )
-    )]
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let recordable_data = extract_recordable_data(&req);
+        if self.tracer.is_excluded(req.path()) {
+            return Box::pin(self.service.call(req));
+        }
 
-        let fut = self.service.call(req);
+        // the callsite name is static, so the configured name is passed in the `span_name`
+        // field, which the subscriber uses to look up the span mapping
+        let span = tracing::info_span!(
+            "request",
+            span_name = self.tracer.span_name,
+            trace_id = Empty,
+            parent_id = Empty,
+            resource = Empty,
+            start = Empty,
+            http_method = Empty,
+            http_url = Empty,
+            http_status_code = Empty,
+            error_type = Empty,
+            error_msg = Empty,
+            error_stack = Empty,
+            tenant = Empty,
+            sampling_priority = Empty,
+        );
+        let recordable_data = extract_recordable_data(&req, &self.tracer);
+        let header_tags = self.header_tags.clone();
+        let status_classifier = self.status_classifier.clone();
+        for (tag, value) in header_tags.request_tags(header_pairs(req.headers())) {
+            set_tag(&span, tag, value);
+        }
+        if let Some(request_id) = req
+            .headers()
+            .get(self.tracer.request_id_header)
+            .and_then(|value| value.to_str().ok())
+        {
+            set_tag(&span, SpanMetaKey::HttpRequestId.to_string(), request_id);
+        }
 
-        Box::pin(async move {
-            let current_span = tracing::Span::current();
-            current_span.record(
-                "resource",
-                &*format!("{} {}", recordable_data.method, recordable_data.resource),
-            );
-            current_span.record("http.method", &*recordable_data.method);
-            current_span.record("http.url", &*recordable_data.url);
+        let late_resource = LateResource::default();
+        req.extensions_mut().insert(RequestSpan {
+            span: span.clone(),
+            late_resource: late_resource.clone(),
+        });
 
-            if let Some(start) = recordable_data.maybe_start {
-                current_span.record("start", start);
-            }
-            if let Some(trace_id) = recordable_data.maybe_trace_id {
-                current_span.record("trace_id", trace_id);
-            }
-            if let Some(parent_id) = recordable_data.maybe_parent_id {
-                current_span.record("parent_id", parent_id);
-            }
-
-            let res = fut.await?;
+        let appsec_hook = self.tracer.appsec_hook.clone();
+        let mut request_appsec_tags = AppSecTags::new();
+        if let Some(hook) = &appsec_hook {
+            hook.on_request(&req, &mut request_appsec_tags);
+        }
 
-            let current_span = tracing::Span::current();
-            current_span.record("http.status_code", res.status().as_str());
+        let fut = span.in_scope(|| self.service.call(req));
 
-            if res.status().is_server_error() {
+        Box::pin(
+            async move {
+                let current_span = tracing::Span::current();
                 current_span.record(
-                    "error.msg",
-                    &*format!(
-                        "Request has failed with HTTP error: {}",
-                        res.status().as_str()
-                    ),
+                    "resource",
+                    &*format!("{} {}", recordable_data.method, recordable_data.resource),
                 );
-                if let Some(err) = res.response().error() {
-                    current_span.record("error.type", &*format!("{:?}", err));
-                    current_span.record("error.stack", &*format!("{:?}", err));
-                } else {
-                    current_span.record("error.type", "Server side error");
+                current_span.record("http_method", &*recordable_data.method);
+                current_span.record("http_url", &*recordable_data.url);
+
+                if let Some(start) = recordable_data.maybe_start {
+                    current_span.record("start", start);
+                }
+                if let Some(context) = &recordable_data.maybe_context {
+                    current_span.record("trace_id", context.trace_id.get());
+                    current_span.record("parent_id", context.span_id.get());
+                    propagation::tag_remote_parent(&current_span, context);
+                }
+                if let Some(tenant) = &recordable_data.maybe_tenant {
+                    current_span.record("tenant", &**tenant);
+                }
+                if let Some(sampling_priority) = recordable_data.maybe_sampling_priority {
+                    current_span.record("sampling_priority", sampling_priority.as_i32());
+                }
+                // after the propagated sampling priority, which a kept trace overrides
+                apply_appsec_tags(&current_span, &request_appsec_tags);
+
+                let res = fut.await;
+                let current_span = tracing::Span::current();
+                if let Some(resource) = late_resource.get() {
+                    current_span.set_dd_resource(resource);
+                }
+                let res = res?;
+
+                let status = res.status();
+                with_span_builder(&current_span, |span_builder| {
+                    span_builder.http_status_with(status, &status_classifier);
+                });
+                if let Some(hook) = &appsec_hook {
+                    let mut response_appsec_tags = AppSecTags::new();
+                    hook.on_response(
+                        res.request(),
+                        res.response().head(),
+                        &mut response_appsec_tags,
+                    );
+                    apply_appsec_tags(&current_span, &response_appsec_tags);
+                }
+                for (tag, value) in header_tags.response_tags(header_pairs(res.headers())) {
+                    set_tag(&current_span, tag, value);
+                }
+
+                if status_classifier.is_error(status.as_u16()) {
+                    current_span.record(
+                        "error_msg",
+                        &*format!("Request has failed with HTTP error: {}", status.as_str()),
+                    );
+                    if let Some(err) = res.response().error() {
+                        current_span.record("error_type", &*format!("{:?}", err));
+                        current_span.record("error_stack", &*format!("{:?}", err));
+                    } else {
+                        current_span.record("error_type", "Server side error");
+                    }
                 }
+
+                Ok(res)
             }
+            .instrument(span),
+        )
+    }
+}
 
-            Ok(res)
-        })
+// The span of the request, in the extensions of the request, for `DdContext`
+struct RequestSpan {
+    span: tracing::Span,
+    late_resource: LateResource,
+}
+
+// A resource set with `DdContext::set_resource_late`, which the middleware applies again once
+// the request is handled, so it wins over the resource recorded by the middleware
+#[derive(Clone, Debug, Default)]
+struct LateResource(Arc<Mutex<Option<String>>>);
+
+impl LateResource {
+    #[inline]
+    fn set(&self, resource: &str) {
+        match self.0.lock() {
+            Ok(mut late_resource) => *late_resource = Some(String::from(resource)),
+            Err(e) => log::error!("Failed to get lock on late resource; err {:?}", e),
+        }
+    }
+
+    #[inline]
+    fn get(&self) -> Option<String> {
+        self.0.lock().ok()?.clone()
     }
 }
 
+/// The Datadog context of the request span, taken by handlers as an argument to read its ids or
+/// tag it:
+///
+/// ```ignore
+/// async fn get_order(dd: DdContext, id: web::Path<u64>) -> HttpResponse {
+///     dd.set_tag("order.id", id.to_string());
+///     HttpResponse::Ok().json(order(id, dd.trace_id()))
+/// }
+/// ```
+///
+/// Without the middleware, it is the context of the current span. The ids are `None` if the span
+/// is not traced by a `TracingSubscriberDatadog`.
+#[derive(Clone, Debug)]
+pub struct DdContext {
+    span: tracing::Span,
+    // `None` without the middleware
+    late_resource: Option<LateResource>,
+}
+
+impl DdContext {
+    /// The id of the Datadog trace of the request.
+    pub fn trace_id(&self) -> Option<NonZeroU64> {
+        self.span.dd_trace_id()
+    }
+
+    /// The Datadog id of the request span.
+    pub fn span_id(&self) -> Option<NonZeroU64> {
+        self.span.dd_span_id()
+    }
+
+    /// The propagated part of the request span, e.g. to continue its trace in a message.
+    pub fn span_context(&self) -> Option<SpanContext> {
+        super::context::context_of(&self.span)
+    }
+
+    /// Tags the request span.
+    pub fn set_tag(&self, key: impl Into<String>, value: impl Into<String>) {
+        self.span.set_dd_tag(key, value);
+    }
+
+    /// Renames the resource of the request span, e.g. to the GraphQL operation parsed from the
+    /// body once the route matched. It can be called any time before the span closes, and wins
+    /// over the resource the middleware records from the match pattern.
+    pub fn set_resource_late(&self, resource: &str) {
+        self.span.set_dd_resource(resource);
+        if let Some(late_resource) = &self.late_resource {
+            late_resource.set(resource);
+        }
+    }
+
+    /// The request span itself.
+    pub fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+}
+
+impl FromRequest for DdContext {
+    type Error = Infallible;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let context = match req.extensions().get::<RequestSpan>() {
+            Some(request_span) => Self {
+                span: request_span.span.clone(),
+                late_resource: Some(request_span.late_resource.clone()),
+            },
+            None => Self {
+                span: tracing::Span::current(),
+                late_resource: None,
+            },
+        };
+        ok(context)
+    }
+}
+
+/// When a request was received, in the extensions of the request. It is inserted by the
+/// `RequestReceived` middleware, or by any earlier layer which knows better.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RequestReceivedAt(pub SystemTime);
+
+/// Middleware recording when each request is received as `RequestReceivedAt`, for
+/// `ActixDatadogTracer::earliest_start`. Middleware registered last runs first, so wrap the app
+/// with it after every other middleware.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RequestReceived;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestReceived
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestReceivedMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestReceivedMiddleware { service })
+    }
+}
+
+pub struct RequestReceivedMiddleware<S> {
+    service: S,
+}
+
+impl<S> Debug for RequestReceivedMiddleware<S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RequestReceivedMiddleware")
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for RequestReceivedMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = S::Future;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        req.extensions_mut()
+            .insert(RequestReceivedAt(SystemTime::now()));
+        self.service.call(req)
+    }
+}
+
+// Taken by the first request of the connection, as later ones weren't waiting since the accept
+struct ConnectionAcceptedAt(Cell<Option<SystemTime>>);
+
+/// Records when connections are accepted, for `ActixDatadogTracer::earliest_start`:
+///
+/// ```ignore
+/// HttpServer::new(app).on_connect(instrumentation_actix_web::on_connect)
+/// ```
+pub fn on_connect(_connection: &dyn Any, data: &mut Extensions) {
+    data.insert(ConnectionAcceptedAt(Cell::new(Some(SystemTime::now()))));
+}
+
+// The earliest time recorded for the request, if any
+#[inline]
+fn earliest_start(req: &ServiceRequest) -> Option<SystemTime> {
+    let maybe_accepted = req
+        .conn_data::<ConnectionAcceptedAt>()
+        .and_then(|accepted| accepted.0.take());
+    let maybe_received = req
+        .extensions()
+        .get::<RequestReceivedAt>()
+        .map(|received| received.0);
+    maybe_accepted.into_iter().chain(maybe_received).min()
+}
+
+/// Debug endpoint listing the spans currently open in the global `TracingSubscriberDatadog`,
+/// oldest first, to diagnose spans which never close. It responds with `404` if the global
+/// subscriber is not a `TracingSubscriberDatadog`. It is not registered by the middleware, so
+/// it has to be mounted explicitly, ideally on an internal-only route:
+///
+/// ```ignore
+/// App::new().route("/debug/open-spans", web::get().to(open_spans))
+/// ```
+pub async fn open_spans() -> HttpResponse {
+    let maybe_open_spans = tracing::dispatcher::get_default(|dispatch| {
+        dispatch
+            .downcast_ref::<TracingSubscriberDatadog>()
+            .map(TracingSubscriberDatadog::snapshot)
+    });
+    match maybe_open_spans {
+        Some(open_spans) => HttpResponse::Ok().json(open_spans),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[inline]
+fn apply_appsec_tags(span: &tracing::Span, tags: &AppSecTags) {
+    if !tags.is_empty() {
+        with_span_builder(span, |span_builder| tags.apply(span_builder));
+    }
+}
+
+// Matches `path` against a pattern in which `*` stands for any sequence of characters
+#[inline]
+fn glob_matches(pattern: &str, path: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = path.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[inline]
+fn header_pairs(headers: &HeaderMap) -> impl Iterator<Item = (&str, &str)> {
+    headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)))
+}
+
 struct RecordableData {
     maybe_start: Option<u64>,
     resource: String,
     method: String,
     url: String,
-    maybe_trace_id: Option<u64>,
-    maybe_parent_id: Option<u64>,
+    maybe_context: Option<SpanContext>,
+    maybe_tenant: Option<String>,
+    maybe_sampling_priority: Option<SamplingPriority>,
 }
 
 #[inline]
-fn extract_recordable_data(req: &ServiceRequest) -> RecordableData {
-    let (maybe_trace_id, maybe_parent_id) = extract_trace_and_parent(req);
+fn extract_recordable_data(req: &ServiceRequest, tracer: &ActixDatadogTracer) -> RecordableData {
+    let maybe_context = propagation::extract(req.headers());
+    // a b3 header may carry only the sampling state, without a context
+    let maybe_sampling_priority = match &maybe_context {
+        Some(context) => context.sampling_priority,
+        None => req
+            .headers()
+            .get("b3")
+            .and_then(|value| value.to_str().ok())
+            .and_then(SamplingPriority::from_b3_sampling_state),
+    };
+    let maybe_earliest_start = tracer.earliest_start.then(|| earliest_start(req)).flatten();
     RecordableData {
-        maybe_start: SystemTime::now()
+        maybe_start: maybe_earliest_start
+            .unwrap_or_else(SystemTime::now)
             .duration_since(UNIX_EPOCH)
             .ok()
             .map(|duration| duration.as_nanos() as u64),
         resource: req.match_pattern().unwrap_or_else(|| String::from("404")),
         method: req.method().to_string(),
         url: req.uri().to_string(),
-        maybe_trace_id,
-        maybe_parent_id,
+        maybe_context,
+        maybe_tenant: tracer
+            .tenant_extractor
+            .as_ref()
+            .and_then(|extractor| extractor(req)),
+        maybe_sampling_priority,
     }
 }
 
+// The host of a Host header without its port, keeping the brackets of IPv6 addresses
 #[inline]
-fn extract_trace_and_parent(req: &ServiceRequest) -> (Option<u64>, Option<u64>) {
-    let mut maybe_trace_id = None;
-    let mut maybe_parent_id = None;
-    req.headers().iter().for_each(|(key, value)| {
-        match &*key.as_str().trim().to_lowercase() {
-            // Datadog headers
-            "x-datadog-trace-id" => value.to_str().map(|s| maybe_trace_id = Some(s)),
-            "x-datadog-parent-id" => value.to_str().map(|s| maybe_parent_id = Some(s)),
-
-            // Zipkin B3 headers
-            "x-b3-traceid" => value.to_str().map(|s| maybe_trace_id = Some(s)),
-            "x-b3-spanid" => value.to_str().map(|s| maybe_parent_id = Some(s)),
-
-            // B3 single header
-            "b3" => {
-                // b3: {TraceId}-{SpanId}-{SamplingState}-{ParentSpanId}
-                value.to_str().map(|s| {
-                    let parts: Vec<&str> = s.split('-').collect();
-                    if parts.len() >= 2 {
-                        maybe_trace_id = Some(parts[0]);
-                        maybe_parent_id = Some(parts[1]);
-                    }
-                })
-            }
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return host
+            .split_once(']')
+            .map_or(host, |(address, _)| &host[..=address.len()]);
+    }
+    host.rsplit_once(':').map_or(host, |(host, _)| host)
+}
 
-            _ => Ok(()),
-        }
-        .ok();
-    });
-    (
-        maybe_trace_id.and_then(|s| u64::from_str(s).ok()),
-        maybe_parent_id.and_then(|s| u64::from_str(s).ok()),
-    )
+impl Extractor for HeaderMap {
+    #[inline]
+    fn get(&self, key: &str) -> Option<&str> {
+        HeaderMap::get(self, key).and_then(|value| value.to_str().ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("/metrics", "/metrics"));
+        assert!(!glob_matches("/metrics", "/metrics/foo"));
+        assert!(!glob_matches("/metrics", "/metric"));
+        assert!(glob_matches("/static/*", "/static/css/app.css"));
+        assert!(glob_matches("/static/*", "/static/"));
+        assert!(!glob_matches("/static/*", "/api/static/app.css"));
+        assert!(glob_matches("/*/health", "/v1/health"));
+        assert!(!glob_matches("/*/health", "/v1/healthz"));
+        assert!(glob_matches("*.js", "/assets/app.js"));
+        assert!(glob_matches("/a*b*c", "/abbc"));
+        assert!(!glob_matches("/a*b*c", "/ac"));
+    }
+
+    #[test]
+    fn test_strip_port() {
+        assert_eq!(strip_port("shop.example.com:8080"), "shop.example.com");
+        assert_eq!(strip_port("shop.example.com"), "shop.example.com");
+        assert_eq!(strip_port("127.0.0.1:8080"), "127.0.0.1");
+        assert_eq!(strip_port("[::1]:8080"), "[::1]");
+        assert_eq!(strip_port("[2001:db8::1]"), "[2001:db8::1]");
+    }
+
+    #[test]
+    fn test_unit_form_is_the_default_tracer() {
+        let tracer: ActixDatadogTracer = ActixDatadogTracer;
+        assert_eq!(tracer.span_name, DEFAULT_SPAN_NAME);
+        assert!(tracer.header_tags.is_none());
+        assert!(tracer.tenant_extractor.is_none());
+    }
 }
@@ -0,0 +1,176 @@
+use super::datadog_client::{SpanName, SpanType};
+use super::integrations::IntegrationMapping;
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::service::Service;
+use hyper::Uri;
+use hyper_tls::MaybeHttpsStream;
+use std::error::Error;
+use std::fmt::{Debug, Formatter};
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use tokio::net::TcpStream;
+use tracing::field::Empty;
+use tracing::{Instrument, Span};
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+type PinnedBoxedFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Name of the spans timing DNS resolutions
+pub const DNS_SPAN_NAME: &str = "http.client.dns";
+
+/// Name of the spans timing TCP connects
+pub const CONNECT_SPAN_NAME: &str = "http.client.connect";
+
+/// Name of the spans timing TLS handshakes
+pub const TLS_SPAN_NAME: &str = "http.client.tls";
+
+/// Maps the phase spans to the `<service>-http-client` service.
+pub const INTEGRATION_MAPPINGS: &[IntegrationMapping] = &[
+    http_client_mapping(DNS_SPAN_NAME),
+    http_client_mapping(CONNECT_SPAN_NAME),
+    http_client_mapping(TLS_SPAN_NAME),
+];
+
+const fn http_client_mapping(span_name: &'static str) -> IntegrationMapping {
+    IntegrationMapping {
+        target: module_path!(),
+        span_name: SpanName(span_name),
+        service_suffix: Some("http-client"),
+        span_type: SpanType::Web,
+    }
+}
+
+/// Hyper connector which traces each phase of opening a connection, for debugging the latency
+/// of outbound requests. The DNS resolution, TCP connect and TLS handshake of every new
+/// connection are timed in `http.client.dns`, `http.client.connect` and `http.client.tls` child
+/// spans of the span which sends the request, mapped by `INTEGRATION_MAPPINGS` by default.
+/// Reused connections have no such spans.
+///
+/// ```ignore
+/// let client = hyper::Client::builder().build::<_, hyper::Body>(TracedConnector::new()?);
+/// ```
+#[derive(Clone)]
+pub struct TracedConnector {
+    resolver: GaiResolver,
+    tls: tokio_native_tls::TlsConnector,
+}
+
+impl TracedConnector {
+    /// Creates a connector with the default TLS settings of the platform
+    pub fn new() -> Result<Self, native_tls::Error> {
+        Ok(Self::with_tls(native_tls::TlsConnector::new()?))
+    }
+
+    /// Creates a connector doing TLS handshakes with `tls`
+    pub fn with_tls(tls: native_tls::TlsConnector) -> Self {
+        Self {
+            resolver: GaiResolver::new(),
+            tls: tls.into(),
+        }
+    }
+}
+
+impl Debug for TracedConnector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TracedConnector").finish_non_exhaustive()
+    }
+}
+
+impl Service<Uri> for TracedConnector {
+    type Response = MaybeHttpsStream<TcpStream>;
+    type Error = BoxError;
+    type Future = PinnedBoxedFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let resolver = self.resolver.clone();
+        let tls = self.tls.clone();
+        // hyper may poll the connection future outside of the span sending the request, so it
+        // runs in the span which is current now, making it the parent of the phase spans
+        let parent = Span::current();
+        Box::pin(connect(resolver, tls, uri).instrument(parent))
+    }
+}
+
+async fn connect(
+    mut resolver: GaiResolver,
+    tls: tokio_native_tls::TlsConnector,
+    uri: Uri,
+) -> Result<MaybeHttpsStream<TcpStream>, BoxError> {
+    let is_https = uri.scheme_str() == Some("https");
+    let host = uri
+        .host()
+        .ok_or_else(|| format!("Missing host in {}", uri))?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = uri.port_u16().unwrap_or(if is_https { 443 } else { 80 });
+
+    let addrs: Vec<SocketAddr> = match IpAddr::from_str(host) {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => {
+            let span = phase_span(DNS_SPAN_NAME, host);
+            let resolved = resolver
+                .call(Name::from_str(host)?)
+                .instrument(span.clone())
+                .await;
+            let addrs = record_result(&span, resolved)?;
+            addrs.map(|addr| SocketAddr::new(addr.ip(), port)).collect()
+        }
+    };
+
+    let tcp = {
+        let span = phase_span(CONNECT_SPAN_NAME, &format!("{}:{}", host, port));
+        let connected = connect_any(&addrs).instrument(span.clone()).await;
+        record_result(&span, connected)?
+    };
+    tcp.set_nodelay(true)?;
+    if !is_https {
+        return Ok(MaybeHttpsStream::Http(tcp));
+    }
+
+    let span = phase_span(TLS_SPAN_NAME, host);
+    let handshake = tls.connect(host, tcp).instrument(span.clone()).await;
+    Ok(MaybeHttpsStream::Https(record_result(&span, handshake)?))
+}
+
+// Tries the addresses in order, like hyper's `HttpConnector` without happy eyeballs
+async fn connect_any(addrs: &[SocketAddr]) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "No address resolved");
+    for addr in addrs {
+        match TcpStream::connect(addr).await {
+            Ok(tcp) => return Ok(tcp),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+#[inline]
+fn phase_span(span_name: &'static str, resource: &str) -> Span {
+    // the callsite name is static, so the phase is passed in the `span_name` field, which the
+    // subscriber uses to look up the span mapping
+    tracing::info_span!(
+        "http.client",
+        span_name = span_name,
+        resource = resource,
+        error_type = Empty,
+        error_msg = Empty,
+    )
+}
+
+#[inline]
+fn record_result<T, E: Error>(span: &Span, result: Result<T, E>) -> Result<T, E> {
+    if let Err(e) = &result {
+        span.record("error_type", std::any::type_name::<E>());
+        span.record("error_msg", e.to_string());
+    }
+    result
+}
@@ -0,0 +1,172 @@
+use super::context::context_of;
+use super::datadog_client::{SpanName, SpanType};
+use super::integrations::IntegrationMapping;
+use super::propagation::{self, Extractor, Injector};
+use super::subscriber::set_tag;
+use lapin::message::Delivery;
+use lapin::types::{AMQPValue, FieldTable};
+use lapin::BasicProperties;
+use tracing::Span;
+
+/// Name of the spans publishing messages
+pub const PUBLISH_SPAN_NAME: &str = "amqp.publish";
+
+/// Name of the spans consuming messages
+pub const CONSUME_SPAN_NAME: &str = "amqp.consume";
+
+/// Maps the publish and consume spans to the `<service>-rabbitmq` service.
+pub const INTEGRATION_MAPPINGS: &[IntegrationMapping] = &[
+    amqp_mapping(PUBLISH_SPAN_NAME),
+    amqp_mapping(CONSUME_SPAN_NAME),
+];
+
+const fn amqp_mapping(span_name: &'static str) -> IntegrationMapping {
+    IntegrationMapping {
+        target: module_path!(),
+        span_name: SpanName(span_name),
+        service_suffix: Some("rabbitmq"),
+        span_type: SpanType::Queue,
+    }
+}
+
+const MESSAGING_SYSTEM_TAG: &str = "messaging.system";
+const EXCHANGE_TAG: &str = "amqp.exchange";
+const ROUTING_KEY_TAG: &str = "amqp.routing_key";
+const QUEUE_TAG: &str = "amqp.queue";
+
+/// Creates an `amqp.publish` span for publishing to `exchange` (empty for the default exchange)
+/// with `routing_key`, whose context is then injected into the properties of the message with
+/// `inject`:
+///
+/// ```ignore
+/// let span = instrumentation_lapin::publish_span("orders", "orders.created");
+/// let properties = instrumentation_lapin::inject(&span, BasicProperties::default());
+/// channel
+///     .basic_publish("orders", "orders.created", options, &payload, properties)
+///     .instrument(span)
+///     .await?;
+/// ```
+pub fn publish_span(exchange: &str, routing_key: &str) -> Span {
+    let span = tracing::info_span!(
+        PUBLISH_SPAN_NAME,
+        resource = %publish_resource(exchange, routing_key),
+        otel.kind = "producer"
+    );
+    set_tag(&span, MESSAGING_SYSTEM_TAG, "rabbitmq");
+    set_tag(&span, EXCHANGE_TAG, exchange);
+    set_tag(&span, ROUTING_KEY_TAG, routing_key);
+    span
+}
+
+/// Injects the context of `span` into the headers of message properties, keeping their other
+/// headers, so consumers continue its trace. The properties are returned unchanged if the span
+/// is not traced by a `TracingSubscriberDatadog`.
+pub fn inject(span: &Span, properties: BasicProperties) -> BasicProperties {
+    let Some(context) = context_of(span) else {
+        return properties;
+    };
+    let mut headers = properties.headers().clone().unwrap_or_default();
+    propagation::inject(&context, &mut headers);
+    properties.with_headers(headers)
+}
+
+/// Creates an `amqp.consume` span for a message delivered from `queue`, which continues the
+/// trace of its publisher when its headers carry one, and starts a new trace otherwise:
+///
+/// ```ignore
+/// while let Some(delivery) = consumer.next().await {
+///     let delivery = delivery?;
+///     let span = instrumentation_lapin::consume_span(&delivery, "orders");
+///     handle(delivery).instrument(span).await;
+/// }
+/// ```
+pub fn consume_span(delivery: &Delivery, queue: &str) -> Span {
+    let span = match delivery
+        .properties
+        .headers()
+        .as_ref()
+        .and_then(propagation::extract)
+    {
+        Some(context) => crate::span_with_remote_parent!(
+            context,
+            CONSUME_SPAN_NAME,
+            resource = queue,
+            otel.kind = "consumer"
+        ),
+        None => tracing::info_span!(CONSUME_SPAN_NAME, resource = queue, otel.kind = "consumer"),
+    };
+    set_tag(&span, MESSAGING_SYSTEM_TAG, "rabbitmq");
+    set_tag(&span, EXCHANGE_TAG, delivery.exchange.as_str());
+    set_tag(&span, ROUTING_KEY_TAG, delivery.routing_key.as_str());
+    set_tag(&span, QUEUE_TAG, queue);
+    span
+}
+
+#[inline]
+fn publish_resource(exchange: &str, routing_key: &str) -> String {
+    let exchange = if exchange.is_empty() {
+        "<default>"
+    } else {
+        exchange
+    };
+    format!("{} -> {}", exchange, routing_key)
+}
+
+// Header values written by other tracers may be short or long strings, and their names may be
+// in any case
+impl Extractor for FieldTable {
+    #[inline]
+    fn get(&self, key: &str) -> Option<&str> {
+        self.inner()
+            .iter()
+            .find(|(name, _)| name.as_str().eq_ignore_ascii_case(key))
+            .and_then(|(_, value)| match value {
+                AMQPValue::LongString(value) => std::str::from_utf8(value.as_bytes()).ok(),
+                AMQPValue::ShortString(value) => Some(value.as_str()),
+                _ => None,
+            })
+    }
+}
+
+impl Injector for FieldTable {
+    #[inline]
+    fn set(&mut self, key: &str, value: String) {
+        self.insert(key.into(), AMQPValue::LongString(value.into()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::propagation::SpanContext;
+    use std::num::NonZeroU64;
+
+    #[test]
+    fn test_headers_carry_span_contexts() {
+        let context = SpanContext::new(NonZeroU64::MIN, NonZeroU64::MAX);
+        let mut headers = FieldTable::default();
+
+        propagation::inject(&context, &mut headers);
+
+        assert_eq!(propagation::extract(&headers), Some(context));
+    }
+
+    #[test]
+    fn test_short_string_headers_are_extracted() {
+        let mut headers = FieldTable::default();
+        headers.insert(
+            "X-Datadog-Trace-Id".into(),
+            AMQPValue::ShortString("123".into()),
+        );
+        headers.insert("x-datadog-parent-id".into(), AMQPValue::LongInt(456));
+
+        assert_eq!(Extractor::get(&headers, "x-datadog-trace-id"), Some("123"));
+        assert_eq!(Extractor::get(&headers, "x-datadog-parent-id"), None);
+    }
+
+    #[test]
+    fn test_publish_resource() {
+        assert_eq!(publish_resource("orders", "created"), "orders -> created");
+        assert_eq!(publish_resource("", "orders"), "<default> -> orders");
+    }
+}
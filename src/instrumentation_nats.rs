@@ -0,0 +1,143 @@
+use super::context::context_of;
+use super::datadog_client::{SpanName, SpanType};
+use super::integrations::IntegrationMapping;
+use super::propagation::{self, Extractor, Injector};
+use super::subscriber::set_tag;
+use async_nats::{HeaderMap, Message};
+use tracing::Span;
+
+/// Name of the spans publishing messages
+pub const PUBLISH_SPAN_NAME: &str = "nats.publish";
+
+/// Name of the spans consuming messages
+pub const CONSUME_SPAN_NAME: &str = "nats.consume";
+
+/// Maps the publish and consume spans to the `<service>-nats` service.
+pub const INTEGRATION_MAPPINGS: &[IntegrationMapping] = &[
+    nats_mapping(PUBLISH_SPAN_NAME),
+    nats_mapping(CONSUME_SPAN_NAME),
+];
+
+const fn nats_mapping(span_name: &'static str) -> IntegrationMapping {
+    IntegrationMapping {
+        target: module_path!(),
+        span_name: SpanName(span_name),
+        service_suffix: Some("nats"),
+        span_type: SpanType::Queue,
+    }
+}
+
+const MESSAGING_SYSTEM_TAG: &str = "messaging.system";
+const MESSAGING_DESTINATION_TAG: &str = "messaging.destination.name";
+
+/// Creates a `nats.publish` span for publishing to `subject`, whose context is then injected
+/// into the headers of the message with `inject`:
+///
+/// ```ignore
+/// let span = instrumentation_nats::publish_span("orders.created");
+/// let mut headers = async_nats::HeaderMap::new();
+/// instrumentation_nats::inject(&span, &mut headers);
+/// client
+///     .publish_with_headers("orders.created", headers, payload)
+///     .instrument(span)
+///     .await?;
+/// ```
+pub fn publish_span(subject: &str) -> Span {
+    let span = tracing::info_span!(
+        PUBLISH_SPAN_NAME,
+        resource = subject,
+        otel.kind = "producer"
+    );
+    tag_messaging(&span, subject);
+    span
+}
+
+/// Injects the context of `span` into NATS message headers, so consumers continue its trace.
+/// It has no effect if the span is not traced by a `TracingSubscriberDatadog`.
+pub fn inject(span: &Span, headers: &mut HeaderMap) {
+    if let Some(context) = context_of(span) {
+        propagation::inject(&context, headers);
+    }
+}
+
+/// Creates a `nats.consume` span for a received message, which continues the trace of its
+/// publisher when its headers carry one, and starts a new trace otherwise:
+///
+/// ```ignore
+/// while let Some(message) = subscriber.next().await {
+///     let span = instrumentation_nats::consume_span(&message);
+///     handle(message).instrument(span).await;
+/// }
+/// ```
+pub fn consume_span(message: &Message) -> Span {
+    let subject = message.subject.as_str();
+    let span = match message.headers.as_ref().and_then(propagation::extract) {
+        Some(context) => crate::span_with_remote_parent!(
+            context,
+            CONSUME_SPAN_NAME,
+            resource = subject,
+            otel.kind = "consumer"
+        ),
+        None => tracing::info_span!(
+            CONSUME_SPAN_NAME,
+            resource = subject,
+            otel.kind = "consumer"
+        ),
+    };
+    tag_messaging(&span, subject);
+    span
+}
+
+#[inline]
+fn tag_messaging(span: &Span, subject: &str) {
+    set_tag(span, MESSAGING_SYSTEM_TAG, "nats");
+    set_tag(span, MESSAGING_DESTINATION_TAG, subject);
+}
+
+// NATS header names are case sensitive, so names written by other tracers in another case are
+// looked up by scanning the headers
+impl Extractor for HeaderMap {
+    #[inline]
+    fn get(&self, key: &str) -> Option<&str> {
+        HeaderMap::get(self, key)
+            .or_else(|| {
+                self.iter()
+                    .find(|(name, _)| AsRef::<str>::as_ref(name).eq_ignore_ascii_case(key))
+                    .and_then(|(_, values)| values.first())
+            })
+            .map(|value| value.as_str())
+    }
+}
+
+impl Injector for HeaderMap {
+    #[inline]
+    fn set(&mut self, key: &str, value: String) {
+        self.insert(key, value.as_str());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::propagation::SpanContext;
+    use std::num::NonZeroU64;
+
+    #[test]
+    fn test_headers_carry_span_contexts() {
+        let context = SpanContext::new(NonZeroU64::MIN, NonZeroU64::MAX);
+        let mut headers = HeaderMap::new();
+
+        propagation::inject(&context, &mut headers);
+
+        assert_eq!(propagation::extract(&headers), Some(context));
+    }
+
+    #[test]
+    fn test_header_names_are_looked_up_in_any_case() {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Datadog-Trace-Id", "123");
+
+        assert_eq!(Extractor::get(&headers, "x-datadog-trace-id"), Some("123"));
+        assert_eq!(Extractor::get(&headers, "x-datadog-parent-id"), None);
+    }
+}
@@ -0,0 +1,94 @@
+use super::span::{ServiceName, SpanName, SpanType};
+use std::collections::HashSet;
+use std::sync::{Mutex, OnceLock};
+
+/// A span mapping contributed by an integration, so users don't need to remember to map the
+/// span names of every integration they enable. It only maps the spans of the integration
+/// itself, so spans of the application with the same name, like `request`, stay unmapped.
+/// Mappings added with `TracingSubscriberDatadogConfig::add_mapping` take precedence.
+#[derive(Clone, Debug)]
+pub struct IntegrationMapping {
+    /// Target of the spans of the integration, the path of the module creating them.
+    pub target: &'static str,
+    pub span_name: SpanName,
+    /// Appended to the service of the process (`DD_SERVICE`, or the default service) with a `-`,
+    /// or `None` to map the span to the service of the process itself.
+    pub service_suffix: Option<&'static str>,
+    pub span_type: SpanType,
+}
+
+impl IntegrationMapping {
+    /// The service of the span in a process whose service is `service`.
+    pub fn service(&self, service: &str) -> ServiceName {
+        match (self.service_suffix, service.is_empty()) {
+            (Some(suffix), true) => ServiceName(suffix),
+            (Some(suffix), false) => ServiceName(intern(format!("{}-{}", service, suffix))),
+            (None, _) => ServiceName(intern(String::from(service))),
+        }
+    }
+}
+
+/// The mappings of the integrations enabled by crate features, and of the `lifecycle` spans.
+pub fn enabled_integration_mappings() -> Vec<IntegrationMapping> {
+    #[allow(unused_mut)]
+    let mut mappings = super::lifecycle::INTEGRATION_MAPPINGS.to_vec();
+    #[cfg(feature = "actix_web")]
+    mappings.extend_from_slice(super::instrumentation_actix_web::INTEGRATION_MAPPINGS);
+    #[cfg(feature = "hyper_client")]
+    mappings.extend_from_slice(super::instrumentation_hyper::INTEGRATION_MAPPINGS);
+    #[cfg(feature = "lapin")]
+    mappings.extend_from_slice(super::instrumentation_lapin::INTEGRATION_MAPPINGS);
+    #[cfg(feature = "nats")]
+    mappings.extend_from_slice(super::instrumentation_nats::INTEGRATION_MAPPINGS);
+    mappings
+}
+
+// Service names must be static, so the names built at runtime are leaked, once per name
+fn intern(service: String) -> &'static str {
+    static SERVICES: OnceLock<Mutex<HashSet<&'static str>>> = OnceLock::new();
+    let mut services = match SERVICES.get_or_init(Default::default).lock() {
+        Ok(services) => services,
+        Err(e) => e.into_inner(),
+    };
+    match services.get(service.as_str()) {
+        Some(interned) => interned,
+        None => {
+            let interned: &'static str = Box::leak(service.into_boxed_str());
+            services.insert(interned);
+            interned
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_service_with_suffix() {
+        let mapping = IntegrationMapping {
+            target: module_path!(),
+            span_name: SpanName("http.client.dns"),
+            service_suffix: Some("http-client"),
+            span_type: SpanType::Web,
+        };
+
+        assert_eq!(mapping.service("orders").0, "orders-http-client");
+        assert_eq!(mapping.service("").0, "http-client");
+    }
+
+    #[test]
+    fn test_services_are_interned() {
+        let mapping = IntegrationMapping {
+            target: module_path!(),
+            span_name: SpanName("request"),
+            service_suffix: None,
+            span_type: SpanType::Web,
+        };
+
+        assert!(std::ptr::eq(
+            mapping.service("orders").0,
+            mapping.service("orders").0
+        ));
+    }
+}
@@ -1,5 +1,80 @@
+#[cfg(feature = "client")]
+mod agentless;
+#[cfg(feature = "client")]
+pub mod appsec;
+mod base64;
+#[cfg(feature = "client")]
+pub mod cardinality;
+#[cfg(feature = "client")]
+mod clock;
+#[cfg(feature = "client")]
+pub mod context;
+#[cfg(feature = "client")]
+pub mod convert;
+#[cfg(feature = "client")]
+pub mod correlation;
+#[cfg(feature = "client")]
 pub mod datadog_client;
+#[cfg(feature = "client")]
+mod dogstatsd;
+#[cfg(feature = "client")]
+pub mod drop_rules;
+#[cfg(feature = "client")]
+pub mod grpc;
+#[cfg(feature = "client")]
+pub mod header_tags;
+pub mod http_status;
+#[cfg(feature = "client")]
+pub mod integrations;
+#[cfg(feature = "client")]
+pub mod lifecycle;
+#[cfg(feature = "client")]
+pub mod local_export;
+#[cfg(feature = "client")]
+pub mod obfuscate;
+pub mod propagation;
+#[cfg(feature = "client")]
+pub mod rate_limit;
+#[cfg(feature = "client")]
+pub mod redaction;
+#[cfg(feature = "client")]
+mod rollout;
+pub mod sampling;
+#[cfg(feature = "client")]
+mod self_instrumentation;
+pub mod span;
+#[cfg(feature = "client")]
+mod span_store;
+#[cfg(feature = "client")]
 pub mod subscriber;
+#[cfg(feature = "client")]
+pub mod truncation;
+#[cfg(feature = "client")]
+mod v05;
+#[cfg(feature = "client")]
+mod v07;
+#[cfg(feature = "client")]
+pub mod validation;
+#[cfg(feature = "client")]
+pub mod writer;
+
+#[cfg(feature = "tokio")]
+pub mod task;
+
+#[cfg(any(feature = "async_std", windows))]
+mod http1;
+
+#[cfg(feature = "test_support")]
+pub mod fixtures;
 
 #[cfg(feature = "actix_web")]
 pub mod instrumentation_actix_web;
+
+#[cfg(feature = "hyper_client")]
+pub mod instrumentation_hyper;
+
+#[cfg(feature = "lapin")]
+pub mod instrumentation_lapin;
+
+#[cfg(feature = "nats")]
+pub mod instrumentation_nats;
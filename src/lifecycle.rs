@@ -0,0 +1,84 @@
+use super::datadog_client::{SpanName, SpanType};
+use super::integrations::IntegrationMapping;
+use super::subscriber::set_tag;
+use tracing::Span;
+
+/// Name of the root spans of the startup and the shutdown of the process
+pub const LIFECYCLE_SPAN_NAME: &str = "process.lifecycle";
+
+/// Name of the spans of the phases of a startup or a shutdown
+pub const PHASE_SPAN_NAME: &str = "process.phase";
+
+/// Maps the lifecycle spans to the service of the process.
+pub const INTEGRATION_MAPPINGS: &[IntegrationMapping] = &[
+    lifecycle_mapping(LIFECYCLE_SPAN_NAME),
+    lifecycle_mapping(PHASE_SPAN_NAME),
+];
+
+const fn lifecycle_mapping(span_name: &'static str) -> IntegrationMapping {
+    IntegrationMapping {
+        target: module_path!(),
+        span_name: SpanName(span_name),
+        service_suffix: None,
+        span_type: SpanType::Custom,
+    }
+}
+
+const STAGE_TAG: &str = "process.lifecycle.stage";
+
+/// The startup or the shutdown of the process, traced as a `process.lifecycle` trace of its own
+/// with a `process.phase` child span per phase, so regressions of deployment times show up in
+/// APM. The root span lasts until the `Lifecycle` is finished or dropped:
+///
+/// ```ignore
+/// let startup = Lifecycle::startup();
+/// let config = startup.in_phase("config load", load_config);
+/// startup.in_phase("migrations", || run_migrations(&config))?;
+/// warm_caches(&config).instrument(startup.phase("warmup")).await;
+/// startup.finish();
+/// ```
+pub struct Lifecycle {
+    span: Span,
+    stage: &'static str,
+}
+
+impl Lifecycle {
+    /// Starts tracing the startup of the process.
+    pub fn startup() -> Self {
+        Self::new("startup")
+    }
+
+    /// Starts tracing the shutdown of the process. Traces are only sent if the client is
+    /// flushed or shut down after the shutdown is finished.
+    pub fn shutdown() -> Self {
+        Self::new("shutdown")
+    }
+
+    // Lifecycle traces are explicit roots, so they never join the trace of the current span
+    fn new(stage: &'static str) -> Self {
+        let span = tracing::info_span!(parent: None, LIFECYCLE_SPAN_NAME, resource = stage);
+        set_tag(&span, STAGE_TAG, stage);
+        Self { span, stage }
+    }
+
+    /// Creates the span of a phase, which lasts until it's dropped, e.g. to instrument an
+    /// asynchronous phase.
+    pub fn phase(&self, phase: &str) -> Span {
+        let span = tracing::info_span!(parent: &self.span, PHASE_SPAN_NAME, resource = phase);
+        set_tag(&span, STAGE_TAG, self.stage);
+        span
+    }
+
+    /// Runs `f` in the span of a phase.
+    pub fn in_phase<R>(&self, phase: &str, f: impl FnOnce() -> R) -> R {
+        self.phase(phase).in_scope(f)
+    }
+
+    /// The root span of the lifecycle trace.
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Ends the root span, like dropping the `Lifecycle`.
+    pub fn finish(self) {}
+}
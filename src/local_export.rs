@@ -0,0 +1,253 @@
+use super::span::{ExportError, Span, SpanExporter, Trace, Traces};
+use super::writer::NonBlockingWriter;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Writes buffered for the terminal or file before traces are dropped
+const BUFFERED_WRITES: usize = 1024;
+
+/// Pretty-prints every trace to stdout as a tree of spans, with their durations and tags, to look
+/// at spans in local development without running a Datadog agent. Writes are buffered and done in
+/// the background, so a slow terminal doesn't hold up the application.
+pub struct StdoutExporter {
+    writer: Mutex<NonBlockingWriter>,
+}
+
+impl Default for StdoutExporter {
+    fn default() -> Self {
+        Self::with_writer(std::io::stdout())
+    }
+}
+
+impl StdoutExporter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pretty-prints traces to `writer` instead of stdout.
+    pub fn with_writer(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            writer: Mutex::new(NonBlockingWriter::new(writer, BUFFERED_WRITES)),
+        }
+    }
+}
+
+impl SpanExporter for StdoutExporter {
+    fn export(&self, traces: Traces) -> Result<(), ExportError> {
+        let mut output = String::new();
+        for trace in &traces {
+            pretty_print(&mut output, trace);
+        }
+        write(&self.writer, output.as_bytes())
+    }
+}
+
+/// Appends every trace to a file as a line of JSON, an array of its spans as they are sent to the
+/// agent, so traces of local runs can be kept and read with tools like `jq`. Writes are buffered
+/// and done in the background.
+pub struct FileExporter {
+    writer: Mutex<NonBlockingWriter>,
+}
+
+impl FileExporter {
+    /// Appends traces to the file at `path`, which is created if it doesn't exist.
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::with_writer(file))
+    }
+
+    /// Writes traces as lines of JSON to `writer` instead of a file.
+    pub fn with_writer(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            writer: Mutex::new(NonBlockingWriter::new(writer, BUFFERED_WRITES)),
+        }
+    }
+}
+
+impl SpanExporter for FileExporter {
+    fn export(&self, traces: Traces) -> Result<(), ExportError> {
+        let mut output = Vec::new();
+        for trace in &traces {
+            serde_json::to_writer(&mut output, trace)?;
+            output.push(b'\n');
+        }
+        write(&self.writer, &output)
+    }
+}
+
+#[inline]
+fn write(writer: &Mutex<NonBlockingWriter>, output: &[u8]) -> Result<(), ExportError> {
+    if output.is_empty() {
+        return Ok(());
+    }
+    let mut writer = writer
+        .lock()
+        .map_err(|e| format!("Failed to get lock on writer; err {:?}", e))?;
+    writer.write_all(output)?;
+    Ok(())
+}
+
+// Prints a trace as a tree of spans; spans whose parent isn't in the trace, like the root span or
+// the local root of a distributed trace, are printed at the top
+fn pretty_print(output: &mut String, trace: &Trace) {
+    let Some(first) = trace.first() else {
+        return;
+    };
+    let span_ids: Vec<u64> = trace.iter().map(Span::span_id).collect();
+    let mut children: HashMap<Option<u64>, Vec<&Span>> = HashMap::new();
+    for span in trace {
+        let parent_id = span.parent_id().filter(|id| span_ids.contains(id));
+        children.entry(parent_id).or_default().push(span);
+    }
+    for spans in children.values_mut() {
+        spans.sort_by_key(|span| span.start());
+    }
+    writeln!(output, "trace {} ({} spans)", first.trace_id(), trace.len()).ok();
+    print_children(output, &children, None, 1);
+}
+
+fn print_children(
+    output: &mut String,
+    children: &HashMap<Option<u64>, Vec<&Span>>,
+    parent_id: Option<u64>,
+    depth: usize,
+) {
+    for span in children.get(&parent_id).into_iter().flatten() {
+        print_span(output, span, depth);
+        print_children(output, children, Some(span.span_id()), depth + 1);
+    }
+}
+
+fn print_span(output: &mut String, span: &Span, depth: usize) {
+    let indent = "  ".repeat(depth);
+    write!(
+        output,
+        "{}{} {} {} {:.2?}",
+        indent,
+        span.service(),
+        span.name(),
+        span.resource(),
+        Duration::from_nanos(span.duration())
+    )
+    .ok();
+    if span.is_error() {
+        output.push_str(" [error]");
+    }
+    output.push('\n');
+    let mut tags: Vec<_> = span.meta().iter().collect();
+    tags.sort();
+    for (key, value) in tags {
+        writeln!(output, "{}    {}={}", indent, key, value).ok();
+    }
+    for span_event in span.span_events() {
+        let offset = span_event
+            .time_unix_nano
+            .saturating_sub(span.start())
+            .min(span.duration());
+        writeln!(
+            output,
+            "{}    @{:.2?} {}",
+            indent,
+            Duration::from_nanos(offset),
+            span_event.name
+        )
+        .ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::span::{ServiceName, SpanBuilder, SpanName};
+    use std::num::NonZeroU64;
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    fn trace() -> Trace {
+        let start = UNIX_EPOCH + Duration::from_secs(1);
+        let mut root = SpanBuilder::default();
+        root.trace_id(NonZeroU64::new(7).unwrap())
+            .service(ServiceName("orders"))
+            .name(SpanName("request"))
+            .resource(String::from("GET /orders"))
+            .start(start)
+            .add_tag("http.method", "GET");
+        let root = root.build_with_duration(Duration::from_millis(12));
+        let mut query = SpanBuilder::default();
+        query
+            .trace_id(NonZeroU64::new(7).unwrap())
+            .parent_id(NonZeroU64::new(root.span_id()).unwrap())
+            .service(ServiceName("orders-db"))
+            .name(SpanName("query"))
+            .resource(String::from("SELECT"))
+            .start(start + Duration::from_millis(1))
+            .error(true);
+        // children come before their parent, like the subscriber sends them
+        vec![query.build_with_duration(Duration::from_millis(3)), root]
+    }
+
+    #[test]
+    fn test_stdout_exporter_prints_span_trees() {
+        let buffer = SharedBuffer::default();
+        let exporter = StdoutExporter::with_writer(buffer.clone());
+
+        exporter.export(vec![trace()]).unwrap();
+        drop(exporter);
+
+        assert_eq!(
+            buffer.contents(),
+            "trace 7 (2 spans)\n\
+             \x20 orders request GET /orders 12.00ms\n\
+             \x20     http.method=GET\n\
+             \x20   orders-db query SELECT 3.00ms [error]\n"
+        );
+    }
+
+    #[test]
+    fn test_file_exporter_appends_a_line_per_trace() {
+        let path = std::env::temp_dir().join(format!(
+            "tracing-datadog-apm-{}.ndjson",
+            rand::random::<u64>()
+        ));
+        for _ in 0..2 {
+            FileExporter::open(&path)
+                .unwrap()
+                .export(vec![trace()])
+                .unwrap();
+        }
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let spans: Vec<Span> = serde_json::from_str(lines[1]).unwrap();
+        let names: Vec<&str> = spans.iter().map(Span::name).collect();
+        assert_eq!(names, ["query", "request"]);
+        assert_eq!(spans[0].parent_id(), Some(spans[1].span_id()));
+    }
+}
@@ -0,0 +1,327 @@
+/// The SQL dialect of the queries to obfuscate, which decides how string literals are quoted and
+/// escaped. Obfuscating a query of one dialect as the other can leak parts of its literals.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SqlDialect {
+    /// Standard SQL, as spoken by PostgreSQL (with `standard_conforming_strings`) and SQLite:
+    /// quotes in `'...'` strings are escaped by doubling them, backslashes are only escapes in
+    /// `E'...'` strings, `$$...$$` and `$tag$...$tag$` are strings and `"..."` are identifiers.
+    #[default]
+    Standard,
+    /// MySQL and MariaDB: `'...'` and `"..."` are strings, in which backslashes escape the next
+    /// character, and `` `...` `` are identifiers.
+    MySql,
+}
+
+/// Obfuscates a SQL query so it can be used as a span resource or `sql.query` tag without
+/// leaking the values it contains: string and numeric literals and bind parameters (`$1`, `?`,
+/// `:name`, `@name`) are replaced by `?`, comments are stripped, whitespace is collapsed and
+/// lists of values (e.g. `IN (1, 2, 3)`) are collapsed into a single `?`.
+///
+/// Quoted identifiers (`"table"`, `` `column` ``) are kept as they are. Queries are read as
+/// standard SQL, see `obfuscate_sql_with` for MySQL queries.
+pub fn obfuscate_sql(query: &str) -> String {
+    obfuscate_sql_with(query, SqlDialect::Standard)
+}
+
+/// Obfuscates a SQL query of the given dialect, like `obfuscate_sql`.
+pub fn obfuscate_sql_with(query: &str, dialect: SqlDialect) -> String {
+    let chars: Vec<char> = query.chars().collect();
+    let mut obfuscated = String::with_capacity(query.len());
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        match c {
+            // line comment
+            '-' if next == Some('-') => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                push_space(&mut obfuscated);
+            }
+            // block comment
+            '/' if next == Some('*') => {
+                i += 2;
+                while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                    i += 1;
+                }
+                i += 2;
+                push_space(&mut obfuscated);
+            }
+            // string literal, where '' is an escaped quote
+            '\'' => {
+                let backslash_escapes = match dialect {
+                    SqlDialect::Standard => pop_escape_string_prefix(&mut obfuscated),
+                    SqlDialect::MySql => true,
+                };
+                i = skip_string(&chars, i, backslash_escapes);
+                push_placeholder(&mut obfuscated);
+            }
+            '"' if dialect == SqlDialect::MySql => {
+                i = skip_string(&chars, i, true);
+                push_placeholder(&mut obfuscated);
+            }
+            // dollar-quoted string, `$$...$$` or `$tag$...$tag$`
+            '$' if dialect == SqlDialect::Standard && dollar_quote_tag(&chars, i).is_some() => {
+                let tag = dollar_quote_tag(&chars, i).unwrap_or_default();
+                i += tag.len();
+                while i < chars.len() && !chars[i..].starts_with(&tag) {
+                    i += 1;
+                }
+                i = (i + tag.len()).min(chars.len());
+                push_placeholder(&mut obfuscated);
+            }
+            // quoted identifiers
+            '"' | '`' => {
+                let start = i;
+                i += 1;
+                while i < chars.len() && chars[i] != c {
+                    i += 1;
+                }
+                i += 1;
+                obfuscated.extend(&chars[start..i.min(chars.len())]);
+            }
+            // numeric literal, unless it is part of an identifier like `table1`
+            '0'..='9' if !previous_is_identifier(&obfuscated) => {
+                while i < chars.len()
+                    && (chars[i].is_ascii_alphanumeric() || chars[i] == '.' || chars[i] == '_')
+                {
+                    i += 1;
+                }
+                push_placeholder(&mut obfuscated);
+            }
+            // bind parameters
+            '$' | ':' | '@' if next.is_some_and(|n| n.is_ascii_alphanumeric() || n == '_') => {
+                // keep casts like `value::text`
+                if c == ':' && obfuscated.ends_with(':') {
+                    obfuscated.push(c);
+                    i += 1;
+                    continue;
+                }
+                i += 1;
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                push_placeholder(&mut obfuscated);
+            }
+            '?' => {
+                i += 1;
+                push_placeholder(&mut obfuscated);
+            }
+            c if c.is_whitespace() => {
+                i += 1;
+                push_space(&mut obfuscated);
+            }
+            c => {
+                i += 1;
+                obfuscated.push(c);
+            }
+        }
+    }
+
+    collapse_lists(obfuscated.trim())
+}
+
+// Skips the string literal quoted by `chars[start]`, in which doubled quotes are escaped quotes,
+// returning the index after its closing quote
+#[inline]
+fn skip_string(chars: &[char], start: usize, backslash_escapes: bool) -> usize {
+    let quote = chars[start];
+    let mut i = start + 1;
+    while i < chars.len() {
+        if backslash_escapes && chars[i] == '\\' {
+            i += 1;
+        } else if chars[i] == quote {
+            if chars.get(i + 1) != Some(&quote) {
+                break;
+            }
+            i += 1;
+        }
+        i += 1;
+    }
+    (i + 1).min(chars.len())
+}
+
+// Removes the `E` prefix of a PostgreSQL escape string (`E'...'`) from the output, returning
+// whether there was one, in which case backslashes are escapes
+#[inline]
+fn pop_escape_string_prefix(obfuscated: &mut String) -> bool {
+    let Some(prefix) = obfuscated.strip_suffix(['E', 'e']) else {
+        return false;
+    };
+    if previous_is_identifier(prefix) {
+        return false;
+    }
+    obfuscated.pop();
+    true
+}
+
+// The opening delimiter of a dollar-quoted string at `start`, like `$$` or `$tag$`. The tag of a
+// dollar quote doesn't start with a digit, unlike bind parameters like `$1`.
+#[inline]
+fn dollar_quote_tag(chars: &[char], start: usize) -> Option<Vec<char>> {
+    let mut end = start + 1;
+    while end < chars.len() && (chars[end].is_alphanumeric() || chars[end] == '_') {
+        end += 1;
+    }
+    let starts_with_digit = chars.get(start + 1).is_some_and(char::is_ascii_digit);
+    (chars.get(end) == Some(&'$') && !starts_with_digit).then(|| chars[start..=end].to_vec())
+}
+
+#[inline]
+fn previous_is_identifier(obfuscated: &str) -> bool {
+    obfuscated
+        .chars()
+        .last()
+        .is_some_and(|c| c.is_alphanumeric() || c == '_')
+}
+
+#[inline]
+fn push_space(obfuscated: &mut String) {
+    if !obfuscated.is_empty() && !obfuscated.ends_with(' ') {
+        obfuscated.push(' ');
+    }
+}
+
+#[inline]
+fn push_placeholder(obfuscated: &mut String) {
+    obfuscated.push('?');
+}
+
+// Collapses `?, ?, ?` into `?` so that queries with a different number of values in a list
+// (or in a multi-row insert) share the same resource
+fn collapse_lists(obfuscated: &str) -> String {
+    let mut collapsed = String::with_capacity(obfuscated.len());
+    let mut chars = obfuscated.chars().peekable();
+    while let Some(c) = chars.next() {
+        collapsed.push(c);
+        if c != '?' {
+            continue;
+        }
+        loop {
+            let mut lookahead = chars.clone();
+            while lookahead.peek() == Some(&' ') {
+                lookahead.next();
+            }
+            if lookahead.next() != Some(',') {
+                break;
+            }
+            while lookahead.peek() == Some(&' ') {
+                lookahead.next();
+            }
+            if lookahead.next() != Some('?') {
+                break;
+            }
+            chars = lookahead;
+        }
+    }
+    collapsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_obfuscate_string_literals() {
+        assert_eq!(
+            obfuscate_sql("SELECT * FROM users WHERE email = 'alice@example.com'"),
+            "SELECT * FROM users WHERE email = ?"
+        );
+    }
+
+    #[test]
+    fn test_obfuscate_escaped_quotes() {
+        assert_eq!(
+            obfuscate_sql("SELECT * FROM users WHERE name = 'O''Brien' AND id = 1"),
+            "SELECT * FROM users WHERE name = ? AND id = ?"
+        );
+    }
+
+    #[test]
+    fn test_obfuscate_backslashes_in_standard_strings() {
+        assert_eq!(
+            obfuscate_sql("SELECT * FROM u WHERE path='C:\\' AND pw='secret'"),
+            "SELECT * FROM u WHERE path=? AND pw=?"
+        );
+    }
+
+    #[test]
+    fn test_obfuscate_escape_strings() {
+        assert_eq!(
+            obfuscate_sql("SELECT * FROM u WHERE name = E'it\\'s' AND pw = e'secret'"),
+            "SELECT * FROM u WHERE name = ? AND pw = ?"
+        );
+        assert_eq!(
+            // the E of a keyword isn't the prefix of an escape string
+            obfuscate_sql("SELECT * FROM u WHERE path LIKE'C:\\' AND pw='secret'"),
+            "SELECT * FROM u WHERE path LIKE? AND pw=?"
+        );
+    }
+
+    #[test]
+    fn test_obfuscate_dollar_quoted_strings() {
+        assert_eq!(
+            obfuscate_sql("SELECT $$it's a secret$$, $body$x $$ y$body$ FROM t WHERE id = $1"),
+            "SELECT ? FROM t WHERE id = ?"
+        );
+    }
+
+    #[test]
+    fn test_obfuscate_mysql_strings() {
+        assert_eq!(
+            obfuscate_sql_with(
+                "SELECT * FROM `u` WHERE name = 'it\\'s' AND pw = \"secret\" AND n = \"a\"\"b\"",
+                SqlDialect::MySql
+            ),
+            "SELECT * FROM `u` WHERE name = ? AND pw = ? AND n = ?"
+        );
+    }
+
+    #[test]
+    fn test_obfuscate_numbers_but_not_identifiers() {
+        assert_eq!(
+            obfuscate_sql("SELECT col1 FROM table2 WHERE price > 10.5 LIMIT 10"),
+            "SELECT col1 FROM table2 WHERE price > ? LIMIT ?"
+        );
+    }
+
+    #[test]
+    fn test_obfuscate_bind_parameters() {
+        assert_eq!(
+            obfuscate_sql("SELECT * FROM users WHERE id = $1 AND org = :org AND x = @p1 AND y = ?"),
+            "SELECT * FROM users WHERE id = ? AND org = ? AND x = ? AND y = ?"
+        );
+    }
+
+    #[test]
+    fn test_obfuscate_keeps_casts_and_quoted_identifiers() {
+        assert_eq!(
+            obfuscate_sql("SELECT \"Id\"::text FROM `users` WHERE a = '1'"),
+            "SELECT \"Id\"::text FROM `users` WHERE a = ?"
+        );
+    }
+
+    #[test]
+    fn test_obfuscate_collapses_lists() {
+        assert_eq!(
+            obfuscate_sql("SELECT * FROM users WHERE id IN (1, 2,3)"),
+            "SELECT * FROM users WHERE id IN (?)"
+        );
+        assert_eq!(
+            obfuscate_sql("INSERT INTO t (a, b) VALUES ('x', 1), ('y', 2)"),
+            "INSERT INTO t (a, b) VALUES (?), (?)"
+        );
+    }
+
+    #[test]
+    fn test_obfuscate_strips_comments_and_whitespace() {
+        assert_eq!(
+            obfuscate_sql(
+                "SELECT *\n  FROM users -- find them all\n /* secret 42 */ WHERE  id = 7"
+            ),
+            "SELECT * FROM users WHERE id = ?"
+        );
+    }
+}
@@ -0,0 +1,458 @@
+use super::base64;
+use super::sampling::SamplingPriority;
+#[cfg(feature = "client")]
+use super::subscriber::with_span_builder;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::num::NonZeroU64;
+use std::str::FromStr;
+
+pub const ORIGIN_TAG: &str = "_dd.origin";
+/// Prefix of the trace-level tags which are propagated with the trace.
+pub const TRACE_TAG_PREFIX: &str = "_dd.p.";
+
+/// The part of a span which is propagated to other services, so they can continue its trace.
+/// It can be serialized, e.g. into the payload of a message or a persisted job.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpanContext {
+    pub trace_id: NonZeroU64,
+    pub span_id: NonZeroU64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sampling_priority: Option<SamplingPriority>,
+    /// Where the trace started, e.g. `synthetics` or `lambda`, from the `x-datadog-origin`
+    /// header.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    /// The `_dd.p.*` tags of the trace, e.g. its sampling decision maker `_dd.p.dm`, from the
+    /// `x-datadog-tags` header.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub trace_tags: HashMap<String, String>,
+}
+
+impl SpanContext {
+    pub fn new(trace_id: NonZeroU64, span_id: NonZeroU64) -> Self {
+        Self {
+            trace_id,
+            span_id,
+            sampling_priority: None,
+            origin: None,
+            trace_tags: HashMap::new(),
+        }
+    }
+}
+
+/// Read access to the carrier of a propagated span context, e.g. the attributes of an SQS, NATS
+/// or AMQP message. Keys are looked up in lowercase.
+pub trait Extractor {
+    fn get(&self, key: &str) -> Option<&str>;
+}
+
+impl Extractor for HashMap<String, String> {
+    #[inline]
+    fn get(&self, key: &str) -> Option<&str> {
+        HashMap::get(self, key)
+            .or_else(|| {
+                self.iter()
+                    .find(|(k, _)| k.eq_ignore_ascii_case(key))
+                    .map(|(_, v)| v)
+            })
+            .map(String::as_str)
+    }
+}
+
+/// Write access to the carrier of a span context being propagated, e.g. the headers of a
+/// published message.
+pub trait Injector {
+    fn set(&mut self, key: &str, value: String);
+}
+
+impl Injector for HashMap<String, String> {
+    #[inline]
+    fn set(&mut self, key: &str, value: String) {
+        self.insert(String::from(key), value);
+    }
+}
+
+/// Injects a span context as Datadog headers, which `extract` reads back. The origin and trace
+/// tags are only injected when present.
+pub fn inject(context: &SpanContext, injector: &mut impl Injector) {
+    injector.set("x-datadog-trace-id", context.trace_id.to_string());
+    injector.set("x-datadog-parent-id", context.span_id.to_string());
+    if let Some(sampling_priority) = context.sampling_priority {
+        injector.set(
+            "x-datadog-sampling-priority",
+            sampling_priority.as_i32().to_string(),
+        );
+    }
+    if let Some(origin) = &context.origin {
+        injector.set("x-datadog-origin", origin.clone());
+    }
+    if !context.trace_tags.is_empty() {
+        let mut trace_tags: Vec<String> = context
+            .trace_tags
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        trace_tags.sort();
+        injector.set("x-datadog-tags", trace_tags.join(","));
+    }
+}
+
+/// Extracts a span context from Datadog headers (`x-datadog-trace-id`, `x-datadog-parent-id`,
+/// `x-datadog-sampling-priority`, `x-datadog-origin` and `x-datadog-tags`), or else from B3 headers (`b3`, or `x-b3-traceid`,
+/// `x-b3-spanid` and `x-b3-sampled`).
+///
+/// For gRPC-Web and gRPC gateway setups, each header is also looked up with the
+/// `grpc-metadata-` prefix gateways forward metadata with, and as base64 encoded binary
+/// metadata (`<header>-bin`), whose ids may be either text or big-endian integers.
+pub fn extract(extractor: &impl Extractor) -> Option<SpanContext> {
+    extract_datadog(extractor)
+        .or_else(|| extract_b3_single(extractor))
+        .or_else(|| extract_b3_multi(extractor))
+}
+
+// A metadata value, which is binary if it was sent as `-bin` metadata and isn't printable text
+enum MetadataValue<'a> {
+    Text(Cow<'a, str>),
+    Binary(Vec<u8>),
+}
+
+impl MetadataValue<'_> {
+    #[inline]
+    fn text(&self) -> Option<&str> {
+        match self {
+            MetadataValue::Text(text) => Some(text.trim()),
+            MetadataValue::Binary(_) => None,
+        }
+    }
+
+    // Binary ids are big-endian integers, of which only the lower 64 bits are kept
+    #[inline]
+    fn id(&self, parse_text: impl FnOnce(&str) -> Option<NonZeroU64>) -> Option<NonZeroU64> {
+        match self {
+            MetadataValue::Text(text) => parse_text(text.trim()),
+            MetadataValue::Binary(bytes) if bytes.len() <= 16 => {
+                let lower_bytes = &bytes[bytes.len().saturating_sub(8)..];
+                NonZeroU64::new(
+                    lower_bytes
+                        .iter()
+                        .fold(0, |id, byte| (id << 8) | u64::from(*byte)),
+                )
+            }
+            MetadataValue::Binary(_) => None,
+        }
+    }
+}
+
+#[inline]
+fn lookup<'a>(extractor: &'a impl Extractor, key: &str) -> Option<MetadataValue<'a>> {
+    let gateway_key = format!("grpc-metadata-{}", key);
+    if let Some(value) = extractor.get(key).or_else(|| extractor.get(&gateway_key)) {
+        return Some(MetadataValue::Text(Cow::Borrowed(value)));
+    }
+    let binary = extractor
+        .get(&format!("{}-bin", key))
+        .or_else(|| extractor.get(&format!("{}-bin", gateway_key)))?;
+    // gRPC binary metadata is base64 encoded, with or without padding
+    let bytes = base64::decode(binary)?;
+    // small big-endian integers are valid UTF-8 too, so only printable values are text
+    if bytes.iter().all(u8::is_ascii_graphic) {
+        Some(MetadataValue::Text(Cow::Owned(
+            String::from_utf8(bytes).ok()?,
+        )))
+    } else {
+        Some(MetadataValue::Binary(bytes))
+    }
+}
+
+#[inline]
+fn extract_datadog(extractor: &impl Extractor) -> Option<SpanContext> {
+    let parse_decimal = |id: &str| NonZeroU64::from_str(id).ok();
+    Some(SpanContext {
+        trace_id: lookup(extractor, "x-datadog-trace-id")?.id(parse_decimal)?,
+        span_id: lookup(extractor, "x-datadog-parent-id")?.id(parse_decimal)?,
+        sampling_priority: lookup(extractor, "x-datadog-sampling-priority")
+            .and_then(|priority| i32::from_str(priority.text()?).ok())
+            .and_then(SamplingPriority::from_i32),
+        origin: lookup(extractor, "x-datadog-origin")
+            .and_then(|origin| origin.text().map(String::from))
+            .filter(|origin| !origin.is_empty()),
+        trace_tags: lookup(extractor, "x-datadog-tags")
+            .and_then(|tags| tags.text().map(parse_trace_tags))
+            .unwrap_or_default(),
+    })
+}
+
+// x-datadog-tags: _dd.p.dm=-4,_dd.p.usr.id=baz64; tags without the `_dd.p.` prefix are ignored
+#[inline]
+fn parse_trace_tags(tags: &str) -> HashMap<String, String> {
+    tags.split(',')
+        .filter_map(|tag| tag.split_once('='))
+        .map(|(key, value)| (key.trim(), value.trim()))
+        .filter(|(key, _)| key.starts_with(TRACE_TAG_PREFIX) && key.len() > TRACE_TAG_PREFIX.len())
+        .map(|(key, value)| (String::from(key), String::from(value)))
+        .collect()
+}
+
+// b3: {TraceId}-{SpanId}-{SamplingState}-{ParentSpanId}
+#[inline]
+fn extract_b3_single(extractor: &impl Extractor) -> Option<SpanContext> {
+    let b3 = lookup(extractor, "b3")?;
+    let mut parts = b3.text()?.split('-');
+    let trace_id = parse_b3_id(parts.next()?)?;
+    let span_id = parse_b3_id(parts.next()?)?;
+    Some(SpanContext {
+        sampling_priority: parts
+            .next()
+            .and_then(SamplingPriority::from_b3_sampling_state),
+        ..SpanContext::new(trace_id, span_id)
+    })
+}
+
+#[inline]
+fn extract_b3_multi(extractor: &impl Extractor) -> Option<SpanContext> {
+    let debug = lookup(extractor, "x-b3-flags").is_some_and(|flags| flags.text() == Some("1"));
+    let trace_id = lookup(extractor, "x-b3-traceid")?.id(parse_b3_id)?;
+    let span_id = lookup(extractor, "x-b3-spanid")?.id(parse_b3_id)?;
+    Some(SpanContext {
+        sampling_priority: if debug {
+            Some(SamplingPriority::UserKeep)
+        } else {
+            lookup(extractor, "x-b3-sampled")
+                .and_then(|sampled| SamplingPriority::from_b3_sampling_state(sampled.text()?))
+        },
+        ..SpanContext::new(trace_id, span_id)
+    })
+}
+
+// B3 ids are hex encoded, and trace ids may be 128 bits long, of which Datadog keeps the
+// lower 64 bits
+#[inline]
+fn parse_b3_id(id: &str) -> Option<NonZeroU64> {
+    let id = id.trim();
+    let lower_bits = &id[id.len().saturating_sub(16)..];
+    NonZeroU64::new(u64::from_str_radix(lower_bits, 16).ok()?)
+}
+
+/// Tags a span continuing the trace of `context` with its origin and trace tags, which
+/// `span_with_remote_parent!` does for the spans it creates. It has no effect on spans which are
+/// not traced by a `TracingSubscriberDatadog`.
+#[cfg(feature = "client")]
+#[inline]
+pub fn tag_remote_parent(span: &tracing::Span, context: &SpanContext) {
+    with_span_builder(span, |span_builder| {
+        span_builder.propagated_tags(context);
+    });
+}
+
+/// Creates an `INFO` span which continues the trace of a `SpanContext`, e.g. one extracted from
+/// the attributes of a consumed message. The span must be mapped like any other span, and can
+/// declare more fields after the name:
+///
+/// ```ignore
+/// let context = propagation::extract(&message.attributes);
+/// let span = match context {
+///     Some(context) => span_with_remote_parent!(context, "queue.consume", resource = "orders"),
+///     None => tracing::info_span!("queue.consume", resource = "orders"),
+/// };
+/// ```
+#[cfg(feature = "client")]
+#[macro_export]
+macro_rules! span_with_remote_parent {
+    ($context:expr, $name:expr) => {
+        $crate::span_with_remote_parent!($context, $name,)
+    };
+    ($context:expr, $name:expr, $($fields:tt)*) => {{
+        let context: &$crate::propagation::SpanContext = &$context;
+        let span = ::tracing::info_span!(
+            $name,
+            trace_id = context.trace_id.get(),
+            parent_id = context.span_id.get(),
+            sampling_priority = context
+                .sampling_priority
+                .map(|sampling_priority| sampling_priority.as_i32()),
+            $($fields)*
+        );
+        $crate::propagation::tag_remote_parent(&span, context);
+        span
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn carrier(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_extract_datadog() {
+        let context = extract(&carrier(&[
+            ("x-datadog-trace-id", "123"),
+            ("X-Datadog-Parent-Id", "456"),
+            ("x-datadog-sampling-priority", "2"),
+        ]))
+        .unwrap();
+        assert_eq!(context.trace_id.get(), 123);
+        assert_eq!(context.span_id.get(), 456);
+        assert_eq!(context.sampling_priority, Some(SamplingPriority::UserKeep));
+    }
+
+    #[test]
+    fn test_inject_is_extracted_back() {
+        let mut context = SpanContext::new(NonZeroU64::MIN.saturating_add(122), NonZeroU64::MAX);
+        context.sampling_priority = Some(SamplingPriority::UserKeep);
+        context.origin = Some(String::from("synthetics"));
+        context
+            .trace_tags
+            .insert(String::from("_dd.p.dm"), String::from("-4"));
+        context
+            .trace_tags
+            .insert(String::from("_dd.p.usr.id"), String::from("baz64"));
+        let mut carrier = HashMap::new();
+
+        inject(&context, &mut carrier);
+
+        assert_eq!(carrier["x-datadog-trace-id"], "123");
+        assert_eq!(carrier["x-datadog-tags"], "_dd.p.dm=-4,_dd.p.usr.id=baz64");
+        assert_eq!(extract(&carrier), Some(context));
+    }
+
+    #[test]
+    fn test_extract_datadog_origin_and_trace_tags() {
+        let context = extract(&carrier(&[
+            ("x-datadog-trace-id", "123"),
+            ("x-datadog-parent-id", "456"),
+            ("x-datadog-origin", "synthetics"),
+            (
+                "x-datadog-tags",
+                "_dd.p.dm=-4, _dd.p.usr.id=baz64,other=1,_dd.p.=x",
+            ),
+        ]))
+        .unwrap();
+        assert_eq!(context.origin.as_deref(), Some("synthetics"));
+        assert_eq!(
+            context.trace_tags,
+            HashMap::from([
+                (String::from("_dd.p.dm"), String::from("-4")),
+                (String::from("_dd.p.usr.id"), String::from("baz64")),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_span_context_serde() {
+        let mut context =
+            SpanContext::new(NonZeroU64::new(123).unwrap(), NonZeroU64::new(456).unwrap());
+        context.sampling_priority = Some(SamplingPriority::UserKeep);
+        context.origin = Some(String::from("lambda"));
+
+        let json = serde_json::to_string(&context).unwrap();
+
+        assert_eq!(
+            json,
+            r#"{"trace_id":123,"span_id":456,"sampling_priority":2,"origin":"lambda"}"#
+        );
+        assert_eq!(serde_json::from_str::<SpanContext>(&json).unwrap(), context);
+        assert_eq!(
+            serde_json::from_str::<SpanContext>(r#"{"trace_id":1,"span_id":2}"#).unwrap(),
+            SpanContext::new(NonZeroU64::new(1).unwrap(), NonZeroU64::new(2).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_extract_b3_single() {
+        let context = extract(&carrier(&[(
+            "b3",
+            "80f198ee56343ba864fe8b2a57d3eff7-e457b5a2e4d86bd1-d-05e3ac9a4f6e3b90",
+        )]))
+        .unwrap();
+        assert_eq!(context.trace_id.get(), 0x64fe8b2a57d3eff7);
+        assert_eq!(context.span_id.get(), 0xe457b5a2e4d86bd1);
+        assert_eq!(context.sampling_priority, Some(SamplingPriority::UserKeep));
+    }
+
+    #[test]
+    fn test_extract_b3_multi() {
+        let context = extract(&carrier(&[
+            ("x-b3-traceid", "a"),
+            ("x-b3-spanid", "b"),
+            ("x-b3-sampled", "0"),
+        ]))
+        .unwrap();
+        assert_eq!(context.trace_id.get(), 10);
+        assert_eq!(context.span_id.get(), 11);
+        assert_eq!(
+            context.sampling_priority,
+            Some(SamplingPriority::AutoReject)
+        );
+    }
+
+    #[test]
+    fn test_extract_mixed_case() {
+        let context = extract(&carrier(&[
+            ("X-DataDog-Trace-Id", "123"),
+            ("X-DATADOG-PARENT-ID", "456"),
+        ]))
+        .unwrap();
+        assert_eq!(context.trace_id.get(), 123);
+        assert_eq!(context.span_id.get(), 456);
+    }
+
+    #[test]
+    fn test_extract_grpc_gateway_metadata() {
+        let context = extract(&carrier(&[
+            ("Grpc-Metadata-X-Datadog-Trace-Id", "123"),
+            ("Grpc-Metadata-X-Datadog-Parent-Id", "456"),
+            ("Grpc-Metadata-X-Datadog-Sampling-Priority", "1"),
+        ]))
+        .unwrap();
+        assert_eq!(context.trace_id.get(), 123);
+        assert_eq!(context.span_id.get(), 456);
+        assert_eq!(context.sampling_priority, Some(SamplingPriority::AutoKeep));
+    }
+
+    #[test]
+    fn test_extract_base64_text_binary_metadata() {
+        // "123", "456" and "2"
+        let context = extract(&carrier(&[
+            ("x-datadog-trace-id-bin", "MTIz"),
+            ("X-Datadog-Parent-Id-Bin", "NDU2"),
+            ("x-datadog-sampling-priority-bin", "Mg=="),
+        ]))
+        .unwrap();
+        assert_eq!(context.trace_id.get(), 123);
+        assert_eq!(context.span_id.get(), 456);
+        assert_eq!(context.sampling_priority, Some(SamplingPriority::UserKeep));
+    }
+
+    #[test]
+    fn test_extract_base64_integer_binary_metadata() {
+        // 16 byte b3 trace id 0x80f198ee56343ba864fe8b2a57d3eff7 and 8 byte span id 0xb
+        let context = extract(&carrier(&[
+            ("x-b3-traceid-bin", "gPGY7lY0O6hk/osqV9Pv9w"),
+            ("x-b3-spanid-bin", "AAAAAAAAAAs="),
+        ]))
+        .unwrap();
+        assert_eq!(context.trace_id.get(), 0x64fe8b2a57d3eff7);
+        assert_eq!(context.span_id.get(), 11);
+    }
+
+    #[test]
+    fn test_extract_missing_or_invalid() {
+        assert_eq!(extract(&carrier(&[])), None);
+        assert_eq!(extract(&carrier(&[("x-datadog-trace-id", "123")])), None);
+        assert_eq!(
+            extract(&carrier(&[
+                ("x-datadog-trace-id", "0"),
+                ("x-datadog-parent-id", "1")
+            ])),
+            None
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Name of the spans summarizing how many spans were dropped by rate limits.
+pub const DROPPED_SPANS_SPAN_NAME: &str = "_dd.dropped_spans";
+
+/// Metric holding how many spans of the name in the resource were dropped since the last report.
+pub const DROPPED_SPANS_METRIC_KEY: &str = "_dd.dropped_spans";
+
+pub(crate) const DROPPED_SPANS_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+// Allows `rate` spans per second on average, and bursts of up to `burst` spans
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: u32, now: Instant) -> Self {
+        Self {
+            rate: rate.max(0.0),
+            burst: f64::from(burst.max(1)),
+            tokens: f64::from(burst.max(1)),
+            refilled_at: now,
+        }
+    }
+
+    #[inline]
+    fn try_acquire(&mut self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Rate limits the creation of spans per span name, and counts the spans it drops so they can be
+// reported periodically instead of individually
+#[derive(Debug)]
+pub(crate) struct SpanRateLimiter {
+    buckets: HashMap<&'static str, Mutex<TokenBucket>>,
+    dropped: Mutex<HashMap<&'static str, u64>>,
+    reported_at: Mutex<Instant>,
+    report_interval: Duration,
+}
+
+impl SpanRateLimiter {
+    pub(crate) fn new(limits: &[(&'static str, f64, u32)], report_interval: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            buckets: limits
+                .iter()
+                .map(|(name, rate, burst)| {
+                    (*name, Mutex::new(TokenBucket::new(*rate, *burst, now)))
+                })
+                .collect(),
+            dropped: Mutex::new(HashMap::new()),
+            reported_at: Mutex::new(now),
+            report_interval,
+        }
+    }
+
+    /// Whether a span of this name may be created now. Spans which may not are counted as
+    /// dropped.
+    #[inline]
+    pub(crate) fn allow(&self, name: &'static str, now: Instant) -> bool {
+        let Some(bucket) = self.buckets.get(name) else {
+            return true;
+        };
+        let allowed = match bucket.lock() {
+            Ok(mut bucket) => bucket.try_acquire(now),
+            Err(e) => {
+                log::error!(
+                    "Unable to acquire lock on rate limit of {}; err {}",
+                    name,
+                    e
+                );
+                true
+            }
+        };
+        if !allowed {
+            if let Ok(mut dropped) = self.dropped.lock() {
+                *dropped.entry(name).or_default() += 1;
+            }
+        }
+        allowed
+    }
+
+    /// The number of spans dropped per span name since the last report, once the report
+    /// interval has elapsed and if any span was dropped.
+    #[inline]
+    pub(crate) fn take_dropped_if_due(&self, now: Instant) -> Option<Vec<(&'static str, u64)>> {
+        let mut reported_at = self.reported_at.lock().ok()?;
+        if now.saturating_duration_since(*reported_at) < self.report_interval {
+            return None;
+        }
+        *reported_at = now;
+        let mut dropped: Vec<(&'static str, u64)> = self.dropped.lock().ok()?.drain().collect();
+        if dropped.is_empty() {
+            return None;
+        }
+        dropped.sort_unstable();
+        Some(dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allows_bursts_then_the_rate() {
+        let start = Instant::now();
+        let limiter = SpanRateLimiter::new(&[("loop", 10.0, 3)], DROPPED_SPANS_REPORT_INTERVAL);
+
+        let allowed = (0..5).filter(|_| limiter.allow("loop", start)).count();
+        assert_eq!(allowed, 3);
+
+        // 10 spans per second refill a token every 100ms
+        assert!(limiter.allow("loop", start + Duration::from_millis(100)));
+        assert!(!limiter.allow("loop", start + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn test_spans_without_limit_are_allowed() {
+        let limiter = SpanRateLimiter::new(&[("loop", 0.0, 1)], DROPPED_SPANS_REPORT_INTERVAL);
+
+        assert!((0..100).all(|_| limiter.allow("request", Instant::now())));
+    }
+
+    #[test]
+    fn test_dropped_spans_are_reported_once_per_interval() {
+        let limiter = SpanRateLimiter::new(&[("loop", 0.0, 1)], Duration::from_secs(10));
+        let start = Instant::now();
+        (0..4).for_each(|_| {
+            limiter.allow("loop", start);
+        });
+
+        assert_eq!(limiter.take_dropped_if_due(start), None);
+        assert_eq!(
+            limiter.take_dropped_if_due(start + Duration::from_secs(10)),
+            Some(vec![("loop", 3)])
+        );
+        assert_eq!(
+            limiter.take_dropped_if_due(start + Duration::from_secs(20)),
+            None
+        );
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::HashSet;
+use std::fmt::Debug;
+use tracing::field::{Field, Visit};
+
+/// The value recorded in place of the values of redacted fields
+pub const REDACTED: &str = "[REDACTED]";
+
+const DEFAULT_REDACTED_FIELDS: [&str; 2] = ["password", "ssn"];
+
+/// A deny-list of field names whose values are never recorded into spans or span events, but
+/// replaced with `[REDACTED]`, whichever span or event declares them. It's enforced by the
+/// subscriber, so a team adding a `password` field to a span doesn't leak it into APM.
+///
+/// By default, the `password` and `ssn` fields are redacted.
+#[derive(Debug, Clone)]
+pub struct FieldRedactor {
+    fields: HashSet<String>,
+}
+
+impl Default for FieldRedactor {
+    fn default() -> Self {
+        Self {
+            fields: DEFAULT_REDACTED_FIELDS
+                .into_iter()
+                .map(String::from)
+                .collect(),
+        }
+    }
+}
+
+impl FieldRedactor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A redactor without the default fields, which redacts nothing until fields are added.
+    pub fn empty() -> Self {
+        Self {
+            fields: HashSet::new(),
+        }
+    }
+
+    /// Redacts the values of the `name` field.
+    pub fn field(mut self, name: impl Into<String>) -> Self {
+        self.fields.insert(name.into());
+        self
+    }
+
+    #[inline]
+    pub fn is_redacted(&self, name: &str) -> bool {
+        self.fields.contains(name)
+    }
+
+    // Wraps a visitor, so it records `[REDACTED]` instead of the values of redacted fields
+    #[inline]
+    pub(crate) fn redacting<'a, V: Visit>(&'a self, visitor: &'a mut V) -> Redacting<'a, V> {
+        Redacting {
+            redactor: self,
+            visitor,
+        }
+    }
+}
+
+pub(crate) struct Redacting<'a, V> {
+    redactor: &'a FieldRedactor,
+    visitor: &'a mut V,
+}
+
+impl<V: Visit> Redacting<'_, V> {
+    // Records `[REDACTED]` for a redacted field, returning whether it was redacted
+    #[inline]
+    fn redacts(&mut self, field: &Field) -> bool {
+        let redacted = self.redactor.is_redacted(field.name());
+        if redacted {
+            self.visitor.record_str(field, REDACTED);
+        }
+        redacted
+    }
+}
+
+impl<V: Visit> Visit for Redacting<'_, V> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        if !self.redacts(field) {
+            self.visitor.record_f64(field, value);
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if !self.redacts(field) {
+            self.visitor.record_i64(field, value);
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if !self.redacts(field) {
+            self.visitor.record_u64(field, value);
+        }
+    }
+
+    fn record_i128(&mut self, field: &Field, value: i128) {
+        if !self.redacts(field) {
+            self.visitor.record_i128(field, value);
+        }
+    }
+
+    fn record_u128(&mut self, field: &Field, value: u128) {
+        if !self.redacts(field) {
+            self.visitor.record_u128(field, value);
+        }
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if !self.redacts(field) {
+            self.visitor.record_bool(field, value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if !self.redacts(field) {
+            self.visitor.record_str(field, value);
+        }
+    }
+
+    fn record_bytes(&mut self, field: &Field, value: &[u8]) {
+        if !self.redacts(field) {
+            self.visitor.record_bytes(field, value);
+        }
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        if !self.redacts(field) {
+            self.visitor.record_error(field, value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if !self.redacts(field) {
+            self.visitor.record_debug(field, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tracing::field::FieldSet;
+    use tracing::metadata::Kind;
+    use tracing::{callsite, Level, Metadata};
+
+    struct Callsite;
+
+    impl callsite::Callsite for Callsite {
+        fn set_interest(&self, _interest: tracing::subscriber::Interest) {}
+
+        fn metadata(&self) -> &Metadata<'_> {
+            &METADATA
+        }
+    }
+
+    static CALLSITE: Callsite = Callsite;
+    static METADATA: Metadata<'static> = Metadata::new(
+        "test",
+        "test",
+        Level::INFO,
+        None,
+        None,
+        None,
+        FieldSet::new(
+            &["password", "user", "ssn"],
+            callsite::Identifier(&CALLSITE),
+        ),
+        Kind::EVENT,
+    );
+
+    #[derive(Default)]
+    struct Recorder(HashMap<String, String>);
+
+    impl Visit for Recorder {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0
+                .insert(String::from(field.name()), format!("{:?}", value));
+        }
+
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0
+                .insert(String::from(field.name()), String::from(value));
+        }
+    }
+
+    fn record(redactor: &FieldRedactor) -> HashMap<String, String> {
+        let fields = METADATA.fields();
+        let field = |name| fields.field(name).unwrap();
+        let mut recorder = Recorder::default();
+        let mut redacting = redactor.redacting(&mut recorder);
+        redacting.record_str(&field("password"), "hunter2");
+        redacting.record_str(&field("user"), "alice");
+        redacting.record_u64(&field("ssn"), 123456789);
+        recorder.0
+    }
+
+    #[test]
+    fn test_default_fields_are_redacted() {
+        let recorded = record(&FieldRedactor::new());
+
+        assert_eq!(recorded["password"], REDACTED);
+        assert_eq!(recorded["ssn"], REDACTED);
+        assert_eq!(recorded["user"], "alice");
+    }
+
+    #[test]
+    fn test_added_fields_are_redacted() {
+        let recorded = record(&FieldRedactor::empty().field("user"));
+
+        assert_eq!(recorded["password"], "hunter2");
+        assert_eq!(recorded["ssn"], "123456789");
+        assert_eq!(recorded["user"], REDACTED);
+    }
+}
@@ -0,0 +1,64 @@
+use super::sampling::keeps;
+use std::collections::HashMap;
+use std::num::NonZeroU64;
+
+// Traces only a percentage of the traces of some span names, to introduce heavy instrumentation
+// gradually. The decision is derived from the trace id, so it is the same for every span of a
+// trace with the same name.
+#[derive(Debug)]
+pub(crate) struct Rollouts {
+    percentages: HashMap<&'static str, f64>,
+}
+
+impl Rollouts {
+    pub(crate) fn new(percentages: HashMap<&'static str, f64>) -> Self {
+        Self { percentages }
+    }
+
+    /// Whether spans named `span_name` are traced in the trace `trace_id`.
+    #[inline]
+    pub(crate) fn includes(&self, span_name: &str, trace_id: NonZeroU64) -> bool {
+        match self.percentages.get(span_name) {
+            Some(percent) => rolled_out(*percent, trace_id),
+            None => true,
+        }
+    }
+}
+
+#[inline]
+fn rolled_out(percent: f64, trace_id: NonZeroU64) -> bool {
+    keeps(trace_id.get(), percent / 100.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::span::generate_id;
+
+    #[test]
+    fn test_unlisted_names_are_fully_rolled_out() {
+        let rollouts = Rollouts::new(HashMap::from([("db.query", 0.0)]));
+
+        assert!(rollouts.includes("request", generate_id()));
+        assert!(!rollouts.includes("db.query", generate_id()));
+    }
+
+    #[test]
+    fn test_decision_is_consistent_per_trace() {
+        let rollouts = Rollouts::new(HashMap::from([("db.query", 50.0)]));
+        let trace_id = generate_id();
+
+        let decision = rollouts.includes("db.query", trace_id);
+
+        assert!((0..10).all(|_| rollouts.includes("db.query", trace_id) == decision));
+    }
+
+    #[test]
+    fn test_share_of_rolled_out_traces() {
+        let rolled_out_traces = (0..10_000)
+            .filter(|_| rolled_out(10.0, generate_id()))
+            .count();
+
+        assert!((800..1200).contains(&rolled_out_traces));
+    }
+}
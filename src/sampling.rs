@@ -0,0 +1,273 @@
+use super::span::{SpanBuilder, SpanMetaKey};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tracing::Metadata;
+
+/// Datadog sampling priorities. A trace whose priority is a "keep" priority is kept by the
+/// agent, while a trace with a "reject" priority is only counted in the stats of its service
+/// before the agent drops it.
+///
+/// The `User*` variants signal a decision made by user code (e.g. business rules), while the
+/// `Auto*` variants signal a decision made by an automatic (e.g. rate-based) sampler.
+///
+/// Priorities are serialized as their integer value, like in the `x-datadog-sampling-priority`
+/// header.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "i32", try_from = "i32")]
+pub enum SamplingPriority {
+    UserReject,
+    AutoReject,
+    AutoKeep,
+    UserKeep,
+}
+
+impl SamplingPriority {
+    #[inline]
+    pub fn as_i32(&self) -> i32 {
+        match *self {
+            SamplingPriority::UserReject => -1,
+            SamplingPriority::AutoReject => 0,
+            SamplingPriority::AutoKeep => 1,
+            SamplingPriority::UserKeep => 2,
+        }
+    }
+
+    #[inline]
+    pub fn from_i32(priority: i32) -> Option<Self> {
+        match priority {
+            -1 => Some(SamplingPriority::UserReject),
+            0 => Some(SamplingPriority::AutoReject),
+            1 => Some(SamplingPriority::AutoKeep),
+            2 => Some(SamplingPriority::UserKeep),
+            _ => None,
+        }
+    }
+
+    /// Maps the sampling state of a b3 header to a sampling priority: `0` (deny) to
+    /// `AutoReject`, `1` (accept) to `AutoKeep` and `d` (debug) to `UserKeep`.
+    #[inline]
+    pub fn from_b3_sampling_state(state: &str) -> Option<Self> {
+        match state.trim() {
+            "0" => Some(SamplingPriority::AutoReject),
+            "1" => Some(SamplingPriority::AutoKeep),
+            "d" => Some(SamplingPriority::UserKeep),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn is_keep(&self) -> bool {
+        matches!(
+            self,
+            SamplingPriority::AutoKeep | SamplingPriority::UserKeep
+        )
+    }
+}
+
+impl From<SamplingPriority> for i32 {
+    #[inline]
+    fn from(priority: SamplingPriority) -> Self {
+        priority.as_i32()
+    }
+}
+
+impl TryFrom<i32> for SamplingPriority {
+    type Error = String;
+
+    #[inline]
+    fn try_from(priority: i32) -> Result<Self, Self::Error> {
+        Self::from_i32(priority).ok_or_else(|| format!("Invalid sampling priority: {}", priority))
+    }
+}
+
+/// A Sampler decides whether a trace is kept or dropped. It is invoked once per trace, when
+/// the root span is created, and the decision is inherited by every child span of that trace.
+///
+/// Any `Fn(&SpanBuilder, &Metadata) -> SamplingPriority` closure can be used as a Sampler.
+pub trait Sampler: Send + Sync {
+    fn should_sample(&self, span: &SpanBuilder, metadata: &Metadata<'_>) -> SamplingPriority;
+}
+
+impl<F> Sampler for F
+where
+    F: Fn(&SpanBuilder, &Metadata<'_>) -> SamplingPriority + Send + Sync,
+{
+    #[inline]
+    fn should_sample(&self, span: &SpanBuilder, metadata: &Metadata<'_>) -> SamplingPriority {
+        self(span, metadata)
+    }
+}
+
+// Key of the rate the agent applies to services it has no rate for
+const DEFAULT_RATE_KEY: &str = "service:,env:";
+
+// Spreads trace ids over the whole `u64` range before comparing them to a rate, like the agent
+// and the other Datadog tracers do, so they all keep the same traces
+const KNUTH_FACTOR: u64 = 1_111_111_111_111_111_111;
+
+/// The sample rates per service and env which the agent returns in its `rate_by_service`
+/// response to trace payloads, so that sampling adapts to the number of traces per second the
+/// agent targets. The rates are updated by the daemon of the `Client` they come from, see
+/// `Client::agent_sample_rates`, and are sampled at by an `AgentRateSampler`.
+#[derive(Debug, Default)]
+pub struct AgentSampleRates {
+    rates: RwLock<HashMap<String, f64>>,
+}
+
+impl AgentSampleRates {
+    /// The rate of traces of `service` in `env`, or the default rate of the agent, or `None`
+    /// until the agent returns rates.
+    pub fn rate(&self, service: &str, env: &str) -> Option<f64> {
+        let rates = self.rates.read().ok()?;
+        rates
+            .get(&format!("service:{},env:{}", service, env))
+            .or_else(|| rates.get(DEFAULT_RATE_KEY))
+            .copied()
+    }
+
+    // Replaces the rates with those of a response of the agent, which has none when the
+    // payload was sent to the intake or to an agent too old to sample
+    #[cfg_attr(not(feature = "client"), allow(dead_code))]
+    pub(crate) fn update(&self, response: &[u8]) {
+        #[derive(Deserialize)]
+        struct AgentResponse {
+            rate_by_service: HashMap<String, f64>,
+        }
+
+        let Ok(response) = serde_json::from_slice::<AgentResponse>(response) else {
+            return;
+        };
+        match self.rates.write() {
+            Ok(mut rates) => *rates = response.rate_by_service,
+            Err(e) => log::error!("Failed to get lock on agent sample rates; err {:?}", e),
+        }
+    }
+}
+
+/// A Sampler keeping root spans at the rates the agent returns for their service and env, like
+/// the default sampling of other Datadog tracers. Traces are kept until the agent has returned
+/// rates.
+///
+/// ```ignore
+/// let client = Client::create_default();
+/// let sampler = AgentRateSampler::new(client.agent_sample_rates());
+/// let subscriber = TracingSubscriberDatadog::new(
+///     client,
+///     TracingSubscriberDatadogConfig::new().sampler(sampler),
+/// );
+/// ```
+pub struct AgentRateSampler {
+    rates: Arc<AgentSampleRates>,
+}
+
+impl AgentRateSampler {
+    pub fn new(rates: Arc<AgentSampleRates>) -> Self {
+        Self { rates }
+    }
+}
+
+impl Sampler for AgentRateSampler {
+    fn should_sample(&self, span: &SpanBuilder, _: &Metadata<'_>) -> SamplingPriority {
+        let env = span
+            .meta_value(&SpanMetaKey::Env.to_string())
+            .unwrap_or_default();
+        match self.rates.rate(span.service_name(), env) {
+            Some(rate) if !keeps(span.trace_id.get(), rate) => SamplingPriority::AutoReject,
+            _ => SamplingPriority::AutoKeep,
+        }
+    }
+}
+
+// Whether a trace is kept at `rate`, deterministically for its id, so that the agent and the other
+// Datadog tracers keep the same traces; no trace is kept at a rate of `NaN`
+#[inline]
+pub(crate) fn keeps(trace_id: u64, rate: f64) -> bool {
+    rate >= 1.0 || (trace_id.wrapping_mul(KNUTH_FACTOR) as f64) < rate * u64::MAX as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_agent_sample_rates() {
+        let rates = AgentSampleRates::default();
+        assert_eq!(rates.rate("web", "prod"), None);
+
+        rates.update(br#"{"rate_by_service": {"service:,env:": 1, "service:web,env:prod": 0.25}}"#);
+        rates.update(b"OK");
+
+        assert_eq!(rates.rate("web", "prod"), Some(0.25));
+        assert_eq!(rates.rate("web", "staging"), Some(1.0));
+    }
+
+    #[test]
+    fn test_keeps_traces_at_rate() {
+        let kept = (1..=10_000u64)
+            .filter(|trace_id| keeps(*trace_id, 0.25))
+            .count();
+        assert!((2_000..3_000).contains(&kept), "{}", kept);
+        assert!(keeps(42, 1.0));
+        assert!(!keeps(42, 0.0));
+    }
+
+    #[test]
+    fn test_sampling_priority_values() {
+        assert_eq!(SamplingPriority::UserReject.as_i32(), -1);
+        assert_eq!(SamplingPriority::AutoReject.as_i32(), 0);
+        assert_eq!(SamplingPriority::AutoKeep.as_i32(), 1);
+        assert_eq!(SamplingPriority::UserKeep.as_i32(), 2);
+    }
+
+    #[test]
+    fn test_sampling_priority_from_i32() {
+        assert_eq!(
+            SamplingPriority::from_i32(-1),
+            Some(SamplingPriority::UserReject)
+        );
+        assert_eq!(
+            SamplingPriority::from_i32(2),
+            Some(SamplingPriority::UserKeep)
+        );
+        assert_eq!(SamplingPriority::from_i32(3), None);
+    }
+
+    #[test]
+    fn test_sampling_priority_from_b3_sampling_state() {
+        assert_eq!(
+            SamplingPriority::from_b3_sampling_state("0"),
+            Some(SamplingPriority::AutoReject)
+        );
+        assert_eq!(
+            SamplingPriority::from_b3_sampling_state("1"),
+            Some(SamplingPriority::AutoKeep)
+        );
+        assert_eq!(
+            SamplingPriority::from_b3_sampling_state("d"),
+            Some(SamplingPriority::UserKeep)
+        );
+        assert_eq!(SamplingPriority::from_b3_sampling_state("true"), None);
+    }
+
+    #[test]
+    fn test_sampling_priority_serde() {
+        assert_eq!(
+            serde_json::to_string(&SamplingPriority::UserReject).unwrap(),
+            "-1"
+        );
+        assert_eq!(
+            serde_json::from_str::<SamplingPriority>("2").unwrap(),
+            SamplingPriority::UserKeep
+        );
+        assert!(serde_json::from_str::<SamplingPriority>("3").is_err());
+    }
+
+    #[test]
+    fn test_sampling_priority_is_keep() {
+        assert!(!SamplingPriority::UserReject.is_keep());
+        assert!(!SamplingPriority::AutoReject.is_keep());
+        assert!(SamplingPriority::AutoKeep.is_keep());
+        assert!(SamplingPriority::UserKeep.is_keep());
+    }
+}
@@ -0,0 +1,159 @@
+// Self-instrumentation of the exporter: the spans of each batch of traces (serializing it,
+// sending it to the agent) are reported under a dedicated service, to see exporter latency and
+// failures in Datadog itself.
+//
+// The exporter must not trace itself through `tracing`, or every batch would produce spans
+// producing another batch. Its spans are built directly instead, kept until the next batch with
+// application traces, and sent along with it. Batches are only traced when they contain
+// application traces, so the exporter's own traces never cause a batch of their own.
+
+use super::span::{ServiceName, SpanBuilder, SpanName, SpanType, Trace};
+use std::time::{Duration, SystemTime};
+
+pub(crate) const PAYLOAD_SPAN_NAME: SpanName = SpanName("datadog_exporter.payload");
+pub(crate) const SERIALIZE_SPAN_NAME: SpanName = SpanName("datadog_exporter.serialize");
+pub(crate) const SEND_SPAN_NAME: SpanName = SpanName("datadog_exporter.send");
+
+pub(crate) const PAYLOAD_SIZE_METRIC_KEY: &str = "payload.size_bytes";
+pub(crate) const PAYLOAD_TRACES_METRIC_KEY: &str = "payload.traces";
+
+// Exporter traces are dropped rather than piling up while the agent is unreachable
+const MAX_PENDING_TRACES: usize = 100;
+
+pub(crate) struct ExporterTracer {
+    service: ServiceName,
+    pending: Vec<Trace>,
+}
+
+impl ExporterTracer {
+    pub(crate) fn new(service: ServiceName) -> Self {
+        Self {
+            service,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Starts tracing a batch, adding the exporter traces of previous batches to it. Returns
+    /// `None` for batches without application traces, which are left untouched.
+    #[inline]
+    pub(crate) fn trace_payload(
+        &mut self,
+        trace_json: &mut serde_json::Value,
+    ) -> Option<PayloadTrace> {
+        let traces = trace_json
+            .as_array_mut()
+            .filter(|traces| !traces.is_empty())?;
+        let application_traces = traces.len();
+        traces.extend(
+            self.pending
+                .drain(..)
+                .filter_map(|trace| serde_json::to_value(trace).ok()),
+        );
+        let mut root = SpanBuilder::default();
+        root.name(PAYLOAD_SPAN_NAME)
+            .service(self.service)
+            .resource(String::from(PAYLOAD_SPAN_NAME.0))
+            .span_type(SpanType::Custom);
+        root.metrics_mut().insert(
+            String::from(PAYLOAD_TRACES_METRIC_KEY),
+            application_traces as f64,
+        );
+        Some(PayloadTrace {
+            service: self.service,
+            root,
+            phases: Vec::new(),
+        })
+    }
+
+    /// Ends the trace of a batch, to be sent with the next one.
+    #[inline]
+    pub(crate) fn finish(&mut self, mut payload_trace: PayloadTrace, size: usize, delivered: bool) {
+        if self.pending.len() >= MAX_PENDING_TRACES {
+            return;
+        }
+        payload_trace.root.error(!delivered);
+        payload_trace
+            .root
+            .metrics_mut()
+            .insert(String::from(PAYLOAD_SIZE_METRIC_KEY), size as f64);
+        let mut trace = vec![payload_trace.root.build()];
+        trace.extend(
+            payload_trace
+                .phases
+                .iter()
+                .map(|(phase, duration)| phase.build_with_duration(*duration)),
+        );
+        self.pending.push(trace);
+    }
+}
+
+// The spans of a payload being exported
+pub(crate) struct PayloadTrace {
+    service: ServiceName,
+    root: SpanBuilder,
+    phases: Vec<(SpanBuilder, Duration)>,
+}
+
+impl PayloadTrace {
+    /// Records a phase of the export which ran from `start` to `end`, as a child of the payload.
+    #[inline]
+    pub(crate) fn phase(
+        &mut self,
+        name: SpanName,
+        start: SystemTime,
+        end: SystemTime,
+    ) -> &mut SpanBuilder {
+        let mut phase = SpanBuilder::default();
+        phase
+            .name(name)
+            .service(self.service)
+            .resource(String::from(name.0))
+            .span_type(SpanType::Custom)
+            .trace_id(self.root.trace_id)
+            .parent_id(self.root.span_id)
+            .start(start);
+        let duration = end.duration_since(start).unwrap_or_default();
+        self.phases.push((phase, duration));
+        &mut self.phases.last_mut().expect("phase was just pushed").0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payloads_without_application_traces_are_not_traced() {
+        let mut tracer = ExporterTracer::new(ServiceName("exporter"));
+        let mut trace_json = serde_json::json!([]);
+
+        assert!(tracer.trace_payload(&mut trace_json).is_none());
+        assert_eq!(trace_json, serde_json::json!([]));
+    }
+
+    #[test]
+    fn test_payload_trace_is_sent_with_the_next_payload() {
+        // given
+        let mut tracer = ExporterTracer::new(ServiceName("exporter"));
+        let mut first = serde_json::json!([[{"name": "app"}]]);
+        let mut payload_trace = tracer.trace_payload(&mut first).unwrap();
+        let now = SystemTime::now();
+        payload_trace.phase(SERIALIZE_SPAN_NAME, now, now + Duration::from_millis(2));
+        tracer.finish(payload_trace, 42, false);
+
+        // when
+        let mut second = serde_json::json!([[{"name": "app"}]]);
+        tracer.trace_payload(&mut second).unwrap();
+
+        // then
+        assert_eq!(first.as_array().unwrap().len(), 1);
+        let exporter_trace = &second[1];
+        assert_eq!(exporter_trace[0]["name"], PAYLOAD_SPAN_NAME.0);
+        assert_eq!(exporter_trace[0]["error"], 1);
+        assert_eq!(exporter_trace[0]["metrics"][PAYLOAD_SIZE_METRIC_KEY], 42.0);
+        assert_eq!(exporter_trace[1]["name"], SERIALIZE_SPAN_NAME.0);
+        assert_eq!(exporter_trace[1]["parent_id"], exporter_trace[0]["span_id"]);
+        assert_eq!(exporter_trace[1]["duration"], 2_000_000);
+        assert!(tracer.pending.is_empty());
+    }
+}
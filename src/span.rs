@@ -0,0 +1,1557 @@
+use super::base64;
+#[cfg(feature = "http")]
+use super::http_status::HttpStatusClassifier;
+use super::propagation::{SpanContext, ORIGIN_TAG};
+use super::sampling::SamplingPriority;
+#[cfg(feature = "client")]
+use rand::Rng;
+use serde::de::{self, Visitor};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::num::NonZeroU64;
+use std::str::FromStr;
+#[cfg(not(feature = "client"))]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const SAMPLING_PRIORITY_METRIC_KEY: &str = "_sampling_priority_v1";
+pub const PROGRESS_ITEMS_PROCESSED_METRIC_KEY: &str = "progress.items_processed";
+pub const PROGRESS_ITEMS_TOTAL_METRIC_KEY: &str = "progress.items_total";
+pub const PROGRESS_PERCENT_METRIC_KEY: &str = "progress.percent";
+
+pub type Traces = Vec<Trace>;
+
+pub type Trace = Vec<Span>;
+
+/// Why an exporter failed to export traces
+pub type ExportError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Receives finished traces. The span model has no I/O of its own, so a host embedding spans
+/// built elsewhere (like in a wasm guest), or a backend of its own like an internal collector,
+/// can export them however it likes, for example by passing them to
+/// `Client::create_with_exporter`. `datadog_client::AgentExporter` sends them to the Datadog
+/// agent. Closures taking `Traces` are exporters which never fail.
+pub trait SpanExporter: Send + Sync {
+    fn export(&self, traces: Traces) -> Result<(), ExportError>;
+}
+
+impl<F: Fn(Traces) + Send + Sync> SpanExporter for F {
+    #[inline]
+    fn export(&self, traces: Traces) -> Result<(), ExportError> {
+        self(traces);
+        Ok(())
+    }
+}
+
+impl SpanExporter for Box<dyn SpanExporter> {
+    #[inline]
+    fn export(&self, traces: Traces) -> Result<(), ExportError> {
+        (**self).export(traces)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Span {
+    duration: u64,
+    #[serde(default)]
+    error: u32,
+    #[serde(default)]
+    meta: Meta,
+    #[serde(
+        default,
+        skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "serialize_meta_struct",
+        deserialize_with = "deserialize_meta_struct"
+    )]
+    meta_struct: HashMap<String, Vec<u8>>,
+    #[serde(default)]
+    metrics: HashMap<String, f64>,
+    name: Cow<'static, str>,
+    #[serde(default, deserialize_with = "deserialize_parent_id")]
+    parent_id: Option<u64>,
+    #[serde(default)]
+    resource: String,
+    #[serde(default)]
+    service: Cow<'static, str>,
+    span_id: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    span_links: Vec<SpanLink>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    span_events: Vec<SpanEvent>,
+    start: u64,
+    trace_id: u64,
+    #[serde(default)]
+    r#type: Cow<'static, str>,
+}
+
+// `meta_struct` values are msgpack documents, which are bytes in msgpack payloads and base64
+// strings in JSON ones, like the agent's JSON decoder expects
+fn serialize_meta_struct<S: Serializer>(
+    meta_struct: &HashMap<String, Vec<u8>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    struct Bytes<'a>(&'a [u8]);
+
+    impl Serialize for Bytes<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_bytes(self.0)
+        }
+    }
+
+    let human_readable = serializer.is_human_readable();
+    let mut map = serializer.serialize_map(Some(meta_struct.len()))?;
+    for (key, value) in meta_struct {
+        if human_readable {
+            map.serialize_entry(key, &base64::encode(value))?;
+        } else {
+            map.serialize_entry(key, &Bytes(value))?;
+        }
+    }
+    map.end()
+}
+
+fn deserialize_meta_struct<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, Vec<u8>>, D::Error> {
+    struct MetaStructValue(Vec<u8>);
+
+    impl<'de> Deserialize<'de> for MetaStructValue {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_any(MetaStructValueVisitor)
+        }
+    }
+
+    struct MetaStructValueVisitor;
+
+    impl<'de> Visitor<'de> for MetaStructValueVisitor {
+        type Value = MetaStructValue;
+
+        fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+            f.write_str("bytes or a base64 string")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+            base64::decode(value)
+                .map(MetaStructValue)
+                .ok_or_else(|| E::invalid_value(de::Unexpected::Str(value), &self))
+        }
+
+        fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<Self::Value, E> {
+            Ok(MetaStructValue(value.to_vec()))
+        }
+
+        fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut bytes = Vec::new();
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(MetaStructValue(bytes))
+        }
+    }
+
+    Ok(
+        HashMap::<String, MetaStructValue>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(key, value)| (key, value.0))
+            .collect(),
+    )
+}
+
+// The agent format uses a parent id of `0` for root spans
+fn deserialize_parent_id<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    Ok(Option::<u64>::deserialize(deserializer)?.filter(|parent_id| *parent_id != 0))
+}
+
+// Bytes of the JSON of a span besides its strings, tags and links: keys, punctuation and
+// numbers of up to 20 digits
+const SPAN_WIRE_OVERHEAD: usize = 230;
+const TAG_WIRE_OVERHEAD: usize = 6;
+const METRIC_WIRE_OVERHEAD: usize = 28;
+const SPAN_LINK_WIRE_OVERHEAD: usize = 80;
+const SPAN_EVENT_WIRE_OVERHEAD: usize = 50;
+
+/// Estimates the size of the JSON payload of a trace, as the sum of the estimated wire sizes of
+/// its spans.
+#[inline]
+pub fn estimated_trace_size(trace: &Trace) -> usize {
+    trace.iter().map(Span::estimated_wire_size).sum::<usize>() + 2
+}
+
+impl Span {
+    /// Estimates how many bytes the span takes in a JSON payload, without serializing it. The
+    /// estimate accounts for every string, tag and metric of the span, but not for escaped
+    /// characters.
+    #[inline]
+    pub fn estimated_wire_size(&self) -> usize {
+        let tags = |tags: &HashMap<String, String>| {
+            tags.iter()
+                .map(|(key, value)| key.len() + value.len() + TAG_WIRE_OVERHEAD)
+                .sum::<usize>()
+        };
+        SPAN_WIRE_OVERHEAD
+            + self.name.len()
+            + self.service.len()
+            + self.resource.len()
+            + self.r#type.len()
+            + self
+                .meta
+                .iter()
+                .map(|(key, value)| key.len() + value.len() + TAG_WIRE_OVERHEAD)
+                .sum::<usize>()
+            + self
+                .meta_struct
+                .iter()
+                .map(|(key, value)| key.len() + value.len().div_ceil(3) * 4 + TAG_WIRE_OVERHEAD)
+                .sum::<usize>()
+            + self
+                .metrics
+                .keys()
+                .map(|key| key.len() + METRIC_WIRE_OVERHEAD)
+                .sum::<usize>()
+            + self
+                .span_links
+                .iter()
+                .map(|span_link| SPAN_LINK_WIRE_OVERHEAD + tags(&span_link.attributes))
+                .sum::<usize>()
+            + self
+                .span_events
+                .iter()
+                .map(|span_event| {
+                    SPAN_EVENT_WIRE_OVERHEAD + span_event.name.len() + tags(&span_event.attributes)
+                })
+                .sum::<usize>()
+    }
+
+    #[inline]
+    pub fn trace_id(&self) -> u64 {
+        self.trace_id
+    }
+
+    #[inline]
+    pub fn span_id(&self) -> u64 {
+        self.span_id
+    }
+
+    #[inline]
+    pub fn parent_id(&self) -> Option<u64> {
+        self.parent_id
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    #[inline]
+    pub fn resource(&self) -> &str {
+        &self.resource
+    }
+
+    #[inline]
+    pub fn span_type(&self) -> &str {
+        &self.r#type
+    }
+
+    /// Start of the span, in nanoseconds since the Unix epoch
+    #[inline]
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Duration of the span, in nanoseconds
+    #[inline]
+    pub fn duration(&self) -> u64 {
+        self.duration
+    }
+
+    #[inline]
+    pub fn is_error(&self) -> bool {
+        self.error != 0
+    }
+
+    #[inline]
+    pub fn meta(&self) -> &Meta {
+        &self.meta
+    }
+
+    #[inline]
+    pub fn metrics(&self) -> &HashMap<String, f64> {
+        &self.metrics
+    }
+
+    /// Structured data of the span, as msgpack documents
+    #[inline]
+    pub fn meta_struct(&self) -> &HashMap<String, Vec<u8>> {
+        &self.meta_struct
+    }
+
+    /// Events which happened during the span, in the order they happened
+    #[inline]
+    pub fn span_events(&self) -> &[SpanEvent] {
+        &self.span_events
+    }
+}
+
+/// The tags of a span: a layer of tags shared by many spans, like the `service`, `env` and
+/// `version` tags of the process, which spans point to instead of copying, overlaid with the
+/// span's own tags. It reads and serializes like a single map, in which the span's own tags win.
+#[derive(Clone, Default)]
+pub struct Meta {
+    shared: Arc<HashMap<String, String>>,
+    own: HashMap<String, String>,
+}
+
+impl Meta {
+    #[inline]
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.own.get(key).or_else(|| self.shared.get(key))
+    }
+
+    #[inline]
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.own.contains_key(key) || self.shared.contains_key(key)
+    }
+
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.shared
+            .iter()
+            .filter(|(key, _)| !self.own.contains_key(*key))
+            .chain(self.own.iter())
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.own.is_empty() && self.shared.is_empty()
+    }
+}
+
+impl From<HashMap<String, String>> for Meta {
+    #[inline]
+    fn from(own: HashMap<String, String>) -> Self {
+        Self {
+            shared: Arc::default(),
+            own,
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a Meta {
+    type Item = (&'a String, &'a String);
+    type IntoIter = Box<dyn Iterator<Item = Self::Item> + 'a>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(self.iter())
+    }
+}
+
+impl std::ops::Index<&str> for Meta {
+    type Output = String;
+
+    #[inline]
+    fn index(&self, key: &str) -> &String {
+        self.get(key).expect("no meta for key")
+    }
+}
+
+impl PartialEq for Meta {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len()
+            && self
+                .iter()
+                .all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+impl PartialEq<HashMap<String, String>> for Meta {
+    #[inline]
+    fn eq(&self, other: &HashMap<String, String>) -> bool {
+        self.len() == other.len()
+            && self
+                .iter()
+                .all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+impl Debug for Meta {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl Serialize for Meta {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.len()))?;
+        for (key, value) in self.iter() {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Meta {
+    #[inline]
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        HashMap::deserialize(deserializer).map(Self::from)
+    }
+}
+
+/// Errors from decoding traces with `TracesDecode`.
+#[derive(Debug)]
+pub enum DecodeError {
+    Json(serde_json::Error),
+    Msgpack(rmp_serde::decode::Error),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Json(e) => write!(f, "invalid JSON traces: {}", e),
+            DecodeError::Msgpack(e) => write!(f, "invalid msgpack traces: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecodeError::Json(e) => Some(e),
+            DecodeError::Msgpack(e) => Some(e),
+        }
+    }
+}
+
+/// Decodes traces from payloads in the format of the Datadog agent (e.g. captured with a proxy
+/// in front of the agent), so they can be inspected, or re-exported with `Client::send_traces`.
+/// The decoded spans own all of their strings.
+pub trait TracesDecode: Sized {
+    fn from_json(bytes: &[u8]) -> Result<Self, DecodeError>;
+
+    fn from_msgpack(bytes: &[u8]) -> Result<Self, DecodeError>;
+}
+
+impl TracesDecode for Traces {
+    #[inline]
+    fn from_json(bytes: &[u8]) -> Result<Self, DecodeError> {
+        serde_json::from_slice(bytes).map_err(DecodeError::Json)
+    }
+
+    #[inline]
+    fn from_msgpack(bytes: &[u8]) -> Result<Self, DecodeError> {
+        rmp_serde::from_slice(bytes).map_err(DecodeError::Msgpack)
+    }
+}
+
+/// A link from a span to a span of another trace, e.g. from a batch consumer's span to the
+/// spans which produced each message of the batch.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpanLink {
+    trace_id: u64,
+    span_id: u64,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    attributes: HashMap<String, String>,
+}
+
+/// Something which happened during a span, like a `tracing::Event` recorded in it, sent as a
+/// structured span event with the v0.7 format.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpanEvent {
+    pub name: String,
+    /// When the event happened, in nanoseconds since the Unix epoch
+    pub time_unix_nano: u64,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub attributes: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum SpanType {
+    Web,
+    Db,
+    Cache,
+    Queue,
+    Custom,
+}
+
+impl SpanType {
+    #[inline]
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            SpanType::Web => "web",
+            SpanType::Db => "db",
+            SpanType::Cache => "cache",
+            SpanType::Queue => "queue",
+            SpanType::Custom => "custom",
+        }
+    }
+}
+
+impl FromStr for SpanType {
+    type Err = ();
+
+    #[inline]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match &*s.trim().to_lowercase() {
+            "web" => SpanType::Web,
+            "db" => SpanType::Db,
+            "cache" => SpanType::Cache,
+            "queue" => SpanType::Queue,
+            "custom" => SpanType::Custom,
+            _ => SpanType::Custom,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ServiceName(pub &'static str);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SpanName(pub &'static str);
+
+// Lets the span name mappings be looked up by a name which is only known at runtime
+impl std::borrow::Borrow<str> for SpanName {
+    #[inline]
+    fn borrow(&self) -> &str {
+        self.0
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum SpanMetaKey {
+    Service,
+    Env,
+    Version,
+    HttpMethod,
+    HttpUrl,
+    HttpStatusCode,
+    ErrorType,
+    ErrorMsg,
+    ErrorStack,
+    Tenant,
+    SqlQuery,
+    GrpcStatusCode,
+    HttpRequestId,
+    SpanKind,
+    CorrelationId,
+}
+
+impl std::fmt::Display for SpanMetaKey {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Service => f.write_str("service"),
+            Self::Env => f.write_str("env"),
+            Self::Version => f.write_str("version"),
+            Self::HttpMethod => f.write_str("http.method"),
+            Self::HttpUrl => f.write_str("http.url"),
+            Self::HttpStatusCode => f.write_str("http.status_code"),
+            Self::ErrorType => f.write_str("error.type"),
+            Self::ErrorMsg => f.write_str("error.msg"),
+            Self::ErrorStack => f.write_str("error.stack"),
+            Self::Tenant => f.write_str("tenant"),
+            Self::SqlQuery => f.write_str("sql.query"),
+            Self::GrpcStatusCode => f.write_str("grpc.status_code"),
+            Self::HttpRequestId => f.write_str("http.request_id"),
+            Self::SpanKind => f.write_str("span.kind"),
+            Self::CorrelationId => f.write_str("correlation_id"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SpanBuilder {
+    pub error: bool,
+    meta: Meta,
+    meta_struct: HashMap<String, Vec<u8>>,
+    metrics: HashMap<String, f64>,
+    pub name: SpanName,
+    pub parent_id: Option<NonZeroU64>,
+    resource: String,
+    pub sampling_priority: Option<SamplingPriority>,
+    service: ServiceName,
+    pub span_id: NonZeroU64,
+    span_links: Vec<SpanLink>,
+    span_events: Vec<SpanEvent>,
+    pub start: SystemTime,
+    pub trace_id: NonZeroU64,
+    r#type: SpanType,
+    last_heartbeat: Option<SystemTime>,
+}
+
+impl Default for SpanBuilder {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            error: false,
+            meta: Meta::default(),
+            meta_struct: HashMap::new(),
+            metrics: HashMap::new(),
+            name: SpanName(""),
+            parent_id: None,
+            resource: String::new(),
+            sampling_priority: None,
+            service: ServiceName(""),
+            span_id: generate_id(),
+            span_links: Vec::new(),
+            span_events: Vec::new(),
+            start: now(),
+            trace_id: generate_id(),
+            r#type: SpanType::Custom,
+            last_heartbeat: None,
+        }
+    }
+}
+
+impl SpanBuilder {
+    #[inline]
+    pub fn name(&mut self, name: SpanName) -> &mut Self {
+        self.name = name;
+        self
+    }
+
+    #[inline]
+    pub fn service(&mut self, service: ServiceName) -> &mut Self {
+        self.service = service;
+        self
+    }
+
+    #[inline]
+    pub fn resource(&mut self, resource: String) -> &mut Self {
+        self.resource = resource;
+        self
+    }
+
+    #[inline]
+    pub fn span_type(&mut self, span_type: SpanType) -> &mut Self {
+        self.r#type = span_type;
+        self
+    }
+
+    #[inline]
+    pub fn trace_id(&mut self, trace_id: NonZeroU64) -> &mut Self {
+        self.trace_id = trace_id;
+        self
+    }
+
+    #[inline]
+    pub fn start(&mut self, start: SystemTime) -> &mut Self {
+        self.start = start;
+        self
+    }
+
+    #[inline]
+    pub fn error(&mut self, error: bool) -> &mut Self {
+        self.error = error;
+        self
+    }
+
+    #[inline]
+    pub fn add_meta(&mut self, key: SpanMetaKey, value: impl Into<String>) -> &mut Self {
+        self.meta.own.insert(key.to_string(), value.into());
+        self
+    }
+
+    /// Sets the tags the span shares with other spans, under its own tags. Spans only hold a
+    /// reference to them, so tags common to every span, like the `service`, `env` and
+    /// `version` tags, cost no allocation per span.
+    #[inline]
+    pub fn shared_meta(&mut self, shared_meta: Arc<HashMap<String, String>>) -> &mut Self {
+        self.meta.shared = shared_meta;
+        self
+    }
+
+    #[inline]
+    pub fn metrics(&mut self, metrics: HashMap<String, f64>) -> &mut Self {
+        self.metrics = metrics;
+        self
+    }
+
+    #[inline]
+    pub fn parent_id(&mut self, parent_id: NonZeroU64) -> &mut Self {
+        self.parent_id = Some(parent_id);
+        self
+    }
+
+    #[inline]
+    pub fn sampling_priority(&mut self, sampling_priority: SamplingPriority) -> &mut Self {
+        self.sampling_priority = Some(sampling_priority);
+        self
+    }
+
+    #[inline]
+    pub fn add_link(
+        &mut self,
+        trace_id: NonZeroU64,
+        span_id: NonZeroU64,
+        attributes: HashMap<String, String>,
+    ) -> &mut Self {
+        self.span_links.push(SpanLink {
+            trace_id: trace_id.get(),
+            span_id: span_id.get(),
+            attributes,
+        });
+        self
+    }
+
+    /// Records an event which happened at `time` during the span. Events are only sent with the
+    /// v0.7 format.
+    #[inline]
+    pub fn add_event(
+        &mut self,
+        name: impl Into<String>,
+        time: SystemTime,
+        attributes: HashMap<String, String>,
+    ) -> &mut Self {
+        self.span_events.push(SpanEvent {
+            name: name.into(),
+            time_unix_nano: time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64,
+            attributes,
+        });
+        self
+    }
+
+    /// Makes the span a child of a span of another service, e.g. extracted with
+    /// `propagation::extract`, continuing its trace with its sampling priority, origin and trace
+    /// tags.
+    #[inline]
+    pub fn remote_parent(&mut self, context: &SpanContext) -> &mut Self {
+        self.trace_id(context.trace_id).parent_id(context.span_id);
+        if let Some(sampling_priority) = context.sampling_priority {
+            self.sampling_priority(sampling_priority);
+        }
+        self.propagated_tags(context)
+    }
+
+    #[inline]
+    pub(crate) fn propagated_tags(&mut self, context: &SpanContext) -> &mut Self {
+        if let Some(origin) = &context.origin {
+            self.add_tag(ORIGIN_TAG, origin.clone());
+        }
+        for (key, value) in &context.trace_tags {
+            self.add_tag(key.clone(), value.clone());
+        }
+        self
+    }
+
+    /// Marks the span as an error and records the whole chain of `Error::source()`s: `error.msg`
+    /// lists the messages starting from the root cause and `error.stack` lists them starting
+    /// from the outermost error, like `anyhow` does.
+    #[inline]
+    pub fn record_error_chain(&mut self, err: &(dyn std::error::Error + 'static)) -> &mut Self {
+        let (error_msg, error_stack) = format_error_chain(err);
+        self.error(true);
+        self.add_meta(SpanMetaKey::ErrorMsg, error_msg);
+        self.add_meta(SpanMetaKey::ErrorStack, error_stack)
+    }
+
+    /// Tags the span with the method of an HTTP request.
+    #[cfg(feature = "http")]
+    #[inline]
+    pub fn http_method(&mut self, method: http::Method) -> &mut Self {
+        self.add_meta(SpanMetaKey::HttpMethod, method.as_str())
+    }
+
+    /// Tags the span with the status of an HTTP response, and marks it as an error if the
+    /// status is classified as one by `DD_TRACE_HTTP_SERVER_ERROR_STATUSES` (`5xx` by default).
+    #[cfg(feature = "http")]
+    #[inline]
+    pub fn http_status(&mut self, status: http::StatusCode) -> &mut Self {
+        self.http_status_with(status, HttpStatusClassifier::global())
+    }
+
+    /// Like `http_status`, with a custom classification of error statuses.
+    #[cfg(feature = "http")]
+    #[inline]
+    pub fn http_status_with(
+        &mut self,
+        status: http::StatusCode,
+        classifier: &HttpStatusClassifier,
+    ) -> &mut Self {
+        if classifier.is_error(status.as_u16()) {
+            self.error(true);
+        }
+        self.add_meta(SpanMetaKey::HttpStatusCode, status.as_str())
+    }
+
+    /// Adds a tag which has no `SpanMetaKey`, e.g. one whose name is configured at runtime.
+    #[inline]
+    pub fn add_tag(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        self.meta.own.insert(key.into(), value.into());
+        self
+    }
+
+    /// Adds structured data to the `meta_struct` of the span under `key`, like the nested
+    /// documents of security products, serialized with msgpack. A value which fails to
+    /// serialize is left out. `meta_struct` isn't sent with the v0.5 format.
+    pub fn add_meta_struct(&mut self, key: impl Into<String>, value: &impl Serialize) -> &mut Self {
+        match rmp_serde::to_vec_named(value) {
+            Ok(bytes) => {
+                self.meta_struct.insert(key.into(), bytes);
+            }
+            Err(e) => log::warn!("Failed to serialize meta_struct value; err {:?}", e),
+        }
+        self
+    }
+
+    // The span's own tags, without the shared ones
+    #[cfg_attr(not(feature = "client"), allow(dead_code))]
+    #[inline]
+    pub(crate) fn meta_mut(&mut self) -> &mut HashMap<String, String> {
+        &mut self.meta.own
+    }
+
+    #[cfg_attr(not(feature = "client"), allow(dead_code))]
+    #[inline]
+    pub(crate) fn metrics_mut(&mut self) -> &mut HashMap<String, f64> {
+        &mut self.metrics
+    }
+
+    #[inline]
+    pub fn meta_value(&self, key: &str) -> Option<&str> {
+        self.meta.get(key).map(String::as_str)
+    }
+
+    #[inline]
+    pub fn service_name(&self) -> &'static str {
+        self.service.0
+    }
+
+    /// The fields Datadog needs to render the span well which are empty, among `service` and
+    /// `resource`.
+    #[inline]
+    pub fn missing_required_fields(&self) -> Vec<&'static str> {
+        let mut missing = Vec::new();
+        if self.service.0.trim().is_empty() {
+            missing.push("service");
+        }
+        if self.resource.trim().is_empty() {
+            missing.push("resource");
+        }
+        missing
+    }
+
+    /// Records the progress of a long running span, e.g. a batch job, as the
+    /// `progress.items_processed`, `progress.items_total` and `progress.percent` metrics.
+    #[inline]
+    pub fn progress(&mut self, items_processed: u64, items_total: Option<u64>) -> &mut Self {
+        self.metrics.insert(
+            String::from(PROGRESS_ITEMS_PROCESSED_METRIC_KEY),
+            items_processed as f64,
+        );
+        if let Some(items_total) = items_total {
+            self.metrics.insert(
+                String::from(PROGRESS_ITEMS_TOTAL_METRIC_KEY),
+                items_total as f64,
+            );
+            if items_total > 0 {
+                self.metrics.insert(
+                    String::from(PROGRESS_PERCENT_METRIC_KEY),
+                    (items_processed as f64 / items_total as f64 * 100.0).min(100.0),
+                );
+            }
+        }
+        self
+    }
+
+    /// Builds a snapshot of the span so far as a child span named `<name>.heartbeat`, with the
+    /// same tags and metrics, which can be exported while the span is still open so operators
+    /// see the progress of long running spans mid-run.
+    #[inline]
+    pub fn heartbeat(&mut self) -> Span {
+        self.last_heartbeat = Some(now());
+        let mut span = self.build();
+        span.name = Cow::Owned(format!("{}.heartbeat", self.name.0));
+        span.parent_id = Some(self.span_id.get());
+        span.span_id = generate_id().get();
+        span.span_links.clear();
+        span.span_events.clear();
+        span
+    }
+
+    // Whether the last heartbeat (or the start of the span, if there was none) is older than
+    // `interval`
+    #[cfg_attr(not(feature = "client"), allow(dead_code))]
+    #[inline]
+    pub(crate) fn heartbeat_due(&self, interval: Duration) -> bool {
+        now()
+            .duration_since(self.last_heartbeat.unwrap_or(self.start))
+            .is_ok_and(|elapsed| elapsed >= interval)
+    }
+
+    #[inline]
+    pub fn build(&self) -> Span {
+        self.build_with_duration(
+            now()
+                .duration_since(self.start)
+                .unwrap_or_else(|_| Duration::from_nanos(0)),
+        )
+    }
+
+    /// Builds the span with a given duration instead of the time elapsed since its start, e.g.
+    /// for deterministic test fixtures.
+    #[inline]
+    pub fn build_with_duration(&self, duration: Duration) -> Span {
+        let duration = duration.as_nanos() as u64;
+        let mut metrics = self.metrics.clone();
+        if let Some(sampling_priority) = self.sampling_priority {
+            metrics.insert(
+                String::from(SAMPLING_PRIORITY_METRIC_KEY),
+                sampling_priority.as_i32() as f64,
+            );
+        }
+        Span {
+            duration,
+            error: if self.error { 1 } else { 0 },
+            meta: self.meta.clone(),
+            meta_struct: self.meta_struct.clone(),
+            metrics,
+            name: Cow::Borrowed(self.name.0),
+            parent_id: self.parent_id.map(NonZeroU64::get),
+            resource: self.resource.clone(),
+            service: Cow::Borrowed(self.service.0),
+            span_id: self.span_id.get(),
+            span_links: self.span_links.clone(),
+            span_events: self.span_events.clone(),
+            start: self
+                .start
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_nanos() as u64,
+            trace_id: self.trace_id.get(),
+            r#type: Cow::Borrowed(self.r#type.as_str()),
+        }
+    }
+}
+
+/// Formats the chain of `Error::source()`s of an error into an `error.msg` (root cause first)
+/// and an `error.stack` (outermost error first).
+pub(crate) fn format_error_chain(err: &(dyn std::error::Error + 'static)) -> (String, String) {
+    let chain: Vec<String> = std::iter::successors(Some(err), |err| err.source())
+        .map(ToString::to_string)
+        .collect();
+    let error_msg = chain
+        .iter()
+        .rev()
+        .map(String::as_str)
+        .collect::<Vec<&str>>()
+        .join(": ");
+    let mut error_stack = chain[0].clone();
+    if chain.len() > 1 {
+        error_stack.push_str("\n\nCaused by:");
+        for (i, cause) in chain[1..].iter().enumerate() {
+            error_stack.push_str(&format!("\n    {}: {}", i, cause));
+        }
+    }
+    (error_msg, error_stack)
+}
+
+/// Where spans get their timestamps and ids from. By default, the system clock and a random
+/// number generator are used, which targets like `wasm32-unknown-unknown` don't have, so hosts
+/// compiling spans to such a target set their own once with `set_span_sources`, e.g. a clock
+/// backed by `Date.now()` and ids drawn from `crypto.getRandomValues()`.
+#[derive(Clone, Copy, Debug)]
+pub struct SpanSources {
+    pub now: fn() -> SystemTime,
+    pub generate_id: fn() -> NonZeroU64,
+}
+
+static SPAN_SOURCES: OnceLock<SpanSources> = OnceLock::new();
+
+/// Sets the clock and id source of spans for the rest of the process, before any span is built.
+/// Fails with the given sources if they were already set.
+///
+/// ```
+/// use std::num::NonZeroU64;
+/// use std::time::{Duration, SystemTime, UNIX_EPOCH};
+/// use tracing_datadog_apm::span::{set_span_sources, SpanBuilder, SpanSources};
+///
+/// fn host_clock() -> SystemTime {
+///     UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+/// }
+///
+/// fn host_id() -> NonZeroU64 {
+///     NonZeroU64::new(42).unwrap()
+/// }
+///
+/// set_span_sources(SpanSources { now: host_clock, generate_id: host_id }).unwrap();
+/// let span = SpanBuilder::default().build();
+/// assert_eq!(span.span_id(), 42);
+/// assert_eq!(span.start(), 1_700_000_000_000_000_000);
+/// assert_eq!(span.duration(), 0);
+/// ```
+pub fn set_span_sources(sources: SpanSources) -> Result<(), SpanSources> {
+    SPAN_SOURCES.set(sources)
+}
+
+#[inline]
+pub fn now() -> SystemTime {
+    match SPAN_SOURCES.get() {
+        Some(sources) => (sources.now)(),
+        None => SystemTime::now(),
+    }
+}
+
+#[inline]
+pub fn generate_id() -> NonZeroU64 {
+    match SPAN_SOURCES.get() {
+        Some(sources) => (sources.generate_id)(),
+        None => random_id(),
+    }
+}
+
+#[cfg(feature = "client")]
+#[inline]
+fn random_id() -> NonZeroU64 {
+    rand::thread_rng().gen()
+}
+
+// Without `rand`, ids are a splitmix64 sequence seeded from the clock on first use
+#[cfg(not(feature = "client"))]
+fn random_id() -> NonZeroU64 {
+    static STATE: AtomicU64 = AtomicU64::new(0);
+    const GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+    let seed = now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    STATE
+        .compare_exchange(0, seed | 1, Ordering::Relaxed, Ordering::Relaxed)
+        .ok();
+    loop {
+        let mut z = STATE
+            .fetch_add(GAMMA, Ordering::Relaxed)
+            .wrapping_add(GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        if let Some(id) = NonZeroU64::new(z ^ (z >> 31)) {
+            return id;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_span_type_web() {
+        let span_type = SpanType::from_str("web").unwrap();
+        assert_eq!(span_type, SpanType::Web);
+        assert_eq!(span_type.as_str(), "web");
+    }
+
+    #[test]
+    fn test_span_type_db() {
+        let span_type = SpanType::from_str("db").unwrap();
+        assert_eq!(span_type, SpanType::Db);
+        assert_eq!(span_type.as_str(), "db");
+    }
+
+    #[test]
+    fn test_span_type_cache() {
+        let span_type = SpanType::from_str("cache").unwrap();
+        assert_eq!(span_type, SpanType::Cache);
+        assert_eq!(span_type.as_str(), "cache");
+    }
+
+    #[test]
+    fn test_span_type_queue() {
+        let span_type = SpanType::from_str("queue").unwrap();
+        assert_eq!(span_type, SpanType::Queue);
+        assert_eq!(span_type.as_str(), "queue");
+    }
+
+    #[test]
+    fn test_span_type_custom() {
+        let span_type = SpanType::from_str("custom").unwrap();
+        assert_eq!(span_type, SpanType::Custom);
+        assert_eq!(span_type.as_str(), "custom");
+    }
+
+    #[test]
+    fn test_span_type_from_str_capitalized() {
+        let span_type = SpanType::from_str("Web").unwrap();
+        assert_eq!(span_type, SpanType::Web);
+        assert_eq!(span_type.as_str(), "web");
+    }
+
+    #[test]
+    fn test_span_type_other_string_defaults_to_custom() {
+        let span_type = SpanType::from_str("fake type").unwrap();
+        assert_eq!(span_type, SpanType::Custom);
+        assert_eq!(span_type.as_str(), "custom");
+    }
+
+    #[test]
+    fn test_default_span_builder() {
+        let span = SpanBuilder::default().build();
+        assert_eq!(span.error, 0);
+        assert_eq!(span.meta, HashMap::new());
+        assert_eq!(span.metrics, HashMap::new());
+        assert_eq!(span.name, "");
+        assert_eq!(span.parent_id, None);
+        assert_eq!(span.resource, "");
+        assert_eq!(span.service, "");
+        assert!(span.span_id > 0);
+        assert!(span.start > 0);
+        assert!(span.trace_id > 0);
+        assert_eq!(span.r#type, "custom");
+    }
+
+    #[test]
+    fn test_span_builder() {
+        let start = SystemTime::now()
+            .checked_sub(Duration::from_nanos(100))
+            .unwrap();
+        let parent_id = NonZeroU64::new(5).unwrap();
+        let trace_id = NonZeroU64::new(100).unwrap();
+        let name = "foo";
+        let resource = "bar";
+        let service = "aliceandbob";
+        let r#type = SpanType::Db;
+        let span = SpanBuilder::default()
+            .start(start)
+            .parent_id(parent_id)
+            .trace_id(trace_id)
+            .error(true)
+            .name(SpanName(name))
+            .resource(String::from(resource))
+            .service(ServiceName(service))
+            .span_type(r#type)
+            .build();
+        assert!(span.duration > 100);
+        assert_eq!(span.error, 1);
+        assert_eq!(span.meta, HashMap::new());
+        assert_eq!(span.metrics, HashMap::new());
+        assert_eq!(span.name, name);
+        assert_eq!(span.parent_id, Some(parent_id.get()));
+        assert_eq!(span.resource, resource);
+        assert_eq!(span.service, service);
+        assert!(span.span_id > 0);
+        assert_eq!(
+            span.start,
+            start.duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64
+        );
+        assert_eq!(span.trace_id, trace_id.get());
+        assert_eq!(span.r#type, "db");
+    }
+
+    #[test]
+    fn test_span_builder_sampling_priority_keep() {
+        let span = SpanBuilder::default()
+            .sampling_priority(SamplingPriority::UserKeep)
+            .build();
+        assert_eq!(span.metrics.get(SAMPLING_PRIORITY_METRIC_KEY), Some(&2.0));
+    }
+
+    #[test]
+    fn test_span_builder_sampling_priority_reject_in_metrics() {
+        let span = SpanBuilder::default()
+            .sampling_priority(SamplingPriority::UserReject)
+            .build();
+        assert_eq!(span.metrics.get(SAMPLING_PRIORITY_METRIC_KEY), Some(&-1.0));
+    }
+
+    #[test]
+    fn test_traces_json_round_trip() {
+        let mut span_builder = SpanBuilder::default();
+        span_builder
+            .name(SpanName("request"))
+            .service(ServiceName("web"))
+            .resource(String::from("GET /"))
+            .parent_id(NonZeroU64::new(7).unwrap())
+            .add_meta(SpanMetaKey::HttpMethod, "GET")
+            .sampling_priority(SamplingPriority::AutoKeep);
+        let traces = vec![vec![span_builder.build()]];
+
+        let decoded = Traces::from_json(&serde_json::to_vec(&traces).unwrap()).unwrap();
+
+        assert_eq!(decoded, traces);
+    }
+
+    #[test]
+    fn test_traces_msgpack_round_trip() {
+        let traces = vec![vec![SpanBuilder::default().build()]];
+
+        let decoded = Traces::from_msgpack(&rmp_serde::to_vec_named(&traces).unwrap()).unwrap();
+
+        assert_eq!(decoded, traces);
+    }
+
+    #[test]
+    fn test_traces_from_agent_format_json() {
+        let payload = br#"[[{"trace_id":1,"span_id":2,"parent_id":0,"name":"request",
+            "service":"web","resource":"GET /","start":10,"duration":5}]]"#;
+
+        let traces = Traces::from_json(payload).unwrap();
+
+        let span = &traces[0][0];
+        assert_eq!(span.trace_id(), 1);
+        assert_eq!(span.span_id(), 2);
+        assert_eq!(span.parent_id(), None);
+        assert_eq!(span.name(), "request");
+        assert_eq!(span.span_type(), "");
+        assert!(span.meta().is_empty());
+        assert!(Traces::from_json(b"{}").is_err());
+    }
+
+    #[test]
+    fn test_span_builder_add_link() {
+        let trace_id = NonZeroU64::new(10).unwrap();
+        let span_id = NonZeroU64::new(20).unwrap();
+        let attributes = HashMap::from([(String::from("reason"), String::from("batch"))]);
+        let span = SpanBuilder::default()
+            .add_link(trace_id, span_id, attributes.clone())
+            .build();
+        assert_eq!(
+            span.span_links,
+            vec![SpanLink {
+                trace_id: 10,
+                span_id: 20,
+                attributes
+            }]
+        );
+    }
+
+    #[test]
+    fn test_span_builder_add_event() {
+        let attributes = HashMap::from([(String::from("rows"), String::from("3"))]);
+        let span = SpanBuilder::default()
+            .add_event(
+                "query done",
+                UNIX_EPOCH + Duration::from_nanos(1_500),
+                attributes.clone(),
+            )
+            .build();
+        assert_eq!(
+            span.span_events(),
+            [SpanEvent {
+                name: String::from("query done"),
+                time_unix_nano: 1_500,
+                attributes
+            }]
+        );
+        assert!(serde_json::to_value(&span).unwrap()["span_events"].is_array());
+    }
+
+    #[test]
+    fn test_span_builder_add_meta_struct() {
+        #[derive(Serialize)]
+        struct Trigger {
+            rule: &'static str,
+        }
+
+        let span = SpanBuilder::default()
+            .add_meta_struct("appsec", &Trigger { rule: "sqli" })
+            .build();
+
+        let value: serde_json::Value =
+            rmp_serde::from_slice(&span.meta_struct()["appsec"]).unwrap();
+        assert_eq!(value, serde_json::json!({"rule": "sqli"}));
+    }
+
+    #[test]
+    fn test_meta_struct_roundtrips_as_base64_in_json_and_bytes_in_msgpack() {
+        let traces = vec![vec![SpanBuilder::default()
+            .add_meta_struct("appsec", &[1, 2, 3])
+            .build()]];
+
+        let span_json = serde_json::to_value(&traces[0][0]).unwrap();
+        assert_eq!(
+            span_json["meta_struct"]["appsec"],
+            base64::encode(&traces[0][0].meta_struct["appsec"])
+        );
+        let json = serde_json::to_vec(&traces).unwrap();
+        assert_eq!(Traces::from_json(&json).unwrap(), traces);
+        let msgpack = rmp_serde::to_vec_named(&traces).unwrap();
+        assert_eq!(Traces::from_msgpack(&msgpack).unwrap(), traces);
+    }
+
+    #[test]
+    fn test_span_links_omitted_from_json_when_empty() {
+        let span_json = serde_json::to_value(SpanBuilder::default().build()).unwrap();
+        assert!(span_json.get("span_links").is_none());
+    }
+
+    #[derive(Debug)]
+    struct ChainedError {
+        msg: &'static str,
+        source: Option<Box<ChainedError>>,
+    }
+
+    impl std::fmt::Display for ChainedError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(self.msg)
+        }
+    }
+
+    impl std::error::Error for ChainedError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            self.source
+                .as_ref()
+                .map(|source| source.as_ref() as &(dyn std::error::Error + 'static))
+        }
+    }
+
+    #[test]
+    fn test_span_builder_record_error_chain() {
+        let err = ChainedError {
+            msg: "failed to load user",
+            source: Some(Box::new(ChainedError {
+                msg: "query failed",
+                source: Some(Box::new(ChainedError {
+                    msg: "connection refused",
+                    source: None,
+                })),
+            })),
+        };
+        let span = SpanBuilder::default().record_error_chain(&err).build();
+        assert_eq!(span.error, 1);
+        assert_eq!(
+            span.meta.get("error.msg").unwrap(),
+            "connection refused: query failed: failed to load user"
+        );
+        assert_eq!(
+            span.meta.get("error.stack").unwrap(),
+            "failed to load user\n\nCaused by:\n    0: query failed\n    1: connection refused"
+        );
+    }
+
+    #[test]
+    fn test_span_builder_record_error_without_source() {
+        let err = ChainedError {
+            msg: "boom",
+            source: None,
+        };
+        let span = SpanBuilder::default().record_error_chain(&err).build();
+        assert_eq!(span.meta.get("error.msg").unwrap(), "boom");
+        assert_eq!(span.meta.get("error.stack").unwrap(), "boom");
+    }
+
+    #[test]
+    fn test_span_meta_key_service() {
+        assert_eq!(&*SpanMetaKey::Service.to_string(), "service");
+    }
+
+    #[test]
+    fn test_span_meta_key_env() {
+        assert_eq!(&*SpanMetaKey::Env.to_string(), "env");
+    }
+
+    #[test]
+    fn test_span_meta_key_version() {
+        assert_eq!(&*SpanMetaKey::Version.to_string(), "version");
+    }
+
+    #[test]
+    fn test_span_meta_key_http_method() {
+        assert_eq!(&*SpanMetaKey::HttpMethod.to_string(), "http.method");
+    }
+
+    #[test]
+    fn test_span_meta_key_http_url() {
+        assert_eq!(&*SpanMetaKey::HttpUrl.to_string(), "http.url");
+    }
+
+    #[test]
+    fn test_span_meta_key_http_status_code() {
+        assert_eq!(
+            &*SpanMetaKey::HttpStatusCode.to_string(),
+            "http.status_code"
+        );
+    }
+
+    #[test]
+    fn test_span_meta_key_error_msg() {
+        assert_eq!(&*SpanMetaKey::ErrorMsg.to_string(), "error.msg");
+    }
+
+    #[test]
+    fn test_span_meta_key_error_stack() {
+        assert_eq!(&*SpanMetaKey::ErrorStack.to_string(), "error.stack");
+    }
+
+    #[test]
+    fn test_span_meta_key_tenant() {
+        assert_eq!(&*SpanMetaKey::Tenant.to_string(), "tenant");
+    }
+
+    #[test]
+    fn test_span_meta_key_correlation_id() {
+        assert_eq!(&*SpanMetaKey::CorrelationId.to_string(), "correlation_id");
+    }
+
+    #[test]
+    fn test_span_meta_key_sql_query() {
+        assert_eq!(&*SpanMetaKey::SqlQuery.to_string(), "sql.query");
+    }
+
+    #[test]
+    fn test_span_meta_key_grpc_status_code() {
+        assert_eq!(
+            &*SpanMetaKey::GrpcStatusCode.to_string(),
+            "grpc.status_code"
+        );
+    }
+
+    #[test]
+    fn test_span_meta_key_error_type() {
+        assert_eq!(&*SpanMetaKey::ErrorType.to_string(), "error.type");
+    }
+
+    #[test]
+    fn test_span_builder_progress() {
+        let mut span_builder = SpanBuilder::default();
+        span_builder.progress(25, Some(200));
+
+        let span = span_builder.build();
+
+        assert_eq!(
+            span.metrics.get(PROGRESS_ITEMS_PROCESSED_METRIC_KEY),
+            Some(&25.0)
+        );
+        assert_eq!(
+            span.metrics.get(PROGRESS_ITEMS_TOTAL_METRIC_KEY),
+            Some(&200.0)
+        );
+        assert_eq!(span.metrics.get(PROGRESS_PERCENT_METRIC_KEY), Some(&12.5));
+
+        span_builder.progress(30, None);
+        let span = span_builder.build();
+        assert_eq!(
+            span.metrics.get(PROGRESS_ITEMS_PROCESSED_METRIC_KEY),
+            Some(&30.0)
+        );
+    }
+
+    #[test]
+    fn test_span_builder_missing_required_fields() {
+        let mut span_builder = SpanBuilder::default();
+        assert_eq!(
+            span_builder.missing_required_fields(),
+            vec!["service", "resource"]
+        );
+
+        span_builder
+            .service(ServiceName("orders"))
+            .resource(String::from("GET /orders"));
+
+        assert!(span_builder.missing_required_fields().is_empty());
+    }
+
+    #[test]
+    fn test_span_builder_heartbeat() {
+        let mut span_builder = SpanBuilder::default();
+        span_builder
+            .name(SpanName("batch"))
+            .resource(String::from("nightly"))
+            .start(SystemTime::now() - Duration::from_secs(60))
+            .progress(10, Some(20));
+        assert!(span_builder.heartbeat_due(Duration::from_secs(30)));
+
+        let heartbeat = span_builder.heartbeat();
+
+        assert_eq!(heartbeat.name, "batch.heartbeat");
+        assert_eq!(heartbeat.resource, "nightly");
+        assert_eq!(heartbeat.trace_id, span_builder.trace_id.get());
+        assert_eq!(heartbeat.parent_id, Some(span_builder.span_id.get()));
+        assert_ne!(heartbeat.span_id, span_builder.span_id.get());
+        assert!(heartbeat.duration >= Duration::from_secs(60).as_nanos() as u64);
+        assert_eq!(
+            heartbeat.metrics.get(PROGRESS_PERCENT_METRIC_KEY),
+            Some(&50.0)
+        );
+        assert!(!span_builder.heartbeat_due(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_estimated_wire_size_bounds_serialized_size() {
+        let mut span_builder = SpanBuilder::default();
+        span_builder
+            .name(SpanName("request"))
+            .service(ServiceName("web"))
+            .resource(String::from("GET /users/{id}"))
+            .add_meta(SpanMetaKey::HttpUrl, "/users/42")
+            .add_meta(SpanMetaKey::HttpMethod, "GET")
+            .metrics(HashMap::from([(String::from("rows"), 12.5)]))
+            .add_link(generate_id(), generate_id(), HashMap::new());
+        let span = span_builder.build();
+
+        let serialized = serde_json::to_string(&span).unwrap().len();
+        let estimated = span.estimated_wire_size();
+
+        assert!(estimated >= serialized, "{} < {}", estimated, serialized);
+        assert!(
+            estimated < serialized * 2,
+            "{} >= 2 * {}",
+            estimated,
+            serialized
+        );
+        assert_eq!(
+            estimated_trace_size(&vec![span.clone(), span]),
+            2 * estimated + 2
+        );
+    }
+
+    #[test]
+    fn test_span_builder_shared_meta() {
+        let shared_meta = Arc::new(HashMap::from([
+            (String::from("env"), String::from("prod")),
+            (String::from("version"), String::from("1.0")),
+        ]));
+        let mut span_builder = SpanBuilder::default();
+        span_builder
+            .shared_meta(Arc::clone(&shared_meta))
+            .add_meta(SpanMetaKey::Env, "staging");
+
+        let span = span_builder.build();
+
+        assert_eq!(span.meta().len(), 2);
+        assert_eq!(span.meta()["env"], "staging");
+        assert_eq!(span.meta()["version"], "1.0");
+        assert_eq!(
+            serde_json::to_value(&span).unwrap()["meta"],
+            serde_json::json!({"env": "staging", "version": "1.0"})
+        );
+        assert_eq!(shared_meta["env"], "prod");
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_span_builder_http_helpers() {
+        let mut span_builder = SpanBuilder::default();
+        span_builder
+            .http_method(http::Method::PATCH)
+            .http_status(http::StatusCode::NOT_FOUND);
+        assert_eq!(span_builder.meta_value("http.method"), Some("PATCH"));
+        assert_eq!(span_builder.meta_value("http.status_code"), Some("404"));
+        assert!(!span_builder.error);
+
+        span_builder.http_status_with(
+            http::StatusCode::TOO_MANY_REQUESTS,
+            &HttpStatusClassifier::parse("429,500-599"),
+        );
+        assert_eq!(span_builder.meta_value("http.status_code"), Some("429"));
+        assert!(span_builder.error);
+    }
+}
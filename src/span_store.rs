@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use tracing::Id;
+
+#[cfg(tracing_datadog_apm_loom)]
+use loom::sync::{Mutex, MutexGuard};
+#[cfg(not(tracing_datadog_apm_loom))]
+use std::sync::{Mutex, MutexGuard};
+
+// The open spans of a subscriber and their reference counts, which `tracing` increments when a
+// span handle is cloned and decrements when one is dropped. A span is closed exactly once, when
+// its last reference is released, and its entry is removed at the same time so nothing leaks.
+//
+// The concurrency of the store is checked with loom:
+// `RUSTFLAGS="--cfg tracing_datadog_apm_loom" cargo test --release --lib span_store`
+pub(crate) struct SpanStore<V> {
+    entries: Mutex<HashMap<Id, Entry<V>>>,
+}
+
+struct Entry<V> {
+    value: V,
+    ref_count: u32,
+}
+
+/// What releasing a reference to a span did.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Release<V> {
+    /// Other references are left, so the span is still open.
+    Open,
+    /// This was the last reference; the span is closed and removed from the store.
+    Closed(V),
+    /// The span is not in the store.
+    Missing,
+}
+
+impl<V> SpanStore<V> {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Locks the store, for reading or updating several spans at once.
+    #[inline]
+    pub(crate) fn lock(&self) -> Option<SpanStoreGuard<'_, V>> {
+        self.entries
+            .lock()
+            .map(SpanStoreGuard)
+            .map_err(|e| {
+                log::error!("Unable to acquire lock on spans map; err {}", e);
+            })
+            .ok()
+    }
+
+    /// Adds a reference to a span. Returns false if the span is not in the store.
+    #[inline]
+    pub(crate) fn clone_ref(&self, id: &Id) -> bool {
+        let Some(mut guard) = self.lock() else {
+            return false;
+        };
+        match guard.0.get_mut(id) {
+            Some(entry) => {
+                entry.ref_count += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Releases a reference to a span, closing it if it was the last one.
+    #[inline]
+    pub(crate) fn release(&self, id: &Id) -> Release<V> {
+        let Some(mut guard) = self.lock() else {
+            return Release::Missing;
+        };
+        match guard.0.get_mut(id) {
+            Some(entry) if entry.ref_count > 1 => {
+                entry.ref_count -= 1;
+                Release::Open
+            }
+            // the entry is removed under the same lock, so the span can't be closed twice
+            Some(_) => match guard.0.remove(id) {
+                Some(entry) => Release::Closed(entry.value),
+                None => Release::Missing,
+            },
+            None => Release::Missing,
+        }
+    }
+}
+
+/// The locked store.
+pub(crate) struct SpanStoreGuard<'a, V>(MutexGuard<'a, HashMap<Id, Entry<V>>>);
+
+impl<V> SpanStoreGuard<'_, V> {
+    /// Adds a span with a single reference.
+    #[inline]
+    pub(crate) fn insert(&mut self, id: Id, value: V) {
+        self.0.insert(
+            id,
+            Entry {
+                value,
+                ref_count: 1,
+            },
+        );
+    }
+
+    #[inline]
+    pub(crate) fn get(&self, id: &Id) -> Option<&V> {
+        self.0.get(id).map(|entry| &entry.value)
+    }
+
+    #[inline]
+    pub(crate) fn get_mut(&mut self, id: &Id) -> Option<&mut V> {
+        self.0.get_mut(id).map(|entry| &mut entry.value)
+    }
+
+    #[inline]
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&Id, &V)> {
+        self.0.iter().map(|(id, entry)| (id, &entry.value))
+    }
+
+    #[inline]
+    pub(crate) fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.0.values_mut().map(|entry| &mut entry.value)
+    }
+
+    /// Removes every span, whatever its references.
+    #[inline]
+    pub(crate) fn drain(&mut self) -> impl Iterator<Item = (Id, V)> + '_ {
+        self.0.drain().map(|(id, entry)| (id, entry.value))
+    }
+}
+
+#[cfg(all(test, not(tracing_datadog_apm_loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_span_is_closed_with_its_last_reference() {
+        let store = SpanStore::new();
+        let id = Id::from_u64(1);
+        store.lock().unwrap().insert(id.clone(), "span");
+        assert!(store.clone_ref(&id));
+
+        assert_eq!(store.release(&id), Release::Open);
+        assert_eq!(store.release(&id), Release::Closed("span"));
+        assert_eq!(store.release(&id), Release::Missing);
+        assert_eq!(store.lock().unwrap().iter().count(), 0);
+    }
+
+    #[test]
+    fn test_missing_spans_are_not_cloned() {
+        let store = SpanStore::<&str>::new();
+
+        assert!(!store.clone_ref(&Id::from_u64(1)));
+    }
+}
+
+#[cfg(all(test, tracing_datadog_apm_loom))]
+mod loom_tests {
+    use super::*;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    fn store_with_span(ref_count: u32) -> (Arc<SpanStore<&'static str>>, Id) {
+        let store = Arc::new(SpanStore::new());
+        let id = Id::from_u64(1);
+        store.lock().unwrap().insert(id.clone(), "span");
+        (1..ref_count).for_each(|_| assert!(store.clone_ref(&id)));
+        (store, id)
+    }
+
+    #[test]
+    fn test_concurrent_releases_close_exactly_once() {
+        loom::model(|| {
+            let (store, id) = store_with_span(2);
+            let handles: Vec<_> = (0..2)
+                .map(|_| {
+                    let store = Arc::clone(&store);
+                    let id = id.clone();
+                    thread::spawn(move || store.release(&id))
+                })
+                .collect();
+
+            let closed = handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .filter(|release| *release == Release::Closed("span"))
+                .count();
+
+            assert_eq!(closed, 1);
+            assert_eq!(store.lock().unwrap().iter().count(), 0);
+        });
+    }
+
+    #[test]
+    fn test_clone_racing_release_never_leaks() {
+        loom::model(|| {
+            let (store, id) = store_with_span(2);
+            let cloning = {
+                let store = Arc::clone(&store);
+                let id = id.clone();
+                // a handle clones the span, then drops both of its references
+                thread::spawn(move || {
+                    assert!(store.clone_ref(&id));
+                    [store.release(&id), store.release(&id)]
+                })
+            };
+            let releasing = {
+                let store = Arc::clone(&store);
+                let id = id.clone();
+                thread::spawn(move || store.release(&id))
+            };
+
+            let mut releases = Vec::from(cloning.join().unwrap());
+            releases.push(releasing.join().unwrap());
+            releases.push(store.release(&id));
+
+            let closed = releases
+                .iter()
+                .filter(|release| **release == Release::Closed("span"))
+                .count();
+            assert_eq!(closed, 1);
+            assert_eq!(releases.last(), Some(&Release::Missing));
+            assert_eq!(store.lock().unwrap().iter().count(), 0);
+        });
+    }
+}
@@ -1,27 +1,160 @@
+use super::agentless;
+use super::cardinality::CardinalityGuard;
 use super::datadog_client::*;
+use super::drop_rules::DropRules;
+use super::grpc::{grpc_status_code_name, GrpcStatusClassifier};
+use super::integrations::enabled_integration_mappings;
+use super::obfuscate::{obfuscate_sql, obfuscate_sql_with, SqlDialect};
+use super::rate_limit::{
+    SpanRateLimiter, DROPPED_SPANS_METRIC_KEY, DROPPED_SPANS_REPORT_INTERVAL,
+    DROPPED_SPANS_SPAN_NAME,
+};
+use super::redaction::FieldRedactor;
+use super::rollout::Rollouts;
+use super::sampling::{Sampler, SamplingPriority};
+use super::span_store::{Release, SpanStore, SpanStoreGuard};
+use super::truncation::MetaTruncator;
+use rand::Rng;
+use serde::Serialize;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::env;
-use std::fmt::Debug;
+use std::fmt::{Debug, Display, Formatter};
 use std::num::NonZeroU64;
 use std::ops::Add;
 use std::str::FromStr;
-use std::sync::{Mutex, MutexGuard};
-use std::time::{Duration, UNIX_EPOCH};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use tracing::field::{Field, Visit};
 use tracing::span::{Attributes, Record};
 use tracing::{Event, Id, Metadata, Subscriber};
+use tracing_core::callsite::Identifier;
 use tracing_core::span::Current;
 
 thread_local! {
-    static CURRENT_SPAN: RefCell<Vec<Id>> = RefCell::new(Vec::new());
+    static CURRENT_SPAN: RefCell<Vec<Id>> = const { RefCell::new(Vec::new()) };
 }
 
-#[derive(Default)]
 pub struct TracingSubscriberDatadogConfig {
     mappings: HashMap<SpanName, (ServiceName, SpanType)>,
+    sampler: Option<Box<dyn Sampler>>,
+    default_service: Option<String>,
+    default_version: Option<String>,
+    grpc_status_classifier: GrpcStatusClassifier,
+    cardinality_guard: Option<CardinalityGuard>,
+    meta_truncator: Option<MetaTruncator>,
+    field_redactor: FieldRedactor,
+    sql_dialect: SqlDialect,
+    error_sample_rate: Option<f64>,
+    strict: Option<bool>,
+    on_misuse: Option<MisuseHook>,
+    heartbeat_interval: Option<Duration>,
+    event_span_rules: Vec<EventSpanRule>,
+    missing_tags: MissingTags,
+    rate_limits: Vec<(&'static str, f64, u32)>,
+    rollouts: HashMap<&'static str, f64>,
+    drop_rules: Option<DropRules>,
+    check_required_fields: bool,
+    root_spans_only: bool,
+    inherited_tags: Vec<String>,
+    start_time_unit: StartTimeUnit,
+    integration_mappings: bool,
+    max_open_spans: Option<usize>,
+}
+
+impl Default for TracingSubscriberDatadogConfig {
+    fn default() -> Self {
+        Self {
+            mappings: HashMap::new(),
+            sampler: None,
+            default_service: None,
+            default_version: None,
+            grpc_status_classifier: GrpcStatusClassifier::default(),
+            cardinality_guard: None,
+            meta_truncator: None,
+            field_redactor: FieldRedactor::default(),
+            sql_dialect: SqlDialect::default(),
+            error_sample_rate: None,
+            strict: None,
+            on_misuse: None,
+            heartbeat_interval: None,
+            event_span_rules: Vec::new(),
+            missing_tags: MissingTags::default(),
+            rate_limits: Vec::new(),
+            rollouts: HashMap::new(),
+            drop_rules: None,
+            check_required_fields: false,
+            root_spans_only: false,
+            inherited_tags: Vec::new(),
+            start_time_unit: StartTimeUnit::default(),
+            integration_mappings: true,
+            max_open_spans: None,
+        }
+    }
+}
+
+/// What to do when the `env`, `service` or `version` tag has no value, because neither its
+/// `DD_ENV`, `DD_SERVICE` or `DD_VERSION` env var nor a default is set.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum MissingTags {
+    /// Tags spans with an empty value, which shows up as `""` in Datadog facets.
+    #[default]
+    Empty,
+    /// Leaves the tag off spans.
+    Omit,
+    /// Makes `TracingSubscriberDatadog::try_new` fail, and `TracingSubscriberDatadog::new` panic.
+    Fail,
+}
+
+/// Unit of the values recorded in the `start` field of spans, since the Unix epoch.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum StartTimeUnit {
+    #[default]
+    Nanos,
+    Micros,
+    Millis,
+    Secs,
+}
+
+impl StartTimeUnit {
+    #[inline]
+    fn to_duration(self, value: u64) -> Duration {
+        match self {
+            Self::Nanos => Duration::from_nanos(value),
+            Self::Micros => Duration::from_micros(value),
+            Self::Millis => Duration::from_millis(value),
+            Self::Secs => Duration::from_secs(value),
+        }
+    }
 }
 
+/// Error from `TracingSubscriberDatadog::try_new` when tags are missing and the config is set to
+/// `MissingTags::Fail`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingTagsError {
+    /// The env vars of the missing tags, e.g. `DD_ENV`.
+    pub env_vars: Vec<&'static str>,
+}
+
+impl Display for MissingTagsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "missing Datadog tags, set the {} env var(s)",
+            self.env_vars.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for MissingTagsError {}
+
+// Events whose target starts with `target` are turned into spans of the service and type
+type EventSpanRule = (&'static str, ServiceName, SpanType);
+
+// A span started by an event, with the metadata of that event, until its end event
+type PendingEventSpan = (SpanBuilder, &'static Metadata<'static>);
+
 impl TracingSubscriberDatadogConfig {
     pub fn new() -> Self {
         Self::default()
@@ -31,77 +164,1085 @@ impl TracingSubscriberDatadogConfig {
         self.mappings.insert(key, value);
         self
     }
+
+    /// Whether the integrations enabled by crate features (actix-web, hyper) map their own spans,
+    /// as listed by `integrations::enabled_integration_mappings`. Enabled by default; mappings
+    /// added with `add_mapping` always take precedence.
+    pub fn integration_mappings(mut self, enabled: bool) -> Self {
+        self.integration_mappings = enabled;
+        self
+    }
+
+    /// Sets a custom sampler which is consulted for every root span. Child spans inherit the
+    /// decision of their root. If no sampler is set, no sampling priority is attached to spans
+    /// and the Datadog agent applies its own sampling.
+    pub fn sampler(mut self, sampler: impl Sampler + 'static) -> Self {
+        self.sampler = Some(Box::new(sampler));
+        self
+    }
+
+    /// Service tag used when the `DD_SERVICE` env var is not set.
+    pub fn default_service(mut self, service: impl Into<String>) -> Self {
+        self.default_service = Some(service.into());
+        self
+    }
+
+    /// Version tag used when the `DD_VERSION` env var is not set.
+    pub fn default_version(mut self, version: impl Into<String>) -> Self {
+        self.default_version = Some(version.into());
+        self
+    }
+
+    /// Decides which `grpc_status_code`s mark a span as an error when it is closed.
+    pub fn grpc_status_classifier(mut self, classifier: GrpcStatusClassifier) -> Self {
+        self.grpc_status_classifier = classifier;
+        self
+    }
+
+    /// Share of the error spans of traces rejected by the sampler which are exported anyway,
+    /// between `0.0` and `1.0` (the default). A kept error span also keeps the spans of its
+    /// trace which are still open; the ones which already closed were dropped. A rate of `NaN` is
+    /// ignored.
+    pub fn error_sample_rate(mut self, rate: f64) -> Self {
+        if rate.is_nan() {
+            log::warn!("Ignoring error sample rate of NaN");
+            return self;
+        }
+        self.error_sample_rate = Some(rate.clamp(0.0, 1.0));
+        self
+    }
+
+    /// In strict mode, misuses of the span API which are otherwise only logged (exiting a span
+    /// which is not the current one, closing a span more times than it was cloned, etc.) panic
+    /// with the metadata of the span, to catch integration bugs in CI. Misuses found while the
+    /// thread is already panicking, e.g. by the guard of a span dropped while unwinding, are only
+    /// logged, as panicking again would abort the process. It is enabled by default when the
+    /// `strict` feature is enabled.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = Some(strict);
+        self
+    }
+
+    /// Hands the diagnostics of span API misuses, with the metadata of the spans involved, to
+    /// `hook` instead of logging them or panicking in strict mode, e.g. to collect them and assert
+    /// there are none at the end of a test.
+    pub fn on_misuse(mut self, hook: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.on_misuse = Some(Box::new(hook));
+        self
+    }
+
+    /// Minimum time between two heartbeats of a span reporting its progress with
+    /// `DatadogSpanExt::dd_progress`, 30 seconds by default.
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = Some(interval);
+        self
+    }
+
+    /// Turns the events of `target` (and its submodules) into child spans of the current span,
+    /// for libraries which emit events but no spans, e.g. connection pool diagnostics. The spans
+    /// are named after the event target and their resource is the event message. They last no
+    /// time, unless the events have a `span_event` field: a `"start"` event then opens a span
+    /// which the next `"end"` event of the same target under the same parent closes.
+    pub fn span_from_events(
+        mut self,
+        target: &'static str,
+        (service, span_type): (ServiceName, SpanType),
+    ) -> Self {
+        self.event_span_rules.push((target, service, span_type));
+        self
+    }
+
+    /// What to do when the `env`, `service` or `version` tag has no value, `MissingTags::Empty`
+    /// by default.
+    pub fn missing_tags(mut self, missing_tags: MissingTags) -> Self {
+        self.missing_tags = missing_tags;
+        self
+    }
+
+    /// Unit of the values recorded in the `start` field of spans, nanoseconds by default. Start
+    /// times more than 30 days in the past or 5 minutes in the future are replaced with the
+    /// current time, and the span is tagged with the rejected value in `_dd.invalid_start`.
+    pub fn start_time_unit(mut self, unit: StartTimeUnit) -> Self {
+        self.start_time_unit = unit;
+        self
+    }
+
+    /// Copies the `key` tag of parent spans to their child spans when the children are created,
+    /// e.g. `customer.id`, so queries over child spans can filter by request attributes. Tags set
+    /// on the parent after a child was created are not copied to that child. `tenant` and `env`
+    /// are always inherited.
+    pub fn inherited_tag(mut self, key: impl Into<String>) -> Self {
+        self.inherited_tags.push(key.into());
+        self
+    }
+
+    /// Exports only root spans, i.e. the spans without a traced parent in this process like
+    /// request spans, to cut ingestion costs while keeping request latencies. Child spans are
+    /// dropped, but counted in the `_dd.child_spans.count` metric of their root, and their total
+    /// time in `_dd.child_spans.duration_ms`. Children closing after their root are lost.
+    pub fn root_spans_only(mut self, enabled: bool) -> Self {
+        self.root_spans_only = enabled;
+        self
+    }
+
+    /// Limits how many spans named `span_name` are traced to `spans_per_sec` on average, with
+    /// bursts of up to `burst` spans, so a misbehaving loop can't overwhelm the exporter. Excess
+    /// spans are not traced (their children become root spans), but counted: every 10 seconds,
+    /// a `_dd.dropped_spans` span per rate limited name reports how many were dropped.
+    pub fn rate_limit(mut self, span_name: SpanName, spans_per_sec: f64, burst: u32) -> Self {
+        self.rate_limits.push((span_name.0, spans_per_sec, burst));
+        self
+    }
+
+    /// Traces spans named `span_name` in only `percent` percent of traces, to roll out heavy new
+    /// instrumentation gradually and measure its overhead before going to 100%. The decision
+    /// depends on the trace id, so within a trace, either every span of the name is traced or
+    /// none is. Spans left out are not traced, and their children become root spans, like rate
+    /// limited spans.
+    pub fn rollout(mut self, span_name: SpanName, percent: f64) -> Self {
+        self.rollouts.insert(span_name.0, percent);
+        self
+    }
+
+    /// Drops spans matching any of `drop_rules` when they close, instead of the rules of the
+    /// `DD_APM_FILTER_TAGS_REJECT` and `DD_APM_FILTER_TAGS_REGEX_REJECT` env vars.
+    pub fn drop_rules(mut self, drop_rules: DropRules) -> Self {
+        self.drop_rules = Some(drop_rules);
+        self
+    }
+
+    /// Checks that exported spans have a service and a resource, which Datadog needs to render
+    /// them well, to catch mapping misconfigurations early. Spans missing either are exported
+    /// anyway, but logged with their name and callsite, at most once a minute per callsite; in
+    /// strict mode, they panic instead. Disabled by default.
+    pub fn check_required_fields(mut self, enabled: bool) -> Self {
+        self.check_required_fields = enabled;
+        self
+    }
+
+    /// Limits how many distinct values each meta tag can have, replacing further values with
+    /// `__truncated_cardinality__`. There is no limit unless a guard is set.
+    pub fn cardinality_guard(mut self, guard: CardinalityGuard) -> Self {
+        self.cardinality_guard = Some(guard);
+        self
+    }
+
+    /// Truncates meta values which are too long, like huge error stacks, before spans are sent
+    pub fn meta_truncator(mut self, truncator: MetaTruncator) -> Self {
+        self.meta_truncator = Some(truncator);
+        self
+    }
+
+    /// Replaces the values of sensitive fields, `password` and `ssn` by default, with
+    /// `[REDACTED]` in every span and event
+    pub fn field_redactor(mut self, redactor: FieldRedactor) -> Self {
+        self.field_redactor = redactor;
+        self
+    }
+
+    /// The dialect in which the queries recorded in `sql_query` fields are obfuscated, standard
+    /// SQL by default. MySQL queries must set `SqlDialect::MySql`, whose strings are escaped
+    /// differently.
+    pub fn sql_dialect(mut self, dialect: SqlDialect) -> Self {
+        self.sql_dialect = dialect;
+        self
+    }
+
+    /// Caps how many traced spans can be open at once, to bound memory during traffic spikes
+    /// or span leaks. Once the cap is reached, new traces are shed: their root spans and the
+    /// children of those are not traced, and counted by `TracingSubscriberDatadog::shed_traces`.
+    /// Child spans of traces already being traced, and root spans whose sampling priority is
+    /// `UserKeep` (e.g. recorded from an upstream service), are never shed. There is no cap by
+    /// default.
+    pub fn max_open_spans(mut self, max_open_spans: usize) -> Self {
+        self.max_open_spans = Some(max_open_spans);
+        self
+    }
+}
+
+/// Creates a `TracingSubscriberDatadogConfig` whose default service and version are the name
+/// and version of the Cargo package this macro is called from, so services without
+/// `DD_SERVICE` / `DD_VERSION` env vars still get meaningful tags.
+///
+/// ```ignore
+/// let config = tracing_datadog_apm::config_from_cargo!()
+///     .add_mapping(SpanName("request"), (ServiceName("my-service"), SpanType::Web));
+/// ```
+#[macro_export]
+macro_rules! config_from_cargo {
+    () => {
+        $crate::subscriber::TracingSubscriberDatadogConfig::new()
+            .default_service(env!("CARGO_PKG_NAME"))
+            .default_version(env!("CARGO_PKG_VERSION"))
+    };
+}
+
+/// Creates an `INFO` span which starts a new trace, even inside another span, e.g. to trace each
+/// message of a batch on its own. The span must be mapped like any other span, and can declare
+/// more fields after the name:
+///
+/// ```ignore
+/// for message in batch {
+///     let span = tracing_datadog_apm::new_trace_root!("queue.message", resource = "orders");
+///     span.in_scope(|| process(message));
+/// }
+/// ```
+#[macro_export]
+macro_rules! new_trace_root {
+    ($name:expr) => {
+        $crate::new_trace_root!($name,)
+    };
+    ($name:expr, $($fields:tt)*) => {
+        ::tracing::info_span!(parent: None, $name, $($fields)*)
+    };
 }
 
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+const REQUIRED_FIELDS_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+// The window around now in which recorded start times are plausible
+const MAX_START_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const MAX_START_AHEAD: Duration = Duration::from_secs(5 * 60);
+
+/// Tag of the spans which were still open when the subscriber was dropped, and were exported
+/// unfinished.
+pub const UNFINISHED_TAG: &str = "_dd.unfinished";
+
+// How long dropping the subscriber waits for the client to send the drained spans
+const DROP_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tag of spans whose recorded `start` was implausible, holding the rejected value.
+pub const INVALID_START_TAG: &str = "_dd.invalid_start";
+
+/// Metric of root spans counting their child spans, when only root spans are exported.
+pub const CHILD_SPANS_COUNT_METRIC_KEY: &str = "_dd.child_spans.count";
+
+/// Metric of root spans summing the durations of their child spans in milliseconds, when only
+/// root spans are exported.
+pub const CHILD_SPANS_DURATION_METRIC_KEY: &str = "_dd.child_spans.duration_ms";
+
 pub struct TracingSubscriberDatadog {
     datadog_client: Client,
     mappings: Mutex<HashMap<SpanName, (ServiceName, SpanType)>>,
-    span_builders: Mutex<HashMap<Id, SpanBuilder>>,
-    span_metadata: Mutex<HashMap<Id, &'static Metadata<'static>>>,
-    span_ref_count: Mutex<HashMap<Id, u32>>,
-    dd_env: String,
-    dd_service: String,
-    dd_version: String,
+    // the mappings of the integrations, per target of their spans
+    integration_mappings: HashMap<&'static str, HashMap<SpanName, (ServiceName, SpanType)>>,
+    sampler: Option<Box<dyn Sampler>>,
+    spans: SpanStore<SpanRecord>,
+    grpc_status_classifier: GrpcStatusClassifier,
+    cardinality_guard: Option<CardinalityGuard>,
+    meta_truncator: Option<MetaTruncator>,
+    field_redactor: FieldRedactor,
+    sql_dialect: SqlDialect,
+    error_sample_rate: f64,
+    strict: bool,
+    on_misuse: Option<MisuseHook>,
+    heartbeat_interval: Duration,
+    event_span_rules: Vec<EventSpanRule>,
+    pending_event_spans: Mutex<HashMap<(&'static str, Option<Id>), PendingEventSpan>>,
+    rate_limiter: Option<SpanRateLimiter>,
+    rollouts: Option<Rollouts>,
+    drop_rules: DropRules,
+    // when the required fields of each callsite were last warned about, if they are checked
+    required_fields_warnings: Option<Mutex<HashMap<Identifier, Instant>>>,
+    root_spans_only: bool,
+    inherited_tags: Vec<String>,
+    start_time_unit: StartTimeUnit,
+    // the `service`, `env` and `version` tags, shared by every span
+    dd_tags: Arc<HashMap<String, String>>,
+    max_open_spans: Option<usize>,
+    open_traced_spans: AtomicUsize,
+    shed_traces: AtomicU64,
 }
 
 impl TracingSubscriberDatadog {
+    /// Creates the subscriber.
+    ///
+    /// # Panics
+    /// If tags are missing and the config is set to `MissingTags::Fail`.
     pub fn new(datadog_client: Client, config: TracingSubscriberDatadogConfig) -> Self {
-        Self {
+        Self::try_new(datadog_client, config).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Creates the subscriber, unless tags are missing and the config is set to
+    /// `MissingTags::Fail`.
+    pub fn try_new(
+        datadog_client: Client,
+        config: TracingSubscriberDatadogConfig,
+    ) -> Result<Self, MissingTagsError> {
+        let dd_env = env::var("DD_ENV").unwrap_or_default();
+        let dd_service = env::var("DD_SERVICE")
+            .ok()
+            .or(config.default_service)
+            .unwrap_or_default();
+        let dd_version = env::var("DD_VERSION")
+            .ok()
+            .or(config.default_version)
+            .unwrap_or_default();
+        if config.missing_tags == MissingTags::Fail {
+            let env_vars: Vec<&'static str> = [
+                ("DD_ENV", &dd_env),
+                ("DD_SERVICE", &dd_service),
+                ("DD_VERSION", &dd_version),
+            ]
+            .into_iter()
+            .filter(|(_, value)| value.is_empty())
+            .map(|(env_var, _)| env_var)
+            .collect();
+            if !env_vars.is_empty() {
+                return Err(MissingTagsError { env_vars });
+            }
+        }
+
+        let omit_missing_tags = config.missing_tags == MissingTags::Omit;
+        let dd_tags: HashMap<String, String> = [
+            (SpanMetaKey::Service, &dd_service),
+            (SpanMetaKey::Env, &dd_env),
+            (SpanMetaKey::Version, &dd_version),
+        ]
+        .into_iter()
+        .filter(|(_, value)| !(omit_missing_tags && value.is_empty()))
+        .map(|(key, value)| (key.to_string(), value.clone()))
+        .collect();
+
+        let mut integration_mappings: HashMap<_, HashMap<_, _>> = HashMap::new();
+        if config.integration_mappings {
+            for mapping in enabled_integration_mappings() {
+                integration_mappings
+                    .entry(mapping.target)
+                    .or_default()
+                    .insert(
+                        mapping.span_name,
+                        (mapping.service(&dd_service), mapping.span_type.clone()),
+                    );
+            }
+        }
+
+        Ok(Self {
             datadog_client,
             mappings: Mutex::new(config.mappings),
-            span_builders: Mutex::new(HashMap::new()),
-            span_metadata: Mutex::new(HashMap::new()),
-            span_ref_count: Mutex::new(HashMap::new()),
-            dd_env: env::var("DD_ENV").unwrap_or_default(),
-            dd_service: env::var("DD_SERVICE").unwrap_or_default(),
-            dd_version: env::var("DD_VERSION").unwrap_or_default(),
-        }
+            integration_mappings,
+            sampler: config.sampler,
+            spans: SpanStore::new(),
+            grpc_status_classifier: config.grpc_status_classifier,
+            cardinality_guard: config.cardinality_guard,
+            meta_truncator: config.meta_truncator,
+            field_redactor: config.field_redactor,
+            sql_dialect: config.sql_dialect,
+            error_sample_rate: config.error_sample_rate.unwrap_or(1.0),
+            strict: config.strict.unwrap_or(cfg!(feature = "strict")),
+            on_misuse: config.on_misuse,
+            heartbeat_interval: config
+                .heartbeat_interval
+                .unwrap_or(DEFAULT_HEARTBEAT_INTERVAL),
+            event_span_rules: config.event_span_rules,
+            pending_event_spans: Mutex::new(HashMap::new()),
+            rate_limiter: (!config.rate_limits.is_empty())
+                .then(|| SpanRateLimiter::new(&config.rate_limits, DROPPED_SPANS_REPORT_INTERVAL)),
+            rollouts: (!config.rollouts.is_empty()).then(|| Rollouts::new(config.rollouts)),
+            drop_rules: config.drop_rules.unwrap_or_else(DropRules::from_env),
+            required_fields_warnings: config
+                .check_required_fields
+                .then(|| Mutex::new(HashMap::new())),
+            root_spans_only: config.root_spans_only,
+            inherited_tags: config.inherited_tags,
+            start_time_unit: config.start_time_unit,
+            dd_tags: Arc::new(dd_tags),
+            max_open_spans: config.max_open_spans,
+            open_traced_spans: AtomicUsize::new(0),
+            shed_traces: AtomicU64::new(0),
+        })
     }
 
+    /// How many traces were not traced because `max_open_spans` spans were open when they
+    /// started.
+    pub fn shed_traces(&self) -> u64 {
+        self.shed_traces.load(Ordering::Relaxed)
+    }
+
+    /// Lists the spans which are currently open, oldest first. Meant for debugging spans which
+    /// never close in production, e.g. from the actix-web `open_spans` debug endpoint.
+    pub fn snapshot(&self) -> Vec<OpenSpan> {
+        let now = now();
+        let mut open_spans: Vec<OpenSpan> = self
+            .spans()
+            .map(|spans| {
+                spans
+                    .iter()
+                    .filter_map(|(id, record)| {
+                        let span_builder = record.builder.as_ref()?;
+                        Some(OpenSpan {
+                            span_id: id.into_u64(),
+                            trace_id: span_builder.trace_id.get(),
+                            parent_id: span_builder.parent_id.map(NonZeroU64::get),
+                            name: span_builder.name.0,
+                            age: now.duration_since(span_builder.start).unwrap_or_default(),
+                            thread: record.thread.clone(),
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        open_spans.sort_by_key(|open_span| std::cmp::Reverse(open_span.age));
+        open_spans
+    }
+
+    // Records the progress of a span, and exports a heartbeat of it if the last one is older
+    // than the heartbeat interval
     #[inline]
-    fn span_builders(&self) -> Option<MutexGuard<HashMap<Id, SpanBuilder>>> {
-        self.span_builders
-            .lock()
-            .map_err(|e| {
-                log::error!("Unable to acquire lock on span builders map; err {}", e);
+    fn record_progress(&self, id: &Id, items_processed: u64, items_total: Option<u64>) {
+        let maybe_heartbeat = self
+            .update_span_builder(id, |span_builder| {
+                span_builder.progress(items_processed, items_total);
+                let sampled = span_builder
+                    .sampling_priority
+                    .is_none_or(|priority| priority.is_keep());
+                (sampled && span_builder.heartbeat_due(self.heartbeat_interval))
+                    .then(|| span_builder.heartbeat())
             })
-            .ok()
+            .flatten();
+        if let Some(heartbeat) = maybe_heartbeat {
+            log::debug!("Sending heartbeat of span {:?}", id);
+            self.datadog_client.send_traces(vec![vec![heartbeat]]);
+        }
+    }
+
+    #[inline]
+    fn spans(&self) -> Option<SpanStoreGuard<'_, SpanRecord>> {
+        self.spans.lock()
+    }
+
+    #[inline]
+    fn put_span(&self, id: Id, builder: Option<SpanBuilder>, metadata: &'static Metadata<'static>) {
+        self.put_record(id, builder, metadata, false);
+    }
+
+    #[inline]
+    fn put_record(
+        &self,
+        id: Id,
+        builder: Option<SpanBuilder>,
+        metadata: &'static Metadata<'static>,
+        shed: bool,
+    ) {
+        let current_thread = std::thread::current();
+        let thread = match current_thread.name() {
+            Some(name) => String::from(name),
+            None => format!("{:?}", current_thread.id()),
+        };
+        if let Some(mut spans) = self.spans() {
+            let local_root = builder
+                .as_ref()
+                .and_then(|span_builder| span_builder.parent_id)
+                .map(Id::from_non_zero_u64)
+                .and_then(|parent_id| {
+                    let parent = spans.get(&parent_id)?;
+                    Some(parent.local_root.clone().unwrap_or(parent_id))
+                });
+            if builder.is_some() {
+                self.open_traced_spans.fetch_add(1, Ordering::Relaxed);
+            }
+            let record = SpanRecord {
+                builder,
+                metadata,
+                thread,
+                local_root,
+                shed,
+            };
+            spans.insert(id, record);
+        }
     }
 
+    // Whether a span which starts a trace is shed, because its untraced parent was shed or
+    // `max_open_spans` spans are open. Shedding a new trace is counted, not its children.
     #[inline]
-    fn put_span_builder(&self, id: Id, span_builder: SpanBuilder) {
-        if let Some(mut map) = self.span_builders() {
-            map.insert(id, span_builder);
+    fn sheds(&self, span_builder: &SpanBuilder, parent_span_id: Option<&Id>) -> bool {
+        let Some(max_open_spans) = self.max_open_spans else {
+            return false;
+        };
+        let parent_shed = parent_span_id.is_some_and(|parent_span_id| {
+            self.spans()
+                .and_then(|spans| spans.get(parent_span_id).map(|record| record.shed))
+                .unwrap_or(false)
+        });
+        if parent_shed {
+            return true;
+        }
+        if span_builder.sampling_priority == Some(SamplingPriority::UserKeep)
+            || self.open_traced_spans.load(Ordering::Relaxed) < max_open_spans
+        {
+            return false;
         }
+        self.shed_traces.fetch_add(1, Ordering::Relaxed);
+        true
     }
 
+    // Records an event in the span `id`, as a span event if the client sends them, and into the
+    // meta of the span otherwise
     #[inline]
-    fn remove_span_builder(&self, id: &Id) -> Option<SpanBuilder> {
-        if let Some(mut map) = self.span_builders() {
-            map.remove(id)
+    fn record_event(&self, id: &Id, event: &Event<'_>) {
+        if self.datadog_client.records_span_events() {
+            let time = now();
+            let mut visitor = SpanEventVisitor::default();
+            event.record(&mut self.field_redactor.redacting(&mut visitor));
+            let name = visitor
+                .message
+                .unwrap_or_else(|| String::from(event.metadata().name()));
+            self.update_span_builder(id, |span_builder| {
+                span_builder.add_event(name, time, visitor.attributes);
+            });
         } else {
-            None
+            self.update_span_builder(id, |span_builder| {
+                self.record_fields(span_builder, |visitor| event.record(visitor))
+            });
         }
     }
 
+    // Records fields into the builder of a span, redacted, and with the queries of `sql_query`
+    // fields obfuscated in the configured dialect
     #[inline]
-    fn span_metadata(&self) -> Option<MutexGuard<HashMap<Id, &'static Metadata<'static>>>> {
-        self.span_metadata
-            .lock()
-            .map_err(|e| {
-                log::error!("Unable to acquire lock on span metadata map; err {}", e);
+    fn record_fields(&self, span_builder: &mut SpanBuilder, record: impl FnOnce(&mut dyn Visit)) {
+        let mut span_fields = SpanFields {
+            span_builder,
+            sql_dialect: self.sql_dialect,
+        };
+        record(&mut self.field_redactor.redacting(&mut span_fields));
+    }
+
+    // Gives access to the builder of a span, if it is traced
+    #[inline]
+    fn update_span_builder<R>(&self, id: &Id, f: impl FnOnce(&mut SpanBuilder) -> R) -> Option<R> {
+        let mut spans = self.spans()?;
+        spans.get_mut(id)?.builder.as_mut().map(f)
+    }
+
+    // The topmost traced ancestor in this process of a traced span, or the span itself
+    #[inline]
+    fn local_root_of(&self, id: &Id) -> Option<Id> {
+        let spans = self.spans()?;
+        let record = spans.get(id)?;
+        record.builder.as_ref()?;
+        Some(record.local_root.clone().unwrap_or_else(|| id.clone()))
+    }
+
+    // When only root spans are exported, counts a child span in its local root instead of
+    // exporting it. Returns whether the span was a child.
+    #[inline]
+    fn aggregate_into_root(&self, maybe_root: Option<&Id>, duration: Duration) -> bool {
+        let Some(root) = maybe_root.filter(|_| self.root_spans_only) else {
+            return false;
+        };
+        let aggregated = self.update_span_builder(root, |root_builder| {
+            let metrics = root_builder.metrics_mut();
+            *metrics
+                .entry(String::from(CHILD_SPANS_COUNT_METRIC_KEY))
+                .or_default() += 1.0;
+            *metrics
+                .entry(String::from(CHILD_SPANS_DURATION_METRIC_KEY))
+                .or_default() += duration.as_secs_f64() * 1000.0;
+        });
+        if aggregated.is_none() {
+            log::debug!("Root span {:?} already closed, dropping its child", root);
+        }
+        true
+    }
+
+    // Reports a misuse of the span API with the metadata of the spans involved. The spans map
+    // must not be locked.
+    #[inline]
+    fn invariant_violated(&self, message: std::fmt::Arguments<'_>, ids: &[&Id]) {
+        let spans: Vec<String> = match self.spans() {
+            Some(spans) => ids
+                .iter()
+                .map(|id| match spans.get(id).map(|record| record.metadata) {
+                    Some(metadata) => format!(
+                        "{:?} = {} ({}, {}:{})",
+                        id,
+                        metadata.name(),
+                        metadata.target(),
+                        metadata.file().unwrap_or("unknown file"),
+                        metadata.line().unwrap_or(0)
+                    ),
+                    None => format!("{:?} = unknown span", id),
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        let message = format!("{}; spans: [{}]", message, spans.join(", "));
+        if !self.misuse_handled(&message) {
+            log::error!("{}", message);
+        }
+    }
+
+    // Hands a misuse to the misuse hook, or panics in strict mode, returning false if it is left
+    // to be logged. Panicking while the thread is already panicking would abort the process.
+    #[inline]
+    fn misuse_handled(&self, message: &str) -> bool {
+        if let Some(on_misuse) = &self.on_misuse {
+            on_misuse(message);
+            return true;
+        }
+        if self.strict && !std::thread::panicking() {
+            panic!("{}", message);
+        }
+        false
+    }
+
+    // Warns about spans without a service or resource, at most once per interval per callsite,
+    // or panics in strict mode
+    #[inline]
+    fn check_required_fields(
+        &self,
+        span_builder: &SpanBuilder,
+        metadata: &'static Metadata<'static>,
+    ) {
+        let Some(warnings) = &self.required_fields_warnings else {
+            return;
+        };
+        let missing = span_builder.missing_required_fields();
+        if missing.is_empty() {
+            return;
+        }
+        let message = format!(
+            "Span {} ({}, {}:{}) has no {}; is its span name mapped?",
+            span_builder.name.0,
+            metadata.target(),
+            metadata.file().unwrap_or("unknown file"),
+            metadata.line().unwrap_or(0),
+            missing.join(" or ")
+        );
+        if self.misuse_handled(&message) {
+            return;
+        }
+        let now = Instant::now();
+        let due = match warnings.lock() {
+            Ok(mut warned_at) => match warned_at.get(&metadata.callsite()) {
+                Some(at)
+                    if now.saturating_duration_since(*at) < REQUIRED_FIELDS_WARNING_INTERVAL =>
+                {
+                    false
+                }
+                _ => {
+                    warned_at.insert(metadata.callsite(), now);
+                    true
+                }
+            },
+            Err(e) => {
+                log::error!(
+                    "Unable to acquire lock on required fields warnings; err {}",
+                    e
+                );
+                false
+            }
+        };
+        if due {
+            log::warn!("{}", message);
+        }
+    }
+
+    // Overrides the start of a span with a recorded `start` field, in the configured unit, unless
+    // it is implausible
+    #[inline]
+    fn record_start(&self, span_builder: &mut SpanBuilder, maybe_start: Option<u64>) {
+        let Some(value) = maybe_start else {
+            return;
+        };
+        let now = now();
+        let start = UNIX_EPOCH
+            .checked_add(self.start_time_unit.to_duration(value))
+            .filter(|start| {
+                now.checked_sub(MAX_START_AGE)
+                    .is_none_or(|earliest| *start >= earliest)
+                    && now
+                        .checked_add(MAX_START_AHEAD)
+                        .is_none_or(|latest| *start <= latest)
+            });
+        match start {
+            Some(start) => {
+                span_builder.start(start);
+            }
+            None => {
+                log::warn!(
+                    "Ignoring implausible start {} ({:?}) of span {:?}",
+                    value,
+                    self.start_time_unit,
+                    span_builder.span_id
+                );
+                span_builder
+                    .start(now)
+                    .add_tag(INVALID_START_TAG, value.to_string());
+            }
+        }
+    }
+
+    // Error spans bypass the sampler at the error sample rate: the span is kept, along with the
+    // spans of its trace which are still open
+    #[inline]
+    fn keep_error_trace(&self, span_builder: &mut SpanBuilder) {
+        let rejected = span_builder
+            .sampling_priority
+            .is_some_and(|priority| !priority.is_keep());
+        if !span_builder.error || !rejected || !rand::thread_rng().gen_bool(self.error_sample_rate)
+        {
+            return;
+        }
+        log::debug!(
+            "Keeping trace {:?} of rejected error span {:?}",
+            span_builder.trace_id,
+            span_builder.span_id
+        );
+        span_builder.sampling_priority(SamplingPriority::UserKeep);
+        if let Some(mut spans) = self.spans() {
+            spans
+                .values_mut()
+                .filter_map(|record| record.builder.as_mut())
+                .filter(|open_span| open_span.trace_id == span_builder.trace_id)
+                .for_each(|open_span| {
+                    open_span.sampling_priority(SamplingPriority::UserKeep);
+                });
+        }
+    }
+
+    // Marks a span as an error if its gRPC status code is classified as one
+    #[inline]
+    fn classify_grpc_status(&self, span_builder: &mut SpanBuilder) {
+        let maybe_code = span_builder
+            .meta_value(&SpanMetaKey::GrpcStatusCode.to_string())
+            .and_then(|code| u32::from_str(code).ok());
+        if let Some(code) = maybe_code {
+            if self.grpc_status_classifier.is_error(code) {
+                span_builder.error(true);
+                if span_builder
+                    .meta_value(&SpanMetaKey::ErrorType.to_string())
+                    .is_none()
+                {
+                    span_builder.add_meta(
+                        SpanMetaKey::ErrorType,
+                        grpc_status_code_name(code).unwrap_or("UNKNOWN"),
+                    );
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn add_dd_tags(&self, span_builder: &mut SpanBuilder) {
+        span_builder.shared_meta(Arc::clone(&self.dd_tags));
+    }
+
+    // The mapping of a span of an integration, which only maps the spans of its own target
+    #[inline]
+    fn integration_mapping(
+        &self,
+        metadata: &Metadata<'_>,
+        name: &str,
+    ) -> Option<(&SpanName, &(ServiceName, SpanType))> {
+        self.integration_mappings
+            .get(metadata.target())?
+            .get_key_value(name)
+    }
+
+    // Sends a span per rate limited span name, with how many of its spans were dropped since the
+    // last report
+    #[inline]
+    fn report_dropped_spans(&self, rate_limiter: &SpanRateLimiter, now: Instant) {
+        let Some(dropped) = rate_limiter.take_dropped_if_due(now) else {
+            return;
+        };
+        let services: Vec<Option<ServiceName>> = match self.mappings.lock() {
+            Ok(mappings) => dropped
+                .iter()
+                .map(|(name, _)| {
+                    mappings
+                        .get(*name)
+                        .or_else(|| {
+                            self.integration_mappings
+                                .values()
+                                .find_map(|integration_mappings| integration_mappings.get(*name))
+                        })
+                        .map(|(service, _)| *service)
+                })
+                .collect(),
+            Err(e) => {
+                log::error!("Failed to get lock on span name mappings; err {:?}", e);
+                return;
+            }
+        };
+        let traces = dropped
+            .into_iter()
+            .zip(services)
+            .map(|((name, count), service)| {
+                log::warn!(
+                    "Dropped {} spans named {} over their rate limit",
+                    count,
+                    name
+                );
+                let mut span_builder = SpanBuilder::default();
+                span_builder
+                    .name(SpanName(DROPPED_SPANS_SPAN_NAME))
+                    .resource(String::from(name))
+                    .span_type(SpanType::Custom)
+                    .metrics(HashMap::from([(
+                        String::from(DROPPED_SPANS_METRIC_KEY),
+                        count as f64,
+                    )]));
+                if let Some(service) = service {
+                    span_builder.service(service);
+                }
+                self.add_dd_tags(&mut span_builder);
+                vec![span_builder.build_with_duration(Duration::ZERO)]
             })
-            .ok()
+            .collect();
+        self.datadog_client.send_traces(traces);
+    }
+
+    // Makes a span a child of its parent, inheriting its trace and the tags which propagate to
+    // children. Returns false if the parent is not traced.
+    #[inline]
+    fn inherit_from_parent(&self, span_builder: &mut SpanBuilder, parent_span_id: &Id) -> bool {
+        let Some(spans) = self.spans() else {
+            return false;
+        };
+        let Some(parent_span_builder) = spans
+            .get(parent_span_id)
+            .and_then(|record| record.builder.as_ref())
+        else {
+            return false;
+        };
+        log::debug!(
+            "Setting trace id to {:?} like parent",
+            parent_span_builder.trace_id
+        );
+        span_builder.trace_id(parent_span_builder.trace_id);
+        if let Some(sampling_priority) = parent_span_builder.sampling_priority {
+            span_builder.sampling_priority(sampling_priority);
+        }
+        if let Some(tenant) = parent_span_builder.meta_value(&SpanMetaKey::Tenant.to_string()) {
+            span_builder.add_meta(SpanMetaKey::Tenant, tenant);
+        }
+        // the env of the process is already shared, only an overridden one is copied
+        let env_key = SpanMetaKey::Env.to_string();
+        if let Some(env) = parent_span_builder.meta_value(&env_key) {
+            if span_builder.meta_value(&env_key) != Some(env) {
+                span_builder.add_meta(SpanMetaKey::Env, env);
+            }
+        }
+        for key in &self.inherited_tags {
+            if let Some(value) = parent_span_builder.meta_value(key) {
+                span_builder.add_tag(key.clone(), value);
+            }
+        }
+        span_builder.parent_id(parent_span_id.into_non_zero_u64());
+        true
+    }
+
+    // Sends a closed span to Datadog. Spans of rejected traces are sent with their priority too,
+    // so that the agent counts them in the stats of their service before dropping them, unless
+    // the client counts them itself. Spans last until now, unless a duration is given.
+    #[inline]
+    fn export(
+        &self,
+        mut span_builder: SpanBuilder,
+        maybe_duration: Option<Duration>,
+        metadata: &'static Metadata<'static>,
+    ) {
+        self.check_required_fields(&span_builder, metadata);
+        self.classify_grpc_status(&mut span_builder);
+        if !self.drop_rules.is_empty() {
+            let duration = maybe_duration
+                .unwrap_or_else(|| now().duration_since(span_builder.start).unwrap_or_default());
+            if self.drop_rules.should_drop(&span_builder, duration) {
+                log::debug!(
+                    "Dropping span {:?} as it matches a drop rule",
+                    span_builder.span_id
+                );
+                return;
+            }
+        }
+        if let Some(meta_truncator) = &self.meta_truncator {
+            meta_truncator.truncate(span_builder.meta_mut());
+        }
+        if let Some(cardinality_guard) = &self.cardinality_guard {
+            cardinality_guard.guard(span_builder.meta_mut());
+        }
+        self.keep_error_trace(&mut span_builder);
+        let rejected = span_builder
+            .sampling_priority
+            .is_some_and(|priority| !priority.is_keep());
+        let span = match maybe_duration {
+            Some(duration) => span_builder.build_with_duration(duration),
+            None => span_builder.build(),
+        };
+        if rejected && self.datadog_client.records_sampled_out() {
+            log::debug!(
+                "Dropping span {:?} as its trace was not sampled",
+                span_builder.span_id
+            );
+            self.datadog_client.record_sampled_out(&span);
+            return;
+        }
+        let traces = vec![vec![span]];
+        log::debug!("Generated traces: {:?}", &traces);
+        self.datadog_client.send_traces(traces);
     }
 
     #[inline]
-    fn put_metadata(&self, id: Id, metadata: &'static Metadata<'static>) {
-        self.span_metadata().map(|mut map| map.insert(id, metadata));
+    fn event_span_rule(&self, metadata: &Metadata<'_>) -> Option<&EventSpanRule> {
+        if !metadata.is_event() {
+            return None;
+        }
+        let target = metadata.target();
+        self.event_span_rules.iter().find(|(rule_target, _, _)| {
+            target
+                .strip_prefix(rule_target)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+        })
     }
 
+    // The local root of the spans synthesized from events, which are children of their parent
     #[inline]
-    fn remove_metadata(&self, id: &Id) {
-        self.span_metadata().map(|mut map| map.remove(id));
+    fn event_span_root(&self, maybe_parent_id: Option<&Id>) -> Option<Id> {
+        maybe_parent_id
+            .filter(|_| self.root_spans_only)
+            .and_then(|parent_id| self.local_root_of(parent_id))
+    }
+
+    // Turns an event into a span, or opens or closes a span for paired start and end events
+    #[inline]
+    fn synthesize_event_span(&self, event: &Event<'_>, rule: &EventSpanRule) {
+        let (_, service, span_type) = rule;
+        let target = event.metadata().target();
+        let maybe_parent_id = if event.is_contextual() {
+            current_span_id()
+        } else {
+            event.parent().cloned()
+        };
+        let mut visitor = EventVisitor::default();
+        event.record(&mut self.field_redactor.redacting(&mut visitor));
+        let pending_key = (target, maybe_parent_id.clone());
+
+        if visitor.span_event.as_deref() == Some("end") {
+            let maybe_pending = self
+                .pending_event_spans
+                .lock()
+                .map(|mut pending| pending.remove(&pending_key))
+                .unwrap_or_else(|e| {
+                    log::error!("Unable to acquire lock on pending event spans; err {}", e);
+                    None
+                });
+            match maybe_pending {
+                Some((span_builder, metadata)) => {
+                    let duration = now().duration_since(span_builder.start).unwrap_or_default();
+                    let maybe_root = self.event_span_root(pending_key.1.as_ref());
+                    if !self.aggregate_into_root(maybe_root.as_ref(), duration) {
+                        self.export(span_builder, None, metadata);
+                    }
+                }
+                None => log::debug!("No span was started by an event of {}", target),
+            }
+            return;
+        }
+
+        let mut span_builder = SpanBuilder::default();
+        span_builder
+            .name(SpanName(target))
+            .service(*service)
+            .span_type(span_type.clone());
+        self.add_dd_tags(&mut span_builder);
+        if let Some(message) = visitor.message {
+            span_builder.resource(message);
+        }
+        let has_parent = maybe_parent_id
+            .as_ref()
+            .is_some_and(|parent_id| self.inherit_from_parent(&mut span_builder, parent_id));
+        if !has_parent {
+            if let (Some(sampler), None) = (&self.sampler, span_builder.sampling_priority) {
+                let sampling_priority = sampler.should_sample(&span_builder, event.metadata());
+                span_builder.sampling_priority(sampling_priority);
+            }
+        }
+        self.record_fields(&mut span_builder, |visitor| event.record(visitor));
+
+        if visitor.span_event.as_deref() == Some("start") {
+            self.pending_event_spans
+                .lock()
+                .map(|mut pending| pending.insert(pending_key, (span_builder, event.metadata())))
+                .map_err(|e| {
+                    log::error!("Unable to acquire lock on pending event spans; err {}", e)
+                })
+                .ok();
+        } else {
+            let maybe_root = self.event_span_root(maybe_parent_id.as_ref());
+            if !self.aggregate_into_root(maybe_root.as_ref(), Duration::ZERO) {
+                self.export(span_builder, Some(Duration::ZERO), event.metadata());
+            }
+        }
+    }
+}
+
+// Everything the subscriber keeps about an open span, in a single map so that each lifecycle
+// event takes a single lock. Spans named at runtime whose name is not mapped have no builder:
+// they are tracked so they can be entered and closed, but they are not traced.
+struct SpanRecord {
+    builder: Option<SpanBuilder>,
+    metadata: &'static Metadata<'static>,
+    thread: String,
+    // the topmost traced ancestor in this process, for child spans
+    local_root: Option<Id>,
+    // whether the span is not traced because its trace was shed by `max_open_spans`
+    shed: bool,
+}
+
+/// A span which has been created but not closed yet, as listed by
+/// `TracingSubscriberDatadog::snapshot`.
+#[derive(Clone, Debug, Serialize)]
+pub struct OpenSpan {
+    pub span_id: u64,
+    pub trace_id: u64,
+    pub parent_id: Option<u64>,
+    pub name: &'static str,
+    pub age: Duration,
+    /// The thread the span was created on
+    pub thread: String,
+}
+
+// Spans still open at the end of `main` would vanish along with the traces queued in the client,
+// so they are exported unfinished and the client is flushed
+impl Drop for TracingSubscriberDatadog {
+    fn drop(&mut self) {
+        let open_spans: Vec<(Id, SpanRecord)> = self
+            .spans()
+            .map(|mut spans| spans.drain().collect())
+            .unwrap_or_default();
+        let pending_event_spans: Vec<PendingEventSpan> = self
+            .pending_event_spans
+            .lock()
+            .map(|mut pending| {
+                pending
+                    .drain()
+                    .map(|(_, pending_span)| pending_span)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let unfinished = open_spans
+            .into_iter()
+            .filter_map(|(_, record)| Some((record.builder?, record.metadata)))
+            .chain(pending_event_spans);
+        let mut drained = 0;
+        for (mut span_builder, metadata) in unfinished {
+            span_builder.add_tag(UNFINISHED_TAG, "true");
+            self.export(span_builder, None, metadata);
+            drained += 1;
+        }
+        // doesn't wait on the thread of a current-thread tokio runtime, which the client's task needs
+        let flush_result = self.datadog_client.flush(DROP_FLUSH_TIMEOUT);
+        if drained > 0 || flush_result != FlushResult::Flushed {
+            log::info!(
+                "Subscriber dropped; exported {} unfinished spans, flush {:?}",
+                drained,
+                flush_result
+            );
+        }
     }
 }
 
@@ -109,14 +1250,30 @@ impl TracingSubscriberDatadog {
 // the current span for formatting events, etc...
 #[inline]
 fn current_span_id() -> Option<Id> {
-    CURRENT_SPAN.with(|stack| stack.borrow().last().map(Id::clone))
+    CURRENT_SPAN.with(|stack| stack.borrow().last().cloned())
 }
 
 impl Subscriber for TracingSubscriberDatadog {
     #[inline]
     fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        // spans named at runtime are checked against the mappings in `new_span`
+        if metadata.fields().field(SPAN_NAME_FIELD).is_some() {
+            return true;
+        }
+        if self.event_span_rule(metadata).is_some() {
+            return true;
+        }
+        // every event may be recorded in the current span as a span event
+        if metadata.is_event() && self.datadog_client.records_span_events() {
+            return true;
+        }
         match self.mappings.lock() {
-            Ok(mappings) => mappings.contains_key(&SpanName(metadata.name())),
+            Ok(mappings) => {
+                mappings.contains_key(&SpanName(metadata.name()))
+                    || self
+                        .integration_mapping(metadata, metadata.name())
+                        .is_some()
+            }
             Err(e) => {
                 log::error!("Failed to get lock on span name mappings; err {:?}", e);
                 false
@@ -131,47 +1288,101 @@ impl Subscriber for TracingSubscriberDatadog {
         log::debug!("Making new span: {:?} with id {:?}", span, id);
 
         // set span name, type, and service
-        let name = SpanName(span.metadata().name());
-        match self.mappings.lock() {
-            Ok(mappings) => {
-                let (service, span_type) = mappings.get(&name).unwrap(); // safe to unwrap because span name was checked in fn `enabled`
-                span_builder.span_type(span_type.clone());
-                span_builder.service(*service);
+        let runtime_name = runtime_span_name(span);
+        let metadata = span.metadata();
+        let name = runtime_name.as_deref().unwrap_or(metadata.name());
+        let mapping = match self.mappings.lock() {
+            Ok(mappings) => runtime_name
+                .as_deref()
+                .and_then(|name| mappings.get_key_value(name))
+                .or_else(|| mappings.get_key_value(metadata.name()))
+                .or_else(|| self.integration_mapping(metadata, name))
+                .map(|(name, (service, span_type))| (*name, *service, span_type.clone())),
+            Err(e) => {
+                log::error!("Failed to get lock on span name mappings; err {:?}", e);
+                None
+            }
+        };
+        let Some((name, service, span_type)) = mapping else {
+            // only spans named at runtime get here; their lifetime is tracked so they can be
+            // entered and closed, but they are not traced and their children become roots
+            log::debug!(
+                "Span name {:?} is not mapped, not tracing span {:?}",
+                runtime_name,
+                id
+            );
+            self.put_span(id.clone(), None, span.metadata());
+            return id;
+        };
+        if let Some(rate_limiter) = &self.rate_limiter {
+            let now = Instant::now();
+            self.report_dropped_spans(rate_limiter, now);
+            if !rate_limiter.allow(name.0, now) {
+                log::debug!("Span {:?} is rate limited, not tracing span {:?}", name, id);
+                self.put_span(id.clone(), None, span.metadata());
+                return id;
             }
-            Err(e) => log::error!("Failed to get lock on span name mappings; err {:?}", e),
         }
+        span_builder.span_type(span_type);
+        span_builder.service(service);
         span_builder.name(name);
 
-        // add DD tags
-        span_builder.add_meta(SpanMetaKey::Service, self.dd_service.clone());
-        span_builder.add_meta(SpanMetaKey::Env, self.dd_env.clone());
-        span_builder.add_meta(SpanMetaKey::Version, self.dd_version.clone());
+        self.add_dd_tags(&mut span_builder);
 
         // set child / parent relationship if applicable
-        if let Some(parent_span_id) = current_span_id() {
-            log::debug!("Span {:?} is a child of span {:?}", id, parent_span_id);
-            if let Some(span_builders_map) = self.span_builders() {
-                if let Some(parent_span_builder) = span_builders_map.get(&parent_span_id) {
-                    log::debug!(
-                        "Setting trace id to {:?} like parent",
-                        parent_span_builder.trace_id
-                    );
-                    span_builder.trace_id(parent_span_builder.trace_id);
-                }
+        // explicit roots (`parent: None`) start a new trace, even inside another span
+        let maybe_parent_span_id = if span.is_root() {
+            None
+        } else if span.is_contextual() {
+            current_span_id()
+        } else {
+            span.parent().cloned()
+        };
+        let mut has_parent = false;
+        if let Some(parent_span_id) = &maybe_parent_span_id {
+            if self.inherit_from_parent(&mut span_builder, parent_span_id) {
+                log::debug!("Span {:?} is a child of span {:?}", id, parent_span_id);
+                has_parent = true;
+            }
+        }
+        self.record_fields(&mut span_builder, |visitor| span.record(visitor));
+        if span.metadata().fields().field(START_FIELD).is_some() {
+            let maybe_start = start_field(|visitor| span.record(visitor));
+            self.record_start(&mut span_builder, maybe_start);
+        }
+        // after the trace id is final, as recorded trace ids (e.g. from upstream) replace it
+        if let Some(rollouts) = &self.rollouts {
+            if !rollouts.includes(name.0, span_builder.trace_id) {
+                log::debug!(
+                    "Span {:?} is not rolled out to trace {:?}, not tracing span {:?}",
+                    name,
+                    span_builder.trace_id,
+                    id
+                );
+                self.put_span(id.clone(), None, span.metadata());
+                return id;
+            }
+        }
+        if !has_parent && self.sheds(&span_builder, maybe_parent_span_id.as_ref()) {
+            log::debug!("Too many open spans, shedding the trace of span {:?}", id);
+            self.put_record(id.clone(), None, span.metadata(), true);
+            return id;
+        }
+        if !has_parent {
+            // a sampling priority recorded on the span (e.g. from an upstream service) wins
+            if let (Some(sampler), None) = (&self.sampler, span_builder.sampling_priority) {
+                let sampling_priority = sampler.should_sample(&span_builder, span.metadata());
+                log::debug!(
+                    "Sampler decided {:?} for root span {:?}",
+                    sampling_priority,
+                    id
+                );
+                span_builder.sampling_priority(sampling_priority);
             }
-            span_builder.parent_id(parent_span_id.into_non_zero_u64());
         }
-        span.record(&mut span_builder);
 
         // store span builder
-        self.put_span_builder(id.clone(), span_builder);
-        self.put_metadata(id.clone(), span.metadata());
-        self.span_ref_count
-            .lock()
-            .map(|mut ref_counts| {
-                ref_counts.insert(id.clone(), 1);
-            })
-            .ok();
+        self.put_span(id.clone(), Some(span_builder), span.metadata());
 
         id
     }
@@ -180,51 +1391,40 @@ impl Subscriber for TracingSubscriberDatadog {
     fn record(&self, span: &Id, values: &Record<'_>) {
         log::debug!("Record {:?} for span {:?}", values, span);
         if !values.is_empty() {
-            match self.span_builders.lock() {
-                Ok(mut span_builders_map) => {
-                    if let Some(span_builder) = span_builders_map.get_mut(span) {
-                        values.record(span_builder);
-                    };
-                }
-                Err(e) => {
-                    log::error!("Unable to acquire lock on span builders map; err {}", e);
-                }
-            }
+            let maybe_start = start_field(|visitor| values.record(visitor));
+            self.update_span_builder(span, |span_builder| {
+                self.record_fields(span_builder, |visitor| values.record(visitor));
+                self.record_start(span_builder, maybe_start);
+            });
         }
     }
 
     #[inline]
-    fn record_follows_from(&self, _span: &Id, _follows: &Id) {
-        unimplemented!()
+    fn record_follows_from(&self, span: &Id, follows: &Id) {
+        log::debug!("Span {:?} follows from span {:?}", span, follows);
+        // spans which are not traced, or closed already, can't be linked to
+        let Some((trace_id, span_id)) = self.spans().and_then(|spans| {
+            let follows_builder = spans.get(follows)?.builder.as_ref()?;
+            Some((follows_builder.trace_id, follows_builder.span_id))
+        }) else {
+            return;
+        };
+        self.update_span_builder(span, |span_builder| {
+            span_builder.add_link(trace_id, span_id, HashMap::new());
+        });
     }
 
     #[inline]
     fn event(&self, event: &Event<'_>) {
         log::debug!("Received event: {:?}", event);
-        if event.is_contextual() {
+        if let Some(rule) = self.event_span_rule(event.metadata()) {
+            self.synthesize_event_span(event, rule);
+        } else if event.is_contextual() {
             if let Some(id) = current_span_id() {
-                match self.span_builders.lock() {
-                    Ok(mut span_builders_map) => {
-                        if let Some(span_builder) = span_builders_map.get_mut(&id) {
-                            event.record(span_builder);
-                        };
-                    }
-                    Err(e) => {
-                        log::error!("Unable to acquire lock on span builders map; err {}", e);
-                    }
-                }
+                self.record_event(&id, event);
             };
         } else if let Some(parent_span) = event.parent() {
-            match self.span_builders.lock() {
-                Ok(mut span_builders_map) => {
-                    if let Some(span_builder) = span_builders_map.get_mut(parent_span) {
-                        event.record(span_builder);
-                    };
-                }
-                Err(e) => {
-                    log::error!("Unable to acquire lock on span builders map; err {}", e);
-                }
-            }
+            self.record_event(parent_span, event);
         }
     }
 
@@ -237,77 +1437,84 @@ impl Subscriber for TracingSubscriberDatadog {
 
     #[inline]
     fn exit(&self, id: &Id) {
-        CURRENT_SPAN.with(|stack| {
-            match stack.borrow_mut().pop() {
-                Some(popped_id) => {
-                    if popped_id != *id {
-                        log::error!("Popped an id which was not the id passed in! Passed in: {:?} - popped: {:?}", id, popped_id);
-                    }
-                }
-                None => {
-                    log::error!("Did not exit a span! Passed in: {:?} - popped: N/A", id);
+        let maybe_popped_id = CURRENT_SPAN.with(|stack| stack.borrow_mut().pop());
+        match maybe_popped_id {
+            Some(popped_id) => {
+                if popped_id != *id {
+                    self.invariant_violated(
+                        format_args!(
+                            "Popped an id which was not the id passed in! Passed in: {:?} - popped: {:?}",
+                            id, popped_id
+                        ),
+                        &[id, &popped_id],
+                    );
                 }
             }
-
-        })
+            None => {
+                self.invariant_violated(
+                    format_args!("Did not exit a span! Passed in: {:?} - popped: N/A", id),
+                    &[id],
+                );
+            }
+        }
     }
 
     #[inline]
     fn clone_span(&self, id: &Id) -> Id {
-        self.span_ref_count
-            .lock()
-            .map(|mut ref_counts| {
-                if let Some(ref_count) = ref_counts.get_mut(id) {
-                    *ref_count += 1;
-                } else {
-                    log::error!("Could not clone span {:?} as it did not exist in map", id);
-                }
-            })
-            .ok();
+        if !self.spans.clone_ref(id) {
+            self.invariant_violated(
+                format_args!("Could not clone span {:?} as it did not exist in map", id),
+                &[id],
+            );
+        }
         id.clone()
     }
 
     #[inline]
     fn try_close(&self, id: Id) -> bool {
         log::debug!("Try close span {:?}", id);
-        match self.span_ref_count.lock() {
-            Ok(mut ref_counts) => {
-                let maybe_ref_count = ref_counts.get_mut(&id);
-                if let Some(ref_count) = maybe_ref_count {
-                    if *ref_count - 1 == 0 {
-                        if let Some(span_builder) = self.remove_span_builder(&id) {
-                            let traces = vec![vec![span_builder.build()]];
-                            log::debug!("Generated traces: {:?}", &traces);
-                            self.datadog_client.send_traces(traces);
-                        } else {
-                            log::error!("Could not find span builder to remove for span {:?}", id);
+        match self.spans.release(&id) {
+            Release::Open => false,
+            Release::Closed(record) => {
+                if record.builder.is_some() {
+                    self.open_traced_spans.fetch_sub(1, Ordering::Relaxed);
+                }
+                match record {
+                    SpanRecord {
+                        builder: Some(span_builder),
+                        metadata,
+                        local_root,
+                        ..
+                    } => {
+                        let duration = now().duration_since(span_builder.start).unwrap_or_default();
+                        if !self.aggregate_into_root(local_root.as_ref(), duration) {
+                            self.export(span_builder, None, metadata);
                         }
-                        self.remove_metadata(&id);
-                        return true;
-                    } else if (*ref_count as i32 - 1) < 0 {
-                        log::error!("Error with reference counting! Ref count was at 0 and try_close was called");
-                    } else {
-                        *ref_count -= 1;
                     }
-                } else {
-                    log::error!(
+                    _ => log::debug!("Span {:?} was not traced", id),
+                }
+                true
+            }
+            Release::Missing => {
+                self.invariant_violated(
+                    format_args!(
                         "Could not try_close span {:?} as it did not exist in map",
                         id
-                    );
-                }
+                    ),
+                    &[&id],
+                );
+                false
             }
-            Err(e) => log::error!("Failed to acquire lock on ref counts; err {:?}", e),
         }
-        false
     }
 
     #[inline]
     fn current_span(&self) -> Current {
         match current_span_id().and_then(|id| {
-            self.span_metadata().and_then(|metadata_map| {
-                metadata_map
+            self.spans().and_then(|spans| {
+                spans
                     .get(&id)
-                    .map(|metadata| Current::new(id.clone(), metadata))
+                    .map(|record| Current::new(id.clone(), record.metadata))
             })
         }) {
             Some(val) => val,
@@ -328,6 +1535,20 @@ enum FieldName {
     ErrorType,
     ErrorMsg,
     ErrorStack,
+    SpanLink,
+    Tenant,
+    CorrelationId,
+    SqlQuery,
+    Env,
+    SamplingPriority,
+    GrpcStatusCode,
+    SpanName,
+    Message,
+    SpanEvent,
+    OtelName,
+    OtelKind,
+    OtelStatusCode,
+    OtelStatusMessage,
 }
 
 impl FromStr for FieldName {
@@ -339,13 +1560,28 @@ impl FromStr for FieldName {
             "trace_id" => Ok(Self::TraceId),
             "parent_id" => Ok(Self::ParentId),
             "resource" => Ok(Self::Resource),
-            "start" => Ok(Self::Start),
+            START_FIELD => Ok(Self::Start),
             "http_method" => Ok(Self::HttpMethod),
             "http_url" => Ok(Self::HttpUrl),
             "http_status_code" => Ok(Self::HttpStatusCode),
             "error_type" => Ok(Self::ErrorType),
             "error_msg" => Ok(Self::ErrorMsg),
             "error_stack" => Ok(Self::ErrorStack),
+            "span_link" => Ok(Self::SpanLink),
+            "tenant" => Ok(Self::Tenant),
+            "correlation_id" => Ok(Self::CorrelationId),
+            SQL_QUERY_FIELD => Ok(Self::SqlQuery),
+            "env" => Ok(Self::Env),
+            "sampling_priority" => Ok(Self::SamplingPriority),
+            "grpc_status_code" => Ok(Self::GrpcStatusCode),
+            SPAN_NAME_FIELD => Ok(Self::SpanName),
+            "message" => Ok(Self::Message),
+            SPAN_EVENT_FIELD => Ok(Self::SpanEvent),
+            // the fields of tracing-opentelemetry, to ease migrating instrumented code
+            "otel.name" => Ok(Self::OtelName),
+            "otel.kind" => Ok(Self::OtelKind),
+            "otel.status_code" => Ok(Self::OtelStatusCode),
+            "otel.status_message" => Ok(Self::OtelStatusMessage),
             _ => Err(()),
         }
     }
@@ -376,6 +1612,9 @@ impl Visit for SpanBuilder {
             FieldName::HttpStatusCode => {
                 self.add_meta(SpanMetaKey::HttpStatusCode, value.to_string());
             }
+            FieldName::GrpcStatusCode => {
+                self.add_meta(SpanMetaKey::GrpcStatusCode, value.to_string());
+            }
             FieldName::Start => {
                 self.start(UNIX_EPOCH.add(Duration::from_nanos(value)));
             }
@@ -428,6 +1667,48 @@ impl Visit for SpanBuilder {
                 self.error(true);
                 self.add_meta(SpanMetaKey::ErrorStack, value);
             }
+            FieldName::SpanLink => match parse_span_link(value) {
+                Some((trace_id, span_id)) => {
+                    self.add_link(trace_id, span_id, HashMap::new());
+                }
+                None => log::error!("Failed parsing span_link: {}", value),
+            },
+            FieldName::Tenant => {
+                self.add_meta(SpanMetaKey::Tenant, value);
+            }
+            FieldName::CorrelationId => {
+                self.add_meta(SpanMetaKey::CorrelationId, value);
+            }
+            FieldName::SqlQuery => {
+                self.add_meta(SpanMetaKey::SqlQuery, obfuscate_sql(value));
+            }
+            FieldName::Env => {
+                self.add_meta(SpanMetaKey::Env, value);
+            }
+            FieldName::GrpcStatusCode => {
+                self.add_meta(SpanMetaKey::GrpcStatusCode, value);
+            }
+            FieldName::SamplingPriority => match i32::from_str(value)
+                .ok()
+                .and_then(SamplingPriority::from_i32)
+            {
+                Some(sampling_priority) => {
+                    self.sampling_priority(sampling_priority);
+                }
+                None => log::error!("Invalid sampling priority: {}", value),
+            },
+            FieldName::OtelName => {
+                self.resource(String::from(value));
+            }
+            FieldName::OtelKind => {
+                self.add_meta(SpanMetaKey::SpanKind, value.to_ascii_lowercase());
+            }
+            FieldName::OtelStatusCode if value.eq_ignore_ascii_case("error") => {
+                self.error(true);
+            }
+            FieldName::OtelStatusMessage => {
+                self.add_meta(SpanMetaKey::ErrorMsg, value);
+            }
             _ => {}
         }
     }
@@ -437,3 +1718,332 @@ impl Visit for SpanBuilder {
         self.record_str(field, &format!("{:?}", value))
     }
 }
+
+// Records the fields of a span into its builder, obfuscating the queries of `sql_query` fields in
+// the dialect of the subscriber rather than as standard SQL
+struct SpanFields<'a> {
+    span_builder: &'a mut SpanBuilder,
+    sql_dialect: SqlDialect,
+}
+
+impl SpanFields<'_> {
+    #[inline]
+    fn record_sql_query(&mut self, field: &Field, query: &str) -> bool {
+        let is_sql_query = field.name() == SQL_QUERY_FIELD;
+        if is_sql_query {
+            self.span_builder.add_meta(
+                SpanMetaKey::SqlQuery,
+                obfuscate_sql_with(query, self.sql_dialect),
+            );
+        }
+        is_sql_query
+    }
+}
+
+impl Visit for SpanFields<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.span_builder.record_f64(field, value);
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.span_builder.record_i64(field, value);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.span_builder.record_u64(field, value);
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.span_builder.record_bool(field, value);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if !self.record_sql_query(field, value) {
+            self.span_builder.record_str(field, value);
+        }
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        self.span_builder.record_error(field, value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if !self.record_sql_query(field, &format!("{:?}", value)) {
+            self.span_builder.record_debug(field, value);
+        }
+    }
+}
+
+type MisuseHook = Box<dyn Fn(&str) + Send + Sync>;
+
+// Name of the field whose query is obfuscated and tagged as `sql.query`
+const SQL_QUERY_FIELD: &str = "sql_query";
+
+// Name of the field which overrides the name of a span when looking up its mapping, for spans
+// whose name is only known at runtime (e.g. the actix-web request span)
+const SPAN_NAME_FIELD: &str = "span_name";
+
+// Name of the field which overrides the start of a span
+const START_FIELD: &str = "start";
+
+// Name of the field which pairs the start and end events of spans synthesized from events
+const SPAN_EVENT_FIELD: &str = "span_event";
+
+// Collects the fields of an event which shape the span synthesized from it
+#[derive(Default)]
+struct EventVisitor {
+    message: Option<String>,
+    span_event: Option<String>,
+}
+
+impl Visit for EventVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = Some(String::from(value)),
+            SPAN_EVENT_FIELD => self.span_event = Some(String::from(value)),
+            _ => {}
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.record_str(field, &format!("{:?}", value))
+    }
+}
+
+// Collects the fields of an event recorded as a span event: its message names the span event,
+// and its other fields are the attributes
+#[derive(Default)]
+struct SpanEventVisitor {
+    message: Option<String>,
+    attributes: HashMap<String, String>,
+}
+
+impl Visit for SpanEventVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = Some(String::from(value));
+        } else {
+            self.attributes
+                .insert(String::from(field.name()), String::from(value));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.record_str(field, &format!("{:?}", value))
+    }
+}
+
+#[inline]
+fn runtime_span_name(span: &Attributes<'_>) -> Option<String> {
+    struct SpanNameVisitor(Option<String>);
+
+    impl Visit for SpanNameVisitor {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            if field.name() == SPAN_NAME_FIELD {
+                self.0 = Some(String::from(value));
+            }
+        }
+
+        fn record_debug(&mut self, _field: &Field, _value: &dyn Debug) {}
+    }
+
+    let mut visitor = SpanNameVisitor(None);
+    span.record(&mut visitor);
+    visitor.0
+}
+
+// The value of the `start` field, if it was recorded
+#[inline]
+fn start_field(record: impl FnOnce(&mut dyn Visit)) -> Option<u64> {
+    struct StartVisitor(Option<u64>);
+
+    impl Visit for StartVisitor {
+        fn record_u64(&mut self, field: &Field, value: u64) {
+            if field.name() == START_FIELD {
+                self.0 = Some(value);
+            }
+        }
+
+        fn record_i64(&mut self, field: &Field, value: i64) {
+            if field.name() == START_FIELD {
+                self.0 = Some(u64::try_from(value).unwrap_or(0));
+            }
+        }
+
+        fn record_debug(&mut self, _field: &Field, _value: &dyn Debug) {}
+    }
+
+    let mut visitor = StartVisitor(None);
+    record(&mut visitor);
+    visitor.0
+}
+
+// Span links are recorded as `{trace_id}-{span_id}`, with both ids in decimal like the Datadog
+// propagation headers (not in hex like b3 ids)
+#[inline]
+fn parse_span_link(value: &str) -> Option<(NonZeroU64, NonZeroU64)> {
+    let (trace_id, span_id) = value.split_once('-')?;
+    Some((
+        NonZeroU64::from_str(trace_id.trim()).ok()?,
+        NonZeroU64::from_str(span_id.trim()).ok()?,
+    ))
+}
+
+/// Formats a span link so it can be recorded on the `span_link` field of a span. Recording the
+/// field multiple times adds one link per recording, which is how a batch consumer links its
+/// span to the producer span of every message in the batch:
+///
+/// ```ignore
+/// let span = tracing::info_span!("batch.consume", span_link = tracing::field::Empty);
+/// for message in &batch {
+///     span.record("span_link", &*span_link(message.trace_id, message.span_id));
+/// }
+/// ```
+#[inline]
+pub fn span_link(trace_id: NonZeroU64, span_id: NonZeroU64) -> String {
+    format!("{}-{}", trace_id, span_id)
+}
+
+/// Tags a span with a tag whose name is only known at runtime, which can't be declared as a
+/// field of the span. It has no effect on spans which are not traced by a
+/// `TracingSubscriberDatadog`.
+#[inline]
+pub fn set_tag(span: &tracing::Span, key: impl Into<String>, value: impl Into<String>) {
+    with_span_builder(span, |span_builder| {
+        span_builder.add_tag(key, value);
+    });
+}
+
+// Gives access to the builder of a span, if it is traced by a `TracingSubscriberDatadog`
+#[inline]
+pub(crate) fn with_span_builder<R>(
+    span: &tracing::Span,
+    f: impl FnOnce(&mut SpanBuilder) -> R,
+) -> Option<R> {
+    span.with_subscriber(|(id, dispatch)| {
+        let subscriber = dispatch.downcast_ref::<TracingSubscriberDatadog>()?;
+        subscriber.update_span_builder(id, f)
+    })
+    .flatten()
+}
+
+/// The request id of the current request, as tagged on the current span or its closest traced
+/// ancestor by the HTTP middlewares (from the `x-request-id` header by default), so applications
+/// can echo it in logs and error responses.
+pub fn current_request_id() -> Option<String> {
+    tracing::Span::current()
+        .with_subscriber(|(id, dispatch)| {
+            let subscriber = dispatch.downcast_ref::<TracingSubscriberDatadog>()?;
+            let spans = subscriber.spans()?;
+            let span_builder_of =
+                |id: &Id| spans.get(id).and_then(|record| record.builder.as_ref());
+            let request_id_key = SpanMetaKey::HttpRequestId.to_string();
+            let mut maybe_span_builder = span_builder_of(id);
+            while let Some(span_builder) = maybe_span_builder {
+                if let Some(request_id) = span_builder.meta_value(&request_id_key) {
+                    return Some(String::from(request_id));
+                }
+                maybe_span_builder = span_builder
+                    .parent_id
+                    .and_then(|parent_id| span_builder_of(&Id::from_non_zero_u64(parent_id)));
+            }
+            None
+        })
+        .flatten()
+}
+
+/// Link to a trace in the Datadog UI of the site set in `DD_SITE` (`datadoghq.com` by default),
+/// to embed in error reports, admin pages or alerts.
+pub fn datadog_trace_url(trace_id: NonZeroU64) -> String {
+    let site = agentless::site_from_env();
+    // regional sites like `us3.datadoghq.com` serve the UI themselves, the others from `app.`
+    let host = if site.matches('.').count() > 1 {
+        site
+    } else {
+        format!("app.{}", site)
+    };
+    format!("https://{}/apm/trace/{}", host, trace_id)
+}
+
+/// Link to the trace of the current span in the Datadog UI, like `datadog_trace_url`.
+pub fn current_trace_url() -> Option<String> {
+    tracing::Span::current()
+        .dd_trace_id()
+        .map(datadog_trace_url)
+}
+
+/// Datadog operations on `tracing` spans, which don't require the span to declare the fields
+/// they set. They have no effect (or return `None`) on spans which are not traced by a
+/// `TracingSubscriberDatadog`.
+pub trait DatadogSpanExt {
+    /// Sets the resource of the span, e.g. the route of a request.
+    fn set_dd_resource(&self, resource: impl Into<String>);
+
+    /// Tags the span, like `set_tag`.
+    fn set_dd_tag(&self, key: impl Into<String>, value: impl Into<String>);
+
+    /// Marks the span as an error and records the chain of sources of `err`, with the same
+    /// layout as `SpanBuilder::record_error_chain`.
+    fn set_dd_error(&self, err: &(dyn std::error::Error + 'static));
+
+    /// The id of the Datadog trace the span belongs to, e.g. to log it or return it to clients.
+    fn dd_trace_id(&self) -> Option<NonZeroU64>;
+
+    /// The Datadog id of the span.
+    fn dd_span_id(&self) -> Option<NonZeroU64>;
+
+    /// Records the progress of a long running span, e.g. a batch job, like
+    /// `SpanBuilder::progress`. At most once per heartbeat interval, a heartbeat of the span
+    /// with its progress so far is exported, so it is visible before the span closes.
+    fn dd_progress(&self, items_processed: u64, items_total: Option<u64>);
+}
+
+impl DatadogSpanExt for tracing::Span {
+    #[inline]
+    fn set_dd_resource(&self, resource: impl Into<String>) {
+        with_span_builder(self, |span_builder| {
+            span_builder.resource(resource.into());
+        });
+    }
+
+    #[inline]
+    fn set_dd_tag(&self, key: impl Into<String>, value: impl Into<String>) {
+        set_tag(self, key, value);
+    }
+
+    #[inline]
+    fn set_dd_error(&self, err: &(dyn std::error::Error + 'static)) {
+        with_span_builder(self, |span_builder| {
+            span_builder.record_error_chain(err);
+        });
+    }
+
+    #[inline]
+    fn dd_trace_id(&self) -> Option<NonZeroU64> {
+        with_span_builder(self, |span_builder| span_builder.trace_id)
+    }
+
+    #[inline]
+    fn dd_span_id(&self) -> Option<NonZeroU64> {
+        with_span_builder(self, |span_builder| span_builder.span_id)
+    }
+
+    #[inline]
+    fn dd_progress(&self, items_processed: u64, items_total: Option<u64>) {
+        self.with_subscriber(|(id, dispatch)| {
+            if let Some(subscriber) = dispatch.downcast_ref::<TracingSubscriberDatadog>() {
+                subscriber.record_progress(id, items_processed, items_total);
+            }
+        });
+    }
+}
+
+/// Records the whole chain of `Error::source()`s of an error on a `tracing` span, with the same
+/// layout as `SpanBuilder::record_error_chain`. The span must declare the `error_msg` and
+/// `error_stack` fields.
+#[inline]
+pub fn record_error_chain(span: &tracing::Span, err: &(dyn std::error::Error + 'static)) {
+    let (error_msg, error_stack) = format_error_chain(err);
+    span.record("error_msg", &*error_msg);
+    span.record("error_stack", &*error_stack);
+}
@@ -0,0 +1,25 @@
+use tracing::{Dispatch, Span};
+
+/// Like `tokio::task::spawn_blocking`, but the closure runs inside the span which is current
+/// when this is called, and with the same subscriber. `spawn_blocking` runs the closure on
+/// another thread, where the span stack of the calling thread is lost, so spans created in
+/// blocking sections would otherwise start new traces instead of being children of the caller.
+///
+/// ```ignore
+/// let rows = spawn_blocking_traced(move || {
+///     let _span = tracing::info_span!("db.query", resource = "SELECT ...").entered();
+///     run_query()
+/// })
+/// .await?;
+/// ```
+pub fn spawn_blocking_traced<F, R>(f: F) -> tokio::task::JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let span = Span::current();
+    let dispatch = tracing::dispatcher::get_default(Dispatch::clone);
+    tokio::task::spawn_blocking(move || {
+        tracing::dispatcher::with_default(&dispatch, || span.in_scope(f))
+    })
+}
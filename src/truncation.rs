@@ -0,0 +1,181 @@
+use super::validation::MAX_TAG_VALUE_LEN;
+use std::collections::HashMap;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// Shortens meta values which are too long for Datadog, like huge error stacks. Values longer
+/// than the max length of their key keep their head and tail, which usually hold the error and
+/// its origin, around a `...[N bytes truncated]...` marker. Optionally, a hash of the whole
+/// value is appended, so truncated values which only differ in the middle can still be told
+/// apart and deduplicated.
+///
+/// By default, only `error.stack` is truncated, to the 25000 bytes Datadog accepts per tag value.
+#[derive(Debug, Clone)]
+pub struct MetaTruncator {
+    max_lengths: HashMap<String, usize>,
+    hash_suffix: bool,
+}
+
+impl Default for MetaTruncator {
+    fn default() -> Self {
+        Self {
+            max_lengths: HashMap::from([(String::from("error.stack"), MAX_TAG_VALUE_LEN)]),
+            hash_suffix: false,
+        }
+    }
+}
+
+impl MetaTruncator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Truncates the values of `key` to `max_len` bytes.
+    pub fn max_length(mut self, key: impl Into<String>, max_len: usize) -> Self {
+        self.max_lengths.insert(key.into(), max_len);
+        self
+    }
+
+    /// Appends ` #<hash>`, a hash of the whole value, to truncated values.
+    pub fn hash_suffix(mut self, enabled: bool) -> Self {
+        self.hash_suffix = enabled;
+        self
+    }
+
+    /// Truncates the values which are longer than the max length of their key.
+    #[inline]
+    pub fn truncate(&self, meta: &mut HashMap<String, String>) {
+        for (key, value) in meta.iter_mut() {
+            if let Some(max_len) = self.max_lengths.get(key) {
+                if value.len() > *max_len {
+                    *value = self.truncate_value(value, *max_len);
+                }
+            }
+        }
+    }
+
+    fn truncate_value(&self, value: &str, max_len: usize) -> String {
+        let suffix = if self.hash_suffix {
+            format!(" #{:016x}", fnv1a(value.as_bytes()))
+        } else {
+            String::new()
+        };
+        // the marker's length depends on the number of truncated bytes, which is bounded by the
+        // length of the value
+        let marker_len = format!("\n...[{} bytes truncated]...\n", value.len()).len();
+        let kept = max_len.saturating_sub(marker_len + suffix.len());
+        let head = floor_char_boundary(value, kept - kept / 2);
+        let tail = ceil_char_boundary(value, value.len() - kept / 2);
+        let mut truncated = format!(
+            "{}\n...[{} bytes truncated]...\n{}{}",
+            &value[..head],
+            tail - head,
+            &value[tail..],
+            suffix
+        );
+        if truncated.len() > max_len {
+            // too short to keep anything but a part of the marker
+            let end = floor_char_boundary(&truncated, max_len);
+            truncated.truncate(end);
+        }
+        truncated
+    }
+}
+
+// Hashes are only compared between values, so a fast non-cryptographic hash which is stable
+// across Rust versions will do
+#[inline]
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+#[inline]
+fn floor_char_boundary(value: &str, mut index: usize) -> usize {
+    while !value.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+#[inline]
+fn ceil_char_boundary(value: &str, mut index: usize) -> usize {
+    while !value.is_char_boundary(index) {
+        index += 1;
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(key: &str, value: &str) -> HashMap<String, String> {
+        HashMap::from([(key.to_string(), value.to_string())])
+    }
+
+    #[test]
+    fn test_short_values_are_kept() {
+        let mut meta = meta("error.stack", "at main.rs:1");
+
+        MetaTruncator::new().truncate(&mut meta);
+
+        assert_eq!(meta["error.stack"], "at main.rs:1");
+    }
+
+    #[test]
+    fn test_keeps_head_and_tail() {
+        let value = format!("HEAD{}TAIL", "x".repeat(1000));
+        let mut meta = meta("error.stack", &value);
+
+        MetaTruncator::new()
+            .max_length("error.stack", 100)
+            .truncate(&mut meta);
+
+        let truncated = &meta["error.stack"];
+        assert!(truncated.len() <= 100);
+        assert!(truncated.starts_with("HEAD"));
+        assert!(truncated.ends_with("TAIL"));
+        assert!(truncated.contains("bytes truncated"));
+    }
+
+    #[test]
+    fn test_hash_suffix_tells_values_apart() {
+        let first = format!("HEAD{}TAIL", "a".repeat(1000));
+        let second = format!("HEAD{}TAIL", "b".repeat(1000));
+        let truncator = MetaTruncator::new()
+            .max_length("error.stack", 100)
+            .hash_suffix(true);
+        let mut first_meta = meta("error.stack", &first);
+        let mut second_meta = meta("error.stack", &second);
+
+        truncator.truncate(&mut first_meta);
+        truncator.truncate(&mut second_meta);
+
+        assert!(first_meta["error.stack"].len() <= 100);
+        assert_ne!(first_meta["error.stack"], second_meta["error.stack"]);
+    }
+
+    #[test]
+    fn test_respects_char_boundaries() {
+        let mut meta = meta("error.stack", &"é".repeat(500));
+
+        MetaTruncator::new()
+            .max_length("error.stack", 101)
+            .truncate(&mut meta);
+
+        assert!(meta["error.stack"].len() <= 101);
+    }
+
+    #[test]
+    fn test_other_keys_are_kept() {
+        let value = "x".repeat(30_000);
+        let mut meta = meta("error.msg", &value);
+
+        MetaTruncator::new().truncate(&mut meta);
+
+        assert_eq!(meta["error.msg"], value);
+    }
+}
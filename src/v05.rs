@@ -0,0 +1,176 @@
+// Encoder of the v0.5 trace format of the Datadog agent, which is much smaller than v0.3 for busy
+// services: every string of the payload is interned in a shared string table, and spans are
+// fixed arrays referring to it instead of maps. The payload is the msgpack array
+// `[strings, traces]`, where each span is
+// `[service, name, resource, trace_id, span_id, parent_id, start, duration, error, meta, metrics,
+// type]`, and strings are indexes into `strings`, whose first string is always empty.
+//
+// v0.5 has no field for span links or `meta_struct`, so they are not sent.
+
+use serde::Serialize;
+use std::collections::HashMap;
+
+pub(crate) const CONTENT_TYPE: &str = "application/msgpack";
+
+#[derive(Serialize)]
+struct SpanV05(
+    u32,
+    u32,
+    u32,
+    u64,
+    u64,
+    u64,
+    i64,
+    i64,
+    i32,
+    HashMap<u32, u32>,
+    HashMap<u32, f64>,
+    u32,
+);
+
+#[derive(Default)]
+struct StringTable {
+    strings: Vec<String>,
+    indexes: HashMap<String, u32>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        let mut table = Self::default();
+        table.index("");
+        table
+    }
+
+    #[inline]
+    fn index(&mut self, string: &str) -> u32 {
+        if let Some(index) = self.indexes.get(string) {
+            return *index;
+        }
+        let index = self.strings.len() as u32;
+        self.strings.push(String::from(string));
+        self.indexes.insert(String::from(string), index);
+        index
+    }
+}
+
+/// Encodes traces, as serialized for the v0.3 format, into a v0.5 payload.
+pub(crate) fn encode(trace_json: &serde_json::Value) -> Vec<u8> {
+    let mut table = StringTable::new();
+    let traces: Vec<Vec<SpanV05>> = trace_json
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|trace| {
+            trace
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|span| encode_span(span, &mut table))
+                .collect()
+        })
+        .collect();
+    rmp_serde::to_vec(&(&table.strings, traces)).unwrap_or_else(|e| {
+        log::error!("Failed to encode traces in the v0.5 format; err {}", e);
+        Vec::new()
+    })
+}
+
+#[inline]
+fn encode_span(span: &serde_json::Value, table: &mut StringTable) -> SpanV05 {
+    let mut string = |key: &str| table.index(span[key].as_str().unwrap_or_default());
+    let service = string("service");
+    let name = string("name");
+    let resource = string("resource");
+    let r#type = string("type");
+    let meta = span["meta"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(key, value)| Some((table.index(key), table.index(value.as_str()?))))
+        .collect();
+    let metrics = span["metrics"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(key, value)| Some((table.index(key), value.as_f64()?)))
+        .collect();
+    SpanV05(
+        service,
+        name,
+        resource,
+        span["trace_id"].as_u64().unwrap_or_default(),
+        span["span_id"].as_u64().unwrap_or_default(),
+        span["parent_id"].as_u64().unwrap_or_default(),
+        span["start"].as_i64().unwrap_or_default(),
+        span["duration"].as_i64().unwrap_or_default(),
+        span["error"].as_i64().unwrap_or_default() as i32,
+        meta,
+        metrics,
+        r#type,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::span::{ServiceName, SpanBuilder, SpanName};
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    struct DecodedSpan(
+        u32,
+        u32,
+        u32,
+        u64,
+        u64,
+        u64,
+        i64,
+        i64,
+        i32,
+        HashMap<u32, u32>,
+        HashMap<u32, f64>,
+        u32,
+    );
+
+    #[test]
+    fn test_encode_interns_strings() {
+        // given
+        let mut span_builder = SpanBuilder::default();
+        span_builder
+            .service(ServiceName("orders"))
+            .name(SpanName("request"))
+            .resource(String::from("GET /orders"))
+            .add_tag("env", "orders");
+        span_builder.metrics_mut().insert(String::from("rows"), 3.0);
+        let span = span_builder.build();
+        let child = span_builder.parent_id(span_builder.span_id).build();
+        let trace_json = serde_json::to_value(vec![vec![span.clone(), child]]).unwrap();
+
+        // when
+        let payload = encode(&trace_json);
+
+        // then
+        let (strings, traces): (Vec<String>, Vec<Vec<DecodedSpan>>) =
+            rmp_serde::from_slice(&payload).unwrap();
+        assert_eq!(strings[0], "");
+        let string = |index: u32| strings[index as usize].as_str();
+        let index_of = |s: &str| strings.iter().position(|t| t == s).unwrap() as u32;
+        let root = &traces[0][0];
+        assert_eq!(string(root.0), "orders");
+        assert_eq!(string(root.1), "request");
+        assert_eq!(string(root.2), "GET /orders");
+        assert_eq!(root.3, span.trace_id());
+        assert_eq!(root.4, span.span_id());
+        assert_eq!(root.5, 0);
+        assert_eq!(root.6, trace_json[0][0]["start"].as_i64().unwrap());
+        assert_eq!(root.7, trace_json[0][0]["duration"].as_i64().unwrap());
+        assert_eq!(root.8, 0);
+        let (key, value) = root.9.iter().next().unwrap();
+        assert_eq!((string(*key), string(*value)), ("env", "orders"));
+        assert_eq!(root.10.get(&index_of("rows")), Some(&3.0));
+        assert_eq!(string(root.11), "custom");
+        // the service and the env value are the same string
+        assert_eq!(strings.iter().filter(|s| *s == "orders").count(), 1);
+        assert_eq!(traces[0][1].5, span.span_id());
+    }
+}
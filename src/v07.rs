@@ -0,0 +1,329 @@
+// Encoder of the v0.7 trace format of the Datadog agent, the msgpack encoding of the agent's own
+// `TracerPayload` (of the datadog-agent `pb` package) with named fields:
+//
+// TracerPayload { language_name, tracer_version, chunks: [TraceChunk], env, hostname, app_version }
+// TraceChunk { priority, spans: [Span] }
+// Span { service, name, resource, trace_id, span_id, parent_id, start, duration, error, meta,
+//        metrics, type, meta_struct, span_links, span_events }
+// SpanEvent { time_unix_nano, name, attributes: { key: AnyValue } }
+//
+// Unlike the older formats, it has a field for span events, so events recorded in spans aren't
+// flattened into their meta, and fields for the env and version of the application, which are
+// those of the spans' unified service tags.
+
+use super::base64;
+use super::span::SAMPLING_PRIORITY_METRIC_KEY;
+use serde::{Serialize, Serializer};
+use std::collections::HashMap;
+
+pub(crate) const CONTENT_TYPE: &str = "application/msgpack";
+
+// `AnyValue.type` of string attributes
+const STRING_VALUE_TYPE: u8 = 0;
+
+// What a payload tells the agent about the process its traces come from
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TracerMetadata {
+    pub(crate) env: String,
+    pub(crate) hostname: String,
+    pub(crate) app_version: String,
+}
+
+#[derive(Serialize)]
+struct TracerPayload<'a> {
+    language_name: &'static str,
+    tracer_version: &'static str,
+    chunks: Vec<TraceChunk<'a>>,
+    env: &'a str,
+    hostname: &'a str,
+    app_version: &'a str,
+}
+
+#[derive(Serialize)]
+struct TraceChunk<'a> {
+    priority: i32,
+    spans: Vec<SpanV07<'a>>,
+}
+
+#[derive(Serialize)]
+struct SpanV07<'a> {
+    service: &'a str,
+    name: &'a str,
+    resource: &'a str,
+    trace_id: u64,
+    span_id: u64,
+    parent_id: u64,
+    start: i64,
+    duration: i64,
+    error: i32,
+    meta: HashMap<&'a str, &'a str>,
+    metrics: HashMap<&'a str, f64>,
+    r#type: &'a str,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    meta_struct: HashMap<&'a str, Bytes>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    span_links: Vec<SpanLinkV07<'a>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    span_events: Vec<SpanEventV07<'a>>,
+}
+
+#[derive(Serialize)]
+struct SpanLinkV07<'a> {
+    trace_id: u64,
+    span_id: u64,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    attributes: HashMap<&'a str, &'a str>,
+}
+
+#[derive(Serialize)]
+struct SpanEventV07<'a> {
+    time_unix_nano: u64,
+    name: &'a str,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    attributes: HashMap<&'a str, AnyValue<'a>>,
+}
+
+#[derive(Serialize)]
+struct AnyValue<'a> {
+    r#type: u8,
+    string_value: &'a str,
+}
+
+// `meta_struct` values are base64 strings in the JSON of spans, but bytes in msgpack payloads
+struct Bytes(Vec<u8>);
+
+impl Serialize for Bytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+/// Encodes traces, as serialized for the v0.3 format, into a v0.7 payload.
+pub(crate) fn encode(trace_json: &serde_json::Value, tracer_metadata: &TracerMetadata) -> Vec<u8> {
+    let chunks = trace_json
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|trace| {
+            let spans = trace.as_array().map(Vec::as_slice).unwrap_or_default();
+            TraceChunk {
+                priority: spans
+                    .iter()
+                    .find_map(|span| span["metrics"][SAMPLING_PRIORITY_METRIC_KEY].as_f64())
+                    .unwrap_or(1.0) as i32,
+                spans: spans.iter().map(encode_span).collect(),
+            }
+        })
+        .collect();
+    let tracer_payload = TracerPayload {
+        language_name: "rust",
+        tracer_version: env!("CARGO_PKG_VERSION"),
+        chunks,
+        env: unified_service_tag(trace_json, "env").unwrap_or(&tracer_metadata.env),
+        hostname: &tracer_metadata.hostname,
+        app_version: unified_service_tag(trace_json, "version")
+            .unwrap_or(&tracer_metadata.app_version),
+    };
+    rmp_serde::to_vec_named(&tracer_payload).unwrap_or_else(|e| {
+        log::error!("Failed to encode traces in the v0.7 format; err {}", e);
+        Vec::new()
+    })
+}
+
+#[inline]
+fn encode_span(span: &serde_json::Value) -> SpanV07<'_> {
+    let string = |key: &str| span[key].as_str().unwrap_or_default();
+    SpanV07 {
+        service: string("service"),
+        name: string("name"),
+        resource: string("resource"),
+        trace_id: span["trace_id"].as_u64().unwrap_or_default(),
+        span_id: span["span_id"].as_u64().unwrap_or_default(),
+        parent_id: span["parent_id"].as_u64().unwrap_or_default(),
+        start: span["start"].as_i64().unwrap_or_default(),
+        duration: span["duration"].as_i64().unwrap_or_default(),
+        error: span["error"].as_i64().unwrap_or_default() as i32,
+        meta: string_map(&span["meta"]),
+        metrics: span["metrics"]
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter_map(|(key, value)| Some((key.as_str(), value.as_f64()?)))
+            .collect(),
+        r#type: string("type"),
+        meta_struct: span["meta_struct"]
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter_map(|(key, value)| {
+                Some((key.as_str(), Bytes(base64::decode(value.as_str()?)?)))
+            })
+            .collect(),
+        span_links: span["span_links"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|span_link| SpanLinkV07 {
+                trace_id: span_link["trace_id"].as_u64().unwrap_or_default(),
+                span_id: span_link["span_id"].as_u64().unwrap_or_default(),
+                attributes: string_map(&span_link["attributes"]),
+            })
+            .collect(),
+        span_events: span["span_events"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|span_event| SpanEventV07 {
+                time_unix_nano: span_event["time_unix_nano"].as_u64().unwrap_or_default(),
+                name: span_event["name"].as_str().unwrap_or_default(),
+                attributes: string_map(&span_event["attributes"])
+                    .into_iter()
+                    .map(|(key, string_value)| {
+                        let value = AnyValue {
+                            r#type: STRING_VALUE_TYPE,
+                            string_value,
+                        };
+                        (key, value)
+                    })
+                    .collect(),
+            })
+            .collect(),
+    }
+}
+
+// A tag of the first span of the payload, which payloads are split by, so that spans whose env or
+// version was overridden are reported under their own rather than that of the process
+#[inline]
+pub(crate) fn unified_service_tag<'a>(
+    trace_json: &'a serde_json::Value,
+    key: &str,
+) -> Option<&'a str> {
+    trace_json[0][0]["meta"][key]
+        .as_str()
+        .filter(|value| !value.is_empty())
+}
+
+#[inline]
+fn string_map(json: &serde_json::Value) -> HashMap<&str, &str> {
+    json.as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(key, value)| Some((key.as_str(), value.as_str()?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::span::{ServiceName, SpanBuilder, SpanName};
+    use serde::Deserialize;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[derive(Deserialize)]
+    struct DecodedPayload {
+        language_name: String,
+        chunks: Vec<DecodedChunk>,
+        env: String,
+        hostname: String,
+        app_version: String,
+    }
+
+    #[derive(Deserialize)]
+    struct DecodedChunk {
+        priority: i32,
+        spans: Vec<DecodedSpan>,
+    }
+
+    #[derive(Deserialize)]
+    struct DecodedSpan {
+        service: String,
+        name: String,
+        parent_id: u64,
+        meta: HashMap<String, String>,
+        #[serde(default)]
+        span_events: Vec<DecodedSpanEvent>,
+    }
+
+    #[derive(Deserialize)]
+    struct DecodedSpanEvent {
+        time_unix_nano: u64,
+        name: String,
+        attributes: HashMap<String, DecodedAnyValue>,
+    }
+
+    #[derive(Deserialize)]
+    struct DecodedAnyValue {
+        r#type: u8,
+        string_value: String,
+    }
+
+    #[test]
+    fn test_encode_sends_span_events() {
+        // given
+        let mut span_builder = SpanBuilder::default();
+        span_builder
+            .service(ServiceName("orders"))
+            .name(SpanName("request"))
+            .add_tag("env", "prod")
+            .add_event(
+                "cache miss",
+                UNIX_EPOCH + Duration::from_nanos(42),
+                HashMap::from([(String::from("key"), String::from("order:1"))]),
+            );
+        let trace_json = serde_json::to_value(vec![vec![span_builder.build()]]).unwrap();
+
+        let tracer_metadata = TracerMetadata {
+            env: String::from("prod"),
+            hostname: String::from("orders-1"),
+            app_version: String::from("1.2.3"),
+        };
+
+        // when
+        let payload = encode(&trace_json, &tracer_metadata);
+
+        // then
+        let decoded: DecodedPayload = rmp_serde::from_slice(&payload).unwrap();
+        assert_eq!(decoded.language_name, "rust");
+        assert_eq!(decoded.env, "prod");
+        assert_eq!(decoded.hostname, "orders-1");
+        assert_eq!(decoded.app_version, "1.2.3");
+        let chunk = &decoded.chunks[0];
+        assert_eq!(chunk.priority, 1);
+        let span = &chunk.spans[0];
+        assert_eq!(span.service, "orders");
+        assert_eq!(span.name, "request");
+        assert_eq!(span.parent_id, 0);
+        assert_eq!(span.meta["env"], "prod");
+        let span_event = &span.span_events[0];
+        assert_eq!(span_event.time_unix_nano, 42);
+        assert_eq!(span_event.name, "cache miss");
+        assert_eq!(span_event.attributes["key"].r#type, STRING_VALUE_TYPE);
+        assert_eq!(span_event.attributes["key"].string_value, "order:1");
+    }
+
+    #[test]
+    fn test_encode_prefers_unified_service_tags_of_spans() {
+        // given
+        let mut span_builder = SpanBuilder::default();
+        span_builder
+            .service(ServiceName("orders"))
+            .add_tag("env", "canary")
+            .add_tag("version", "1.3.0-rc.1");
+        let trace_json = serde_json::to_value(vec![vec![span_builder.build()]]).unwrap();
+        let tracer_metadata = TracerMetadata {
+            env: String::from("prod"),
+            hostname: String::from("orders-1"),
+            app_version: String::from("1.2.3"),
+        };
+
+        // when
+        let payload = encode(&trace_json, &tracer_metadata);
+
+        // then
+        let decoded: DecodedPayload = rmp_serde::from_slice(&payload).unwrap();
+        assert_eq!(decoded.env, "canary");
+        assert_eq!(decoded.app_version, "1.3.0-rc.1");
+        assert_eq!(decoded.chunks[0].spans[0].service, "orders");
+        assert_eq!(decoded.chunks[0].spans[0].meta["version"], "1.3.0-rc.1");
+    }
+}
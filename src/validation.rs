@@ -0,0 +1,214 @@
+use super::datadog_client::{Span, Traces};
+use std::fmt::{Display, Formatter};
+
+const MAX_NAME_LEN: usize = 100;
+const MAX_SERVICE_LEN: usize = 100;
+const MAX_RESOURCE_LEN: usize = 5000;
+const MAX_TAG_KEY_LEN: usize = 200;
+pub(crate) const MAX_TAG_VALUE_LEN: usize = 25_000;
+pub(crate) const MAX_PAYLOAD_BYTES: usize = 10 * 1024 * 1024;
+
+/// A way in which a payload doesn't meet the constraints of Datadog. The agent either rejects
+/// such spans or silently normalizes them (e.g. truncating names), so they are worth catching
+/// before they reach production.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    ZeroTraceId {
+        span_id: u64,
+    },
+    ZeroSpanId {
+        trace_id: u64,
+    },
+    MissingService {
+        span_id: u64,
+    },
+    MissingResource {
+        span_id: u64,
+    },
+    NameTooLong {
+        span_id: u64,
+        len: usize,
+    },
+    ServiceTooLong {
+        span_id: u64,
+        len: usize,
+    },
+    ResourceTooLong {
+        span_id: u64,
+        len: usize,
+    },
+    InvalidTagKey {
+        span_id: u64,
+        key: String,
+    },
+    TagValueTooLong {
+        span_id: u64,
+        key: String,
+        len: usize,
+    },
+    PayloadTooLarge {
+        bytes: usize,
+    },
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Violation::ZeroTraceId { span_id } => write!(f, "span {} has a zero trace id", span_id),
+            Violation::ZeroSpanId { trace_id } => {
+                write!(f, "a span of trace {} has a zero span id", trace_id)
+            }
+            Violation::MissingService { span_id } => write!(f, "span {} has no service", span_id),
+            Violation::MissingResource { span_id } => {
+                write!(f, "span {} has no resource", span_id)
+            }
+            Violation::NameTooLong { span_id, len } => write!(
+                f,
+                "span {} has a name of {} chars, above {}",
+                span_id, len, MAX_NAME_LEN
+            ),
+            Violation::ServiceTooLong { span_id, len } => write!(
+                f,
+                "span {} has a service of {} chars, above {}",
+                span_id, len, MAX_SERVICE_LEN
+            ),
+            Violation::ResourceTooLong { span_id, len } => write!(
+                f,
+                "span {} has a resource of {} chars, above {}",
+                span_id, len, MAX_RESOURCE_LEN
+            ),
+            Violation::InvalidTagKey { span_id, key } => {
+                write!(f, "span {} has an invalid tag name {:?}", span_id, key)
+            }
+            Violation::TagValueTooLong { span_id, key, len } => write!(
+                f,
+                "span {} has a value of {} chars for tag {}, above {}",
+                span_id, len, key, MAX_TAG_VALUE_LEN
+            ),
+            Violation::PayloadTooLarge { bytes } => write!(
+                f,
+                "payload of {} bytes is above {} bytes",
+                bytes, MAX_PAYLOAD_BYTES
+            ),
+        }
+    }
+}
+
+/// Checks traces against the constraints of Datadog without sending them.
+pub fn validate_traces(traces: &Traces) -> Vec<Violation> {
+    let mut violations: Vec<Violation> = traces.iter().flatten().flat_map(validate_span).collect();
+    match serde_json::to_vec(traces) {
+        Ok(payload) if payload.len() > MAX_PAYLOAD_BYTES => {
+            violations.push(Violation::PayloadTooLarge {
+                bytes: payload.len(),
+            });
+        }
+        Ok(_) => {}
+        Err(e) => log::error!("Failed to serialize traces for validation; err {}", e),
+    }
+    violations
+}
+
+#[inline]
+fn validate_span(span: &Span) -> Vec<Violation> {
+    let span_id = span.span_id();
+    let mut violations = Vec::new();
+    if span.trace_id() == 0 {
+        violations.push(Violation::ZeroTraceId { span_id });
+    }
+    if span_id == 0 {
+        violations.push(Violation::ZeroSpanId {
+            trace_id: span.trace_id(),
+        });
+    }
+    if span.service().is_empty() {
+        violations.push(Violation::MissingService { span_id });
+    }
+    if span.resource().is_empty() {
+        violations.push(Violation::MissingResource { span_id });
+    }
+    if span.name().len() > MAX_NAME_LEN {
+        violations.push(Violation::NameTooLong {
+            span_id,
+            len: span.name().len(),
+        });
+    }
+    if span.service().len() > MAX_SERVICE_LEN {
+        violations.push(Violation::ServiceTooLong {
+            span_id,
+            len: span.service().len(),
+        });
+    }
+    if span.resource().len() > MAX_RESOURCE_LEN {
+        violations.push(Violation::ResourceTooLong {
+            span_id,
+            len: span.resource().len(),
+        });
+    }
+    for (key, value) in span.meta() {
+        if !is_valid_tag_key(key) {
+            violations.push(Violation::InvalidTagKey {
+                span_id,
+                key: key.clone(),
+            });
+        }
+        if value.len() > MAX_TAG_VALUE_LEN {
+            violations.push(Violation::TagValueTooLong {
+                span_id,
+                key: key.clone(),
+                len: value.len(),
+            });
+        }
+    }
+    violations
+}
+
+// Tag names must start with a letter and only contain alphanumerics, underscores, minuses,
+// colons, periods and slashes
+#[inline]
+fn is_valid_tag_key(key: &str) -> bool {
+    key.len() <= MAX_TAG_KEY_LEN
+        && key.starts_with(|c: char| c.is_alphabetic())
+        && key
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | ':' | '.' | '/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datadog_client::{ServiceName, SpanBuilder, SpanMetaKey};
+
+    #[test]
+    fn test_valid_span() {
+        let mut span_builder = SpanBuilder::default();
+        span_builder
+            .service(ServiceName("web"))
+            .resource(String::from("GET /users"))
+            .add_meta(SpanMetaKey::HttpMethod, "GET");
+        assert_eq!(validate_traces(&vec![vec![span_builder.build()]]), vec![]);
+    }
+
+    #[test]
+    fn test_missing_service_and_resource() {
+        let span = SpanBuilder::default().build();
+        let span_id = span.span_id();
+        assert_eq!(
+            validate_traces(&vec![vec![span]]),
+            vec![
+                Violation::MissingService { span_id },
+                Violation::MissingResource { span_id }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tag_key_rules() {
+        assert!(is_valid_tag_key("http.status_code"));
+        assert!(is_valid_tag_key("peer.hostname:port/path-1"));
+        assert!(!is_valid_tag_key("1st"));
+        assert!(!is_valid_tag_key("_private"));
+        assert!(!is_valid_tag_key("with space"));
+        assert!(!is_valid_tag_key(&"a".repeat(MAX_TAG_KEY_LEN + 1)));
+    }
+}
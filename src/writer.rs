@@ -0,0 +1,141 @@
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// A lossy writer which never blocks its caller, like the one of `tracing-appender`: writes are
+/// queued in a bounded buffer and written to the inner writer by a background thread. When the
+/// buffer is full, writes are dropped and counted instead of waiting for the inner writer.
+///
+/// Meant for the stdout and file exporters, so that a slow terminal or disk doesn't hold up the
+/// daemon. Queued writes are flushed when the writer is dropped.
+pub struct NonBlockingWriter {
+    sender: Option<SyncSender<Vec<u8>>>,
+    dropped: Arc<AtomicU64>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl NonBlockingWriter {
+    /// Creates a writer which queues up to `buffered_writes` writes for `writer`.
+    pub fn new(mut writer: impl Write + Send + 'static, buffered_writes: usize) -> Self {
+        let (sender, receiver) = sync_channel::<Vec<u8>>(buffered_writes);
+        let worker = std::thread::spawn(move || {
+            for buf in receiver {
+                if let Err(e) = writer.write_all(&buf) {
+                    log::error!("Failed to write to non-blocking writer; err {}", e);
+                }
+            }
+            writer.flush().ok();
+        });
+        Self {
+            sender: Some(sender),
+            dropped: Arc::new(AtomicU64::new(0)),
+            worker: Some(worker),
+        }
+    }
+
+    /// How many writes were dropped because the buffer was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Write for NonBlockingWriter {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(sender) = &self.sender {
+            match sender.try_send(buf.to_vec()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::BrokenPipe,
+                        "non-blocking writer thread stopped",
+                    ));
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    // the buffer is written in the background, so there is nothing to wait for
+    #[inline]
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for NonBlockingWriter {
+    fn drop(&mut self) {
+        // closing the channel lets the worker write what is left in the buffer and stop
+        self.sender.take();
+        if let Some(worker) = self.worker.take() {
+            worker.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::sync::Mutex;
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Blocks every write until the gate is opened by dropping its sender
+    struct GatedWriter(Receiver<()>);
+
+    impl Write for GatedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.recv().ok();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_writes_are_flushed_on_drop() {
+        let buffer = SharedBuffer::default();
+        let mut writer = NonBlockingWriter::new(buffer.clone(), 16);
+
+        writer.write_all(b"first\n").unwrap();
+        writer.write_all(b"second\n").unwrap();
+        drop(writer);
+
+        assert_eq!(&*buffer.0.lock().unwrap(), b"first\nsecond\n");
+    }
+
+    #[test]
+    fn test_writes_are_dropped_when_buffer_is_full() {
+        let (gate, gated) = channel();
+        let mut writer = NonBlockingWriter::new(GatedWriter(gated), 1);
+
+        // the worker takes the first write and blocks on it, the second fills the buffer
+        writer.write_all(b"1").unwrap();
+        while writer.write(b"2").is_ok() && writer.dropped() == 0 {}
+        writer.write_all(b"3").unwrap();
+
+        assert!(writer.dropped() >= 1);
+        // let the blocked writes through so the writer can be dropped
+        drop(gate);
+    }
+}
@@ -0,0 +1,274 @@
+#![cfg(feature = "actix_web")]
+
+use actix_web::dev::Service;
+use actix_web::dev::{ResponseHead, ServiceRequest};
+use actix_web::{test, web, App, HttpMessage, HttpRequest, HttpResponse};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing_datadog_apm::appsec::AppSecTags;
+use tracing_datadog_apm::datadog_client::{Client, ClientConfig};
+use tracing_datadog_apm::http_status::HttpStatusClassifier;
+use tracing_datadog_apm::instrumentation_actix_web::{
+    ActixDatadogTracer, AppSecHook, DdContext, RequestReceived, RequestReceivedAt,
+};
+use tracing_datadog_apm::subscriber::{TracingSubscriberDatadog, TracingSubscriberDatadogConfig};
+
+struct AttackDetector;
+
+impl AppSecHook for AttackDetector {
+    fn on_request(&self, request: &ServiceRequest, tags: &mut AppSecTags) {
+        if request.headers().contains_key("x-attack") {
+            tags.tag("json", "{\"triggers\":[{\"rule\":\"ua0-600-12x\"}]}")
+                .keep();
+        }
+    }
+
+    fn on_response(&self, _request: &HttpRequest, response: &ResponseHead, tags: &mut AppSecTags) {
+        tags.tag("response.status", response.status.as_str());
+    }
+}
+
+type Spans = Arc<Mutex<Vec<serde_json::Value>>>;
+
+// A subscriber whose exported spans are captured rather than sent
+fn capturing_subscriber() -> (TracingSubscriberDatadog, Spans) {
+    let spans = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&spans);
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(ClientConfig::new().datadog_agent_port(1).on_payload(
+            move |payload, _| {
+                let payload: serde_json::Value = serde_json::from_slice(payload).unwrap();
+                seen.lock().unwrap().push(payload[0][0].clone());
+            },
+        )),
+        TracingSubscriberDatadogConfig::new(),
+    );
+    (subscriber, spans)
+}
+
+// The daemon sends spans in the background
+async fn wait_for_span(spans: &Spans) -> serde_json::Value {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while spans.lock().unwrap().is_empty() && Instant::now() < deadline {
+        actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+    }
+    let spans = spans.lock().unwrap();
+    assert_eq!(spans.len(), 1);
+    spans[0].clone()
+}
+
+#[actix_web::test]
+async fn test_appsec_hook_tags_and_keeps_request_span() {
+    // given
+    let (subscriber, spans) = capturing_subscriber();
+    let _guard = tracing::subscriber::set_default(subscriber);
+    let app = test::init_service(
+        App::new()
+            .wrap(ActixDatadogTracer::default().appsec(AttackDetector))
+            .route("/", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+
+    // when
+    let request = test::TestRequest::get()
+        .uri("/")
+        .insert_header(("x-attack", "1"))
+        // the upstream service rejected the trace
+        .insert_header(("b3", "0"))
+        .to_request();
+    test::call_service(&app, request).await;
+
+    // then
+    let span = wait_for_span(&spans).await;
+    assert_eq!(
+        span["meta"]["_dd.appsec.json"],
+        "{\"triggers\":[{\"rule\":\"ua0-600-12x\"}]}"
+    );
+    assert_eq!(span["meta"]["_dd.appsec.response.status"], "200");
+    assert_eq!(span["meta"]["appsec.event"], "true");
+    assert_eq!(span["meta"]["_dd.p.dm"], "-5");
+    assert_eq!(span["metrics"]["_sampling_priority_v1"], 2.0);
+}
+
+#[actix_web::test]
+async fn test_request_span_is_tagged_with_status_and_classified_as_error() {
+    // given
+    let (subscriber, spans) = capturing_subscriber();
+    let _guard = tracing::subscriber::set_default(subscriber);
+    let app = test::init_service(
+        App::new()
+            .wrap(
+                ActixDatadogTracer::default()
+                    .http_status_classifier(HttpStatusClassifier::parse("429")),
+            )
+            .route("/", web::get().to(HttpResponse::TooManyRequests)),
+    )
+    .await;
+
+    // when
+    let request = test::TestRequest::get().uri("/").to_request();
+    test::call_service(&app, request).await;
+
+    // then
+    let span = wait_for_span(&spans).await;
+    assert_eq!(span["meta"]["http.status_code"], "429");
+    assert_eq!(span["meta"]["http.method"], "GET");
+    assert_eq!(span["error"], 1);
+    assert_eq!(
+        span["meta"]["error.msg"],
+        "Request has failed with HTTP error: 429"
+    );
+}
+
+#[actix_web::test]
+async fn test_request_span_is_not_an_error_for_unclassified_status() {
+    // given
+    let (subscriber, spans) = capturing_subscriber();
+    let _guard = tracing::subscriber::set_default(subscriber);
+    let app = test::init_service(
+        App::new()
+            .wrap(
+                ActixDatadogTracer::default()
+                    .http_status_classifier(HttpStatusClassifier::parse("429")),
+            )
+            .route("/", web::get().to(HttpResponse::InternalServerError)),
+    )
+    .await;
+
+    // when
+    let request = test::TestRequest::get().uri("/").to_request();
+    test::call_service(&app, request).await;
+
+    // then
+    let span = wait_for_span(&spans).await;
+    assert_eq!(span["meta"]["http.status_code"], "500");
+    assert_eq!(span["error"], 0);
+    assert_eq!(span["meta"].get("error.msg"), None);
+}
+
+#[actix_web::test]
+async fn test_dd_context_extractor_reads_and_tags_request_span() {
+    // given
+    let (subscriber, spans) = capturing_subscriber();
+    let _guard = tracing::subscriber::set_default(subscriber);
+    let app = test::init_service(App::new().wrap(ActixDatadogTracer::default()).route(
+        "/orders",
+        web::get().to(|dd: DdContext| async move {
+            dd.set_tag("order.id", "42");
+            HttpResponse::Ok().body(dd.trace_id().unwrap().to_string())
+        }),
+    ))
+    .await;
+
+    // when
+    let request = test::TestRequest::get().uri("/orders").to_request();
+    let body = test::call_and_read_body(&app, request).await;
+
+    // then
+    let span = wait_for_span(&spans).await;
+    assert_eq!(span["meta"]["order.id"], "42");
+    assert_eq!(span["trace_id"].to_string(), String::from_utf8_lossy(&body));
+}
+
+#[actix_web::test]
+async fn test_unit_form_tracer_tags_the_tenant_of_ipv6_hosts() {
+    // given
+    let (subscriber, spans) = capturing_subscriber();
+    let _guard = tracing::subscriber::set_default(subscriber);
+    let app = test::init_service(
+        App::new()
+            .wrap(ActixDatadogTracer.tenant_from_host())
+            .route("/orders", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+
+    // when
+    let request = test::TestRequest::get()
+        .uri("/orders")
+        .insert_header(("host", "[::1]:8080"))
+        .to_request();
+    test::call_service(&app, request).await;
+
+    // then
+    let span = wait_for_span(&spans).await;
+    assert_eq!(span["meta"]["tenant"], "[::1]");
+}
+
+#[actix_web::test]
+async fn test_late_resource_wins_over_the_match_pattern() {
+    use actix_web::FromRequest;
+
+    // given
+    let (subscriber, spans) = capturing_subscriber();
+    let _guard = tracing::subscriber::set_default(subscriber);
+    let app = test::init_service(
+        App::new()
+            // runs before the middleware records the resource of the match pattern
+            .wrap_fn(|req, srv| {
+                let dd = DdContext::extract(req.request()).into_inner().unwrap();
+                dd.set_resource_late("query GetOrders");
+                srv.call(req)
+            })
+            .wrap(ActixDatadogTracer::default())
+            .route("/graphql", web::post().to(HttpResponse::Ok)),
+    )
+    .await;
+
+    // when
+    let request = test::TestRequest::post().uri("/graphql").to_request();
+    test::call_service(&app, request).await;
+
+    // then
+    let span = wait_for_span(&spans).await;
+    assert_eq!(span["resource"], "query GetOrders");
+}
+
+#[actix_web::test]
+async fn test_earliest_start_starts_span_when_request_was_received() {
+    // given
+    let (subscriber, spans) = capturing_subscriber();
+    let _guard = tracing::subscriber::set_default(subscriber);
+    let app = test::init_service(
+        App::new()
+            .wrap(ActixDatadogTracer::default().earliest_start(true))
+            .route("/", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+    let received = SystemTime::now() - Duration::from_secs(5);
+
+    // when
+    let request = test::TestRequest::get().uri("/").to_request();
+    request.extensions_mut().insert(RequestReceivedAt(received));
+    test::call_service(&app, request).await;
+
+    // then
+    let span = wait_for_span(&spans).await;
+    let received_ns = received.duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64;
+    assert_eq!(span["start"], received_ns);
+    assert!(span["duration"].as_u64().unwrap() >= 5_000_000_000);
+}
+
+#[actix_web::test]
+async fn test_request_received_is_recorded_before_inner_middleware() {
+    // given
+    let (subscriber, spans) = capturing_subscriber();
+    let _guard = tracing::subscriber::set_default(subscriber);
+    let app = test::init_service(
+        App::new()
+            .wrap(ActixDatadogTracer::default().earliest_start(true))
+            .wrap_fn(|req, srv| {
+                std::thread::sleep(Duration::from_millis(20));
+                srv.call(req)
+            })
+            .wrap(RequestReceived)
+            .route("/", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+
+    // when
+    test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+
+    // then
+    let span = wait_for_span(&spans).await;
+    assert!(span["duration"].as_u64().unwrap() >= 20_000_000);
+}
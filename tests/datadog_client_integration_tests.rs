@@ -31,3 +31,1083 @@ fn test_create_datadog_client_with_config_chained_setters() {
     // when
     client.send_traces(Traces::new()); // no output here, just checking it didn't panic
 }
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn test_flush_async_resolves_after_enqueued_traces() {
+    use tracing_datadog_apm::datadog_client::FlushResult;
+
+    // given
+    let client = Client::create_with_config(ClientConfig::new().datadog_agent_port(1));
+    client.send_traces(Traces::new());
+
+    // when
+    let result = client.flush_async().await;
+
+    // then
+    assert_eq!(result, FlushResult::Flushed);
+}
+
+#[test]
+fn test_flush_returns_after_enqueued_traces() {
+    use std::time::Duration;
+    use tracing_datadog_apm::datadog_client::FlushResult;
+
+    // given
+    let client = Client::create_with_config(ClientConfig::new().datadog_agent_port(1));
+    client.send_traces(Traces::new());
+
+    // when
+    let result = client.flush(Duration::from_secs(10));
+
+    // then
+    assert_eq!(result, FlushResult::Flushed);
+}
+
+#[test]
+fn test_idle_daemon_is_woken_up_by_traces() {
+    use std::time::Duration;
+    use tracing_datadog_apm::datadog_client::FlushResult;
+
+    // given
+    let client =
+        Client::create_with_config(ClientConfig::new().datadog_agent_port(1).idle_park_ms(10));
+    client.send_traces(Traces::new());
+    assert_eq!(client.flush(Duration::from_secs(10)), FlushResult::Flushed);
+    std::thread::sleep(Duration::from_millis(100));
+
+    // when
+    client.send_traces(Traces::new());
+    let result = client.flush(Duration::from_secs(10));
+
+    // then
+    assert_eq!(result, FlushResult::Flushed);
+    assert_eq!(client.stats().requests_failed, 2);
+}
+
+#[test]
+fn test_suspended_client_queues_traces_until_resumed() {
+    use std::time::Duration;
+    use tracing_datadog_apm::datadog_client::FlushResult;
+
+    // given
+    let client = Client::create_with_config(ClientConfig::new().datadog_agent_port(1));
+    client.suspend();
+    client.send_traces(Traces::new());
+    assert_eq!(
+        client.flush(Duration::from_millis(100)),
+        FlushResult::TimedOut
+    );
+    assert_eq!(client.stats().requests_failed, 0);
+
+    // when
+    client.resume();
+    let result = client.flush(Duration::from_secs(10));
+
+    // then
+    assert_eq!(result, FlushResult::Flushed);
+    assert_eq!(client.stats().requests_failed, 1);
+}
+
+#[test]
+fn test_full_queue_drops_newest_traces() {
+    use std::time::Duration;
+    use tracing_datadog_apm::span::SpanBuilder;
+
+    // given
+    let client =
+        Client::create_with_config(ClientConfig::new().datadog_agent_port(1).queue_capacity(2));
+    client.suspend();
+
+    // when
+    for _ in 0..3 {
+        client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+    }
+
+    // then
+    assert_eq!(client.stats().dropped_traces, 1);
+    client.resume();
+    client.flush(Duration::from_secs(10));
+    assert_eq!(client.stats().dropped_traces, 1);
+}
+
+#[test]
+fn test_full_queue_drops_oldest_traces() {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tracing_datadog_apm::datadog_client::OverflowPolicy;
+    use tracing_datadog_apm::span::SpanBuilder;
+
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let client = Client::create_with_config(
+        ClientConfig::new()
+            .datadog_agent_port(1)
+            .queue_capacity(2)
+            .overflow_policy(OverflowPolicy::DropOldest)
+            .on_payload(move |payload, _| {
+                seen.lock().unwrap().push(payload.to_vec());
+            }),
+    );
+    client.suspend();
+
+    // when
+    for resource in ["first", "second", "third"] {
+        client.send_traces(vec![vec![SpanBuilder::default()
+            .resource(String::from(resource))
+            .build()]]);
+    }
+    client.resume();
+    client.flush(Duration::from_secs(10));
+
+    // then
+    assert_eq!(client.stats().dropped_traces, 1);
+    let resources: Vec<String> = payloads
+        .lock()
+        .unwrap()
+        .iter()
+        .flat_map(|payload| serde_json::from_slice::<Vec<serde_json::Value>>(payload).unwrap())
+        .map(|trace| trace[0]["resource"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(resources, ["second", "third"]);
+}
+
+#[test]
+fn test_evictions_leave_pending_flushes_in_place() {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tracing_datadog_apm::datadog_client::{FlushResult, OverflowPolicy};
+    use tracing_datadog_apm::span::SpanBuilder;
+
+    // given
+    let client = Arc::new(Client::create_with_config(
+        ClientConfig::new()
+            .datadog_agent_port(1)
+            .queue_capacity(1)
+            .overflow_policy(OverflowPolicy::DropOldest),
+    ));
+    client.suspend();
+    let flushing = Arc::clone(&client);
+    let flush = std::thread::spawn(move || flushing.flush(Duration::from_secs(10)));
+    std::thread::sleep(Duration::from_millis(50));
+
+    // when
+    for _ in 0..3 {
+        client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+    }
+    client.resume();
+
+    // then
+    assert_eq!(flush.join().unwrap(), FlushResult::Flushed);
+    assert_eq!(client.stats().dropped_traces, 2);
+}
+
+#[test]
+fn test_full_queue_blocks_senders_until_the_timeout() {
+    use std::time::{Duration, Instant};
+    use tracing_datadog_apm::datadog_client::OverflowPolicy;
+    use tracing_datadog_apm::span::SpanBuilder;
+
+    // given
+    let client = Client::create_with_config(
+        ClientConfig::new()
+            .datadog_agent_port(1)
+            .queue_capacity(1)
+            .overflow_policy(OverflowPolicy::Block { timeout_ms: 100 }),
+    );
+    client.suspend();
+    client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+    let started = Instant::now();
+
+    // when
+    client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+
+    // then
+    assert!(started.elapsed() >= Duration::from_millis(100));
+    assert_eq!(client.stats().dropped_traces, 1);
+}
+
+#[test]
+fn test_shutdown_reports_traces_abandoned_past_the_deadline() {
+    use std::time::Duration;
+    use tracing_datadog_apm::datadog_client::ShutdownReport;
+    use tracing_datadog_apm::span::SpanBuilder;
+
+    // given
+    let client = Client::create_with_config(ClientConfig::new().datadog_agent_port(1));
+    client.suspend();
+    client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+    client.send_traces(vec![
+        vec![SpanBuilder::default().build()],
+        vec![SpanBuilder::default().build()],
+    ]);
+
+    // when
+    let report = client.shutdown(Duration::ZERO);
+
+    // then
+    assert_eq!(
+        report,
+        Ok(ShutdownReport {
+            attempted_traces: 0,
+            abandoned_traces: 3,
+        })
+    );
+    assert_eq!(client.stats().requests_failed, 0);
+}
+
+#[test]
+fn test_shutdown_attempts_traces_within_the_deadline() {
+    use std::time::Duration;
+    use tracing_datadog_apm::span::SpanBuilder;
+
+    // given
+    let client = Client::create_with_config(ClientConfig::new().datadog_agent_port(1));
+    client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+
+    // when
+    let report = client.shutdown(Duration::from_secs(10)).unwrap();
+
+    // then
+    assert_eq!(report.attempted_traces, 1);
+    assert_eq!(report.abandoned_traces, 0);
+    assert_eq!(client.stats().requests_failed, 1);
+}
+
+#[test]
+fn test_traces_are_held_until_the_batch_is_full() {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tracing_datadog_apm::span::SpanBuilder;
+
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let client = Client::create_with_config(
+        ClientConfig::new()
+            .datadog_agent_port(1)
+            .max_batch_age_ms(60_000)
+            .max_batch_traces(3)
+            .on_payload(move |payload, _| {
+                seen.lock().unwrap().push(payload.to_vec());
+            }),
+    );
+    client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+    client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(payloads.lock().unwrap().is_empty());
+
+    // when
+    client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+
+    // then
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while payloads.lock().unwrap().is_empty() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let payloads = payloads.lock().unwrap();
+    assert_eq!(payloads.len(), 1);
+    let traces: Vec<serde_json::Value> = serde_json::from_slice(&payloads[0]).unwrap();
+    assert_eq!(traces.len(), 3);
+}
+
+#[test]
+fn test_held_traces_are_sent_once_the_batch_is_old_enough() {
+    use std::time::{Duration, Instant};
+    use tracing_datadog_apm::span::SpanBuilder;
+
+    // given
+    let client = Client::create_with_config(
+        ClientConfig::new()
+            .datadog_agent_port(1)
+            .max_batch_age_ms(200),
+    );
+    let started = Instant::now();
+
+    // when
+    client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+
+    // then
+    while client.stats().requests_failed == 0 && started.elapsed() < Duration::from_secs(10) {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(client.stats().requests_failed, 1);
+    assert!(started.elapsed() >= Duration::from_millis(200));
+}
+
+#[test]
+fn test_retained_payloads_are_retried_after_their_backoff() {
+    use std::time::{Duration, Instant};
+    use tracing_datadog_apm::span::SpanBuilder;
+
+    // given
+    let client = Client::create_with_config(
+        ClientConfig::new()
+            .datadog_agent_port(1)
+            .max_send_attempts(1)
+            .retry_backoff_ms(50)
+            .max_retained_payloads(1),
+    );
+    let started = Instant::now();
+
+    // when
+    client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+
+    // then
+    while client.stats().requests_failed < 2 && started.elapsed() < Duration::from_secs(10) {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(client.stats().requests_failed >= 2);
+}
+
+#[test]
+fn test_open_circuit_breaker_drops_traces_without_contacting_the_agent() {
+    use tracing_datadog_apm::span::SpanBuilder;
+
+    // given
+    let client = Client::create_with_config(
+        ClientConfig::new()
+            .datadog_agent_port(1)
+            .inline_export(true)
+            .circuit_breaker_failures(2)
+            .circuit_breaker_probe_ms(60_000),
+    );
+
+    // when
+    for _ in 0..5 {
+        client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+    }
+
+    // then
+    let stats = client.stats();
+    assert_eq!(stats.requests_failed, 2);
+    assert!(stats.circuit_breaker_open);
+    assert_eq!(stats.circuit_breaker_dropped_traces, 3);
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn test_payloads_are_sent_compressed() {
+    use std::io::Read;
+    use std::sync::{Arc, Mutex};
+    use tracing_datadog_apm::datadog_client::Compression;
+    use tracing_datadog_apm::span::SpanBuilder;
+
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let client = Client::create_with_config(
+        ClientConfig::new()
+            .datadog_agent_port(1)
+            .inline_export(true)
+            .compression(Compression::Gzip)
+            .on_payload(move |payload, _| {
+                seen.lock().unwrap().push(payload.to_vec());
+            }),
+    );
+
+    // when
+    client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+
+    // then
+    let payloads = payloads.lock().unwrap();
+    let mut decompressed = Vec::new();
+    flate2::read::GzDecoder::new(payloads[0].as_slice())
+        .read_to_end(&mut decompressed)
+        .unwrap();
+    let traces: Vec<serde_json::Value> = serde_json::from_slice(&decompressed).unwrap();
+    assert_eq!(traces.len(), 1);
+}
+
+#[test]
+fn test_client_stats_start_empty() {
+    // given
+    let client = Client::create_with_config(ClientConfig::new().request_timeout_ms(250));
+
+    // when
+    let stats = client.stats();
+
+    // then
+    assert_eq!(stats.requests_sent, 0);
+    assert_eq!(stats.requests_failed, 0);
+    assert_eq!(stats.request_timeout_ms, 250);
+    assert!(!stats.agent_slow);
+}
+
+#[test]
+fn test_inline_export_attempts_send_before_returning() {
+    // given
+    let client = Client::create_with_config(
+        ClientConfig::new()
+            .datadog_agent_port(1)
+            .inline_export(true),
+    );
+
+    // when
+    client.send_traces(Traces::new());
+
+    // then
+    let stats = client.stats();
+    assert_eq!(stats.requests_sent + stats.requests_failed, 1);
+}
+
+#[test]
+fn test_client_sends_tracer_headers_to_agent() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::time::Duration;
+    use tracing_datadog_apm::datadog_client::{FlushResult, SpanBuilder};
+
+    // given
+    let agent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let agent_port = agent.local_addr().unwrap().port();
+    let agent_thread = std::thread::spawn(move || {
+        let (mut stream, _) = agent.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+            let read = stream.read(&mut buf).unwrap();
+            request.extend_from_slice(&buf[..read]);
+        }
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK")
+            .unwrap();
+        String::from_utf8_lossy(&request).into_owned()
+    });
+    let client = Client::create_with_config(
+        ClientConfig::new()
+            .datadog_agent_host("127.0.0.1")
+            .datadog_agent_port(agent_port as u32),
+    );
+
+    // when
+    client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+    let result = client.flush(Duration::from_secs(10));
+
+    // then
+    assert_eq!(result, FlushResult::Flushed);
+    let request = agent_thread.join().unwrap();
+    assert!(request.contains("content-type: application/json\r\n"));
+    assert!(request.contains("x-datadog-trace-count: 1\r\n"));
+    assert!(request.contains("datadog-meta-lang: rust\r\n"));
+    assert!(request.contains("datadog-meta-lang-version: 1."));
+    assert!(request.contains(&format!(
+        "datadog-meta-tracer-version: {}\r\n",
+        env!("CARGO_PKG_VERSION")
+    )));
+}
+
+#[test]
+fn test_client_reads_the_sample_rates_of_the_agent() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::time::Duration;
+    use tracing_datadog_apm::datadog_client::{FlushResult, SpanBuilder};
+
+    // given
+    let agent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let agent_port = agent.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        let (mut stream, _) = agent.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+            let read = stream.read(&mut buf).unwrap();
+            request.extend_from_slice(&buf[..read]);
+        }
+        let body = r#"{"rate_by_service":{"service:,env:":1,"service:web,env:prod":0.5}}"#;
+        stream
+            .write_all(
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+    });
+    let client = Client::create_with_config(
+        ClientConfig::new()
+            .datadog_agent_host("127.0.0.1")
+            .datadog_agent_port(agent_port as u32),
+    );
+    let sample_rates = client.agent_sample_rates();
+    assert_eq!(sample_rates.rate("web", "prod"), None);
+
+    // when
+    client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+    let result = client.flush(Duration::from_secs(10));
+
+    // then
+    assert_eq!(result, FlushResult::Flushed);
+    assert_eq!(sample_rates.rate("web", "prod"), Some(0.5));
+    assert_eq!(sample_rates.rate("db", "prod"), Some(1.0));
+}
+
+#[cfg(feature = "async_std")]
+#[test]
+fn test_async_std_client_sends_traces_to_agent() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::time::{Duration, Instant};
+
+    // given
+    let agent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let agent_port = agent.local_addr().unwrap().port();
+    let agent_thread = std::thread::spawn(move || {
+        let (mut stream, _) = agent.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !String::from_utf8_lossy(&request).ends_with("[]") {
+            let read = stream.read(&mut buf).unwrap();
+            request.extend_from_slice(&buf[..read]);
+        }
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK")
+            .unwrap();
+        String::from_utf8(request).unwrap()
+    });
+
+    // when
+    let stats = async_std::task::block_on(async {
+        let client = Client::create_async_std(
+            ClientConfig::new()
+                .datadog_agent_host("127.0.0.1")
+                .datadog_agent_port(agent_port as u32),
+        );
+        client.send_traces(Traces::new());
+        let started = Instant::now();
+        while client.stats().requests_sent == 0 && started.elapsed() < Duration::from_secs(5) {
+            async_std::task::sleep(Duration::from_millis(10)).await;
+        }
+        client.stats()
+    });
+
+    // then
+    let request = agent_thread.join().unwrap();
+    assert!(request.starts_with("PUT /v0.3/traces HTTP/1.1\r\n"));
+    assert!(request.contains("X-Datadog-Trace-Count: 0\r\n"));
+    assert!(request.contains("Datadog-Meta-Lang: rust\r\n"));
+    assert_eq!(stats.requests_sent, 1);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn test_tokio_client_sends_traces_to_agent() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use tracing_datadog_apm::datadog_client::FlushResult;
+
+    // given
+    let agent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let agent_port = agent.local_addr().unwrap().port();
+    let agent_thread = std::thread::spawn(move || {
+        let (mut stream, _) = agent.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !String::from_utf8_lossy(&request).ends_with("[]") {
+            let read = stream.read(&mut buf).unwrap();
+            request.extend_from_slice(&buf[..read]);
+        }
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK")
+            .unwrap();
+        String::from_utf8(request).unwrap()
+    });
+    let client = Client::create_tokio(
+        ClientConfig::new()
+            .datadog_agent_host("127.0.0.1")
+            .datadog_agent_port(agent_port as u32),
+    );
+
+    // when
+    client.send_traces(Traces::new());
+    let result = client.flush_async().await;
+
+    // then
+    assert_eq!(result, FlushResult::Flushed);
+    let request = agent_thread.join().unwrap();
+    assert!(request.starts_with("PUT /v0.3/traces HTTP/1.1\r\n"));
+    assert!(request.contains("x-datadog-trace-count: 0\r\n"));
+    assert!(request.contains("datadog-meta-lang: rust\r\n"));
+    assert_eq!(client.stats().requests_sent, 1);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn test_tokio_client_flush_does_not_block_current_thread_runtime() {
+    use std::time::{Duration, Instant};
+    use tracing_datadog_apm::datadog_client::FlushResult;
+
+    // given
+    let client = Client::create_tokio(ClientConfig::new().datadog_agent_port(1));
+    let started = Instant::now();
+
+    // when
+    let result = client.flush(Duration::from_secs(5));
+
+    // then
+    assert_eq!(result, FlushResult::TimedOut);
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn test_tokio_client_reports_stopped_task() {
+    use tracing_datadog_apm::datadog_client::ClientHealth;
+
+    // given
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let client = runtime.block_on(async { Client::create_tokio(ClientConfig::new()) });
+    assert_eq!(client.health(), ClientHealth::Healthy);
+
+    // when
+    drop(runtime);
+
+    // then
+    assert_eq!(client.health(), ClientHealth::DaemonDead);
+}
+
+#[test]
+fn test_validating_client_reports_violations() {
+    use std::sync::{Arc, Mutex};
+    use tracing_datadog_apm::datadog_client::SpanBuilder;
+    use tracing_datadog_apm::validation::Violation;
+
+    // given
+    let violations = Arc::new(Mutex::new(Vec::new()));
+    let reported = Arc::clone(&violations);
+    let client = Client::create_validating(move |violation| {
+        reported.lock().unwrap().push(violation.clone());
+    });
+    let span = SpanBuilder::default().build();
+    let span_id = span.span_id();
+
+    // when
+    client.send_traces(vec![vec![span]]);
+
+    // then
+    assert_eq!(
+        *violations.lock().unwrap(),
+        vec![
+            Violation::MissingService { span_id },
+            Violation::MissingResource { span_id }
+        ]
+    );
+    assert_eq!(client.stats().requests_sent, 0);
+}
+
+#[test]
+fn test_client_with_exporter_hands_over_traces() {
+    use std::sync::{Arc, Mutex};
+    use tracing_datadog_apm::span::SpanBuilder;
+
+    // given
+    let exported = Arc::new(Mutex::new(Vec::new()));
+    let host = Arc::clone(&exported);
+    let client = Client::create_with_exporter(move |traces: Traces| {
+        host.lock().unwrap().extend(traces);
+    });
+    let span = SpanBuilder::default().build();
+    let span_id = span.span_id();
+
+    // when
+    client.send_traces(vec![vec![span]]);
+
+    // then
+    let exported = exported.lock().unwrap();
+    assert_eq!(exported.len(), 1);
+    assert_eq!(exported[0][0].span_id(), span_id);
+    assert_eq!(client.stats().requests_sent, 0);
+}
+
+#[test]
+fn test_client_with_boxed_exporter_hands_over_traces() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tracing_datadog_apm::span::{ExportError, SpanBuilder, SpanExporter};
+
+    struct CountingExporter(Arc<AtomicUsize>);
+
+    impl SpanExporter for CountingExporter {
+        fn export(&self, traces: Traces) -> Result<(), ExportError> {
+            self.0.fetch_add(traces.len(), Ordering::Relaxed);
+            Err(ExportError::from("collector is down"))
+        }
+    }
+
+    // given
+    let exported = Arc::new(AtomicUsize::new(0));
+    let exporter: Box<dyn SpanExporter> = Box::new(CountingExporter(Arc::clone(&exported)));
+    let client = Client::create_with_exporter(exporter);
+
+    // when
+    client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+    client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+
+    // then
+    assert_eq!(exported.load(Ordering::Relaxed), 2);
+}
+
+#[test]
+fn test_client_with_file_output_appends_traces_to_the_file() {
+    use tracing_datadog_apm::datadog_client::TraceOutput;
+    use tracing_datadog_apm::span::{Span, SpanBuilder};
+
+    // given
+    let path =
+        std::env::temp_dir().join(format!("tracing-datadog-apm-{}.ndjson", std::process::id()));
+    let client = Client::create_with_config(
+        ClientConfig::new()
+            .datadog_agent_port(1)
+            .trace_output(TraceOutput::File(path.clone())),
+    );
+    let span = SpanBuilder::default().build();
+    let span_id = span.span_id();
+
+    // when
+    client.send_traces(vec![vec![span]]);
+    drop(client);
+
+    // then
+    let contents = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).ok();
+    let spans: Vec<Span> = serde_json::from_str(contents.trim_end()).unwrap();
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0].span_id(), span_id);
+}
+
+#[test]
+fn test_client_with_unopenable_file_output_reports_failed_transport() {
+    use tracing_datadog_apm::datadog_client::{ClientHealth, TraceOutput};
+    use tracing_datadog_apm::span::SpanBuilder;
+
+    // given
+    let path = std::env::temp_dir().join("missing-directory/traces.ndjson");
+    let client = Client::create_with_config(
+        ClientConfig::new().trace_output(TraceOutput::File(path.clone())),
+    );
+
+    // when
+    client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+
+    // then
+    assert_eq!(client.health(), ClientHealth::TransportFailed);
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_agent_exporter_fails_when_the_agent_is_unreachable() {
+    use tracing_datadog_apm::datadog_client::AgentExporter;
+    use tracing_datadog_apm::span::{SpanBuilder, SpanExporter};
+
+    // given
+    let exporter = AgentExporter::new(&ClientConfig::new().datadog_agent_port(1)).unwrap();
+
+    // when
+    let result = exporter.export(vec![vec![SpanBuilder::default().build()]]);
+
+    // then
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_on_payload_hook_sees_sent_payload() {
+    use std::sync::{Arc, Mutex};
+    use tracing_datadog_apm::datadog_client::{PayloadMeta, SpanBuilder};
+
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let client = Client::create_with_config(
+        ClientConfig::new()
+            .datadog_agent_port(1)
+            .inline_export(true)
+            .on_payload(move |payload, meta| {
+                seen.lock().unwrap().push((payload.to_vec(), meta));
+            }),
+    );
+
+    // when
+    client.send_traces(vec![
+        vec![
+            SpanBuilder::default().build(),
+            SpanBuilder::default().build(),
+        ],
+        vec![SpanBuilder::default().build()],
+    ]);
+
+    // then
+    let payloads = payloads.lock().unwrap();
+    assert_eq!(payloads.len(), 1);
+    let (payload, meta) = &payloads[0];
+    assert!(payload.starts_with(b"[["));
+    assert_eq!(
+        *meta,
+        PayloadMeta {
+            size: payload.len(),
+            trace_count: 2,
+            span_count: 3,
+            destination: String::from("http://localhost:1/v0.3/traces"),
+            delivered: false,
+        }
+    );
+}
+
+#[test]
+fn test_v07_payload_describes_the_process() {
+    use std::sync::{Arc, Mutex};
+    use tracing_datadog_apm::datadog_client::{SpanBuilder, TraceApiVersion};
+
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let client = Client::create_with_config(
+        ClientConfig::new()
+            .datadog_agent_port(1)
+            .inline_export(true)
+            .trace_api_version(TraceApiVersion::V07)
+            .env("staging")
+            .app_version("2.0.1")
+            .on_payload(move |payload, _| {
+                seen.lock().unwrap().push(payload.to_vec());
+            }),
+    );
+
+    // when
+    client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+
+    // then
+    let payloads = payloads.lock().unwrap();
+    let payload: serde_json::Value = rmp_serde::from_slice(&payloads[0]).unwrap();
+    assert_eq!(payload["language_name"], "rust");
+    assert_eq!(payload["env"], "staging");
+    assert_eq!(payload["app_version"], "2.0.1");
+    assert!(payload["hostname"].is_string());
+}
+
+#[test]
+fn test_self_instrumentation_traces_previous_payloads() {
+    use std::sync::{Arc, Mutex};
+    use tracing_datadog_apm::datadog_client::{ServiceName, SpanBuilder};
+
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let client = Client::create_with_config(
+        ClientConfig::new()
+            .datadog_agent_port(1)
+            .inline_export(true)
+            .self_instrumentation(ServiceName("exporter"))
+            .on_payload(move |payload, _| {
+                seen.lock()
+                    .unwrap()
+                    .push(serde_json::from_slice::<serde_json::Value>(payload).unwrap());
+            }),
+    );
+
+    // when
+    client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+    client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+
+    // then
+    let payloads = payloads.lock().unwrap();
+    // the exporter's traces are sent to the agent with the next batch, in their own payload
+    assert_eq!(payloads.len(), 3);
+    assert_eq!(payloads[1].as_array().unwrap().len(), 1);
+    let exporter_trace = &payloads[2][0];
+    let names: Vec<_> = exporter_trace
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|span| span["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "datadog_exporter.payload",
+            "datadog_exporter.serialize",
+            "datadog_exporter.send"
+        ]
+    );
+    assert!(exporter_trace
+        .as_array()
+        .unwrap()
+        .iter()
+        .all(|span| span["service"] == "exporter"));
+    // the agent is unreachable
+    assert_eq!(exporter_trace[0]["error"], 1);
+    assert_eq!(exporter_trace[2]["error"], 1);
+}
+
+#[test]
+fn test_payloads_are_grouped_by_service() {
+    use std::sync::{Arc, Mutex};
+    use tracing_datadog_apm::datadog_client::{ServiceName, SpanBuilder};
+
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let client = Client::create_with_config(
+        ClientConfig::new()
+            .datadog_agent_port(1)
+            .inline_export(true)
+            .on_payload(move |_, meta| {
+                seen.lock().unwrap().push(meta.trace_count);
+            }),
+    );
+    let trace_of = |service| vec![SpanBuilder::default().service(ServiceName(service)).build()];
+
+    // when
+    client.send_traces(vec![
+        trace_of("orders"),
+        trace_of("billing"),
+        trace_of("orders"),
+    ]);
+
+    // then
+    assert_eq!(*payloads.lock().unwrap(), [2, 1]);
+    assert_eq!(client.stats().requests_failed, 2);
+}
+
+#[test]
+fn test_v05_payloads_intern_strings() {
+    use std::sync::{Arc, Mutex};
+    use tracing_datadog_apm::datadog_client::{ServiceName, SpanBuilder, TraceApiVersion};
+
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let client = Client::create_with_config(
+        ClientConfig::new()
+            .datadog_agent_port(1)
+            .inline_export(true)
+            .trace_api_version(TraceApiVersion::V05)
+            .on_payload(move |payload, meta| {
+                seen.lock().unwrap().push((payload.to_vec(), meta));
+            }),
+    );
+    let span = || {
+        SpanBuilder::default()
+            .service(ServiceName("orders"))
+            .build()
+    };
+
+    // when
+    client.send_traces(vec![vec![span(), span()]]);
+
+    // then
+    let payloads = payloads.lock().unwrap();
+    let (payload, meta) = &payloads[0];
+    assert_eq!(meta.destination, "http://localhost:1/v0.5/traces");
+    assert_eq!(meta.span_count, 2);
+    let (strings, traces): (Vec<String>, Vec<Vec<serde_json::Value>>) =
+        rmp_serde::from_slice(payload).unwrap();
+    assert_eq!(strings.iter().filter(|s| *s == "orders").count(), 1);
+    assert_eq!(traces[0].len(), 2);
+    assert_eq!(traces[0][0].as_array().unwrap().len(), 12);
+}
+
+#[test]
+fn test_api_key_sends_traces_to_intake() {
+    use std::sync::{Arc, Mutex};
+    use tracing_datadog_apm::datadog_client::SpanBuilder;
+
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let client = Client::create_with_config(
+        ClientConfig::new()
+            .api_key("api-key")
+            .datadog_site("localhost:1")
+            .inline_export(true)
+            .on_payload(move |payload, meta| {
+                seen.lock().unwrap().push((payload.to_vec(), meta));
+            }),
+    );
+
+    // when
+    client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+
+    // then
+    let payloads = payloads.lock().unwrap();
+    let (payload, meta) = &payloads[0];
+    assert_eq!(
+        meta.destination,
+        "https://trace.agent.localhost:1/api/v0.2/traces"
+    );
+    assert!(!meta.delivered);
+    // a protobuf AgentPayload, rather than JSON
+    assert!(!payload.starts_with(b"["));
+    assert!(!payload.windows(7).any(|bytes| bytes == b"api-key"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_forked_child_sends_traces_through_its_own_daemon() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+    use tracing_datadog_apm::datadog_client::{FlushResult, SpanBuilder};
+
+    // given a client whose daemon thread runs in the parent
+    let agent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let agent_port = agent.local_addr().unwrap().port();
+    let (requests, received) = mpsc::channel();
+    std::thread::spawn(move || {
+        for mut stream in agent.incoming().flatten() {
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => request.extend_from_slice(&buf[..read]),
+                }
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}")
+                .ok();
+            requests
+                .send(String::from_utf8_lossy(&request).into_owned())
+                .ok();
+        }
+    });
+    let client = Client::create_with_config(
+        ClientConfig::new()
+            .datadog_agent_host("127.0.0.1")
+            .datadog_agent_port(agent_port as u32),
+    );
+    client.flush(Duration::from_secs(10));
+
+    // when a forked child sends a trace
+    // SAFETY: the child only uses the client, and leaves with `_exit` without unwinding or
+    // running the destructors of the parent's state
+    let child = unsafe { libc::fork() };
+    assert!(child >= 0, "fork failed");
+    if child == 0 {
+        client.send_traces(vec![vec![SpanBuilder::default().build()]]);
+        let flushed = client.flush(Duration::from_secs(10)) == FlushResult::Flushed;
+        unsafe { libc::_exit(if flushed { 0 } else { 1 }) };
+    }
+
+    // then the child flushed it to the agent, from a daemon thread of its own
+    let deadline = Instant::now() + Duration::from_secs(30);
+    let mut status = 0;
+    while unsafe { libc::waitpid(child, &mut status, libc::WNOHANG) } == 0 {
+        if Instant::now() > deadline {
+            unsafe { libc::kill(child, libc::SIGKILL) };
+            panic!("forked child didn't exit");
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(libc::WIFEXITED(status));
+    assert_eq!(libc::WEXITSTATUS(status), 0, "child failed to flush");
+    let request = received.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert!(request.contains("x-datadog-trace-count: 1\r\n"));
+}
@@ -0,0 +1,65 @@
+#![cfg(feature = "test_support")]
+
+use tracing_datadog_apm::fixtures::{
+    assert_golden, deterministic_trace, to_golden_json, to_golden_msgpack,
+};
+
+#[test]
+fn test_json_payload_matches_golden_file() {
+    // given
+    let traces = vec![deterministic_trace()];
+
+    // when
+    let payload = to_golden_json(&traces);
+
+    // then
+    assert_golden("tests/golden/trace.json", payload);
+}
+
+#[test]
+fn test_msgpack_payload_matches_golden_file() {
+    // given
+    let traces = vec![deterministic_trace()];
+
+    // when
+    let payload = to_golden_msgpack(&traces);
+
+    // then
+    assert_golden("tests/golden/trace.msgpack", payload);
+}
+
+#[test]
+fn test_v07_payload_matches_golden_file() {
+    use std::sync::{Arc, Mutex};
+    use tracing_datadog_apm::datadog_client::{Client, ClientConfig, TraceApiVersion};
+
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let client = Client::create_with_config(
+        ClientConfig::new()
+            .datadog_agent_port(1)
+            .inline_export(true)
+            .trace_api_version(TraceApiVersion::V07)
+            .env("fixture-env")
+            .app_version("1.0.0")
+            .hostname("fixture-host")
+            .on_payload(move |payload, _| {
+                seen.lock().unwrap().push(payload.to_vec());
+            }),
+    );
+
+    // when
+    client.send_traces(vec![deterministic_trace()]);
+
+    // then
+    let payloads = payloads.lock().unwrap();
+    let payload: serde_json::Value = rmp_serde::from_slice(&payloads[0]).unwrap();
+    assert_eq!(payload["env"], "fixture-env");
+    assert_eq!(payload["app_version"], "1.0.0");
+    assert_eq!(payload["hostname"], "fixture-host");
+    assert_eq!(payload["chunks"][0]["spans"][0]["service"], "fixture-web");
+    let mut json = serde_json::to_string_pretty(&payload).unwrap();
+    json.push('\n');
+    assert_golden("tests/golden/trace.v07.json", json);
+}
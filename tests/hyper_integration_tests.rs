@@ -0,0 +1,124 @@
+#![cfg(feature = "hyper_client")]
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::Instrument;
+use tracing_datadog_apm::datadog_client::{Client, ClientConfig, ServiceName, SpanName, SpanType};
+use tracing_datadog_apm::instrumentation_hyper::{
+    TracedConnector, CONNECT_SPAN_NAME, DNS_SPAN_NAME, TLS_SPAN_NAME,
+};
+use tracing_datadog_apm::subscriber::{
+    DatadogSpanExt, TracingSubscriberDatadog, TracingSubscriberDatadogConfig,
+};
+
+#[test]
+fn test_traced_connector_times_connection_phases() {
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(ClientConfig::new().datadog_agent_port(1).on_payload(
+            move |payload, _| {
+                seen.lock().unwrap().push(payload.to_vec());
+            },
+        )),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("call"), (ServiceName("test"), SpanType::Web))
+            .add_mapping(
+                SpanName(DNS_SPAN_NAME),
+                (ServiceName("test"), SpanType::Web),
+            )
+            .add_mapping(
+                SpanName(CONNECT_SPAN_NAME),
+                (ServiceName("test"), SpanType::Web),
+            )
+            .add_mapping(
+                SpanName(TLS_SPAN_NAME),
+                (ServiceName("test"), SpanType::Web),
+            ),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+    let _guard = tracing::dispatcher::set_default(&dispatch);
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let (port, call_span_id, connected) = runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut connector = TracedConnector::new().unwrap();
+
+        // when
+        let call = tracing::info_span!("call");
+        let call_span_id = call.dd_span_id().unwrap().get();
+        let uri = format!("http://localhost:{}/", port).parse().unwrap();
+        let connected = hyper::service::Service::call(&mut connector, uri)
+            .instrument(call)
+            .await;
+        (port, call_span_id, connected)
+    });
+
+    // then
+    assert!(connected.is_ok());
+    // the daemon sends the spans in the background
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let spans = loop {
+        let spans: Vec<serde_json::Value> = payloads
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|payload| {
+                serde_json::from_slice::<Vec<Vec<serde_json::Value>>>(payload).unwrap()
+            })
+            .flatten()
+            .collect();
+        if spans.len() >= 3 || Instant::now() > deadline {
+            break spans;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    let names: Vec<&str> = spans
+        .iter()
+        .map(|span| span["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, [DNS_SPAN_NAME, CONNECT_SPAN_NAME, "call"]);
+    assert_eq!(spans[0]["resource"], "localhost");
+    assert_eq!(spans[0]["parent_id"], call_span_id);
+    assert_eq!(spans[1]["resource"], format!("localhost:{}", port));
+    assert_eq!(spans[1]["parent_id"], call_span_id);
+}
+
+#[test]
+fn test_phase_spans_are_mapped_by_the_integration() {
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(
+            ClientConfig::new()
+                .datadog_agent_port(1)
+                .inline_export(true)
+                .on_payload(move |payload, _| {
+                    seen.lock().unwrap().push(payload.to_vec());
+                }),
+        ),
+        TracingSubscriberDatadogConfig::new().default_service("orders"),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+
+    tracing::dispatcher::with_default(&dispatch, || {
+        // when a span of the integration is created
+        drop(tracing::info_span!(
+            target: "tracing_datadog_apm::instrumentation_hyper",
+            "http.client",
+            span_name = DNS_SPAN_NAME
+        ));
+    });
+
+    // then
+    let payloads = payloads.lock().unwrap();
+    let span = &serde_json::from_slice::<serde_json::Value>(&payloads[0]).unwrap()[0][0];
+    assert_eq!(span["name"], DNS_SPAN_NAME);
+    assert_eq!(span["service"], "orders-http-client");
+    assert_eq!(span["type"], "web");
+}
@@ -0,0 +1,63 @@
+#![cfg(feature = "lapin")]
+
+use std::sync::{Arc, Mutex};
+use tracing_datadog_apm::datadog_client::{Client, ClientConfig};
+use tracing_datadog_apm::instrumentation_lapin::{consume_span, inject, publish_span};
+use tracing_datadog_apm::subscriber::{TracingSubscriberDatadog, TracingSubscriberDatadogConfig};
+
+#[test]
+fn test_consume_span_continues_the_trace_of_the_publisher() {
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(
+            ClientConfig::new()
+                .datadog_agent_port(1)
+                .inline_export(true)
+                .on_payload(move |payload, _| {
+                    seen.lock().unwrap().push(payload.to_vec());
+                }),
+        ),
+        TracingSubscriberDatadogConfig::new(),
+    );
+
+    tracing::subscriber::with_default(subscriber, || {
+        // when
+        let publish = publish_span("orders", "orders.created");
+        let properties = inject(
+            &publish,
+            lapin::BasicProperties::default().with_content_type("application/json".into()),
+        );
+        drop(publish);
+        let delivery = lapin::message::Delivery {
+            delivery_tag: 1,
+            exchange: "orders".into(),
+            routing_key: "orders.created".into(),
+            redelivered: false,
+            properties,
+            data: Vec::new(),
+            acker: Default::default(),
+        };
+        consume_span(&delivery, "billing").in_scope(|| {});
+    });
+
+    // then
+    let payloads = payloads.lock().unwrap();
+    let spans: Vec<serde_json::Value> = payloads
+        .iter()
+        .map(|payload| serde_json::from_slice::<serde_json::Value>(payload).unwrap()[0][0].clone())
+        .collect();
+    let (publish, consume) = (&spans[0], &spans[1]);
+    assert_eq!(publish["name"], "amqp.publish");
+    assert_eq!(publish["type"], "queue");
+    assert_eq!(publish["resource"], "orders -> orders.created");
+    assert_eq!(publish["meta"]["amqp.exchange"], "orders");
+    assert_eq!(publish["meta"]["amqp.routing_key"], "orders.created");
+    assert_eq!(consume["name"], "amqp.consume");
+    assert_eq!(consume["resource"], "billing");
+    assert_eq!(consume["meta"]["amqp.queue"], "billing");
+    assert_eq!(consume["meta"]["span.kind"], "consumer");
+    assert_eq!(consume["trace_id"], publish["trace_id"]);
+    assert_eq!(consume["parent_id"], publish["span_id"]);
+}
@@ -0,0 +1,52 @@
+use std::sync::{Arc, Mutex};
+use tracing_datadog_apm::datadog_client::{Client, ClientConfig};
+use tracing_datadog_apm::lifecycle::Lifecycle;
+use tracing_datadog_apm::subscriber::{TracingSubscriberDatadog, TracingSubscriberDatadogConfig};
+
+#[test]
+fn test_startup_phases_are_traced_under_a_lifecycle_trace() {
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(
+            ClientConfig::new()
+                .datadog_agent_port(1)
+                .inline_export(true)
+                .on_payload(move |payload, _| {
+                    seen.lock().unwrap().push(payload.to_vec());
+                }),
+        ),
+        TracingSubscriberDatadogConfig::new().default_service("orders"),
+    );
+
+    tracing::subscriber::with_default(subscriber, || {
+        // when
+        let request = tracing::info_span!("request");
+        let _entered = request.enter();
+        let startup = Lifecycle::startup();
+        let port = startup.in_phase("config load", || 8080);
+        startup.in_phase("warmup", || assert_eq!(port, 8080));
+        startup.finish();
+    });
+
+    // then
+    let payloads = payloads.lock().unwrap();
+    let spans: Vec<serde_json::Value> = payloads
+        .iter()
+        .map(|payload| serde_json::from_slice::<serde_json::Value>(payload).unwrap()[0][0].clone())
+        .collect();
+    let (config_load, warmup, startup) = (&spans[0], &spans[1], &spans[2]);
+    assert_eq!(startup["name"], "process.lifecycle");
+    assert_eq!(startup["resource"], "startup");
+    assert_eq!(startup["service"], "orders");
+    assert!(startup["parent_id"].is_null());
+    assert_eq!(config_load["name"], "process.phase");
+    assert_eq!(config_load["resource"], "config load");
+    assert_eq!(config_load["meta"]["process.lifecycle.stage"], "startup");
+    assert_eq!(warmup["resource"], "warmup");
+    for phase in [config_load, warmup] {
+        assert_eq!(phase["trace_id"], startup["trace_id"]);
+        assert_eq!(phase["parent_id"], startup["span_id"]);
+    }
+}
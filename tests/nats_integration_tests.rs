@@ -0,0 +1,63 @@
+#![cfg(feature = "nats")]
+
+use std::sync::{Arc, Mutex};
+use tracing_datadog_apm::datadog_client::{Client, ClientConfig};
+use tracing_datadog_apm::instrumentation_nats::{consume_span, inject, publish_span};
+use tracing_datadog_apm::subscriber::{TracingSubscriberDatadog, TracingSubscriberDatadogConfig};
+
+#[test]
+fn test_consume_span_continues_the_trace_of_the_publisher() {
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(
+            ClientConfig::new()
+                .datadog_agent_port(1)
+                .inline_export(true)
+                .on_payload(move |payload, _| {
+                    seen.lock().unwrap().push(payload.to_vec());
+                }),
+        ),
+        TracingSubscriberDatadogConfig::new(),
+    );
+
+    tracing::subscriber::with_default(subscriber, || {
+        // when
+        let mut headers = async_nats::HeaderMap::new();
+        let publish = publish_span("orders.created");
+        inject(&publish, &mut headers);
+        drop(publish);
+        let message = async_nats::Message {
+            subject: "orders.created".into(),
+            reply: None,
+            payload: Default::default(),
+            headers: Some(headers),
+            status: None,
+            description: None,
+            length: 0,
+        };
+        consume_span(&message).in_scope(|| {});
+    });
+
+    // then
+    let payloads = payloads.lock().unwrap();
+    let spans: Vec<serde_json::Value> = payloads
+        .iter()
+        .map(|payload| serde_json::from_slice::<serde_json::Value>(payload).unwrap()[0][0].clone())
+        .collect();
+    let (publish, consume) = (&spans[0], &spans[1]);
+    assert_eq!(publish["name"], "nats.publish");
+    assert_eq!(publish["type"], "queue");
+    assert_eq!(publish["resource"], "orders.created");
+    assert_eq!(publish["meta"]["span.kind"], "producer");
+    assert_eq!(publish["meta"]["messaging.system"], "nats");
+    assert_eq!(consume["name"], "nats.consume");
+    assert_eq!(consume["meta"]["span.kind"], "consumer");
+    assert_eq!(
+        consume["meta"]["messaging.destination.name"],
+        "orders.created"
+    );
+    assert_eq!(consume["trace_id"], publish["trace_id"]);
+    assert_eq!(consume["parent_id"], publish["span_id"]);
+}
@@ -0,0 +1,1508 @@
+use tracing_datadog_apm::datadog_client::{Client, ClientConfig, ServiceName, SpanName, SpanType};
+use tracing_datadog_apm::subscriber::{TracingSubscriberDatadog, TracingSubscriberDatadogConfig};
+
+#[test]
+fn test_snapshot_lists_open_spans() {
+    // given
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(ClientConfig::new().datadog_agent_port(1)),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("parent"), (ServiceName("test"), SpanType::Custom))
+            .add_mapping(SpanName("child"), (ServiceName("test"), SpanType::Custom)),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+
+    tracing::dispatcher::with_default(&dispatch, || {
+        let parent = tracing::info_span!("parent");
+        let _entered = parent.enter();
+        let child = tracing::info_span!("child");
+
+        // when
+        let open_spans = dispatch
+            .downcast_ref::<TracingSubscriberDatadog>()
+            .unwrap()
+            .snapshot();
+
+        // then
+        assert_eq!(open_spans.len(), 2);
+        let open_parent = open_spans.iter().find(|s| s.name == "parent").unwrap();
+        let open_child = open_spans.iter().find(|s| s.name == "child").unwrap();
+        assert_eq!(open_parent.parent_id, None);
+        assert_eq!(open_child.parent_id, Some(open_parent.span_id));
+        assert_eq!(open_child.trace_id, open_parent.trace_id);
+        assert_eq!(open_child.span_id, child.id().unwrap().into_u64());
+        assert!(!open_child.thread.is_empty());
+
+        drop(child);
+        let open_spans = dispatch
+            .downcast_ref::<TracingSubscriberDatadog>()
+            .unwrap()
+            .snapshot();
+        assert_eq!(open_spans.len(), 1);
+    });
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn test_spawn_blocking_traced_parents_spans_to_caller() {
+    use tracing_datadog_apm::task::spawn_blocking_traced;
+
+    // given
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(ClientConfig::new().datadog_agent_port(1)),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("parent"), (ServiceName("test"), SpanType::Custom))
+            .add_mapping(
+                SpanName("blocking"),
+                (ServiceName("test"), SpanType::Custom),
+            ),
+    );
+    let _default = tracing::subscriber::set_default(subscriber);
+    let parent = tracing::info_span!("parent");
+    let parent_id = parent.id().unwrap().into_u64();
+
+    // when
+    let open_spans = {
+        let _entered = parent.enter();
+        spawn_blocking_traced(|| {
+            let _blocking = tracing::info_span!("blocking").entered();
+            tracing::dispatcher::get_default(|dispatch| {
+                dispatch
+                    .downcast_ref::<TracingSubscriberDatadog>()
+                    .unwrap()
+                    .snapshot()
+            })
+        })
+    }
+    .await
+    .unwrap();
+
+    // then
+    let blocking = open_spans.iter().find(|s| s.name == "blocking").unwrap();
+    assert_eq!(blocking.parent_id, Some(parent_id));
+}
+
+#[test]
+fn test_span_name_field_overrides_mapping() {
+    // given
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(ClientConfig::new().datadog_agent_port(1)),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(
+                SpanName("admin.request"),
+                (ServiceName("admin"), SpanType::Web),
+            )
+            .add_mapping(SpanName("child"), (ServiceName("test"), SpanType::Custom)),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+
+    tracing::dispatcher::with_default(&dispatch, || {
+        // when
+        let mapped = tracing::info_span!("request", span_name = "admin.request");
+        let unmapped = tracing::info_span!("request", span_name = "api.request");
+        let _entered = unmapped.enter();
+        let child = tracing::info_span!("child");
+
+        // then
+        let open_spans = dispatch
+            .downcast_ref::<TracingSubscriberDatadog>()
+            .unwrap()
+            .snapshot();
+        assert_eq!(open_spans.len(), 2);
+        let open_mapped = open_spans
+            .iter()
+            .find(|s| s.name == "admin.request")
+            .unwrap();
+        assert_eq!(open_mapped.span_id, mapped.id().unwrap().into_u64());
+        let open_child = open_spans.iter().find(|s| s.name == "child").unwrap();
+        assert_eq!(open_child.span_id, child.id().unwrap().into_u64());
+        assert_eq!(open_child.parent_id, None);
+    });
+}
+
+#[test]
+fn test_span_with_remote_parent_continues_trace() {
+    use std::collections::HashMap;
+    use tracing_datadog_apm::propagation;
+
+    // given
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(ClientConfig::new().datadog_agent_port(1)),
+        TracingSubscriberDatadogConfig::new().add_mapping(
+            SpanName("queue.consume"),
+            (ServiceName("test"), SpanType::Custom),
+        ),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+    let attributes = HashMap::from([
+        (String::from("x-datadog-trace-id"), String::from("123")),
+        (String::from("x-datadog-parent-id"), String::from("456")),
+    ]);
+    let context = propagation::extract(&attributes).unwrap();
+
+    tracing::dispatcher::with_default(&dispatch, || {
+        // when
+        let _span = tracing_datadog_apm::span_with_remote_parent!(
+            context,
+            "queue.consume",
+            resource = "orders"
+        );
+
+        // then
+        let open_spans = dispatch
+            .downcast_ref::<TracingSubscriberDatadog>()
+            .unwrap()
+            .snapshot();
+        assert_eq!(open_spans.len(), 1);
+        assert_eq!(open_spans[0].trace_id, 123);
+        assert_eq!(open_spans[0].parent_id, Some(456));
+
+        let bare_span = tracing_datadog_apm::span_with_remote_parent!(context, "queue.consume");
+        assert!(bare_span.id().is_some());
+    });
+}
+
+#[test]
+fn test_remote_parent_origin_and_trace_tags_are_kept() {
+    use std::collections::HashMap;
+    use tracing_datadog_apm::context::context_of;
+    use tracing_datadog_apm::propagation;
+
+    // given
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(ClientConfig::new().datadog_agent_port(1)),
+        TracingSubscriberDatadogConfig::new().add_mapping(
+            SpanName("queue.consume"),
+            (ServiceName("test"), SpanType::Custom),
+        ),
+    );
+    let attributes = HashMap::from([
+        (String::from("x-datadog-trace-id"), String::from("123")),
+        (String::from("x-datadog-parent-id"), String::from("456")),
+        (String::from("x-datadog-origin"), String::from("synthetics")),
+        (String::from("x-datadog-tags"), String::from("_dd.p.dm=-4")),
+    ]);
+    let context = propagation::extract(&attributes).unwrap();
+
+    tracing::subscriber::with_default(subscriber, || {
+        // when
+        let span = tracing_datadog_apm::span_with_remote_parent!(context, "queue.consume");
+
+        // then
+        let span_context = context_of(&span).unwrap();
+        assert_eq!(span_context.trace_id.get(), 123);
+        assert_eq!(span_context.origin.as_deref(), Some("synthetics"));
+        assert_eq!(
+            span_context.trace_tags,
+            HashMap::from([(String::from("_dd.p.dm"), String::from("-4"))])
+        );
+    });
+}
+
+#[test]
+#[should_panic(expected = "Did not exit a span!")]
+fn test_strict_mode_panics_on_exit_without_enter() {
+    use tracing::Subscriber;
+
+    // given
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(ClientConfig::new().datadog_agent_port(1)),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("request"), (ServiceName("test"), SpanType::Web))
+            .strict(true),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+    let span = tracing::dispatcher::with_default(&dispatch, || tracing::info_span!("request"));
+
+    // when
+    dispatch
+        .downcast_ref::<TracingSubscriberDatadog>()
+        .unwrap()
+        .exit(&span.id().unwrap());
+}
+
+#[test]
+fn test_strict_mode_logs_misuses_found_while_panicking() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use tracing::Subscriber;
+
+    // exits a span it never entered when dropped, like a buggy guard
+    struct ExitOnDrop<'a>(&'a tracing::Dispatch, tracing::Span);
+
+    impl Drop for ExitOnDrop<'_> {
+        fn drop(&mut self) {
+            self.0
+                .downcast_ref::<TracingSubscriberDatadog>()
+                .unwrap()
+                .exit(&self.1.id().unwrap());
+        }
+    }
+
+    // given
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(ClientConfig::new().datadog_agent_port(1)),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("request"), (ServiceName("test"), SpanType::Web))
+            .strict(true),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+    let span = tracing::dispatcher::with_default(&dispatch, || tracing::info_span!("request"));
+
+    // when the guard finds the misuse while the test is already failing
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        let _guard = ExitOnDrop(&dispatch, span);
+        panic!("assertion failed in the test");
+    }));
+
+    // then the test fails with its own panic instead of aborting
+    let panic = result.unwrap_err();
+    assert_eq!(
+        panic.downcast_ref::<&str>(),
+        Some(&"assertion failed in the test")
+    );
+}
+
+#[test]
+fn test_misuses_are_handed_to_the_misuse_hook() {
+    use std::sync::{Arc, Mutex};
+    use tracing::Subscriber;
+
+    // given
+    let misuses = Arc::new(Mutex::new(Vec::new()));
+    let collected = Arc::clone(&misuses);
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(ClientConfig::new().datadog_agent_port(1)),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("request"), (ServiceName("test"), SpanType::Web))
+            .check_required_fields(true)
+            .strict(true)
+            .on_misuse(move |misuse| collected.lock().unwrap().push(String::from(misuse))),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+
+    // when
+    tracing::dispatcher::with_default(&dispatch, || {
+        let span = tracing::info_span!("request");
+        dispatch
+            .downcast_ref::<TracingSubscriberDatadog>()
+            .unwrap()
+            .exit(&span.id().unwrap());
+    });
+
+    // then
+    let misuses = misuses.lock().unwrap();
+    assert_eq!(misuses.len(), 2);
+    assert!(misuses[0].starts_with("Did not exit a span!"));
+    assert!(misuses[0].contains("= request ("));
+    assert!(misuses[1].starts_with("Span request"));
+}
+
+#[test]
+#[should_panic(expected = "Span request")]
+fn test_strict_mode_panics_on_missing_resource() {
+    // given
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(ClientConfig::new().datadog_agent_port(1)),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("request"), (ServiceName("test"), SpanType::Web))
+            .check_required_fields(true)
+            .strict(true),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+
+    // when
+    tracing::dispatcher::with_default(&dispatch, || drop(tracing::info_span!("request")));
+}
+
+#[test]
+fn test_datadog_span_ext() {
+    use std::sync::{Arc, Mutex};
+    use tracing_datadog_apm::subscriber::DatadogSpanExt;
+    use tracing_datadog_apm::validation::Violation;
+
+    // given
+    let violations = Arc::new(Mutex::new(Vec::new()));
+    let reported = Arc::clone(&violations);
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_validating(move |violation| {
+            reported.lock().unwrap().push(violation.clone());
+        }),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("parent"), (ServiceName("test"), SpanType::Custom))
+            .add_mapping(SpanName("child"), (ServiceName("test"), SpanType::Custom)),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+
+    tracing::dispatcher::with_default(&dispatch, || {
+        let parent = tracing::info_span!("parent");
+        let _entered = parent.enter();
+        let child = tracing::info_span!("child");
+
+        // when
+        parent.set_dd_resource("GET /users");
+        child.set_dd_resource("x".repeat(5001));
+        child.set_dd_tag("user.id", "42");
+
+        // then
+        assert!(parent.dd_trace_id().is_some());
+        assert_eq!(child.dd_trace_id(), parent.dd_trace_id());
+        assert_eq!(
+            child.dd_span_id().map(u64::from),
+            child.id().map(|id| id.into_u64())
+        );
+        assert_eq!(tracing::Span::none().dd_trace_id(), None);
+
+        let child_span_id = child.dd_span_id().unwrap().get();
+        drop(child);
+        assert_eq!(
+            *violations.lock().unwrap(),
+            vec![Violation::ResourceTooLong {
+                span_id: child_span_id,
+                len: 5001
+            }]
+        );
+    });
+}
+
+#[test]
+fn test_dd_progress_exports_heartbeats() {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tracing_datadog_apm::subscriber::DatadogSpanExt;
+    use tracing_datadog_apm::validation::Violation;
+
+    // given
+    let violations = Arc::new(Mutex::new(Vec::new()));
+    let reported = Arc::clone(&violations);
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_validating(move |violation| {
+            reported.lock().unwrap().push(violation.clone());
+        }),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("batch"), (ServiceName("test"), SpanType::Custom))
+            .heartbeat_interval(Duration::ZERO),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+
+    tracing::dispatcher::with_default(&dispatch, || {
+        let batch = tracing::info_span!("batch");
+        // an invalid resource makes the exported heartbeats visible to the validating client
+        batch.set_dd_resource("x".repeat(5001));
+
+        // when
+        batch.dd_progress(10, Some(100));
+        batch.dd_progress(20, Some(100));
+
+        // then
+        let batch_span_id = batch.dd_span_id().unwrap().get();
+        let heartbeat_span_ids: Vec<u64> = violations
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|violation| match violation {
+                Violation::ResourceTooLong { span_id, .. } => *span_id,
+                violation => panic!("Unexpected violation {}", violation),
+            })
+            .collect();
+        assert_eq!(heartbeat_span_ids.len(), 2);
+        assert!(!heartbeat_span_ids.contains(&batch_span_id));
+    });
+}
+
+#[test]
+fn test_current_request_id_is_found_on_ancestors() {
+    use tracing_datadog_apm::subscriber::{current_request_id, set_tag};
+
+    // given
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(ClientConfig::new().datadog_agent_port(1)),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("request"), (ServiceName("test"), SpanType::Web))
+            .add_mapping(SpanName("child"), (ServiceName("test"), SpanType::Custom)),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+
+    tracing::dispatcher::with_default(&dispatch, || {
+        assert_eq!(current_request_id(), None);
+        let request = tracing::info_span!("request");
+        set_tag(&request, "http.request_id", "abc-123");
+        let _request_entered = request.enter();
+
+        // when
+        let _child_entered = tracing::info_span!("child").entered();
+
+        // then
+        assert_eq!(current_request_id(), Some(String::from("abc-123")));
+    });
+}
+
+#[test]
+fn test_events_are_sent_as_span_events_with_v07() {
+    use std::sync::{Arc, Mutex};
+    use tracing_datadog_apm::datadog_client::TraceApiVersion;
+
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(
+            ClientConfig::new()
+                .datadog_agent_port(1)
+                .inline_export(true)
+                .trace_api_version(TraceApiVersion::V07)
+                .on_payload(move |payload, _| {
+                    seen.lock().unwrap().push(payload.to_vec());
+                }),
+        ),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("request"), (ServiceName("test"), SpanType::Web)),
+    );
+
+    tracing::subscriber::with_default(subscriber, || {
+        // when
+        tracing::info_span!("request").in_scope(|| {
+            tracing::info!(rows = 3, error_msg = "timeout", "query done");
+        });
+    });
+
+    // then
+    let payloads = payloads.lock().unwrap();
+    let payload: serde_json::Value = rmp_serde::from_slice(&payloads[0]).unwrap();
+    let span = &payload["chunks"][0]["spans"][0];
+    assert_eq!(span["name"], "request");
+    assert!(span["meta"].get("error.msg").is_none());
+    let span_event = &span["span_events"][0];
+    assert_eq!(span_event["name"], "query done");
+    assert!(span_event["time_unix_nano"].as_u64().unwrap() >= span["start"].as_u64().unwrap());
+    assert_eq!(span_event["attributes"]["rows"]["string_value"], "3");
+    assert_eq!(
+        span_event["attributes"]["error_msg"]["string_value"],
+        "timeout"
+    );
+}
+
+#[test]
+fn test_redacted_fields_are_not_recorded() {
+    use std::sync::{Arc, Mutex};
+    use tracing_datadog_apm::datadog_client::TraceApiVersion;
+    use tracing_datadog_apm::redaction::FieldRedactor;
+
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(
+            ClientConfig::new()
+                .datadog_agent_port(1)
+                .inline_export(true)
+                .trace_api_version(TraceApiVersion::V07)
+                .on_payload(move |payload, _| {
+                    seen.lock().unwrap().push(payload.to_vec());
+                }),
+        ),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("request"), (ServiceName("test"), SpanType::Web))
+            .field_redactor(FieldRedactor::new().field("http_url")),
+    );
+
+    tracing::subscriber::with_default(subscriber, || {
+        // when
+        tracing::info_span!("request", http_url = "/reset?token=secret").in_scope(|| {
+            tracing::info!(password = "hunter2", user = "alice", "signed in");
+        });
+    });
+
+    // then
+    let payloads = payloads.lock().unwrap();
+    let payload: serde_json::Value = rmp_serde::from_slice(&payloads[0]).unwrap();
+    let span = &payload["chunks"][0]["spans"][0];
+    assert_eq!(span["meta"]["http.url"], "[REDACTED]");
+    let attributes = &span["span_events"][0]["attributes"];
+    assert_eq!(attributes["password"]["string_value"], "[REDACTED]");
+    assert_eq!(attributes["user"]["string_value"], "alice");
+}
+
+#[test]
+fn test_span_from_events_synthesizes_child_spans() {
+    use std::sync::{Arc, Mutex};
+    use tracing_datadog_apm::subscriber::DatadogSpanExt;
+
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(
+            ClientConfig::new()
+                .datadog_agent_port(1)
+                .inline_export(true)
+                .on_payload(move |payload, _| {
+                    seen.lock().unwrap().push(payload.to_vec());
+                }),
+        ),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("request"), (ServiceName("test"), SpanType::Web))
+            .span_from_events("pool", (ServiceName("test-pool"), SpanType::Custom)),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+
+    tracing::dispatcher::with_default(&dispatch, || {
+        let request = tracing::info_span!("request");
+        let request_span_id = request.dd_span_id().unwrap().get();
+
+        // when
+        request.in_scope(|| {
+            tracing::info!(target: "pool::diagnostics", "connection checked out");
+            tracing::info!(target: "pool", span_event = "start", "waiting for connection");
+            tracing::info!(target: "pool", span_event = "end", "connection acquired");
+            tracing::info!(target: "other", "not traced");
+        });
+
+        // then
+        let spans: Vec<serde_json::Value> = payloads
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|payload| {
+                serde_json::from_slice::<serde_json::Value>(payload).unwrap()[0][0].clone()
+            })
+            .collect();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0]["name"], "pool::diagnostics");
+        assert_eq!(spans[0]["resource"], "connection checked out");
+        assert_eq!(spans[0]["service"], "test-pool");
+        assert_eq!(spans[0]["duration"], 0);
+        assert_eq!(spans[0]["parent_id"], request_span_id);
+        assert_eq!(spans[1]["name"], "pool");
+        assert_eq!(spans[1]["resource"], "waiting for connection");
+        assert_eq!(spans[1]["parent_id"], request_span_id);
+        assert_eq!(spans[0]["trace_id"], spans[1]["trace_id"]);
+    });
+}
+
+#[test]
+fn test_trace_urls() {
+    use std::num::NonZeroU64;
+    use tracing_datadog_apm::subscriber::{current_trace_url, datadog_trace_url, DatadogSpanExt};
+
+    // given
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(ClientConfig::new().datadog_agent_port(1)),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("request"), (ServiceName("test"), SpanType::Web)),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+
+    tracing::dispatcher::with_default(&dispatch, || {
+        assert_eq!(current_trace_url(), None);
+        let request = tracing::info_span!("request");
+
+        // when
+        let url = request.in_scope(current_trace_url);
+
+        // then
+        let trace_id = request.dd_trace_id().unwrap();
+        assert_eq!(url, Some(datadog_trace_url(trace_id)));
+    });
+    assert_eq!(
+        datadog_trace_url(NonZeroU64::new(42).unwrap()),
+        "https://app.datadoghq.com/apm/trace/42"
+    );
+}
+
+#[test]
+fn test_missing_tags_fail() {
+    use tracing_datadog_apm::subscriber::MissingTags;
+
+    // given
+    let config = TracingSubscriberDatadogConfig::new()
+        .default_service("test")
+        .missing_tags(MissingTags::Fail);
+    let expected_missing: Vec<&str> = ["DD_ENV", "DD_VERSION"]
+        .into_iter()
+        .filter(|env_var| std::env::var(env_var).unwrap_or_default().is_empty())
+        .collect();
+
+    // when
+    let result = TracingSubscriberDatadog::try_new(
+        Client::create_with_config(ClientConfig::new().datadog_agent_port(1)),
+        config,
+    );
+
+    // then
+    match result {
+        Ok(_) => assert!(expected_missing.is_empty()),
+        Err(e) => assert_eq!(e.env_vars, expected_missing),
+    }
+}
+
+#[test]
+fn test_missing_tags_omit() {
+    use std::sync::{Arc, Mutex};
+    use tracing_datadog_apm::subscriber::MissingTags;
+
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(
+            ClientConfig::new()
+                .datadog_agent_port(1)
+                .inline_export(true)
+                .on_payload(move |payload, _| {
+                    seen.lock().unwrap().push(payload.to_vec());
+                }),
+        ),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("request"), (ServiceName("test"), SpanType::Web))
+            .default_service("test")
+            .missing_tags(MissingTags::Omit),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+
+    // when
+    tracing::dispatcher::with_default(&dispatch, || {
+        tracing::info_span!("request").in_scope(|| {});
+    });
+
+    // then
+    let payloads = payloads.lock().unwrap();
+    let span = &serde_json::from_slice::<serde_json::Value>(&payloads[0]).unwrap()[0][0];
+    assert_eq!(span["meta"]["service"], "test");
+    assert_eq!(
+        span["meta"].get("env").is_some(),
+        !std::env::var("DD_ENV").unwrap_or_default().is_empty()
+    );
+}
+
+// Sends the spans of `f` inline, returning the spans of every payload in the order they were sent
+fn exported_spans(
+    config: TracingSubscriberDatadogConfig,
+    f: impl FnOnce(),
+) -> Vec<serde_json::Value> {
+    use std::sync::{Arc, Mutex};
+
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(
+            ClientConfig::new()
+                .datadog_agent_port(1)
+                .inline_export(true)
+                .on_payload(move |payload, _| {
+                    seen.lock().unwrap().push(payload.to_vec());
+                }),
+        ),
+        config,
+    );
+    tracing::dispatcher::with_default(&tracing::Dispatch::new(subscriber), f);
+    let payloads = payloads.lock().unwrap();
+    payloads
+        .iter()
+        .flat_map(|payload| {
+            let traces: Vec<Vec<serde_json::Value>> = serde_json::from_slice(payload).unwrap();
+            traces.into_iter().flatten()
+        })
+        .collect()
+}
+
+fn first_exported_span(
+    config: TracingSubscriberDatadogConfig,
+    f: impl FnOnce(),
+) -> serde_json::Value {
+    exported_spans(config, f).swap_remove(0)
+}
+
+// The tag an env var sets, or `default` when the env var is not set
+fn tag_or(env_var: &str, default: &str) -> String {
+    std::env::var(env_var).unwrap_or_else(|_| String::from(default))
+}
+
+#[test]
+fn test_default_service_and_version_are_used_without_env_vars() {
+    use tracing_datadog_apm::lifecycle::Lifecycle;
+
+    // given
+    let config = TracingSubscriberDatadogConfig::new()
+        .default_service("orders")
+        .default_version("1.2.3");
+
+    // when the span's mapping, of the lifecycle integration, gives no service of its own
+    let span = first_exported_span(config, || Lifecycle::startup().finish());
+
+    // then
+    let service = tag_or("DD_SERVICE", "orders");
+    assert_eq!(span["service"], *service);
+    assert_eq!(span["meta"]["service"], *service);
+    assert_eq!(span["meta"]["version"], *tag_or("DD_VERSION", "1.2.3"));
+}
+
+#[test]
+fn test_integration_mappings_only_map_spans_of_their_integration() {
+    use tracing_datadog_apm::lifecycle::{Lifecycle, LIFECYCLE_SPAN_NAME};
+
+    // when
+    let spans = exported_spans(TracingSubscriberDatadogConfig::new(), || {
+        tracing::info_span!(LIFECYCLE_SPAN_NAME, resource = "app").in_scope(|| {});
+        Lifecycle::startup().finish();
+    });
+
+    // then
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0]["resource"], "startup");
+}
+
+#[test]
+fn test_config_from_cargo_uses_the_package_name_and_version() {
+    // given
+    let config = tracing_datadog_apm::config_from_cargo!()
+        .add_mapping(SpanName("request"), (ServiceName("web"), SpanType::Web));
+
+    // when
+    let span = first_exported_span(config, || {
+        tracing::info_span!("request").in_scope(|| {});
+    });
+
+    // then
+    assert_eq!(span["service"], "web");
+    assert_eq!(
+        span["meta"]["service"],
+        *tag_or("DD_SERVICE", "tracing-datadog-apm")
+    );
+    assert_eq!(
+        span["meta"]["version"],
+        *tag_or("DD_VERSION", env!("CARGO_PKG_VERSION"))
+    );
+}
+
+#[test]
+fn test_env_field_overrides_the_env_tag_of_the_span_and_its_children() {
+    // given
+    let config = TracingSubscriberDatadogConfig::new()
+        .add_mapping(
+            SpanName("message"),
+            (ServiceName("worker"), SpanType::Custom),
+        )
+        .add_mapping(
+            SpanName("handle"),
+            (ServiceName("worker"), SpanType::Custom),
+        );
+
+    // when
+    let spans = exported_spans(config, || {
+        tracing::info_span!("message", env = "staging").in_scope(|| {
+            tracing::info_span!("handle").in_scope(|| {});
+        });
+        tracing::info_span!("message").in_scope(|| {});
+    });
+
+    // then
+    let env = |name: &str, index: usize| {
+        spans
+            .iter()
+            .filter(|span| span["name"] == name)
+            .nth(index)
+            .map(|span| span["meta"]["env"].clone())
+            .unwrap()
+    };
+    assert_eq!(env("message", 0), "staging");
+    assert_eq!(env("handle", 0), "staging");
+    assert_eq!(env("message", 1), *tag_or("DD_ENV", ""));
+}
+
+#[test]
+fn test_sql_queries_are_obfuscated_in_the_configured_dialect() {
+    use tracing_datadog_apm::obfuscate::SqlDialect;
+
+    // given
+    let record = |dialect, query: &str| {
+        let config = TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("query"), (ServiceName("db"), SpanType::Db))
+            .sql_dialect(dialect);
+        first_exported_span(config, || {
+            tracing::info_span!("query", sql_query = query).in_scope(|| {});
+        })
+    };
+
+    // when
+    let mysql_span = record(
+        SqlDialect::MySql,
+        r#"SELECT * FROM users WHERE name = "alice" AND pw = 'it\'s secret'"#,
+    );
+    let standard_span = record(
+        SqlDialect::Standard,
+        r#"SELECT * FROM "files" WHERE path = 'C:\' AND pw = 'secret'"#,
+    );
+
+    // then
+    assert_eq!(
+        mysql_span["meta"]["sql.query"],
+        "SELECT * FROM users WHERE name = ? AND pw = ?"
+    );
+    assert_eq!(
+        standard_span["meta"]["sql.query"],
+        r#"SELECT * FROM "files" WHERE path = ? AND pw = ?"#
+    );
+}
+
+#[test]
+fn test_span_records_are_removed_with_the_last_reference() {
+    // given
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(ClientConfig::new().datadog_agent_port(1)),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("request"), (ServiceName("test"), SpanType::Web)),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+
+    tracing::dispatcher::with_default(&dispatch, || {
+        let subscriber = dispatch.downcast_ref::<TracingSubscriberDatadog>().unwrap();
+        let request = tracing::info_span!("request");
+        let clone = request.clone();
+
+        // when
+        drop(request);
+        let open_after_first_drop = subscriber.snapshot().len();
+        drop(clone);
+
+        // then
+        assert_eq!(open_after_first_drop, 1);
+        assert!(subscriber.snapshot().is_empty());
+    });
+}
+
+#[test]
+fn test_rate_limited_spans_are_not_exported() {
+    use std::sync::{Arc, Mutex};
+
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(
+            ClientConfig::new()
+                .datadog_agent_port(1)
+                .inline_export(true)
+                .on_payload(move |payload, _| {
+                    seen.lock().unwrap().push(payload.to_vec());
+                }),
+        ),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("loop"), (ServiceName("test"), SpanType::Custom))
+            .rate_limit(SpanName("loop"), 0.0, 2),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+
+    // when
+    tracing::dispatcher::with_default(&dispatch, || {
+        for _ in 0..5 {
+            tracing::info_span!("loop").in_scope(|| {});
+        }
+    });
+
+    // then
+    assert_eq!(payloads.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn test_new_trace_root_starts_a_new_trace() {
+    use tracing_datadog_apm::subscriber::DatadogSpanExt;
+
+    // given
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(ClientConfig::new().datadog_agent_port(1)),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("batch"), (ServiceName("test"), SpanType::Custom))
+            .add_mapping(SpanName("message"), (ServiceName("test"), SpanType::Custom)),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+
+    tracing::dispatcher::with_default(&dispatch, || {
+        let batch = tracing::info_span!("batch");
+        let _entered = batch.enter();
+
+        // when
+        let child = tracing::info_span!("message");
+        let root = tracing_datadog_apm::new_trace_root!("message", resource = "orders");
+        let explicit_child = tracing::info_span!(parent: &root, "message");
+
+        // then
+        assert_eq!(child.dd_trace_id(), batch.dd_trace_id());
+        assert_ne!(root.dd_trace_id(), batch.dd_trace_id());
+        assert_eq!(explicit_child.dd_trace_id(), root.dd_trace_id());
+    });
+}
+
+#[test]
+fn test_current_context_from_other_threads() {
+    use tracing_datadog_apm::context::{context_of, current_context};
+    use tracing_datadog_apm::subscriber::DatadogSpanExt;
+
+    // given
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(ClientConfig::new().datadog_agent_port(1)),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("request"), (ServiceName("test"), SpanType::Web))
+            .add_mapping(SpanName("worker"), (ServiceName("test"), SpanType::Custom)),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+
+    tracing::dispatcher::with_default(&dispatch, || {
+        assert_eq!(current_context(), None);
+        let request = tracing::info_span!("request");
+
+        // when
+        let worker_context = std::thread::scope(|scope| {
+            let dispatch = dispatch.clone();
+            let request = request.clone();
+            scope
+                .spawn(move || {
+                    tracing::dispatcher::with_default(&dispatch, || {
+                        tracing::info_span!(parent: &request, "worker").in_scope(current_context)
+                    })
+                })
+                .join()
+                .unwrap()
+        });
+
+        // then
+        let request_context = context_of(&request).unwrap();
+        assert_eq!(request_context.trace_id, request.dd_trace_id().unwrap());
+        assert_eq!(request_context.span_id, request.dd_span_id().unwrap());
+        let worker_context = worker_context.unwrap();
+        assert_eq!(worker_context.trace_id, request_context.trace_id);
+        assert_ne!(worker_context.span_id, request_context.span_id);
+        assert_eq!(request.in_scope(current_context), Some(request_context));
+    });
+}
+
+#[test]
+fn test_root_spans_only_aggregates_children() {
+    use std::sync::{Arc, Mutex};
+    use tracing_datadog_apm::subscriber::{
+        CHILD_SPANS_COUNT_METRIC_KEY, CHILD_SPANS_DURATION_METRIC_KEY,
+    };
+
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(
+            ClientConfig::new()
+                .datadog_agent_port(1)
+                .inline_export(true)
+                .on_payload(move |payload, _| {
+                    seen.lock().unwrap().push(payload.to_vec());
+                }),
+        ),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("request"), (ServiceName("test"), SpanType::Web))
+            .add_mapping(SpanName("query"), (ServiceName("test"), SpanType::Db))
+            .root_spans_only(true),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+
+    tracing::dispatcher::with_default(&dispatch, || {
+        // when
+        tracing::info_span!("request").in_scope(|| {
+            tracing::info_span!("query").in_scope(|| {
+                std::thread::sleep(std::time::Duration::from_millis(5));
+                tracing::info_span!("query").in_scope(|| {});
+            });
+        });
+    });
+
+    // then
+    let spans: Vec<serde_json::Value> = payloads
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|payload| serde_json::from_slice::<serde_json::Value>(payload).unwrap()[0][0].clone())
+        .collect();
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0]["name"], "request");
+    assert_eq!(spans[0]["metrics"][CHILD_SPANS_COUNT_METRIC_KEY], 2.0);
+    assert!(
+        spans[0]["metrics"][CHILD_SPANS_DURATION_METRIC_KEY]
+            .as_f64()
+            .unwrap()
+            >= 5.0
+    );
+}
+
+#[test]
+fn test_inherited_tags_are_copied_to_children() {
+    use std::sync::{Arc, Mutex};
+    use tracing_datadog_apm::subscriber::set_tag;
+
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(
+            ClientConfig::new()
+                .datadog_agent_port(1)
+                .inline_export(true)
+                .on_payload(move |payload, _| {
+                    seen.lock().unwrap().push(payload.to_vec());
+                }),
+        ),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("request"), (ServiceName("test"), SpanType::Web))
+            .add_mapping(SpanName("query"), (ServiceName("test"), SpanType::Db))
+            .inherited_tag("customer.id"),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+
+    tracing::dispatcher::with_default(&dispatch, || {
+        let request = tracing::info_span!("request");
+        set_tag(&request, "customer.id", "42");
+        set_tag(&request, "http.route", "/orders");
+
+        // when
+        request.in_scope(|| tracing::info_span!("query").in_scope(|| {}));
+    });
+
+    // then
+    let spans: Vec<serde_json::Value> = payloads
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|payload| serde_json::from_slice::<serde_json::Value>(payload).unwrap()[0][0].clone())
+        .collect();
+    assert_eq!(spans.len(), 2);
+    assert_eq!(spans[0]["name"], "query");
+    assert_eq!(spans[0]["meta"]["customer.id"], "42");
+    assert!(spans[0]["meta"].get("http.route").is_none());
+    assert_eq!(spans[1]["meta"]["customer.id"], "42");
+}
+
+#[test]
+fn test_opentelemetry_fields_are_mapped() {
+    use std::sync::{Arc, Mutex};
+
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(
+            ClientConfig::new()
+                .datadog_agent_port(1)
+                .inline_export(true)
+                .on_payload(move |payload, _| {
+                    seen.lock().unwrap().push(payload.to_vec());
+                }),
+        ),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("request"), (ServiceName("test"), SpanType::Web)),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+
+    tracing::dispatcher::with_default(&dispatch, || {
+        // when
+        let span = tracing::info_span!(
+            "request",
+            otel.name = "GET /orders",
+            otel.kind = "Server",
+            otel.status_code = tracing::field::Empty,
+            otel.status_message = tracing::field::Empty,
+        );
+        span.record("otel.status_code", "ERROR");
+        span.record("otel.status_message", "upstream timed out");
+    });
+
+    // then
+    let payloads = payloads.lock().unwrap();
+    let span = &serde_json::from_slice::<serde_json::Value>(&payloads[0]).unwrap()[0][0];
+    assert_eq!(span["resource"], "GET /orders");
+    assert_eq!(span["meta"]["span.kind"], "server");
+    assert_eq!(span["meta"]["error.msg"], "upstream timed out");
+    assert_eq!(span["error"], 1);
+}
+
+#[test]
+fn test_start_time_unit_and_validation() {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use tracing_datadog_apm::subscriber::{StartTimeUnit, INVALID_START_TAG};
+
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(
+            ClientConfig::new()
+                .datadog_agent_port(1)
+                .inline_export(true)
+                .on_payload(move |payload, _| {
+                    seen.lock().unwrap().push(payload.to_vec());
+                }),
+        ),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("job"), (ServiceName("test"), SpanType::Custom))
+            .start_time_unit(StartTimeUnit::Millis),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+    let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    let an_hour_ago_ms = an_hour_ago.duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+
+    tracing::dispatcher::with_default(&dispatch, || {
+        // when
+        drop(tracing::info_span!("job", start = an_hour_ago_ms));
+        drop(tracing::info_span!("job", start = 42u64));
+    });
+
+    // then
+    let spans: Vec<serde_json::Value> = payloads
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|payload| serde_json::from_slice::<serde_json::Value>(payload).unwrap()[0][0].clone())
+        .collect();
+    assert_eq!(spans[0]["start"].as_u64(), Some(an_hour_ago_ms * 1_000_000));
+    assert!(spans[0]["meta"].get(INVALID_START_TAG).is_none());
+    assert_eq!(spans[1]["meta"][INVALID_START_TAG], "42");
+    let now_ns = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64;
+    assert!(spans[1]["start"].as_u64().unwrap() > now_ns - 60_000_000_000);
+}
+
+#[test]
+fn test_dropping_the_subscriber_exports_unfinished_spans() {
+    use std::sync::{Arc, Mutex};
+    use tracing_datadog_apm::subscriber::UNFINISHED_TAG;
+
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(
+            ClientConfig::new()
+                .datadog_agent_port(1)
+                .inline_export(true)
+                .on_payload(move |payload, _| {
+                    seen.lock().unwrap().push(payload.to_vec());
+                }),
+        ),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("request"), (ServiceName("test"), SpanType::Web))
+            .span_from_events("pool", (ServiceName("test-pool"), SpanType::Custom)),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+    tracing::dispatcher::with_default(&dispatch, || {
+        // a reference which is never released, like one leaked by a misbehaving layer
+        let request = tracing::info_span!("request");
+        dispatch.clone_span(&request.id().unwrap());
+        drop(request);
+        tracing::info!(target: "pool", span_event = "start", "waiting for connection");
+    });
+    assert!(payloads.lock().unwrap().is_empty());
+
+    // when
+    drop(dispatch);
+
+    // then
+    let mut spans: Vec<serde_json::Value> = payloads
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|payload| serde_json::from_slice::<serde_json::Value>(payload).unwrap()[0][0].clone())
+        .collect();
+    spans.sort_by_key(|span| span["name"].as_str().unwrap().to_string());
+    assert_eq!(spans.len(), 2);
+    assert_eq!(spans[0]["name"], "pool");
+    assert_eq!(spans[1]["name"], "request");
+    assert!(spans
+        .iter()
+        .all(|span| span["meta"][UNFINISHED_TAG] == "true"));
+}
+
+#[test]
+fn test_rollout_traces_a_share_of_traces() {
+    use std::sync::{Arc, Mutex};
+
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(
+            ClientConfig::new()
+                .datadog_agent_port(1)
+                .inline_export(true)
+                .on_payload(move |payload, _| {
+                    seen.lock().unwrap().push(payload.to_vec());
+                }),
+        ),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("request"), (ServiceName("test"), SpanType::Web))
+            .add_mapping(SpanName("query"), (ServiceName("test"), SpanType::Db))
+            .add_mapping(SpanName("cache"), (ServiceName("test"), SpanType::Cache))
+            .rollout(SpanName("query"), 0.0)
+            .rollout(SpanName("cache"), 100.0),
+    );
+
+    tracing::subscriber::with_default(subscriber, || {
+        // when
+        tracing::info_span!("request").in_scope(|| {
+            tracing::info_span!("query").in_scope(|| {});
+            tracing::info_span!("cache").in_scope(|| {});
+        });
+    });
+
+    // then
+    let names: Vec<String> = payloads
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|payload| {
+            let payload = serde_json::from_slice::<serde_json::Value>(payload).unwrap();
+            String::from(payload[0][0]["name"].as_str().unwrap())
+        })
+        .collect();
+    assert_eq!(names, ["cache", "request"]);
+}
+
+#[test]
+fn test_drop_rules_drop_matching_spans() {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tracing_datadog_apm::drop_rules::{DropRules, Regex};
+
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(
+            ClientConfig::new()
+                .datadog_agent_port(1)
+                .inline_export(true)
+                .on_payload(move |payload, _| {
+                    seen.lock().unwrap().push(payload.to_vec());
+                }),
+        ),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("request"), (ServiceName("test"), SpanType::Web))
+            .add_mapping(SpanName("cache"), (ServiceName("test"), SpanType::Cache))
+            .drop_rules(
+                DropRules::new()
+                    .reject_tag_matching("http.url", Regex::new("^/health").unwrap())
+                    .reject_shorter_than(Duration::from_secs(60)),
+            ),
+    );
+
+    tracing::subscriber::with_default(subscriber, || {
+        // when
+        tracing::info_span!("request", http_url = "/healthz", error_msg = "down").in_scope(|| {
+            tracing::info_span!("cache").in_scope(|| {});
+            tracing::info_span!("cache", error_msg = "miss").in_scope(|| {});
+        });
+        tracing::info_span!("request", http_url = "/orders", error_msg = "timeout").in_scope(|| {});
+    });
+
+    // then
+    let spans: Vec<(String, String)> = payloads
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|payload| {
+            let payload = serde_json::from_slice::<serde_json::Value>(payload).unwrap();
+            let span = &payload[0][0];
+            (
+                String::from(span["name"].as_str().unwrap()),
+                String::from(span["meta"]["error.msg"].as_str().unwrap()),
+            )
+        })
+        .collect();
+    assert_eq!(
+        spans,
+        [
+            (String::from("cache"), String::from("miss")),
+            (String::from("request"), String::from("timeout"))
+        ]
+    );
+}
+
+#[test]
+fn test_correlation_id_is_recorded_and_keys_the_trace() {
+    use std::sync::{Arc, Mutex};
+    use tracing_datadog_apm::correlation::id_from_correlation_id;
+
+    // given
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&payloads);
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(
+            ClientConfig::new()
+                .datadog_agent_port(1)
+                .inline_export(true)
+                .on_payload(move |payload, _| {
+                    seen.lock().unwrap().push(payload.to_vec());
+                }),
+        ),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("request"), (ServiceName("test"), SpanType::Web)),
+    );
+    let correlation_id = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+
+    tracing::subscriber::with_default(subscriber, || {
+        // when
+        tracing::info_span!(
+            "request",
+            trace_id = id_from_correlation_id(correlation_id).get(),
+            correlation_id = %correlation_id,
+        )
+        .in_scope(|| {});
+    });
+
+    // then
+    let payloads = payloads.lock().unwrap();
+    let payload = serde_json::from_slice::<serde_json::Value>(&payloads[0]).unwrap();
+    let span = &payload[0][0];
+    assert_eq!(span["meta"]["correlation_id"], correlation_id);
+    assert_eq!(
+        span["trace_id"],
+        id_from_correlation_id(correlation_id).get()
+    );
+}
+
+#[test]
+fn test_spans_dropped_by_sampling_are_sent_as_dogstatsd_metrics() {
+    // given
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(
+            ClientConfig::new()
+                .datadog_agent_port(1)
+                .sampled_out_stats_port(socket.local_addr().unwrap().port()),
+        ),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("dropped"), (ServiceName("test"), SpanType::Custom))
+            .sampler(reject_all),
+    );
+
+    // when
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info_span!("dropped", resource = "job").in_scope(|| {});
+    });
+
+    // then
+    let mut datagram = [0; 1024];
+    let len = socket.recv(&mut datagram).unwrap();
+    let lines = String::from_utf8_lossy(&datagram[..len]).into_owned();
+    assert!(
+        lines.starts_with("trace.dropped.hits:1|c|#service:test,resource:job"),
+        "{}",
+        lines
+    );
+}
+
+#[test]
+fn test_follows_from_links_spans() {
+    // given
+    let config = TracingSubscriberDatadogConfig::new()
+        .add_mapping(SpanName("batch"), (ServiceName("test"), SpanType::Custom))
+        .add_mapping(SpanName("item"), (ServiceName("test"), SpanType::Custom));
+
+    // when
+    let spans = exported_spans(config, || {
+        let item = tracing::info_span!(parent: None, "item");
+        let batch = tracing::info_span!(parent: None, "batch");
+        batch.follows_from(&item);
+        drop(batch);
+        drop(item);
+    });
+
+    // then
+    let item = spans.iter().find(|span| span["name"] == "item").unwrap();
+    let batch = spans.iter().find(|span| span["name"] == "batch").unwrap();
+    assert_eq!(batch["span_links"][0]["trace_id"], item["trace_id"]);
+    assert_eq!(batch["span_links"][0]["span_id"], item["span_id"]);
+    assert!(item.get("span_links").is_none());
+}
+
+#[test]
+fn test_nan_error_sample_rate_is_ignored() {
+    // given
+    let config = TracingSubscriberDatadogConfig::new()
+        .add_mapping(SpanName("job"), (ServiceName("test"), SpanType::Custom))
+        .sampler(reject_all)
+        .error_sample_rate(f64::NAN);
+
+    // when
+    let spans = exported_spans(config, || {
+        tracing::info_span!("job", error_msg = "timeout").in_scope(|| {});
+    });
+
+    // then the default rate keeps every error span
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0]["meta"]["error.msg"], "timeout");
+    assert_eq!(spans[0]["metrics"]["_sampling_priority_v1"], 2.0);
+}
+
+#[test]
+fn test_rejected_traces_are_sent_with_their_priority() {
+    // given
+    let config = TracingSubscriberDatadogConfig::new()
+        .add_mapping(SpanName("job"), (ServiceName("test"), SpanType::Custom))
+        .sampler(reject_all);
+
+    // when
+    let spans = exported_spans(config, || {
+        tracing::info_span!("job").in_scope(|| {
+            tracing::info_span!("job").in_scope(|| {});
+        });
+    });
+
+    // then the agent counts them in the stats before dropping them
+    assert_eq!(spans.len(), 2);
+    assert!(spans
+        .iter()
+        .all(|span| span["metrics"]["_sampling_priority_v1"] == -1.0));
+}
+
+fn reject_all(
+    _: &tracing_datadog_apm::datadog_client::SpanBuilder,
+    _: &tracing::Metadata<'_>,
+) -> tracing_datadog_apm::sampling::SamplingPriority {
+    tracing_datadog_apm::sampling::SamplingPriority::UserReject
+}
+
+#[test]
+fn test_new_traces_are_shed_over_max_open_spans() {
+    use tracing_datadog_apm::subscriber::DatadogSpanExt;
+
+    // given
+    let subscriber = TracingSubscriberDatadog::new(
+        Client::create_with_config(ClientConfig::new().datadog_agent_port(1)),
+        TracingSubscriberDatadogConfig::new()
+            .add_mapping(SpanName("request"), (ServiceName("test"), SpanType::Web))
+            .add_mapping(SpanName("query"), (ServiceName("test"), SpanType::Db))
+            .max_open_spans(2),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+
+    tracing::dispatcher::with_default(&dispatch, || {
+        let request = tracing::info_span!("request");
+        let query = tracing::info_span!(parent: &request, "query");
+
+        // when
+        let shed_request = tracing::info_span!(parent: None, "request");
+        let shed_query = tracing::info_span!(parent: &shed_request, "query");
+
+        // then
+        assert!(request.dd_span_id().is_some());
+        assert!(query.dd_span_id().is_some());
+        assert!(shed_request.dd_span_id().is_none());
+        assert!(shed_query.dd_span_id().is_none());
+        let subscriber = dispatch.downcast_ref::<TracingSubscriberDatadog>().unwrap();
+        assert_eq!(subscriber.shed_traces(), 1);
+
+        drop(query);
+        drop(request);
+        assert!(tracing::info_span!("request").dd_span_id().is_some());
+    });
+}